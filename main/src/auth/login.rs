@@ -9,7 +9,11 @@ use thiserror::Error as ThisError;
 
 use crate::{db::Database, models::User, utils::default_head};
 
-use super::LOGIN_COOKIE;
+use super::{
+    sessions::{start_session, UserAgent},
+    two_factor::{self, SecondFactor},
+    LOGIN_COOKIE,
+};
 
 fn login_form() -> malvolio::prelude::Form {
     malvolio::prelude::Form::new()
@@ -43,7 +47,21 @@ fn login_form() -> malvolio::prelude::Form {
 pub fn login_page() -> Html {
     Html::default()
         .head(default_head("Login".to_string()))
-        .body(Body::default().child(H1::new("Login")).child(login_form()))
+        .body(
+            Body::default()
+                .child(H1::new("Login"))
+                .child(login_form())
+                .child(
+                    A::new()
+                        .href("/auth/reset")
+                        .text("Forgotten your password?"),
+                )
+                .child(
+                    A::new()
+                        .href("/auth/sso")
+                        .text("Log in with your institution"),
+                ),
+        )
 }
 
 #[derive(ThisError, Debug)]
@@ -52,13 +70,47 @@ pub enum LoginError {
     UserNotFound,
     #[error("password not valid")]
     PasswordNotValid,
+    #[error("a two-factor authentication code is needed")]
+    TwoFactorRequired,
+    #[error("two-factor authentication code not valid")]
+    TwoFactorCodeNotValid,
+    #[error("too many incorrect two-factor authentication codes have been entered")]
+    TwoFactorLockedOut,
+    #[error(
+        "the user's institution requires two-factor authentication, which they haven't set up"
+    )]
+    TwoFactorSetupRequired,
     #[error("database error")]
     DatabaseError,
 }
 
+/// Starts a session for a user who has been authenticated.
+pub(super) async fn finish_login(
+    cookies: &CookieJar<'_>,
+    user_id: i32,
+    user_agent: UserAgent,
+    conn: &Database,
+) -> Result<(), LoginError> {
+    let token = conn
+        .run(move |c| start_session(user_id, user_agent.0.as_deref(), c))
+        .await
+        .map_err(|e| {
+            error!("{:#?}", e);
+            LoginError::DatabaseError
+        })?;
+    cookies.add_private(Cookie::new(LOGIN_COOKIE, token));
+    Ok(())
+}
+
+/// Checks the user's password and (if they have set it up) their two-factor authentication code.
+///
+/// If a code is needed but wasn't provided (or the user's institution requires two-factor
+/// authentication and they haven't set it up yet) we remember that the password was correct, so
+/// that the user can finish logging in from `two_factor`.
 async fn login_base(
     cookies: &CookieJar<'_>,
     data: &LoginData,
+    user_agent: UserAgent,
     conn: Database,
 ) -> Result<User, LoginError> {
     use crate::schema::users;
@@ -77,7 +129,29 @@ async fn login_base(
                 .map_err(|e| error!("{:#?}", e))
                 .unwrap_or(false)
             {
-                cookies.add_private(Cookie::new(LOGIN_COOKIE, user.id.to_string()));
+                let user_id = user.id;
+                let code = data.code.clone();
+                let second_factor = conn
+                    .run(move |c| two_factor::check_second_factor(user_id, code.as_deref(), c))
+                    .await
+                    .map_err(|e| {
+                        error!("{:#?}", e);
+                        LoginError::DatabaseError
+                    })?;
+                match second_factor {
+                    SecondFactor::NotNeeded | SecondFactor::Verified => {}
+                    SecondFactor::Missing => {
+                        two_factor::start_pending_login(cookies, user_id);
+                        return Err(LoginError::TwoFactorRequired);
+                    }
+                    SecondFactor::NotValid => return Err(LoginError::TwoFactorCodeNotValid),
+                    SecondFactor::LockedOut => return Err(LoginError::TwoFactorLockedOut),
+                    SecondFactor::SetupRequired => {
+                        two_factor::start_pending_login(cookies, user_id);
+                        return Err(LoginError::TwoFactorSetupRequired);
+                    }
+                }
+                finish_login(cookies, user_id, user_agent, &conn).await?;
                 Ok(user)
             } else {
                 Err(LoginError::PasswordNotValid)
@@ -94,6 +168,9 @@ async fn login_base(
 pub struct LoginData {
     identifier: String,
     password: String,
+    /// A two-factor authentication code (or recovery code). API clients can send this with the
+    /// password, rather than finishing the login at `/api/auth/login/two_factor`.
+    code: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -112,9 +189,10 @@ pub struct LoginErrorExplanation {
 pub async fn api_login(
     cookies: &CookieJar<'_>,
     data: Json<LoginData>,
+    user_agent: UserAgent,
     conn: Database,
 ) -> Json<LoginResponse> {
-    Json(match login_base(cookies, &data, conn).await {
+    Json(match login_base(cookies, &data, user_agent, conn).await {
         Ok(user) => LoginResponse {
             success: true,
             data: Some(user),
@@ -135,6 +213,38 @@ pub async fn api_login(
                     reason: "That password is not correct.".to_string(),
                 }),
             },
+            LoginError::TwoFactorRequired => LoginResponse {
+                success: false,
+                data: None,
+                error: Some(LoginErrorExplanation {
+                    reason: "A two-factor authentication code is needed to log in.".to_string(),
+                }),
+            },
+            LoginError::TwoFactorCodeNotValid => LoginResponse {
+                success: false,
+                data: None,
+                error: Some(LoginErrorExplanation {
+                    reason: "That two-factor authentication code is not correct.".to_string(),
+                }),
+            },
+            LoginError::TwoFactorLockedOut => LoginResponse {
+                success: false,
+                data: None,
+                error: Some(LoginErrorExplanation {
+                    reason: "Too many incorrect two-factor authentication codes have been \
+                    entered, so your account has been locked for a few minutes."
+                        .to_string(),
+                }),
+            },
+            LoginError::TwoFactorSetupRequired => LoginResponse {
+                success: false,
+                data: None,
+                error: Some(LoginErrorExplanation {
+                    reason: "Your institution requires two-factor authentication, which you need \
+                    to set up before you can log in."
+                        .to_string(),
+                }),
+            },
             LoginError::DatabaseError => LoginResponse {
                 success: false,
                 data: None,
@@ -146,20 +256,40 @@ pub async fn api_login(
     })
 }
 
+pub(super) fn logged_in_page() -> Html {
+    Html::default()
+        .head(default_head("Logged in".to_string()))
+        .body(
+            Body::default()
+                .child(H1::new("Logged in!"))
+                .child(P::with_text("You are now logged in."))
+                .child(
+                    A::new()
+                        .href("/auth/sessions")
+                        .text("See everywhere that you're logged in"),
+                )
+                .child(
+                    A::new()
+                        .href("/auth/tokens")
+                        .text("Manage your access tokens"),
+                )
+                .child(
+                    A::new()
+                        .href("/auth/two_factor")
+                        .text("Set up two-factor authentication"),
+                ),
+        )
+}
+
 #[post("/login", data = "<data>")]
 pub async fn html_login(
     cookies: &CookieJar<'_>,
     data: rocket::form::Form<LoginData>,
+    user_agent: UserAgent,
     conn: Database,
 ) -> Html {
-    match login_base(cookies, &data, conn).await {
-        Ok(_) => Html::default()
-            .head(default_head("Logged in".to_string()))
-            .body(
-                Body::default()
-                    .child(H1::new("Logged in!"))
-                    .child(P::with_text("You are now logged in.")),
-            ),
+    match login_base(cookies, &data, user_agent, conn).await {
+        Ok(_) => logged_in_page(),
         Err(e) => match e {
             LoginError::UserNotFound => Html::default()
                 .status(404)
@@ -182,6 +312,23 @@ pub async fn html_login(
                         .child(P::with_text("The password you've supplied isn't correct."))
                         .child(login_form()),
                 ),
+            LoginError::TwoFactorRequired => two_factor::code_page(None),
+            LoginError::TwoFactorCodeNotValid => {
+                two_factor::code_page(Some("That code isn't correct."))
+            }
+            LoginError::TwoFactorLockedOut => Html::default()
+                .status(403)
+                .head(default_head("Account locked".to_string()))
+                .body(
+                    Body::default()
+                        .child(H1::new("Account locked"))
+                        .child(P::with_text(
+                            "Too many incorrect two-factor authentication codes have been \
+                            entered, so your account has been locked for a few minutes. Please \
+                            try logging in again later.",
+                        )),
+                ),
+            LoginError::TwoFactorSetupRequired => two_factor::setup_required_page(),
             LoginError::DatabaseError => Html::default()
                 .status(500)
                 .head(default_head("Unknown error".to_string()))
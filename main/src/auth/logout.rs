@@ -2,20 +2,32 @@ use malvolio::prelude::*;
 use rocket::http::{Cookie, CookieJar};
 use rocket_contrib::json::Json;
 
-use crate::utils::default_head;
+use crate::{db::Database, utils::default_head};
 
-use super::LOGIN_COOKIE;
+use super::{sessions::end_session, LOGIN_COOKIE};
+
+/// Ends the session in the login cookie (if there is one), returning whether there was one.
+async fn logout_base(cookies: &CookieJar<'_>, conn: Database) -> bool {
+    let token = match cookies.get_private(LOGIN_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return false,
+    };
+    if let Err(e) = conn.run(move |c| end_session(&token, c)).await {
+        error!("{:#?}", e);
+    }
+    cookies.remove_private(Cookie::named(LOGIN_COOKIE));
+    true
+}
 
 #[get("/logout")]
-pub fn html_logout_user(cookies: &CookieJar<'_>) -> Html {
-    if cookies.get_private(LOGIN_COOKIE).is_none() {
+pub async fn html_logout_user(cookies: &CookieJar<'_>, conn: Database) -> Html {
+    if !logout_base(cookies, conn).await {
         return Html::default()
             .head(default_head("Cannot log you out.".to_string()))
             .body(
                 Body::default().child(H1::new("You are not logged in, so we cannot log you out.")),
             );
     }
-    cookies.remove_private(Cookie::named(LOGIN_COOKIE));
     Html::default()
         .head(default_head("Logged out.".to_string()))
         .body(Body::default().child(H1::new("You are logged out.".to_string())))
@@ -33,19 +45,18 @@ pub struct LogoutError {
 }
 
 #[get("/logout")]
-pub fn api_logout(cookies: &CookieJar<'_>) -> Json<LogoutResponse> {
-    Json(if cookies.get_private(LOGIN_COOKIE).is_none() {
+pub async fn api_logout(cookies: &CookieJar<'_>, conn: Database) -> Json<LogoutResponse> {
+    Json(if logout_base(cookies, conn).await {
+        LogoutResponse {
+            success: true,
+            error: None,
+        }
+    } else {
         LogoutResponse {
             success: false,
             error: Some(LogoutError {
                 reason: "You are not logged in, so you cannot be logged out.".to_string(),
             }),
         }
-    } else {
-        cookies.remove_private(Cookie::named(LOGIN_COOKIE));
-        LogoutResponse {
-            success: true,
-            error: None,
-        }
     })
 }
@@ -3,25 +3,104 @@ This source code file is distributed subject to the terms of the GNU Affero Gene
 A copy of this exp: (), user_id: () exp: (), user_id: () exp: (), user_id: () exp: (), user_id: () license can be found in the `licenses` directory at the root of this project.
 */
 
-use rocket::{outcome::IntoOutcome, request::FromRequest};
+use rocket::{http::Status, outcome::Outcome, request::FromRequest};
 use thiserror::Error as ThisError;
 
+use self::tokens::Bearer;
+
 pub const LOGIN_COOKIE: &str = "AUTHORISED";
 
 mod login;
 mod logout;
 mod register;
 mod reset;
+mod sessions;
+mod sso;
+mod tokens;
+mod two_factor;
 mod verify;
 
 pub use login::{api_login, html_login, login_page};
 pub use logout::{api_logout, html_logout_user};
 pub use register::{html_register, register_page};
+pub use reset::{
+    api_request_reset, api_reset_password, html_request_reset, html_reset_password,
+    request_reset_page, reset_password_page,
+};
+pub use sessions::{
+    api_list_sessions, api_revoke_session, html_list_sessions, html_revoke_other_sessions,
+    html_revoke_session,
+};
+pub use sso::{api_start_sso, html_start_sso, sso_callback, sso_page};
+pub use tokens::{
+    api_create_token, api_issue_jwt, api_list_tokens, api_revoke_token, html_create_token,
+    html_list_tokens, html_revoke_token,
+};
+pub use two_factor::{
+    api_begin_enrolment, api_confirm_enrolment, api_disable_two_factor,
+    api_regenerate_recovery_codes, api_two_factor_login, api_two_factor_settings,
+    html_begin_enrolment, html_confirm_enrolment, html_disable_two_factor,
+    html_regenerate_recovery_codes, html_two_factor_login, html_two_factor_settings,
+};
 pub use verify::verify_email;
 
+/// The key which is used to sign the tokens which we give out (password reset links and bearer
+/// tokens). Anybody who knows the key can forge these tokens (and so log in as anybody), so there
+/// is no default key – if `SECRET_KEY` isn't set then we don't issue or accept them.
+#[cfg(not(test))]
+pub(crate) fn secret_key() -> Option<String> {
+    let key = std::env::var("SECRET_KEY").ok();
+    if key.is_none() {
+        error!("SECRET_KEY isn't set, so tokens can't be issued or checked");
+    }
+    key
+}
+
+/// The tests don't set `SECRET_KEY`, so they use a fixed key instead.
+#[cfg(test)]
+pub(crate) fn secret_key() -> Option<String> {
+    Some(
+        std::env::var("SECRET_KEY")
+            .unwrap_or_else(|_| "NNnXxqFeQ/1Sn8lh9MtlIW2uePR4TL/1O5dB2CPkTmg=".to_string()),
+    )
+}
+
+/// The key which tokens are signed with (see `secret_key`).
+pub(crate) fn encoding_key() -> Option<jwt::EncodingKey> {
+    match jwt::EncodingKey::from_base64_secret(&secret_key()?) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            error!("the secret key is invalid: {:#?}", e);
+            None
+        }
+    }
+}
+
+/// The key which the signatures of tokens are checked with (see `secret_key`).
+pub(crate) fn decoding_key() -> Option<jwt::DecodingKey<'static>> {
+    match jwt::DecodingKey::from_base64_secret(&secret_key()?) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            error!("the secret key is invalid: {:#?}", e);
+            None
+        }
+    }
+}
+
 #[derive(ThisError, Debug)]
-pub enum AuthError {}
+pub enum AuthError {
+    #[error("the bearer token doesn't have the scope needed for this request")]
+    InsufficientScope,
+}
 
+/// The id of the logged in user.
+///
+/// API routes also accept bearer tokens (see `tokens`) in place of the login cookie. If the token
+/// doesn't have the scope which the request needs, the request fails with `403 Forbidden`.
+///
+/// Checking the login cookie (against the user's sessions) uses a database connection, so this
+/// guard has to come before any `Database` guards of a route (otherwise the tests, which only have
+/// one connection, hang).
 #[derive(Debug, Copy, Clone)]
 pub struct AuthCookie(pub i32);
 
@@ -32,12 +111,16 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthCookie {
     async fn from_request(
         request: &'a rocket::Request<'r>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        request
-            .cookies()
-            .get_private(LOGIN_COOKIE)
-            .and_then(|cookie| cookie.value().parse().ok())
-            .map(AuthCookie)
-            .or_forward(())
+        if let Some(session) = sessions::current_session(request).await {
+            return Outcome::Success(AuthCookie(session.user_id));
+        }
+        match tokens::bearer_user(request).await {
+            Bearer::Allowed(user_id) => Outcome::Success(AuthCookie(user_id)),
+            Bearer::InsufficientScope => {
+                Outcome::Failure((Status::Forbidden, AuthError::InsufficientScope))
+            }
+            Bearer::Missing => Outcome::Forward(()),
+        }
     }
 }
 
@@ -51,12 +134,14 @@ impl<'a, 'r> FromRequest<'a, 'r> for OptionAuthCookie {
     async fn from_request(
         request: &'a rocket::Request<'r>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        rocket::request::Outcome::Success(OptionAuthCookie(
-            request
-                .cookies()
-                .get_private(LOGIN_COOKIE)
-                .and_then(|cookie| cookie.value().parse().ok()),
-        ))
+        let user_id = match sessions::current_session(request).await {
+            Some(session) => Some(session.user_id),
+            None => match tokens::bearer_user(request).await {
+                Bearer::Allowed(user_id) => Some(user_id),
+                Bearer::InsufficientScope | Bearer::Missing => None,
+            },
+        };
+        Outcome::Success(OptionAuthCookie(user_id))
     }
 }
 
@@ -71,16 +156,25 @@ mod test_authentication {
     use crate::{
         db::Database,
         models::{NewUser, User},
-        utils::{client, login_user},
+        utils::{client, create_user, login_user},
     };
     use diesel::prelude::*;
-    use rocket::http::ContentType;
+    use rocket::{
+        http::{ContentType, Cookie, Status},
+        tokio::sync::Mutex,
+    };
     use wiremock::{
         matchers::{method, path_regex},
         Mock, MockServer, ResponseTemplate,
     };
 
-    use super::verify::EmailVerificationToken;
+    use super::{verify::EmailVerificationToken, LOGIN_COOKIE};
+
+    lazy_static! {
+        /// The mail server is set through environment variables (which all the tests share), so
+        /// tests which send emails take turns.
+        static ref MAIL_SERVER: Mutex<()> = Mutex::new(());
+    }
 
     #[rocket::async_test]
     async fn test_register_validation() {
@@ -107,6 +201,7 @@ mod test_authentication {
 
     #[rocket::async_test]
     async fn test_auth() {
+        let _mail_server = MAIL_SERVER.lock().await;
         let mock_server = MockServer::start().await;
         std::env::set_var("SENDGRID_API_KEY", "SomeRandomAPIKey");
         std::env::set_var("SENDGRID_API_SERVER", mock_server.uri());
@@ -155,6 +250,559 @@ mod test_authentication {
         // test can login
         login_user(USERNAME, PASSWORD, &client).await;
     }
+    #[rocket::async_test]
+    async fn test_password_reset() {
+        const NEW_PASSWORD: &str = "AnotherPasswordWhichM33tsTh3Criteri@";
+        let _mail_server = MAIL_SERVER.lock().await;
+        let mock_server = MockServer::start().await;
+        std::env::set_var("SENDGRID_API_KEY", "SomeRandomAPIKey");
+        std::env::set_var("SENDGRID_API_SERVER", mock_server.uri());
+        Mock::given(method("post"))
+            .and(path_regex("/v3/mail/send"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let client = &client().await;
+        create_user(USERNAME, EMAIL, TIMEZONE, PASSWORD, client).await;
+        let login_res = client
+            .post("/auth/login")
+            .header(ContentType::Form)
+            .body(format!("identifier={}&password={}", USERNAME, PASSWORD))
+            .dispatch()
+            .await;
+        let old_login = login_res
+            .cookies()
+            .get(LOGIN_COOKIE)
+            .expect("should have logged in")
+            .clone()
+            .into_owned();
+        let logged_in = |cookie: Cookie<'static>| async move {
+            client
+                .get("/api/class")
+                .cookie(cookie)
+                .dispatch()
+                .await
+                .status()
+                == Status::Ok
+        };
+        assert!(logged_in(old_login.clone()).await);
+
+        // nobody finds out whether an account exists (and no email is sent if it doesn't)
+        for identifier in &["somebody-else", EMAIL] {
+            let res = client
+                .post("/auth/reset")
+                .header(ContentType::Form)
+                .body(format!("identifier={}", identifier))
+                .dispatch()
+                .await;
+            let page = res.into_string().await.expect("invalid body response");
+            assert!(page.contains("Check your email"));
+        }
+        let requests = mock_server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let email = String::from_utf8(requests[0].body.clone()).unwrap();
+        let code: String = email[email.find("code=").expect("no reset link") + "code=".len()..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c))
+            .collect();
+        let page = client
+            .get(format!("/auth/reset/password?code={}", code))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .expect("invalid body response");
+        assert!(page.contains("Choose a new password"));
+
+        let code = &code;
+        let reset = |password: &'static str, confirmation: &'static str| {
+            client
+                .post("/auth/reset/password")
+                .header(ContentType::Form)
+                .body(format!(
+                    "code={}&password={}&password_confirmation={}",
+                    code, password, confirmation
+                ))
+                .dispatch()
+        };
+        let page = reset(NEW_PASSWORD, PASSWORD)
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(page.contains("Passwords don't match"));
+        let page = reset(NEW_PASSWORD, NEW_PASSWORD)
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(page.contains("Your password has been changed"));
+        // each link only works once
+        let page = reset(PASSWORD, PASSWORD).await.into_string().await.unwrap();
+        assert!(page.contains("doesn't work any more"));
+
+        // the old login has stopped working, but the new password works
+        assert!(!logged_in(old_login).await);
+        login_user(USERNAME, NEW_PASSWORD, client).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_sessions() {
+        use rocket::{http::Header, local::asynchronous::Client};
+        use serde_json::Value;
+
+        // the cookies are passed explicitly, so that we can use more than one session at a time
+        let client = &Client::untracked(crate::utils::launch()).await.unwrap();
+        create_user(USERNAME, EMAIL, TIMEZONE, PASSWORD, client).await;
+        let login = |device: &'static str| async move {
+            client
+                .post("/auth/login")
+                .header(ContentType::Form)
+                .header(Header::new("User-Agent", device))
+                .body(format!("identifier={}&password={}", USERNAME, PASSWORD))
+                .dispatch()
+                .await
+                .cookies()
+                .get(LOGIN_COOKIE)
+                .expect("should have logged in")
+                .clone()
+                .into_owned()
+        };
+        let logged_in = |cookie: Cookie<'static>| async move {
+            client
+                .get("/api/class")
+                .cookie(cookie)
+                .dispatch()
+                .await
+                .status()
+                == Status::Ok
+        };
+        let phone = login("Phone").await;
+        let laptop = login("Laptop").await;
+        assert!(logged_in(phone.clone()).await);
+        assert!(logged_in(laptop.clone()).await);
+
+        let sessions: Value = serde_json::from_str(
+            &client
+                .get("/api/auth/sessions")
+                .cookie(laptop.clone())
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .expect("invalid body response"),
+        )
+        .unwrap();
+        let sessions = sessions["data"]
+            .as_array()
+            .expect("no sessions were listed");
+        assert_eq!(sessions.len(), 2);
+        let session = |device: &str| {
+            sessions
+                .iter()
+                .find(|session| session["user_agent"] == device)
+                .expect("session not listed")
+        };
+        assert_eq!(session("Laptop")["current"], true);
+        assert_eq!(session("Phone")["current"], false);
+        assert!(session("Phone").get("token_hash").is_none());
+
+        // logging the phone out from the laptop
+        let res: Value = serde_json::from_str(
+            &client
+                .post("/api/auth/sessions/revoke")
+                .cookie(laptop.clone())
+                .header(ContentType::JSON)
+                .body(format!("{{\"id\": {}}}", session("Phone")["id"]))
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .expect("invalid body response"),
+        )
+        .unwrap();
+        assert_eq!(res["success"], true);
+        assert!(!logged_in(phone).await);
+        assert!(logged_in(laptop.clone()).await);
+
+        // the session is ended, so the cookie stops working even if it is sent again
+        client
+            .get("/api/auth/logout")
+            .cookie(laptop.clone())
+            .dispatch()
+            .await;
+        assert!(!logged_in(laptop).await);
+
+        // only the hash of a session's token is stored
+        let (token, token_hash) = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                use crate::schema::{session, users};
+                let user_id = users::table
+                    .filter(users::username.eq(USERNAME))
+                    .select(users::id)
+                    .first::<i32>(c)
+                    .unwrap();
+                let token = super::sessions::start_session(user_id, None, c).unwrap();
+                let token_hash = session::table
+                    .filter(session::user_id.eq(user_id))
+                    .select(session::token_hash)
+                    .first::<String>(c)
+                    .unwrap();
+                (token, token_hash)
+            })
+            .await;
+        assert_ne!(token, token_hash);
+        assert_eq!(token_hash, super::tokens::hash_token(&token));
+    }
+
+    #[rocket::async_test]
+    async fn test_access_tokens() {
+        use rocket::{http::Header, local::asynchronous::Client};
+        use serde_json::Value;
+
+        let client = &Client::untracked(crate::utils::launch()).await.unwrap();
+        create_user(USERNAME, EMAIL, TIMEZONE, PASSWORD, client).await;
+        let cookie = client
+            .post("/auth/login")
+            .header(ContentType::Form)
+            .body(format!("identifier={}&password={}", USERNAME, PASSWORD))
+            .dispatch()
+            .await
+            .cookies()
+            .get(LOGIN_COOKIE)
+            .expect("should have logged in")
+            .clone()
+            .into_owned();
+        let post_json = |path: &'static str, body: String| {
+            let cookie = cookie.clone();
+            async move {
+                let res: Value = serde_json::from_str(
+                    &client
+                        .post(path)
+                        .cookie(cookie)
+                        .header(ContentType::JSON)
+                        .body(body)
+                        .dispatch()
+                        .await
+                        .into_string()
+                        .await
+                        .expect("invalid body response"),
+                )
+                .unwrap();
+                assert_eq!(res["success"], true, "{}", res);
+                res["data"].clone()
+            }
+        };
+        let create_token = |scope: &'static str| {
+            post_json(
+                "/api/auth/tokens/create",
+                format!("{{\"name\": \"script\", \"scopes\": [\"{}\"]}}", scope),
+            )
+        };
+        let list_classes = |token: String| async move {
+            client
+                .get("/api/class")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .dispatch()
+                .await
+                .status()
+        };
+        let create_class = |token: String| async move {
+            client
+                .post("/api/class/create")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .header(ContentType::JSON)
+                .body("{\"name\": \"Some class\", \"description\": \"A class.\"}")
+                .dispatch()
+                .await
+                .status()
+        };
+
+        // tokens can only make the requests which their scopes allow
+        let read_only = create_token("read_only").await;
+        let read_only_token = read_only["token"].as_str().unwrap().to_string();
+        assert_eq!(list_classes(read_only_token.clone()).await, Status::Ok);
+        assert_eq!(
+            create_class(read_only_token.clone()).await,
+            Status::Forbidden
+        );
+        // joining a class is a `GET` request, but it isn't read only
+        let join_class = client
+            .get("/api/join/some-join-code")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", read_only_token),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(join_class.status(), Status::Forbidden);
+        let admin = create_token("admin").await;
+        let admin_token = admin["token"].as_str().unwrap().to_string();
+        assert_eq!(create_class(admin_token).await, Status::Ok);
+        assert_ne!(list_classes("not-a-token".to_string()).await, Status::Ok);
+
+        // revoked tokens stop working
+        post_json(
+            "/api/auth/tokens/revoke",
+            format!("{{\"id\": {}}}", read_only["id"]),
+        )
+        .await;
+        assert_ne!(list_classes(read_only_token).await, Status::Ok);
+
+        // JWTs work until the session which they were created from ends
+        let jwt = post_json(
+            "/api/auth/token",
+            "{\"scopes\": [\"read_only\"]}".to_string(),
+        )
+        .await;
+        let jwt = jwt["token"].as_str().unwrap().to_string();
+        assert_eq!(list_classes(jwt.clone()).await, Status::Ok);
+        assert_eq!(create_class(jwt.clone()).await, Status::Forbidden);
+        // JWTs which were signed for something else (such as the links which we email) don't work
+        let mut claims = jwt::dangerous_insecure_decode::<super::tokens::BearerToken>(&jwt)
+            .unwrap()
+            .claims;
+        let forge = |claims: &super::tokens::BearerToken| {
+            jwt::encode(
+                &jwt::Header::default(),
+                claims,
+                &super::encoding_key().unwrap(),
+            )
+            .unwrap()
+        };
+        claims.typ = "reset".to_string();
+        assert_ne!(list_classes(forge(&claims)).await, Status::Ok);
+        // and neither do JWTs which weren't created from the session that they name
+        claims.typ = "bearer".to_string();
+        claims.session = "a-guess".to_string();
+        assert_ne!(list_classes(forge(&claims)).await, Status::Ok);
+        client
+            .get("/api/auth/logout")
+            .cookie(cookie.clone())
+            .dispatch()
+            .await;
+        assert_ne!(list_classes(jwt).await, Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_two_factor() {
+        use rocket::local::asynchronous::Client;
+        use serde_json::Value;
+
+        use super::two_factor::code_at;
+
+        let client = &Client::untracked(crate::utils::launch()).await.unwrap();
+        create_user(USERNAME, EMAIL, TIMEZONE, PASSWORD, client).await;
+        // returns the response, and the cookies which were set
+        let post = |path: &'static str, cookies: Vec<Cookie<'static>>, body: String| async move {
+            let res = cookies
+                .into_iter()
+                .fold(client.post(path), |req, cookie| req.cookie(cookie))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .await;
+            let cookies = res
+                .cookies()
+                .iter()
+                .map(|cookie| cookie.clone().into_owned())
+                .collect::<Vec<_>>();
+            let res: Value =
+                serde_json::from_str(&res.into_string().await.expect("invalid body response"))
+                    .unwrap();
+            (res, cookies)
+        };
+        let login = |code: Option<String>| {
+            post(
+                "/api/auth/login",
+                vec![],
+                serde_json::json!({
+                    "identifier": USERNAME,
+                    "password": PASSWORD,
+                    "code": code
+                })
+                .to_string(),
+            )
+        };
+        let find_cookie = |cookies: &[Cookie<'static>], name: &str| {
+            cookies.iter().find(|cookie| cookie.name() == name).cloned()
+        };
+        let current_code = |secret: &str| code_at(secret, chrono::Utc::now().timestamp());
+
+        // setting up two-factor authentication
+        let (res, cookies) = login(None).await;
+        assert_eq!(res["success"], true);
+        let session = find_cookie(&cookies, LOGIN_COOKIE).expect("should have logged in");
+        let (res, _) = post(
+            "/api/auth/two_factor/enrol",
+            vec![session.clone()],
+            String::new(),
+        )
+        .await;
+        let secret = res["data"]["secret"].as_str().unwrap().to_string();
+        let (res, _) = post(
+            "/api/auth/two_factor/confirm",
+            vec![session.clone()],
+            format!("{{\"code\": \"{}\"}}", current_code(&secret)),
+        )
+        .await;
+        let recovery_codes = res["data"].as_array().expect("no recovery codes").clone();
+        assert_eq!(recovery_codes.len(), 10);
+
+        // the password isn't enough to log in any more
+        let (res, cookies) = login(None).await;
+        assert_eq!(res["success"], false);
+        assert!(find_cookie(&cookies, LOGIN_COOKIE).is_none());
+        let pending = find_cookie(&cookies, "TWO_FACTOR_PENDING").expect("no login in progress");
+        let (res, _) = post(
+            "/api/auth/login/two_factor",
+            vec![pending.clone()],
+            "{\"code\": \"not-a-code\"}".to_string(),
+        )
+        .await;
+        assert_eq!(res["success"], false);
+        let recovery_code = |i: usize| recovery_codes[i].as_str().unwrap().to_string();
+        let (res, cookies) = post(
+            "/api/auth/login/two_factor",
+            vec![pending],
+            format!("{{\"code\": \"{}\"}}", recovery_code(0)),
+        )
+        .await;
+        assert_eq!(res["success"], true);
+        assert!(find_cookie(&cookies, LOGIN_COOKIE).is_some());
+        // recovery codes only work once
+        let (res, _) = login(Some(recovery_code(0))).await;
+        assert_eq!(res["success"], false);
+
+        // codes from the authenticator also only work once (the code used to confirm the setup was
+        // for the current time step, so this is for the next one)
+        let next_code = code_at(&secret, chrono::Utc::now().timestamp() + 30);
+        let (res, _) = login(Some(next_code.clone())).await;
+        assert_eq!(res["success"], true);
+        let (res, _) = login(Some(next_code)).await;
+        assert_eq!(res["success"], false);
+
+        // turning two-factor authentication off again
+        let (res, _) = post(
+            "/api/auth/two_factor/disable",
+            vec![session],
+            format!("{{\"code\": \"{}\"}}", recovery_code(1)),
+        )
+        .await;
+        assert_eq!(res["success"], true);
+        let (res, _) = login(None).await;
+        assert_eq!(res["success"], true);
+    }
+
+    #[rocket::async_test]
+    async fn test_two_factor_lockout() {
+        use rocket::local::asynchronous::Client;
+        use serde_json::Value;
+
+        use super::two_factor::code_at;
+        use crate::schema::totp;
+
+        let client = &Client::untracked(crate::utils::launch()).await.unwrap();
+        create_user(USERNAME, EMAIL, TIMEZONE, PASSWORD, client).await;
+        // returns the response, and the cookies which were set
+        let post = |path: &'static str, cookies: Vec<Cookie<'static>>, body: String| async move {
+            let res = cookies
+                .into_iter()
+                .fold(client.post(path), |req, cookie| req.cookie(cookie))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .await;
+            let cookies = res
+                .cookies()
+                .iter()
+                .map(|cookie| cookie.clone().into_owned())
+                .collect::<Vec<_>>();
+            let res: Value =
+                serde_json::from_str(&res.into_string().await.expect("invalid body response"))
+                    .unwrap();
+            (res, cookies)
+        };
+        let login = |code: Option<String>| {
+            post(
+                "/api/auth/login",
+                vec![],
+                serde_json::json!({
+                    "identifier": USERNAME,
+                    "password": PASSWORD,
+                    "code": code
+                })
+                .to_string(),
+            )
+        };
+        let find_cookie = |cookies: &[Cookie<'static>], name: &str| {
+            cookies.iter().find(|cookie| cookie.name() == name).cloned()
+        };
+        let enter_code = |pending: Cookie<'static>, code: String| {
+            post(
+                "/api/auth/login/two_factor",
+                vec![pending],
+                format!("{{\"code\": \"{}\"}}", code),
+            )
+        };
+
+        let (_, cookies) = login(None).await;
+        let session = find_cookie(&cookies, LOGIN_COOKIE).expect("should have logged in");
+        let (res, _) = post(
+            "/api/auth/two_factor/enrol",
+            vec![session.clone()],
+            String::new(),
+        )
+        .await;
+        let secret = res["data"]["secret"].as_str().unwrap().to_string();
+        let (res, _) = post(
+            "/api/auth/two_factor/confirm",
+            vec![session],
+            format!(
+                "{{\"code\": \"{}\"}}",
+                code_at(&secret, chrono::Utc::now().timestamp())
+            ),
+        )
+        .await;
+        assert_eq!(res["success"], true);
+        // this is for the next time step (the one used to confirm the setup can't be used again)
+        let next_code = code_at(&secret, chrono::Utc::now().timestamp() + 30);
+
+        // the account is locked after too many incorrect codes...
+        let (_, cookies) = login(None).await;
+        let pending = find_cookie(&cookies, "TWO_FACTOR_PENDING").expect("no login in progress");
+        for _ in 0..4 {
+            let (res, _) = enter_code(pending.clone(), "not-a-code".to_string()).await;
+            assert_eq!(res["success"], false);
+            assert!(!res["error"]["reason"].as_str().unwrap().contains("locked"));
+        }
+        let (res, _) = enter_code(pending.clone(), "not-a-code".to_string()).await;
+        assert!(res["error"]["reason"].as_str().unwrap().contains("locked"));
+        // ...so nobody can log in (even with the right code) until the lock expires
+        let (res, cookies) = enter_code(pending, next_code.clone()).await;
+        assert_eq!(res["success"], false);
+        assert!(find_cookie(&cookies, LOGIN_COOKIE).is_none());
+        let (res, _) = login(Some(next_code.clone())).await;
+        assert_eq!(res["success"], false);
+        assert!(res["error"]["reason"].as_str().unwrap().contains("locked"));
+
+        Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                diesel::update(totp::table)
+                    .set(totp::locked_until.eq(chrono::Utc::now().naive_utc()))
+                    .execute(c)
+            })
+            .await
+            .unwrap();
+        let (res, _) = login(Some(next_code)).await;
+        assert_eq!(res["success"], true);
+    }
+
     #[rocket::async_test]
     async fn test_email_verification() {
         use crate::schema::users::dsl as users;
@@ -109,6 +109,17 @@ pub enum RegisterError {
     DatabaseError,
 }
 
+/// Checks that a new password is acceptable, and then hashes it.
+pub(super) fn hash_password(password: &str, confirmation: &str) -> Result<String, RegisterError> {
+    if password != confirmation {
+        return Err(RegisterError::NonMatchingPasswords);
+    }
+    hash(password, DEFAULT_COST).map_err(|err| {
+        error!("{:#?}", err);
+        RegisterError::EncryptingPasswordError
+    })
+}
+
 pub async fn register_base(
     data: &RegisterData,
     conn: Database,
@@ -125,16 +136,7 @@ pub async fn register_base(
     if !EMAIL_RE.is_match(&data.email) {
         return Err(RegisterError::InvalidEmail);
     }
-    if data.password != data.password_confirmation {
-        return Err(RegisterError::NonMatchingPasswords);
-    }
-    let hashed_password = match hash(&data.password, DEFAULT_COST) {
-        Ok(string) => string,
-        Err(err) => {
-            error!("{:#?}", err);
-            return Err(RegisterError::EncryptingPasswordError);
-        }
-    };
+    let hashed_password = hash_password(&data.password, &data.password_confirmation)?;
     let data_clone = data.clone();
     match conn
         .run(move |c| {
@@ -1,11 +1,419 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+//! Lets people who have forgotten their password set a new one.
+//!
+//! We email a link containing a signed token to the user. The token records when it was created,
+//! and stops working once the password has been changed after that time (so each link can only be
+//! used once). Changing the password also ends all of the user's sessions.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
 use malvolio::prelude::*;
+use mercutio::Apply;
+use portia::form::{FormStyle, FormSubmitInputStyle, FormTextInputStyle};
+use rocket::http::{Cookie, CookieJar};
+use rocket_contrib::json::Json;
+use thiserror::Error as ThisError;
+
+use crate::{
+    db::Database,
+    email::{EmailBuilder, RecipientBuilder, RecipientsBuilder, SendMail, SendgridMailSender},
+    models::User,
+    utils::{default_head, json_response::ApiResponse},
+};
+
+use super::{
+    decoding_key, encoding_key,
+    register::{hash_password, RegisterError},
+    sessions::end_all_sessions,
+    LOGIN_COOKIE,
+};
+
+/// How long password reset links work for.
+const RESET_LINK_VALIDITY_HOURS: i64 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetToken {
+    pub exp: usize,
+    pub user_id: i32,
+    /// When the token was created (in milliseconds since the Unix epoch).
+    pub issued: i64,
+}
+
+fn request_reset_form() -> malvolio::prelude::Form {
+    malvolio::prelude::Form::new()
+        .apply(FormStyle)
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/reset"))
+        .child(
+            Input::default()
+                .apply(FormTextInputStyle)
+                .attribute(Type::Text)
+                .attribute(Placeholder::new("Username or email"))
+                .attribute(Name::new("identifier")),
+        )
+        .child(Br)
+        .child(
+            Input::default()
+                .apply(FormSubmitInputStyle)
+                .attribute(Type::Submit)
+                .attribute(Value::new("Reset my password")),
+        )
+}
+
+fn reset_password_form(code: &str) -> malvolio::prelude::Form {
+    malvolio::prelude::Form::new()
+        .apply(FormStyle)
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/reset/password"))
+        .child(
+            Input::default()
+                .attribute(Type::Hidden)
+                .attribute(Name::new("code"))
+                .attribute(Value::new(code.to_string())),
+        )
+        .child(
+            Input::default()
+                .apply(FormTextInputStyle)
+                .attribute(Type::Password)
+                .attribute(Placeholder::new("New password"))
+                .attribute(Name::new("password")),
+        )
+        .child(Br)
+        .child(
+            Input::default()
+                .apply(FormTextInputStyle)
+                .attribute(Type::Password)
+                .attribute(Placeholder::new("New password confirmation"))
+                .attribute(Name::new("password_confirmation")),
+        )
+        .child(Br)
+        .child(
+            Input::default()
+                .apply(FormSubmitInputStyle)
+                .attribute(Type::Submit)
+                .attribute(Value::new("Set my password")),
+        )
+}
 
 #[get("/reset")]
-pub fn reset() -> Html {
-    todo!()
+pub fn request_reset_page() -> Html {
+    Html::default()
+        .head(default_head("Reset your password".to_string()))
+        .body(
+            Body::default()
+                .child(H1::new("Reset your password"))
+                .child(P::with_text(
+                    "Enter your username or email and we'll email you a link which you can use to \
+                    choose a new password.",
+                ))
+                .child(request_reset_form()),
+        )
+}
+
+#[derive(ThisError, Debug)]
+pub enum ResetError {
+    #[error("invalid or expired reset link")]
+    InvalidCode,
+    #[error("passwords do not match")]
+    NonMatchingPasswords,
+    #[error("encrypting password error")]
+    EncryptingPasswordError,
+    #[error("could not send the reset email")]
+    EmailError,
+    #[error("could not create the reset link")]
+    SigningError,
+    #[error("database error")]
+    DatabaseError,
+}
+
+impl From<RegisterError> for ResetError {
+    fn from(e: RegisterError) -> Self {
+        match e {
+            RegisterError::NonMatchingPasswords => Self::NonMatchingPasswords,
+            RegisterError::EncryptingPasswordError => Self::EncryptingPasswordError,
+            _ => Self::DatabaseError,
+        }
+    }
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct RequestResetData {
+    identifier: String,
+}
+
+async fn request_reset_base(data: &RequestResetData, conn: Database) -> Result<(), ResetError> {
+    use crate::schema::users;
+    let identifier = data.identifier.clone();
+    let user = match conn
+        .run(move |c| {
+            users::table
+                .filter(users::username.eq(&identifier))
+                .or_filter(users::email.eq(&identifier))
+                .first::<User>(c)
+        })
+        .await
+    {
+        Ok(user) => user,
+        // we don't tell people whether an account exists (otherwise this form could be used to
+        // find out who has registered)
+        Err(diesel::result::Error::NotFound) => return Ok(()),
+        Err(e) => {
+            error!("{:#?}", e);
+            return Err(ResetError::DatabaseError);
+        }
+    };
+    let now = Utc::now();
+    let key = encoding_key().ok_or(ResetError::SigningError)?;
+    let code = jwt::encode(
+        &jwt::Header::default(),
+        &PasswordResetToken {
+            exp: (now + Duration::hours(RESET_LINK_VALIDITY_HOURS)).timestamp() as usize,
+            user_id: user.id,
+            issued: now.timestamp_millis(),
+        },
+        &key,
+    )
+    .map_err(|e| {
+        error!("{:#?}", e);
+        ResetError::SigningError
+    })?;
+    let reset_link = format!("/auth/reset/password?code={}", code);
+    SendgridMailSender::default()
+        .send(
+            &EmailBuilder::default()
+                .subject("Reset your password".to_string())
+                .plaintext(Some(format!(
+                    "Somebody (hopefully you) asked to reset your password. If it was you, copy \
+                    and paste this link into your browser (it works for an hour): {}",
+                    reset_link
+                )))
+                .html_text(Some(
+                    Html::new()
+                        .head(default_head("Reset your password".to_string()))
+                        .body(
+                            Body::new()
+                                .child(P::with_text(
+                                    "Somebody (hopefully you) asked to reset your password. If it \
+                                    was you, use this link (which works for an hour) to choose a \
+                                    new one.",
+                                ))
+                                .child(A::new().href(reset_link).text("Reset my password")),
+                        )
+                        .to_string(),
+                ))
+                .recipients(
+                    RecipientsBuilder::default()
+                        .recipients(vec![RecipientBuilder::default()
+                            .email(user.email.clone())
+                            .name(user.username.clone())
+                            .build()
+                            .unwrap()])
+                        .build()
+                        .unwrap(),
+                )
+                .from(("Lovelace".to_string(), "no-reply@lovelace.ga".to_string()))
+                .reply_to(("Lovelace".to_string(), "contact@lovelace.ga".to_string()))
+                .build()
+                .unwrap(),
+        )
+        .await
+        .map_err(|_| ResetError::EmailError)
+}
+
+#[post("/reset", data = "<data>")]
+pub async fn html_request_reset(
+    data: rocket::form::Form<RequestResetData>,
+    conn: Database,
+) -> Html {
+    match request_reset_base(&data, conn).await {
+        Ok(()) => Html::default()
+            .head(default_head("Check your email".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("Check your email"))
+                    .child(P::with_text(
+                        "If there is an account with that username or email, we've sent it a \
+                        link which you can use to reset your password.",
+                    )),
+            ),
+        Err(e) => Html::default()
+            .status(500)
+            .head(default_head("Error".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("Something went wrong"))
+                    .child(P::with_text(match e {
+                        ResetError::EmailError => {
+                            "We couldn't send you an email. Please try again in a little while."
+                        }
+                        _ => {
+                            "Something's up on our end. We're working to fix it as fast as we can!"
+                        }
+                    }))
+                    .child(request_reset_form()),
+            ),
+    }
+}
+
+#[post("/reset", data = "<data>")]
+pub async fn api_request_reset(
+    data: Json<RequestResetData>,
+    conn: Database,
+) -> Json<ApiResponse<()>> {
+    Json(match request_reset_base(&data, conn).await {
+        Ok(()) => ApiResponse::new_ok(()),
+        Err(ResetError::EmailError) => ApiResponse::new_err("Could not send the reset email."),
+        Err(ResetError::SigningError) => ApiResponse::new_err("Could not create the reset link."),
+        Err(_) => {
+            ApiResponse::new_err("Encountered a database error while undertaking this operation.")
+        }
+    })
+}
+
+#[get("/reset/password?<code>")]
+pub fn reset_password_page(code: &str) -> Html {
+    Html::default()
+        .head(default_head("Choose a new password".to_string()))
+        .body(
+            Body::default()
+                .child(H1::new("Choose a new password"))
+                .child(reset_password_form(code)),
+        )
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct ResetPasswordData {
+    code: String,
+    password: String,
+    password_confirmation: String,
+}
+
+async fn reset_password_base(
+    data: &ResetPasswordData,
+    conn: Database,
+    cookies: &CookieJar<'_>,
+) -> Result<(), ResetError> {
+    use crate::schema::users::dsl as users;
+    let key = decoding_key().ok_or(ResetError::InvalidCode)?;
+    let token = jwt::decode::<PasswordResetToken>(&data.code, &key, &jwt::Validation::default())
+        .map_err(|_| ResetError::InvalidCode)?
+        .claims;
+    let hashed_password = hash_password(&data.password, &data.password_confirmation)?;
+    let issued = NaiveDateTime::from_timestamp(
+        token.issued.div_euclid(1000),
+        (token.issued.rem_euclid(1000) * 1_000_000) as u32,
+    );
+    let updated = conn
+        .run(move |c| {
+            c.transaction::<_, diesel::result::Error, _>(|| {
+                let updated = diesel::update(
+                    users::users
+                        .filter(users::id.eq(token.user_id))
+                        // if the password has been changed since the token was created, then the
+                        // token has already been used
+                        .filter(
+                            users::password_changed
+                                .is_null()
+                                .or(users::password_changed.lt(issued)),
+                        ),
+                )
+                .set((
+                    users::password.eq(hashed_password),
+                    users::password_changed.eq(Utc::now().naive_utc()),
+                ))
+                .execute(c)?;
+                if updated > 0 {
+                    end_all_sessions(token.user_id, c)?;
+                }
+                Ok(updated)
+            })
+        })
+        .await
+        .map_err(|e| {
+            error!("{:#?}", e);
+            ResetError::DatabaseError
+        })?;
+    if updated == 0 {
+        return Err(ResetError::InvalidCode);
+    }
+    cookies.remove_private(Cookie::named(LOGIN_COOKIE));
+    Ok(())
+}
+
+#[post("/reset/password", data = "<data>")]
+pub async fn html_reset_password(
+    data: rocket::form::Form<ResetPasswordData>,
+    conn: Database,
+    cookies: &CookieJar<'_>,
+) -> Html {
+    match reset_password_base(&data, conn, cookies).await {
+        Ok(()) => Html::default()
+            .head(default_head("Password changed".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("Your password has been changed"))
+                    .child(P::with_text(
+                        "You have been logged out everywhere, so please log in again with your \
+                        new password.",
+                    ))
+                    .child(A::new().href("/auth/login").text("Login")),
+            ),
+        Err(ResetError::InvalidCode) => Html::default()
+            .status(400)
+            .head(default_head("Invalid link".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("This link doesn't work any more"))
+                    .child(P::with_text(
+                        "Password reset links only work once, and expire after an hour. You can \
+                        ask for a new one below.",
+                    ))
+                    .child(request_reset_form()),
+            ),
+        Err(ResetError::NonMatchingPasswords) => Html::default()
+            .status(400)
+            .head(default_head("Passwords don't match".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("Passwords don't match"))
+                    .child(P::with_text("The passwords you entered weren't the same."))
+                    .child(reset_password_form(&data.code)),
+            ),
+        Err(_) => Html::default()
+            .status(500)
+            .head(default_head("Unknown error".to_string()))
+            .body(
+                Body::default()
+                    .child(H1::new("Something went wrong"))
+                    .child(P::with_text(
+                        "Something's up on our end. We're working to fix it as fast as we can!",
+                    ))
+                    .child(reset_password_form(&data.code)),
+            ),
+    }
 }
 
-#[post("/reset")]
-pub fn reset_page() -> Html {
-    todo!()
+#[post("/reset/password", data = "<data>")]
+pub async fn api_reset_password(
+    data: Json<ResetPasswordData>,
+    conn: Database,
+    cookies: &CookieJar<'_>,
+) -> Json<ApiResponse<()>> {
+    Json(match reset_password_base(&data, conn, cookies).await {
+        Ok(()) => ApiResponse::new_ok(()),
+        Err(e) => ApiResponse::new_err(match e {
+            ResetError::InvalidCode => "The reset code is invalid, has expired or has been used.",
+            ResetError::NonMatchingPasswords => "The passwords supplied do not match",
+            ResetError::EncryptingPasswordError => "Could not encrypt the provided password.",
+            ResetError::EmailError => "Could not send the reset email.",
+            ResetError::SigningError => "Could not create the reset link.",
+            ResetError::DatabaseError => {
+                "Encountered a database error while undertaking this operation."
+            }
+        }),
+    })
 }
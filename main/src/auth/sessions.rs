@@ -0,0 +1,385 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+//! Keeps track of everywhere that people are logged in.
+//!
+//! Logging in starts a session, and the login cookie only contains the session's (random) token (of
+//! which we only store a hash). Every request checks that the session still exists, so sessions can
+//! be ended from anywhere – by logging out, from the page which lists a user's sessions or by
+//! resetting the password.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket::{
+    http::{Cookie, CookieJar},
+    outcome::IntoOutcome,
+    request::FromRequest,
+};
+use rocket_contrib::json::Json;
+
+use crate::{
+    db::{Database, DatabaseConnection},
+    models::{NewSession, Session},
+    schema::session,
+    utils::{default_head, error_messages::database_error, json_response::ApiResponse},
+};
+
+use super::{tokens::hash_token, AuthError, LOGIN_COOKIE};
+
+/// How long sessions last for. Rocket's private cookies expire after a week anyway, so there would
+/// be no point in sessions lasting any longer.
+const SESSION_DAYS: i64 = 7;
+/// How often we record that a session is still being used (so that we don't write to the database
+/// on every request).
+const LAST_SEEN_MINUTES: i64 = 5;
+const TOKEN_LENGTH: usize = 32;
+
+/// Starts a session for a user who has just logged in, returning the token which should be put in
+/// their login cookie. The user's expired sessions are cleared out at the same time.
+pub(crate) fn start_session(
+    user_id: i32,
+    user_agent: Option<&str>,
+    conn: &DatabaseConnection,
+) -> QueryResult<String> {
+    let now = Utc::now().naive_utc();
+    let token = nanoid!(TOKEN_LENGTH);
+    diesel::delete(
+        session::table
+            .filter(session::user_id.eq(user_id))
+            .filter(session::expires.le(now)),
+    )
+    .execute(conn)?;
+    diesel::insert_into(session::table)
+        .values(NewSession {
+            token_hash: &hash_token(&token),
+            user_id,
+            created: now,
+            last_seen: now,
+            expires: now + Duration::days(SESSION_DAYS),
+            user_agent,
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// Ends the session with the provided token (i.e. logs somebody out).
+pub(crate) fn end_session(token: &str, conn: &DatabaseConnection) -> QueryResult<usize> {
+    diesel::delete(session::table.filter(session::token_hash.eq(hash_token(token)))).execute(conn)
+}
+
+/// Ends all of a user's sessions (e.g. because their password has been changed).
+pub(crate) fn end_all_sessions(user_id: i32, conn: &DatabaseConnection) -> QueryResult<usize> {
+    diesel::delete(session::table.filter(session::user_id.eq(user_id))).execute(conn)
+}
+
+async fn find_session(request: &rocket::Request<'_>) -> Option<Session> {
+    let token_hash = hash_token(request.cookies().get_private(LOGIN_COOKIE)?.value());
+    let conn = request.guard::<Database>().await.succeeded()?;
+    conn.run(move |c| {
+        let now = Utc::now().naive_utc();
+        let found = session::table
+            .filter(session::token_hash.eq(&token_hash))
+            .filter(session::expires.gt(now))
+            .first::<Session>(c)
+            .optional()?;
+        if let Some(found) = &found {
+            if now - found.last_seen > Duration::minutes(LAST_SEEN_MINUTES) {
+                diesel::update(session::table.find(found.id))
+                    .set(session::last_seen.eq(now))
+                    .execute(c)?;
+            }
+        }
+        Ok(found)
+    })
+    .await
+    .unwrap_or_else(|e: diesel::result::Error| {
+        error!("{:#?}", e);
+        None
+    })
+}
+
+/// Returns the session which the request belongs to (if the login cookie refers to a session which
+/// hasn't expired or been ended). This is only looked up once per request.
+pub(crate) async fn current_session(request: &rocket::Request<'_>) -> Option<Session> {
+    request
+        .local_cache_async(find_session(request))
+        .await
+        .clone()
+}
+
+/// The session of the logged in user. This has the same requirements as `AuthCookie`.
+#[derive(Debug, Clone)]
+pub struct CurrentSession(pub Session);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for CurrentSession {
+    type Error = AuthError;
+
+    async fn from_request(
+        request: &'a rocket::Request<'r>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        current_session(request)
+            .await
+            .map(CurrentSession)
+            .or_forward(())
+    }
+}
+
+/// The `User-Agent` header of the request (if it has one).
+#[derive(Debug, Clone)]
+pub struct UserAgent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
+    type Error = AuthError;
+
+    async fn from_request(
+        request: &'a rocket::Request<'r>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(UserAgent(
+            request.headers().get_one("User-Agent").map(str::to_string),
+        ))
+    }
+}
+
+/// A session as it is shown to the user who it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSummary {
+    id: i32,
+    created: NaiveDateTime,
+    last_seen: NaiveDateTime,
+    expires: NaiveDateTime,
+    user_agent: Option<String>,
+    /// Whether this is the session which was used to make the request.
+    current: bool,
+}
+
+async fn list_sessions(
+    current: &Session,
+    conn: &Database,
+) -> Result<Vec<SessionSummary>, diesel::result::Error> {
+    let user_id = current.user_id;
+    let current_id = current.id;
+    let sessions = conn
+        .run(move |c| {
+            session::table
+                .filter(session::user_id.eq(user_id))
+                .filter(session::expires.gt(Utc::now().naive_utc()))
+                .order_by(session::last_seen.desc())
+                .load::<Session>(c)
+        })
+        .await?;
+    Ok(sessions
+        .into_iter()
+        .map(|session| SessionSummary {
+            id: session.id,
+            created: session.created,
+            last_seen: session.last_seen,
+            expires: session.expires,
+            user_agent: session.user_agent,
+            current: session.id == current_id,
+        })
+        .collect())
+}
+
+/// Ends one of the user's sessions, returning whether it existed.
+async fn revoke_session(
+    current: &Session,
+    id: i32,
+    conn: &Database,
+    cookies: &CookieJar<'_>,
+) -> Result<bool, diesel::result::Error> {
+    let user_id = current.user_id;
+    let deleted = conn
+        .run(move |c| {
+            diesel::delete(
+                session::table
+                    .filter(session::id.eq(id))
+                    .filter(session::user_id.eq(user_id)),
+            )
+            .execute(c)
+        })
+        .await?;
+    if id == current.id {
+        cookies.remove_private(Cookie::named(LOGIN_COOKIE));
+    }
+    Ok(deleted > 0)
+}
+
+fn format_time(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn revoke_session_form(id: i32) -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/sessions/revoke"))
+        .child(
+            Input::new()
+                .attribute(Type::Hidden)
+                .attribute(Name::new("id"))
+                .attribute(Value::new(id.to_string())),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Log out")),
+        )
+}
+
+fn revoke_other_sessions_form() -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/sessions/revoke_others"))
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Log out everywhere else")),
+        )
+}
+
+async fn sessions_page(current: &Session, conn: &Database, message: Option<&str>) -> Html {
+    let sessions = match list_sessions(current, conn).await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            error!("{:#?}", e);
+            return database_error();
+        }
+    };
+    Html::new()
+        .head(default_head("Where you're logged in"))
+        .body(
+            Body::new()
+                .child(H1::new("Where you're logged in"))
+                .map(|body| match message {
+                    Some(message) => body.child(P::with_text(message)),
+                    None => body,
+                })
+                .children(sessions.into_iter().map(|session| {
+                    let div = Div::new()
+                        .child(H3::new(
+                            session
+                                .user_agent
+                                .unwrap_or_else(|| "Unknown device".to_string()),
+                        ))
+                        .child(P::with_text(format!(
+                            "Logged in at {}, last used at {}.",
+                            format_time(session.created),
+                            format_time(session.last_seen)
+                        )));
+                    if session.current {
+                        div.child(P::with_text("This is where you're logged in right now."))
+                    } else {
+                        div.child(revoke_session_form(session.id))
+                    }
+                }))
+                .child(revoke_other_sessions_form()),
+        )
+}
+
+#[get("/sessions")]
+pub async fn html_list_sessions(current: CurrentSession, conn: Database) -> Html {
+    sessions_page(&current.0, &conn, None).await
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeSessionForm {
+    id: i32,
+}
+
+#[post("/sessions/revoke", data = "<form>")]
+pub async fn html_revoke_session(
+    current: CurrentSession,
+    conn: Database,
+    form: rocket::form::Form<RevokeSessionForm>,
+    cookies: &CookieJar<'_>,
+) -> Html {
+    match revoke_session(&current.0, form.id, &conn, cookies).await {
+        Ok(_) if form.id == current.0.id => Html::new()
+            .head(default_head("Logged out."))
+            .body(Body::new().child(H1::new("You are logged out."))),
+        Ok(true) => {
+            sessions_page(&current.0, &conn, Some("That session has been logged out.")).await
+        }
+        Ok(false) => {
+            sessions_page(
+                &current.0,
+                &conn,
+                Some("That session doesn't exist (it might have already been logged out)."),
+            )
+            .await
+        }
+        Err(e) => {
+            error!("{:#?}", e);
+            database_error()
+        }
+    }
+}
+
+#[post("/sessions/revoke_others")]
+pub async fn html_revoke_other_sessions(current: CurrentSession, conn: Database) -> Html {
+    let user_id = current.0.user_id;
+    let current_id = current.0.id;
+    match conn
+        .run(move |c| {
+            diesel::delete(
+                session::table
+                    .filter(session::user_id.eq(user_id))
+                    .filter(session::id.ne(current_id)),
+            )
+            .execute(c)
+        })
+        .await
+    {
+        Ok(_) => {
+            sessions_page(
+                &current.0,
+                &conn,
+                Some("You have been logged out everywhere else."),
+            )
+            .await
+        }
+        Err(e) => {
+            error!("{:#?}", e);
+            database_error()
+        }
+    }
+}
+
+#[get("/sessions")]
+pub async fn api_list_sessions(
+    current: CurrentSession,
+    conn: Database,
+) -> Json<ApiResponse<Vec<SessionSummary>>> {
+    Json(match list_sessions(&current.0, &conn).await {
+        Ok(sessions) => ApiResponse::new_ok(sessions),
+        Err(e) => {
+            error!("{:#?}", e);
+            ApiResponse::new_err("Encountered a database error while undertaking this operation.")
+        }
+    })
+}
+
+#[post("/sessions/revoke", data = "<form>")]
+pub async fn api_revoke_session(
+    current: CurrentSession,
+    conn: Database,
+    form: Json<RevokeSessionForm>,
+    cookies: &CookieJar<'_>,
+) -> Json<ApiResponse<()>> {
+    Json(
+        match revoke_session(&current.0, form.id, &conn, cookies).await {
+            Ok(true) => ApiResponse::new_ok(()),
+            Ok(false) => ApiResponse::new_err("A session with that id could not be found."),
+            Err(e) => {
+                error!("{:#?}", e);
+                ApiResponse::new_err(
+                    "Encountered a database error while undertaking this operation.",
+                )
+            }
+        },
+    )
+}
@@ -0,0 +1,939 @@
+//! Single sign-on (with OpenID Connect) for institutions.
+//!
+//! Institutions configure their provider (see `institution::sso`). People log in by entering their
+//! email address, which we use to find their institution, and are then sent to the provider using
+//! the authorization code flow. When they come back, we create an account for them (if they don't
+//! have one already) and add them to their institution as a teacher or a student depending on the
+//! claims which the provider gives us.
+//!
+//! Providers are only trusted for the email domain which their institution has verified (see
+//! `institution::sso`), and accounts which were created some other way are never linked to an
+//! account at a provider (otherwise whoever runs a provider could log in as anybody with an email
+//! address that it vouches for). People who use two-factor authentication still have to enter a
+//! code after they come back from their provider.
+
+use bcrypt::DEFAULT_COST;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket::{
+    http::{Cookie, CookieJar},
+    response::Redirect,
+};
+use rocket_contrib::json::Json;
+use serde_json::{Map, Value};
+use thiserror::Error as ThisError;
+
+use crate::{
+    db::{Database, DatabaseConnection},
+    institution::sso::email_domain,
+    models::{
+        institution::{
+            oidc::{InstitutionOidc, NewOidcIdentity, NewOidcLogin, OidcLogin},
+            student::NewInstitutionStudent,
+            teacher::NewInstitutionTeacher,
+        },
+        NewUser,
+    },
+    schema::{
+        institution_oidc, institution_student, institution_teacher, oidc_identity, oidc_login,
+        users,
+    },
+    utils::{
+        default_head, error_messages::database_error, html_or_redirect::HtmlOrRedirect,
+        json_response::ApiResponse,
+    },
+};
+
+use super::{
+    login::{finish_login, logged_in_page},
+    sessions::UserAgent,
+    two_factor::{self, SecondFactor},
+};
+
+/// How long (in minutes) somebody has to log in with their provider before the `state` value which
+/// we gave them stops working.
+const STATE_LIFETIME: i64 = 15;
+/// Stores the `state` value of the login which was started in this browser, so that somebody can't
+/// finish logging in with a `state` value that they were given by somebody else (which would log
+/// them into the other person's account).
+const STATE_COOKIE: &str = "SSO_STATE";
+
+#[derive(ThisError, Debug)]
+pub enum SsoError {
+    #[error("no institution with single sign-on uses this email address")]
+    NoInstitution,
+    #[error("the state value is not valid (or has expired)")]
+    InvalidState,
+    #[error("error communicating with the provider")]
+    ProviderError,
+    #[error("the id token is not valid")]
+    InvalidIdToken,
+    #[error("the user's roles don't allow them to use Lovelace")]
+    NotAllowed,
+    #[error("the provider didn't give us an email address")]
+    MissingEmail,
+    #[error("the email address doesn't belong to the institution's domain")]
+    WrongDomain,
+    #[error("an account with this email address already exists")]
+    EmailTaken,
+    #[error("too many incorrect two-factor authentication codes have been entered")]
+    LockedOut,
+    #[error("database error")]
+    DatabaseError,
+}
+
+impl From<diesel::result::Error> for SsoError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+impl From<reqwest::Error> for SsoError {
+    fn from(e: reqwest::Error) -> Self {
+        error!("{:#?}", e);
+        Self::ProviderError
+    }
+}
+
+impl SsoError {
+    fn reason(&self) -> &'static str {
+        match self {
+            SsoError::NoInstitution => {
+                "We couldn't find an institution which uses single sign-on for that email address."
+            }
+            SsoError::InvalidState => "Your login has expired. Please try logging in again.",
+            SsoError::ProviderError => {
+                "We couldn't log you in with your institution's provider. Please try again."
+            }
+            SsoError::InvalidIdToken => {
+                "Your institution's provider sent us an invalid response. Please try again."
+            }
+            SsoError::NotAllowed => {
+                "Your institution hasn't given you access to Lovelace. If you think that this is a \
+                mistake, please contact your institution."
+            }
+            SsoError::MissingEmail => {
+                "Your institution's provider didn't tell us your email address, which we need to \
+                log you in."
+            }
+            SsoError::WrongDomain => {
+                "Your email address doesn't belong to your institution, so you can't log in with \
+                its provider."
+            }
+            SsoError::EmailTaken => {
+                "An account with your email address already exists (and wasn't created by logging \
+                in with your institution), so please log in with your password instead."
+            }
+            SsoError::LockedOut => {
+                "Too many incorrect two-factor authentication codes have been entered, so your \
+                account has been locked for a few minutes. Please try logging in again later."
+            }
+            SsoError::DatabaseError => {
+                "Encountered a database error while undertaking this operation."
+            }
+        }
+    }
+}
+
+/// The parts of the provider's discovery document which we use.
+#[derive(Deserialize, Debug, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+async fn discover(issuer: &str) -> Result<ProviderMetadata, SsoError> {
+    let res = reqwest::Client::new()
+        .get(&format!("{}/.well-known/openid-configuration", issuer))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(SsoError::ProviderError);
+    }
+    let metadata = res.json::<ProviderMetadata>().await?;
+    if metadata.issuer.trim_end_matches('/') != issuer {
+        return Err(SsoError::ProviderError);
+    }
+    Ok(metadata)
+}
+
+fn redirect_uri() -> String {
+    cfg_if! {
+        if #[cfg(test)] {
+            let hostname = "http://localhost:8000".to_string();
+        } else {
+            let hostname = std::env::var("HOSTNAME")
+                .expect("the `HOSTNAME` environment variable has not been set");
+        }
+    };
+    format!("{}/auth/sso/callback", hostname)
+}
+
+/// Finds the institution (which has single sign-on set up) that the email address belongs to.
+/// There is at most one, because each domain can only be used by one institution.
+fn find_institution(email: &str, c: &DatabaseConnection) -> Result<InstitutionOidc, SsoError> {
+    let domain = email_domain(email).ok_or(SsoError::NoInstitution)?;
+    institution_oidc::table
+        .filter(institution_oidc::domain.eq(domain))
+        .first::<InstitutionOidc>(c)
+        .optional()?
+        .ok_or(SsoError::NoInstitution)
+}
+
+/// Creates (and stores) a new `state` and `nonce` for a login. Any logins which have expired are
+/// cleaned up at the same time.
+fn create_login(institution_id: i32, c: &DatabaseConnection) -> QueryResult<(String, String)> {
+    let state = uuid::Uuid::new_v4().to_string();
+    let nonce = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now().naive_utc();
+    diesel::delete(oidc_login::table.filter(oidc_login::expires.le(now))).execute(c)?;
+    diesel::insert_into(oidc_login::table)
+        .values(NewOidcLogin {
+            state: &state,
+            nonce: &nonce,
+            institution_id,
+            created: now,
+            expires: now + Duration::minutes(STATE_LIFETIME),
+        })
+        .execute(c)?;
+    Ok((state, nonce))
+}
+
+/// Removes (and returns) the login for the provided `state` value, as long as it has not expired.
+/// Because the login is removed, each `state` value can only be used once.
+fn take_login(state: &str, c: &DatabaseConnection) -> QueryResult<Option<OidcLogin>> {
+    diesel::delete(
+        oidc_login::table
+            .filter(oidc_login::state.eq(state))
+            .filter(oidc_login::expires.gt(Utc::now().naive_utc())),
+    )
+    .get_result::<OidcLogin>(c)
+    .optional()
+}
+
+/// Returns the URL of the provider's login page for the user's institution.
+async fn start_sso(
+    email: String,
+    cookies: &CookieJar<'_>,
+    conn: &Database,
+) -> Result<String, SsoError> {
+    let config = conn.run(move |c| find_institution(&email, c)).await?;
+    let metadata = discover(&config.issuer).await?;
+    let institution_id = config.institution_id;
+    let (state, nonce) = conn.run(move |c| create_login(institution_id, c)).await?;
+    cookies.add_private(Cookie::new(STATE_COOKIE, state.clone()));
+    reqwest::Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", redirect_uri().as_str()),
+            ("scope", "openid email profile"),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+        ],
+    )
+    .map(String::from)
+    .map_err(|_| SsoError::ProviderError)
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+    access_token: String,
+}
+
+/// The `aud` claim can be either a single value or a list of them.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    /// Everything else, including the claims which we look up the user's roles and email address
+    /// in.
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+/// Checks the id token that the provider sent us.
+///
+/// We received the token directly from the provider's token endpoint (over TLS) so, as the
+/// OpenID Connect specification allows, we don't check its signature.
+fn validate_id_token(
+    id_token: &str,
+    metadata: &ProviderMetadata,
+    config: &InstitutionOidc,
+    nonce: &str,
+) -> Result<IdTokenClaims, SsoError> {
+    let claims = jwt::dangerous_insecure_decode::<IdTokenClaims>(id_token)
+        .map_err(|_| SsoError::InvalidIdToken)?
+        .claims;
+    if claims.iss != metadata.issuer
+        || !claims.aud.contains(&config.client_id)
+        || claims.exp <= Utc::now().timestamp()
+        || claims.nonce.as_deref() != Some(nonce)
+    {
+        return Err(SsoError::InvalidIdToken);
+    }
+    Ok(claims)
+}
+
+async fn exchange_code(
+    code: &str,
+    metadata: &ProviderMetadata,
+    config: &InstitutionOidc,
+    nonce: &str,
+) -> Result<IdTokenClaims, SsoError> {
+    let client = reqwest::Client::new();
+    let res = client
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri().as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+        ])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(SsoError::ProviderError);
+    }
+    let tokens = res.json::<TokenResponse>().await?;
+    let mut claims = validate_id_token(&tokens.id_token, metadata, config, nonce)?;
+    // some providers only put the email address (or custom claims) in the userinfo response
+    if !claims.other.contains_key(&config.role_claim) || !claims.other.contains_key("email") {
+        if let Some(userinfo_endpoint) = &metadata.userinfo_endpoint {
+            let userinfo = client
+                .get(userinfo_endpoint)
+                .bearer_auth(&tokens.access_token)
+                .send()
+                .await?
+                .json::<Map<String, Value>>()
+                .await?;
+            if userinfo.get("sub").and_then(Value::as_str) != Some(&claims.sub) {
+                return Err(SsoError::InvalidIdToken);
+            }
+            for (key, value) in userinfo {
+                claims.other.entry(key).or_insert(value);
+            }
+        }
+    }
+    Ok(claims)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Role {
+    Teacher,
+    Student,
+}
+
+/// Works out (using the institution's configuration) whether the user is a teacher or a student.
+fn role_from_claims(claims: &Map<String, Value>, config: &InstitutionOidc) -> Option<Role> {
+    let has_value = |expected: &str| match claims.get(&config.role_claim) {
+        Some(Value::String(value)) => value == expected,
+        Some(Value::Array(values)) => values.iter().any(|value| value.as_str() == Some(expected)),
+        _ => false,
+    };
+    if has_value(&config.teacher_value) {
+        Some(Role::Teacher)
+    } else if has_value(&config.student_value) {
+        Some(Role::Student)
+    } else {
+        None
+    }
+}
+
+/// Picks a username which nobody else has, based on the one which the provider gave us (or the
+/// user's email address).
+fn unique_username(
+    claims: &Map<String, Value>,
+    email: &str,
+    c: &DatabaseConnection,
+) -> QueryResult<String> {
+    let base = claims
+        .get("preferred_username")
+        .and_then(Value::as_str)
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+        .chars()
+        .filter(|ch| ch.is_ascii_alphanumeric() || *ch == '-' || *ch == '_' || *ch == '.')
+        .collect::<String>();
+    let base = if base.is_empty() {
+        "user".to_string()
+    } else {
+        base
+    };
+    let mut username = base.clone();
+    let mut n = 1;
+    while diesel::select(diesel::dsl::exists(
+        users::table.filter(users::username.eq(&username)),
+    ))
+    .get_result::<bool>(c)?
+    {
+        n += 1;
+        username = format!("{}-{}", base, n);
+    }
+    Ok(username)
+}
+
+/// Finds (or creates) the user who the claims belong to, and makes sure that they are part of
+/// their institution with the right role.
+fn provision_user(
+    claims: &IdTokenClaims,
+    email: &str,
+    role: Role,
+    institution_id: i32,
+    c: &DatabaseConnection,
+) -> Result<i32, SsoError> {
+    c.transaction::<_, SsoError, _>(|| {
+        let identity = oidc_identity::table
+            .filter(oidc_identity::institution_id.eq(institution_id))
+            .filter(oidc_identity::subject.eq(&claims.sub))
+            .select(oidc_identity::user_id)
+            .first::<i32>(c)
+            .optional()?;
+        let user_id = match identity {
+            Some(user_id) => user_id,
+            None => {
+                // accounts which already exist are never linked (the provider saying that the
+                // email address is theirs doesn't mean that they own the account)
+                let taken = diesel::select(diesel::dsl::exists(
+                    users::table.filter(users::email.eq(email)),
+                ))
+                .get_result::<bool>(c)?;
+                if taken {
+                    return Err(SsoError::EmailTaken);
+                }
+                let email_verified = claims
+                    .other
+                    .get("email_verified")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                let timezone = claims
+                    .other
+                    .get("zoneinfo")
+                    .and_then(Value::as_str)
+                    .filter(|tz| tz.parse::<chrono_tz::Tz>().is_ok())
+                    .unwrap_or("Etc/UTC");
+                // people log in with their provider, but they can set a password (using the
+                // password reset flow) if they want to
+                let password =
+                    bcrypt::hash(nanoid!(32), DEFAULT_COST).map_err(|_| SsoError::DatabaseError)?;
+                let user_id = diesel::insert_into(users::table)
+                    .values(NewUser {
+                        username: &unique_username(&claims.other, email, c)?,
+                        email,
+                        password: &password,
+                        created: Utc::now().naive_utc(),
+                        email_verified,
+                        timezone,
+                    })
+                    .returning(users::id)
+                    .get_result::<i32>(c)?;
+                diesel::insert_into(oidc_identity::table)
+                    .values(NewOidcIdentity {
+                        user_id,
+                        institution_id,
+                        subject: &claims.sub,
+                    })
+                    .execute(c)?;
+                user_id
+            }
+        };
+        let is_teacher = diesel::select(diesel::dsl::exists(
+            institution_teacher::table
+                .filter(institution_teacher::user_id.eq(user_id))
+                .filter(institution_teacher::institution_id.eq(institution_id)),
+        ))
+        .get_result::<bool>(c)?;
+        let is_student = diesel::select(diesel::dsl::exists(
+            institution_student::table
+                .filter(institution_student::user_id.eq(user_id))
+                .filter(institution_student::institution_id.eq(institution_id)),
+        ))
+        .get_result::<bool>(c)?;
+        match role {
+            Role::Teacher => {
+                if !is_teacher {
+                    diesel::insert_into(institution_teacher::table)
+                        .values(NewInstitutionTeacher {
+                            user_id,
+                            institution_id,
+                        })
+                        .execute(c)?;
+                }
+                diesel::delete(
+                    institution_student::table
+                        .filter(institution_student::user_id.eq(user_id))
+                        .filter(institution_student::institution_id.eq(institution_id)),
+                )
+                .execute(c)?;
+            }
+            Role::Student => {
+                if !is_student {
+                    diesel::insert_into(institution_student::table)
+                        .values(NewInstitutionStudent {
+                            user_id,
+                            institution_id,
+                        })
+                        .execute(c)?;
+                }
+                diesel::delete(
+                    institution_teacher::table
+                        .filter(institution_teacher::user_id.eq(user_id))
+                        .filter(institution_teacher::institution_id.eq(institution_id)),
+                )
+                .execute(c)?;
+            }
+        }
+        Ok(user_id)
+    })
+}
+
+/// Finishes logging in somebody who has come back from their institution's provider, returning
+/// whether they still need to enter a two-factor authentication code (or set it up) – if they do,
+/// they finish logging in from `two_factor`.
+async fn finish_sso(
+    cookies: &CookieJar<'_>,
+    code: String,
+    state: String,
+    user_agent: UserAgent,
+    conn: &Database,
+) -> Result<SecondFactor, SsoError> {
+    let started_here = cookies
+        .get_private(STATE_COOKIE)
+        .map(|cookie| cookie.value() == state)
+        .unwrap_or(false);
+    cookies.remove_private(Cookie::named(STATE_COOKIE));
+    if !started_here {
+        return Err(SsoError::InvalidState);
+    }
+    let (login, config) = conn
+        .run(move |c| {
+            let login = take_login(&state, c)?.ok_or(SsoError::InvalidState)?;
+            let config = institution_oidc::table
+                .filter(institution_oidc::institution_id.eq(login.institution_id))
+                .first::<InstitutionOidc>(c)
+                .optional()?
+                .ok_or(SsoError::InvalidState)?;
+            Ok::<_, SsoError>((login, config))
+        })
+        .await?;
+    let metadata = discover(&config.issuer).await?;
+    let claims = exchange_code(&code, &metadata, &config, &login.nonce).await?;
+    let role = role_from_claims(&claims.other, &config).ok_or(SsoError::NotAllowed)?;
+    let email = claims
+        .other
+        .get("email")
+        .and_then(Value::as_str)
+        .ok_or(SsoError::MissingEmail)?
+        .to_string();
+    // the provider is only trusted for its institution's domain
+    if config.domain.is_none() || email_domain(&email) != config.domain {
+        return Err(SsoError::WrongDomain);
+    }
+    let institution_id = config.institution_id;
+    let (user_id, second_factor) = conn
+        .run(move |c| {
+            let user_id = provision_user(&claims, &email, role, institution_id, c)?;
+            let second_factor = two_factor::check_second_factor(user_id, None, c)?;
+            Ok::<_, SsoError>((user_id, second_factor))
+        })
+        .await?;
+    match second_factor {
+        SecondFactor::NotNeeded | SecondFactor::Verified => {
+            finish_login(cookies, user_id, user_agent, conn)
+                .await
+                .map_err(|_| SsoError::DatabaseError)?
+        }
+        SecondFactor::Missing | SecondFactor::NotValid | SecondFactor::SetupRequired => {
+            two_factor::start_pending_login(cookies, user_id)
+        }
+        SecondFactor::LockedOut => return Err(SsoError::LockedOut),
+    }
+    Ok(second_factor)
+}
+
+fn error_page(e: SsoError) -> Html {
+    match e {
+        SsoError::DatabaseError => database_error(),
+        e => Html::new()
+            .status(400)
+            .head(default_head("Log in with your institution"))
+            .body(
+                Body::new()
+                    .child(H1::new("Log in with your institution"))
+                    .child(P::with_text(e.reason()))
+                    .child(A::new().href("/auth/sso").text("Try again")),
+            ),
+    }
+}
+
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone)]
+pub struct StartSsoForm {
+    email: String,
+}
+
+#[get("/sso")]
+pub fn sso_page() -> Html {
+    Html::new()
+        .head(default_head("Log in with your institution"))
+        .body(
+            Body::new()
+                .child(H1::new("Log in with your institution"))
+                .child(P::with_text(
+                    "If your institution has set up single sign-on, you can log in with your \
+                    institution's account. Please enter your email address to get started.",
+                ))
+                .child(
+                    Form::new()
+                        .attribute(Method::Post)
+                        .attribute(Action::new("/auth/sso"))
+                        .child(
+                            Input::new()
+                                .attribute(Type::Email)
+                                .attribute(Name::new("email"))
+                                .attribute(Placeholder::new("Email")),
+                        )
+                        .child(
+                            Input::new()
+                                .attribute(Type::Submit)
+                                .attribute(Value::new("Continue")),
+                        ),
+                ),
+        )
+}
+
+#[post("/sso", data = "<form>")]
+pub async fn html_start_sso(
+    cookies: &CookieJar<'_>,
+    conn: Database,
+    form: rocket::form::Form<StartSsoForm>,
+) -> HtmlOrRedirect {
+    match start_sso(form.into_inner().email, cookies, &conn).await {
+        Ok(url) => HtmlOrRedirect::Redirect(Redirect::to(url)),
+        Err(e) => HtmlOrRedirect::Html(error_page(e)),
+    }
+}
+
+/// The page on the provider which the user should be sent to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SsoRedirect {
+    url: String,
+}
+
+#[post("/sso", data = "<form>")]
+pub async fn api_start_sso(
+    cookies: &CookieJar<'_>,
+    conn: Database,
+    form: Json<StartSsoForm>,
+) -> Json<ApiResponse<SsoRedirect>> {
+    Json(
+        match start_sso(form.into_inner().email, cookies, &conn).await {
+            Ok(url) => ApiResponse::new_ok(SsoRedirect { url }),
+            Err(e) => ApiResponse::new_err(e.reason()),
+        },
+    )
+}
+
+#[get("/sso/callback?<code>&<state>&<error>")]
+pub async fn sso_callback(
+    cookies: &CookieJar<'_>,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    user_agent: UserAgent,
+    conn: Database,
+) -> Html {
+    let (code, state) = match (code, state, error) {
+        (Some(code), Some(state), None) => (code, state),
+        (_, _, Some(error)) => {
+            warn!("single sign-on provider returned an error: {}", error);
+            return error_page(SsoError::ProviderError);
+        }
+        _ => return error_page(SsoError::InvalidState),
+    };
+    match finish_sso(cookies, code, state, user_agent, &conn).await {
+        Ok(SecondFactor::NotNeeded) | Ok(SecondFactor::Verified) => logged_in_page(),
+        Ok(SecondFactor::SetupRequired) => two_factor::setup_required_page(),
+        Ok(_) => two_factor::code_page(None),
+        Err(e) => error_page(e),
+    }
+}
+
+#[cfg(test)]
+mod test_single_sign_on {
+    use diesel::prelude::*;
+    use rocket::{
+        http::{ContentType, Status},
+        local::asynchronous::Client,
+    };
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        db::Database,
+        institution::test_ctx::{setup_env, ADMIN_EMAIL, ADMIN_PASSWORD, STUDENT_EMAIL},
+        schema::{institution_student, institution_teacher, users},
+        utils::{client, login_user, logout},
+    };
+
+    const EMAIL: &str = "new.person@example.com";
+
+    /// Logs in through the fake provider (which says that the user has the provided email address
+    /// and roles), returning the page which the callback responds with and the `state` value which
+    /// was used.
+    async fn sso_login(
+        client: &Client,
+        provider: &MockServer,
+        email: &str,
+        roles: &[&str],
+    ) -> (String, String) {
+        let res = client
+            .post("/auth/sso")
+            .header(ContentType::Form)
+            .body(format!("email={}", email))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::SeeOther);
+        let location = reqwest::Url::parse(res.headers().get_one("Location").unwrap()).unwrap();
+        assert_eq!(location.path(), "/authorize");
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        assert_eq!(param("client_id"), "lovelace");
+        let (state, nonce) = (param("state"), param("nonce"));
+        let id_token = jwt::encode(
+            &jwt::Header::default(),
+            &json!({
+                "iss": provider.uri(),
+                "sub": "some-subject",
+                "aud": "lovelace",
+                "exp": chrono::Utc::now().timestamp() + 300,
+                "nonce": nonce,
+                "email": email,
+                "email_verified": true,
+                "preferred_username": "new.person",
+                "roles": roles
+            }),
+            &jwt::EncodingKey::from_secret(b"the provider's key"),
+        )
+        .unwrap();
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "an-access-token",
+                "token_type": "Bearer",
+                "id_token": id_token
+            })))
+            .up_to_n_times(1)
+            .mount(provider)
+            .await;
+        let page = client
+            .get(format!("/auth/sso/callback?code=some-code&state={}", state))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .expect("invalid body response");
+        (page, state)
+    }
+
+    #[rocket::async_test]
+    async fn test_single_sign_on() {
+        let provider = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": provider.uri(),
+                "authorization_endpoint": format!("{}/authorize", provider.uri()),
+                "token_endpoint": format!("{}/token", provider.uri()),
+                "userinfo_endpoint": format!("{}/userinfo", provider.uri())
+            })))
+            .mount(&provider)
+            .await;
+        let client = client().await;
+        let (_, _, _, institution_id, _) = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| setup_env(c))
+            .await;
+
+        // nobody can log in before the institution sets up single sign-on
+        let res = client
+            .post("/auth/sso")
+            .header(ContentType::Form)
+            .body(format!("email={}", EMAIL))
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .expect("invalid body response")
+            .contains("couldn't find an institution"));
+
+        login_user(ADMIN_EMAIL, ADMIN_PASSWORD, &client).await;
+        let res = client
+            .post(format!("/institution/{}/sso", institution_id))
+            .header(ContentType::Form)
+            .body(format!(
+                "issuer={}&client_id=lovelace&client_secret=secret&role_claim=roles\
+                &teacher_value=staff&student_value=pupil",
+                provider.uri()
+            ))
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .expect("invalid body response")
+            .contains("Single sign-on is set up"));
+        logout(&client).await;
+
+        let memberships = || async {
+            Database::get_one(client.rocket())
+                .await
+                .unwrap()
+                .run(move |c| {
+                    let user_id = users::table
+                        .filter(users::email.eq(EMAIL))
+                        .select(users::id)
+                        .get_result::<i32>(c)
+                        .unwrap();
+                    let teacher = diesel::select(diesel::dsl::exists(
+                        institution_teacher::table
+                            .filter(institution_teacher::user_id.eq(user_id))
+                            .filter(institution_teacher::institution_id.eq(institution_id)),
+                    ))
+                    .get_result::<bool>(c)
+                    .unwrap();
+                    let student = diesel::select(diesel::dsl::exists(
+                        institution_student::table
+                            .filter(institution_student::user_id.eq(user_id))
+                            .filter(institution_student::institution_id.eq(institution_id)),
+                    ))
+                    .get_result::<bool>(c)
+                    .unwrap();
+                    (teacher, student)
+                })
+                .await
+        };
+
+        // the first login creates an account (and adds the user to the institution)
+        let (page, state) = sso_login(&client, &provider, EMAIL, &["pupil"]).await;
+        assert!(page.contains("Logged in"));
+        assert_eq!(
+            client.get("/api/class").dispatch().await.status(),
+            Status::Ok
+        );
+        assert_eq!(memberships().await, (false, true));
+
+        // each state value can only be used once
+        let page = client
+            .get(format!("/auth/sso/callback?code=some-code&state={}", state))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .expect("invalid body response");
+        assert!(page.contains("Your login has expired"));
+
+        // logins can only be finished in the browser which started them (here the cookie is
+        // replaced by starting another login)
+        logout(&client).await;
+        let start = || async {
+            let res = client
+                .post("/auth/sso")
+                .header(ContentType::Form)
+                .body(format!("email={}", EMAIL))
+                .dispatch()
+                .await;
+            let location = reqwest::Url::parse(res.headers().get_one("Location").unwrap()).unwrap();
+            location
+                .query_pairs()
+                .find(|(key, _)| key == "state")
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        let someone_elses_state = start().await;
+        start().await;
+        let page = client
+            .get(format!(
+                "/auth/sso/callback?code=some-code&state={}",
+                someone_elses_state
+            ))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .expect("invalid body response");
+        assert!(page.contains("Your login has expired"));
+        assert_ne!(
+            client.get("/api/class").dispatch().await.status(),
+            Status::Ok
+        );
+
+        // the user's role is updated when it changes
+        let (page, _) = sso_login(&client, &provider, EMAIL, &["staff"]).await;
+        assert!(page.contains("Logged in"));
+        assert_eq!(memberships().await, (true, false));
+        let accounts = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                users::table
+                    .filter(users::email.eq(EMAIL))
+                    .count()
+                    .get_result::<i64>(c)
+            })
+            .await
+            .unwrap();
+        assert_eq!(accounts, 1);
+
+        // people whose roles aren't recognised can't log in
+        logout(&client).await;
+        let (page, _) = sso_login(&client, &provider, EMAIL, &["visitor"]).await;
+        assert!(page.contains("hasn't given you access"));
+        assert_ne!(
+            client.get("/api/class").dispatch().await.status(),
+            Status::Ok
+        );
+
+        // accounts which weren't created through single sign-on can't be logged into with it
+        let (page, _) = sso_login(&client, &provider, STUDENT_EMAIL, &["pupil"]).await;
+        assert!(page.contains("already exists"));
+        assert_ne!(
+            client.get("/api/class").dispatch().await.status(),
+            Status::Ok
+        );
+    }
+}
@@ -0,0 +1,684 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+//! Lets scripts and apps call the API without the login cookie.
+//!
+//! There are two kinds of bearer token (which are sent in the `Authorization` header):
+//! - personal access tokens, which people create (and revoke) from their settings page. We only
+//!   store a hash of them, and they last until they expire or are revoked.
+//! - short-lived JWTs, which somebody who is logged in can ask for. These belong to the session
+//!   which was used to create them, so they stop working when that session ends.
+//!
+//! Both kinds of token have scopes, which limit which API routes they can be used for.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket_contrib::json::Json;
+use sha2::{Digest, Sha256};
+use thiserror::Error as ThisError;
+
+use crate::{
+    db::{Database, DatabaseConnection},
+    models::{AccessToken, NewAccessToken},
+    schema::{access_token, session},
+    utils::{default_head, error_messages::database_error, json_response::ApiResponse},
+};
+
+use super::{decoding_key, encoding_key, sessions::CurrentSession, AuthCookie};
+
+/// Personal access tokens start with this, so that they can be told apart from JWTs (and so that
+/// they are easy to spot if they are accidentally committed somewhere).
+const TOKEN_PREFIX: &str = "lovelace_pat_";
+const TOKEN_LENGTH: usize = 40;
+/// How long JWTs are valid for.
+const JWT_MINUTES: i64 = 15;
+/// The `typ` claim of bearer JWTs. The links which we email to people are signed with the same key,
+/// so this stops them from being used as bearer tokens.
+const JWT_TYPE: &str = "bearer";
+/// How often we record that a personal access token is still being used.
+const LAST_USED_MINUTES: i64 = 5;
+
+/// What a bearer token can be used for.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Any request which doesn't change anything.
+    ReadOnly,
+    /// Creating and editing tasks (and recording progress on them).
+    Tasks,
+    /// Posting and editing messages.
+    Messages,
+    /// Anything at all (including managing tokens).
+    Admin,
+}
+
+impl Scope {
+    const ALL: [Scope; 4] = [Scope::ReadOnly, Scope::Tasks, Scope::Messages, Scope::Admin];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadOnly => "read_only",
+            Scope::Tasks => "tasks",
+            Scope::Messages => "messages",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.as_str() == scope)
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Scope::ReadOnly => "Read only",
+            Scope::Tasks => "Tasks",
+            Scope::Messages => "Messages",
+            Scope::Admin => "Admin (can do anything, including managing tokens)",
+        }
+    }
+
+    /// The scope which a token needs in order to call the route with the provided name.
+    ///
+    /// Any route which isn't listed here needs an admin token, so new routes (and routes which
+    /// change something in response to a `GET` request) can't be called with a read-only token by
+    /// accident.
+    fn required_for(route: &str) -> Self {
+        match route {
+            "api_dashboard"
+            | "api_view_all_classes"
+            | "api_view_class_overview"
+            | "api_view_class_members_page"
+            | "api_list_all_messages"
+            | "api_view_message"
+            | "api_view_all_async_tasks_in_class"
+            | "api_view_specific_asynchronous_task"
+            | "api_view_all_sync_tasks_in_class"
+            | "api_view_specific_synchronous_task"
+            | "api_list_notifications"
+            | "api_view_preferences"
+            | "api_view_connected_calendar" => Scope::ReadOnly,
+            "api_create_new_async_task"
+            | "api_apply_edit_task"
+            | "api_delete_task"
+            | "api_set_estimate" => Scope::Tasks,
+            "api_apply_create_new_class_message"
+            | "api_apply_message_edit"
+            | "api_apply_message_reply_edit"
+            | "api_reply_to_teacher_message" => Scope::Messages,
+            _ => Scope::Admin,
+        }
+    }
+
+    /// Whether a token with this scope can make requests which need the `required` scope.
+    fn allows(self, required: Scope) -> bool {
+        self == Scope::Admin || required == Scope::ReadOnly || self == required
+    }
+}
+
+/// Hashes a token (or anything else which is random enough that it doesn't need a slow hash).
+pub(super) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BearerToken {
+    /// This is always `JWT_TYPE`.
+    pub typ: String,
+    pub exp: usize,
+    pub user_id: i32,
+    /// The session which the token was created from.
+    pub session_id: i32,
+    /// The hash of the session's token hash. Session ids are easy to guess, so this shows that
+    /// the token really was created from the session (without giving away what we store).
+    pub session: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// The result of checking the bearer token (if there is one) which was sent with a request.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Bearer {
+    /// No (valid) bearer token was sent.
+    Missing,
+    /// The token belongs to this user, and has the scope needed for the request.
+    Allowed(i32),
+    /// The token is valid, but doesn't have the scope needed for the request.
+    InsufficientScope,
+}
+
+/// Finds the user (and scopes) of a bearer token.
+fn find_token_user(
+    token: &str,
+    conn: &DatabaseConnection,
+) -> QueryResult<Option<(i32, Vec<Scope>)>> {
+    let now = Utc::now().naive_utc();
+    if token.starts_with(TOKEN_PREFIX) {
+        let found = access_token::table
+            .filter(access_token::token_hash.eq(hash_token(token)))
+            .filter(
+                access_token::expires
+                    .is_null()
+                    .or(access_token::expires.gt(now)),
+            )
+            .first::<AccessToken>(conn)
+            .optional()?;
+        let found = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if found
+            .last_used
+            .map(|last_used| now - last_used > Duration::minutes(LAST_USED_MINUTES))
+            .unwrap_or(true)
+        {
+            diesel::update(access_token::table.find(found.id))
+                .set(access_token::last_used.eq(now))
+                .execute(conn)?;
+        }
+        Ok(Some((
+            found.user_id,
+            found
+                .scopes
+                .iter()
+                .filter_map(|s| Scope::parse(s))
+                .collect(),
+        )))
+    } else {
+        let key = match decoding_key() {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let claims = match jwt::decode::<BearerToken>(token, &key, &jwt::Validation::default()) {
+            Ok(token) if token.claims.typ == JWT_TYPE => token.claims,
+            _ => return Ok(None),
+        };
+        let session_token_hash = session::table
+            .filter(session::id.eq(claims.session_id))
+            .filter(session::user_id.eq(claims.user_id))
+            .filter(session::expires.gt(now))
+            .select(session::token_hash)
+            .first::<String>(conn)
+            .optional()?;
+        Ok(match session_token_hash {
+            Some(token_hash) if hash_token(&token_hash) == claims.session => {
+                Some((claims.user_id, claims.scopes))
+            }
+            _ => None,
+        })
+    }
+}
+
+async fn check_bearer(request: &rocket::Request<'_>) -> Bearer {
+    if !request.uri().path().starts_with("/api/") {
+        return Bearer::Missing;
+    }
+    let token = match request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+    {
+        Some(token) => token.trim().to_string(),
+        None => return Bearer::Missing,
+    };
+    let conn = match request.guard::<Database>().await.succeeded() {
+        Some(conn) => conn,
+        None => return Bearer::Missing,
+    };
+    let required = request
+        .route()
+        .and_then(|route| route.name.as_deref())
+        .map(Scope::required_for)
+        .unwrap_or(Scope::Admin);
+    match conn.run(move |c| find_token_user(&token, c)).await {
+        Ok(Some((user_id, scopes))) => {
+            if scopes.iter().any(|scope| scope.allows(required)) {
+                Bearer::Allowed(user_id)
+            } else {
+                Bearer::InsufficientScope
+            }
+        }
+        Ok(None) => Bearer::Missing,
+        Err(e) => {
+            error!("{:#?}", e);
+            Bearer::Missing
+        }
+    }
+}
+
+/// Checks the bearer token which was sent with the request (this is only done once per request,
+/// and only for API routes).
+pub(crate) async fn bearer_user(request: &rocket::Request<'_>) -> Bearer {
+    *request.local_cache_async(check_bearer(request)).await
+}
+
+#[derive(ThisError, Debug)]
+pub enum TokenError {
+    #[error("tokens need a name")]
+    NoName,
+    #[error("tokens need at least one scope")]
+    NoScopes,
+    #[error("tokens have to last for at least a day")]
+    InvalidExpiry,
+    #[error("database error")]
+    DatabaseError,
+    #[error("could not sign the token")]
+    SigningError,
+}
+
+impl From<diesel::result::Error> for TokenError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+impl TokenError {
+    fn reason(&self) -> &'static str {
+        match self {
+            TokenError::NoName => "Please give the token a name.",
+            TokenError::NoScopes => "Please choose at least one scope for the token.",
+            TokenError::InvalidExpiry => "Tokens have to last for at least one day.",
+            TokenError::DatabaseError => {
+                "Encountered a database error while undertaking this operation."
+            }
+            TokenError::SigningError => "Encountered an error while creating the token.",
+        }
+    }
+}
+
+/// A personal access token as it is shown to the user who it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenSummary {
+    id: i32,
+    name: String,
+    scopes: Vec<Scope>,
+    created: NaiveDateTime,
+    last_used: Option<NaiveDateTime>,
+    expires: Option<NaiveDateTime>,
+}
+
+impl From<AccessToken> for TokenSummary {
+    fn from(token: AccessToken) -> Self {
+        Self {
+            id: token.id,
+            scopes: token
+                .scopes
+                .iter()
+                .filter_map(|s| Scope::parse(s))
+                .collect(),
+            name: token.name,
+            created: token.created,
+            last_used: token.last_used,
+            expires: token.expires,
+        }
+    }
+}
+
+async fn list_tokens(user_id: i32, conn: &Database) -> Result<Vec<TokenSummary>, TokenError> {
+    let tokens = conn
+        .run(move |c| {
+            access_token::table
+                .filter(access_token::user_id.eq(user_id))
+                .order_by(access_token::created.desc())
+                .load::<AccessToken>(c)
+        })
+        .await?;
+    Ok(tokens.into_iter().map(From::from).collect())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CreateTokenData {
+    name: String,
+    scopes: Vec<Scope>,
+    /// How many days the token should work for (it lasts until it is revoked if this is missing).
+    expires_in_days: Option<i64>,
+}
+
+#[derive(FromForm, Debug, Clone)]
+pub struct CreateTokenForm {
+    name: String,
+    read_only: bool,
+    tasks: bool,
+    messages: bool,
+    admin: bool,
+    expires_in_days: Option<i64>,
+}
+
+impl From<&CreateTokenForm> for CreateTokenData {
+    fn from(form: &CreateTokenForm) -> Self {
+        Self {
+            name: form.name.clone(),
+            scopes: [
+                (form.read_only, Scope::ReadOnly),
+                (form.tasks, Scope::Tasks),
+                (form.messages, Scope::Messages),
+                (form.admin, Scope::Admin),
+            ]
+            .iter()
+            .filter(|(chosen, _)| *chosen)
+            .map(|(_, scope)| *scope)
+            .collect(),
+            expires_in_days: form.expires_in_days,
+        }
+    }
+}
+
+/// A newly created personal access token (this is the only time that the token itself is shown).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreatedToken {
+    id: i32,
+    token: String,
+}
+
+async fn create_token(
+    user_id: i32,
+    data: CreateTokenData,
+    conn: &Database,
+) -> Result<CreatedToken, TokenError> {
+    if data.name.trim().is_empty() {
+        return Err(TokenError::NoName);
+    }
+    if data.scopes.is_empty() {
+        return Err(TokenError::NoScopes);
+    }
+    let now = Utc::now().naive_utc();
+    let expires = match data.expires_in_days {
+        Some(days) if days < 1 => return Err(TokenError::InvalidExpiry),
+        Some(days) => Some(now + Duration::days(days)),
+        None => None,
+    };
+    let token = format!("{}{}", TOKEN_PREFIX, nanoid!(TOKEN_LENGTH));
+    let token_hash = hash_token(&token);
+    let id = conn
+        .run(move |c| {
+            diesel::insert_into(access_token::table)
+                .values(NewAccessToken {
+                    user_id,
+                    name: data.name.trim(),
+                    token_hash: &token_hash,
+                    scopes: data
+                        .scopes
+                        .iter()
+                        .map(|scope| scope.as_str().to_string())
+                        .collect(),
+                    created: now,
+                    expires,
+                })
+                .returning(access_token::id)
+                .get_result::<i32>(c)
+        })
+        .await?;
+    Ok(CreatedToken { id, token })
+}
+
+/// Revokes one of the user's tokens, returning whether it existed.
+async fn revoke_token(user_id: i32, id: i32, conn: &Database) -> Result<bool, TokenError> {
+    let deleted = conn
+        .run(move |c| {
+            diesel::delete(
+                access_token::table
+                    .filter(access_token::id.eq(id))
+                    .filter(access_token::user_id.eq(user_id)),
+            )
+            .execute(c)
+        })
+        .await?;
+    Ok(deleted > 0)
+}
+
+fn format_time(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn revoke_token_form(id: i32) -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/tokens/revoke"))
+        .child(
+            Input::new()
+                .attribute(Type::Hidden)
+                .attribute(Name::new("id"))
+                .attribute(Value::new(id.to_string())),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Revoke")),
+        )
+}
+
+fn create_token_form() -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/auth/tokens/create"))
+        .child(
+            Input::new()
+                .attribute(Type::Text)
+                .attribute(Name::new("name"))
+                .attribute(Placeholder::new("What is this token for?")),
+        )
+        .children(Scope::ALL.iter().map(|scope| {
+            Div::new().child(Label::new(scope.describe())).child(
+                Input::new()
+                    .attribute(Type::Checkbox)
+                    .attribute(Name::new(scope.as_str())),
+            )
+        }))
+        .child(Label::new(
+            "How many days the token should work for (leave this empty for it to work until you \
+            revoke it)",
+        ))
+        .child(
+            Input::new()
+                .attribute(Type::Number)
+                .attribute(Name::new("expires_in_days")),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Create token")),
+        )
+}
+
+fn render_token(token: TokenSummary) -> Div {
+    Div::new()
+        .child(H3::new(token.name))
+        .child(P::with_text(format!(
+            "Scopes: {}",
+            token
+                .scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+        .child(P::with_text(format!(
+            "Created at {}, {}, {}.",
+            format_time(token.created),
+            token
+                .last_used
+                .map(|time| format!("last used at {}", format_time(time)))
+                .unwrap_or_else(|| "never used".to_string()),
+            token
+                .expires
+                .map(|time| format!("expires at {}", format_time(time)))
+                .unwrap_or_else(|| "never expires".to_string())
+        )))
+        .child(revoke_token_form(token.id))
+}
+
+async fn tokens_page(user_id: i32, conn: &Database, message: Option<String>) -> Html {
+    let tokens = match list_tokens(user_id, conn).await {
+        Ok(tokens) => tokens,
+        Err(_) => return database_error(),
+    };
+    Html::new().head(default_head("Access tokens")).body(
+        Body::new()
+            .child(H1::new("Access tokens"))
+            .child(P::with_text(
+                "Access tokens let scripts and apps use the Lovelace API on your behalf. Send \
+                    them in the `Authorization` header, as `Bearer <token>`.",
+            ))
+            .map(|body| match message {
+                Some(message) => body.child(P::with_text(message)),
+                None => body,
+            })
+            .children(tokens.into_iter().map(render_token))
+            .child(H2::new("Create a new token"))
+            .child(create_token_form()),
+    )
+}
+
+#[get("/tokens")]
+pub async fn html_list_tokens(auth: AuthCookie, conn: Database) -> Html {
+    tokens_page(auth.0, &conn, None).await
+}
+
+#[post("/tokens/create", data = "<form>")]
+pub async fn html_create_token(
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<CreateTokenForm>,
+) -> Html {
+    let message = match create_token(auth.0, CreateTokenData::from(&*form), &conn).await {
+        Ok(created) => format!(
+            "Your new token is {} – copy it now, because you won't be able to see it again.",
+            created.token
+        ),
+        Err(TokenError::DatabaseError) => return database_error(),
+        Err(e) => e.reason().to_string(),
+    };
+    tokens_page(auth.0, &conn, Some(message)).await
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeTokenForm {
+    id: i32,
+}
+
+#[post("/tokens/revoke", data = "<form>")]
+pub async fn html_revoke_token(
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<RevokeTokenForm>,
+) -> Html {
+    let message = match revoke_token(auth.0, form.id, &conn).await {
+        Ok(true) => "That token has been revoked.",
+        Ok(false) => "That token doesn't exist (it might have already been revoked).",
+        Err(_) => return database_error(),
+    };
+    tokens_page(auth.0, &conn, Some(message.to_string())).await
+}
+
+#[get("/tokens")]
+pub async fn api_list_tokens(
+    auth: AuthCookie,
+    conn: Database,
+) -> Json<ApiResponse<Vec<TokenSummary>>> {
+    Json(match list_tokens(auth.0, &conn).await {
+        Ok(tokens) => ApiResponse::new_ok(tokens),
+        Err(e) => ApiResponse::new_err(e.reason()),
+    })
+}
+
+#[post("/tokens/create", data = "<data>")]
+pub async fn api_create_token(
+    auth: AuthCookie,
+    conn: Database,
+    data: Json<CreateTokenData>,
+) -> Json<ApiResponse<CreatedToken>> {
+    Json(match create_token(auth.0, data.into_inner(), &conn).await {
+        Ok(created) => ApiResponse::new_ok(created),
+        Err(e) => ApiResponse::new_err(e.reason()),
+    })
+}
+
+#[post("/tokens/revoke", data = "<form>")]
+pub async fn api_revoke_token(
+    auth: AuthCookie,
+    conn: Database,
+    form: Json<RevokeTokenForm>,
+) -> Json<ApiResponse<()>> {
+    Json(match revoke_token(auth.0, form.id, &conn).await {
+        Ok(true) => ApiResponse::new_ok(()),
+        Ok(false) => ApiResponse::new_err("A token with that id could not be found."),
+        Err(e) => ApiResponse::new_err(e.reason()),
+    })
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IssueJwtData {
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssuedJwt {
+    token: String,
+    expires: NaiveDateTime,
+}
+
+/// Creates a short-lived bearer token for the session which made the request (this needs the login
+/// cookie, so bearer tokens can't be used to create more bearer tokens).
+#[post("/token", data = "<data>")]
+pub async fn api_issue_jwt(
+    current: CurrentSession,
+    data: Json<IssueJwtData>,
+) -> Json<ApiResponse<IssuedJwt>> {
+    if data.scopes.is_empty() {
+        return Json(ApiResponse::new_err(TokenError::NoScopes.reason()));
+    }
+    let expires = Utc::now() + Duration::minutes(JWT_MINUTES);
+    let claims = BearerToken {
+        typ: JWT_TYPE.to_string(),
+        exp: expires.timestamp() as usize,
+        user_id: current.0.user_id,
+        session_id: current.0.id,
+        session: hash_token(&current.0.token_hash),
+        scopes: data.into_inner().scopes,
+    };
+    Json(
+        match encoding_key().map(|key| jwt::encode(&jwt::Header::default(), &claims, &key)) {
+            Some(Ok(token)) => ApiResponse::new_ok(IssuedJwt {
+                token,
+                expires: expires.naive_utc(),
+            }),
+            Some(Err(e)) => {
+                error!("{:#?}", e);
+                ApiResponse::new_err(TokenError::SigningError.reason())
+            }
+            None => ApiResponse::new_err(TokenError::SigningError.reason()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod test_scopes {
+    use super::Scope;
+
+    #[test]
+    fn test_required_scopes() {
+        let required = Scope::required_for;
+        assert_eq!(required("api_view_all_classes"), Scope::ReadOnly);
+        assert_eq!(required("api_create_new_async_task"), Scope::Tasks);
+        // deleting tasks is a `GET` request, but it still needs the tasks scope
+        assert_eq!(required("api_delete_task"), Scope::Tasks);
+        assert_eq!(
+            required("api_apply_create_new_class_message"),
+            Scope::Messages
+        );
+        assert_eq!(required("api_apply_message_reply_edit"), Scope::Messages);
+        assert_eq!(required("api_create_class"), Scope::Admin);
+        assert_eq!(required("api_join_class"), Scope::Admin);
+        assert_eq!(required("api_list_tokens"), Scope::Admin);
+
+        assert!(Scope::Tasks.allows(Scope::ReadOnly));
+        assert!(Scope::Admin.allows(Scope::Messages));
+        assert!(!Scope::ReadOnly.allows(Scope::Tasks));
+        assert!(!Scope::Messages.allows(Scope::Tasks));
+    }
+}
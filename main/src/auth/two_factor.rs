@@ -0,0 +1,985 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+//! Two-factor authentication, using time-based one-time passwords (TOTP, RFC 6238).
+//!
+//! People set it up by scanning a QR code (of a provisioning URI) with their authenticator app and
+//! then entering a code from the app. From then on, logging in needs a code as well as the
+//! password – or one of the single-use recovery codes which we show them when they set it up.
+//!
+//! Administrators can require everybody in their institution to use two-factor authentication.
+//! People who haven't set it up yet then have to do so before they can finish logging in.
+//!
+//! Between checking the password and checking the code we keep track of who is logging in with a
+//! (private) cookie, which only lets them finish logging in or set up two-factor authentication.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use malvolio::prelude::*;
+use qrcode::{render::svg, QrCode};
+use rocket::{
+    http::{Cookie, CookieJar},
+    request::FromRequest,
+};
+use rocket_contrib::json::Json;
+use sha1::Sha1;
+use thiserror::Error as ThisError;
+
+use crate::{
+    db::{Database, DatabaseConnection},
+    models::{NewRecoveryCode, NewTotp, Totp},
+    schema::{
+        administrator, institution, institution_student, institution_teacher, recovery_code, totp,
+        users,
+    },
+    utils::{default_head, error_messages::database_error, json_response::ApiResponse},
+};
+
+use super::{
+    login::{finish_login, logged_in_page},
+    sessions::{current_session, CurrentSession, UserAgent},
+    tokens::hash_token,
+    AuthError,
+};
+
+/// Keeps track of somebody whose password was correct, but who still needs to enter a code.
+const TWO_FACTOR_COOKIE: &str = "TWO_FACTOR_PENDING";
+/// How long people have to enter their code (or set up two-factor authentication) after entering
+/// their password.
+const PENDING_LOGIN_MINUTES: i64 = 10;
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// We also accept codes from this many time steps either side of the current one, because the
+/// clocks of people's phones are not always quite right.
+const TOTP_SKEW: i64 = 1;
+/// How many incorrect codes can be entered in a row before the account is locked.
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+/// How long accounts are locked for. This is longer than `PENDING_LOGIN_MINUTES`, so logins which
+/// were started before the account was locked can't be finished afterwards.
+const LOCKOUT_MINUTES: i64 = 15;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// Letters and numbers which are hard to confuse with each other.
+const RECOVERY_CODE_ALPHABET: [char; 31] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v',
+    'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+const ISSUER: &str = "Lovelace";
+
+/// Computes the HOTP value (RFC 4226) for a counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC can take keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    value % 10u32.pow(TOTP_DIGITS)
+}
+
+fn format_code(value: u32) -> String {
+    format!("{:0width$}", value, width = TOTP_DIGITS as usize)
+}
+
+/// Returns the time step which `code` is valid for (if it is valid for one close enough to `now`,
+/// which is a Unix timestamp).
+fn check_totp(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = base32::decode(BASE32, secret)?;
+    let current = now / TOTP_STEP_SECONDS;
+    (current - TOTP_SKEW..=current + TOTP_SKEW)
+        .find(|step| format_code(hotp(&secret, *step as u64)) == code)
+}
+
+/// Whether a code looks like it came from an authenticator app (rather than being a recovery
+/// code).
+fn is_totp_code(code: &str) -> bool {
+    code.len() == TOTP_DIGITS as usize && code.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Removes the formatting that people might add (or copy) when typing in a code.
+fn normalise_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn generate_secret() -> String {
+    base32::encode(BASE32, &rand::random::<[u8; SECRET_BYTES]>())
+}
+
+/// Percent-encodes the label of a provisioning URI.
+fn encode_label(label: &str) -> String {
+    label
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The URI which authenticator apps read (from a QR code) to set up two-factor authentication.
+fn provisioning_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{label}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={}\
+        &period={}",
+        TOTP_DIGITS,
+        TOTP_STEP_SECONDS,
+        issuer = ISSUER,
+        label = encode_label(username),
+        secret = secret
+    )
+}
+
+/// Remembers that `user_id` has entered their password correctly, so that they can finish logging
+/// in once they have entered a code (or set up two-factor authentication).
+pub(super) fn start_pending_login(cookies: &CookieJar<'_>, user_id: i32) {
+    cookies.add_private(Cookie::new(
+        TWO_FACTOR_COOKIE,
+        format!("{}:{}", user_id, Utc::now().timestamp()),
+    ));
+}
+
+/// The user who is part way through logging in (if there is one).
+fn pending_login(cookies: &CookieJar<'_>) -> Option<i32> {
+    let cookie = cookies.get_private(TWO_FACTOR_COOKIE)?;
+    let mut parts = cookie.value().splitn(2, ':');
+    let user_id = parts.next()?.parse::<i32>().ok()?;
+    let started = parts.next()?.parse::<i64>().ok()?;
+    if Utc::now().timestamp() - started > Duration::minutes(PENDING_LOGIN_MINUTES).num_seconds() {
+        return None;
+    }
+    Some(user_id)
+}
+
+fn end_pending_login(cookies: &CookieJar<'_>) {
+    cookies.remove_private(Cookie::named(TWO_FACTOR_COOKIE));
+}
+
+fn confirmed_totp(user_id: i32, conn: &DatabaseConnection) -> QueryResult<Option<Totp>> {
+    totp::table
+        .filter(totp::user_id.eq(user_id))
+        .filter(totp::confirmed.eq(true))
+        .first::<Totp>(conn)
+        .optional()
+}
+
+/// Whether the user belongs to an institution which requires two-factor authentication.
+fn required_by_institution(user_id: i32, conn: &DatabaseConnection) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        institution::table
+            .filter(institution::require_two_factor.eq(true))
+            .filter(
+                institution::id
+                    .eq_any(
+                        administrator::table
+                            .filter(administrator::user_id.eq(user_id))
+                            .select(administrator::institution_id),
+                    )
+                    .or(institution::id.eq_any(
+                        institution_teacher::table
+                            .filter(institution_teacher::user_id.eq(user_id))
+                            .select(institution_teacher::institution_id),
+                    ))
+                    .or(institution::id.eq_any(
+                        institution_student::table
+                            .filter(institution_student::user_id.eq(user_id))
+                            .select(institution_student::institution_id),
+                    )),
+            ),
+    ))
+    .get_result(conn)
+}
+
+/// Checks a code from an authenticator app, making sure that it hasn't been used before.
+fn use_totp_code(totp: &Totp, code: &str, conn: &DatabaseConnection) -> QueryResult<bool> {
+    let step = match check_totp(&totp.secret, code, Utc::now().timestamp()) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+    diesel::update(
+        totp::table.filter(totp::id.eq(totp.id)).filter(
+            totp::last_used_step
+                .is_null()
+                .or(totp::last_used_step.lt(step)),
+        ),
+    )
+    .set(totp::last_used_step.eq(step))
+    .execute(conn)
+    .map(|updated| updated > 0)
+}
+
+/// Checks a recovery code, using it up if it is valid.
+fn use_recovery_code(user_id: i32, code: &str, conn: &DatabaseConnection) -> QueryResult<bool> {
+    diesel::delete(
+        recovery_code::table
+            .filter(recovery_code::user_id.eq(user_id))
+            .filter(recovery_code::code_hash.eq(hash_token(code))),
+    )
+    .execute(conn)
+    .map(|deleted| deleted > 0)
+}
+
+fn is_locked(totp: &Totp) -> bool {
+    totp.locked_until
+        .map(|locked_until| locked_until > Utc::now().naive_utc())
+        .unwrap_or(false)
+}
+
+/// The result of checking a code which the user has entered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CodeCheck {
+    Valid,
+    NotValid,
+    /// Too many incorrect codes have been entered, so the code wasn't checked.
+    LockedOut,
+}
+
+/// Checks a code which the user has entered (which can be either from their authenticator app or
+/// one of their recovery codes).
+///
+/// Incorrect codes are counted, and after `MAX_FAILED_ATTEMPTS` of them in a row the account is
+/// locked for `LOCKOUT_MINUTES` (so that codes can't be guessed).
+fn verify_code(user_id: i32, code: &str, conn: &DatabaseConnection) -> QueryResult<CodeCheck> {
+    let code = normalise_code(code);
+    let totp = match confirmed_totp(user_id, conn)? {
+        Some(totp) => totp,
+        None => return Ok(CodeCheck::NotValid),
+    };
+    if is_locked(&totp) {
+        return Ok(CodeCheck::LockedOut);
+    }
+    let valid = if is_totp_code(&code) {
+        use_totp_code(&totp, &code, conn)?
+    } else {
+        use_recovery_code(user_id, &code, conn)?
+    };
+    if valid {
+        diesel::update(totp::table.find(totp.id))
+            .set((
+                totp::failed_attempts.eq(0),
+                totp::locked_until.eq(None::<NaiveDateTime>),
+            ))
+            .execute(conn)?;
+        return Ok(CodeCheck::Valid);
+    }
+    let failed_attempts = diesel::update(totp::table.find(totp.id))
+        .set(totp::failed_attempts.eq(totp::failed_attempts + 1))
+        .returning(totp::failed_attempts)
+        .get_result::<i32>(conn)?;
+    if failed_attempts < MAX_FAILED_ATTEMPTS {
+        return Ok(CodeCheck::NotValid);
+    }
+    diesel::update(totp::table.find(totp.id))
+        .set((
+            totp::failed_attempts.eq(0),
+            totp::locked_until.eq(Utc::now().naive_utc() + Duration::minutes(LOCKOUT_MINUTES)),
+        ))
+        .execute(conn)?;
+    Ok(CodeCheck::LockedOut)
+}
+
+/// Replaces all of the user's recovery codes with new ones, which are returned.
+fn replace_recovery_codes(user_id: i32, conn: &DatabaseConnection) -> QueryResult<Vec<String>> {
+    diesel::delete(recovery_code::table.filter(recovery_code::user_id.eq(user_id)))
+        .execute(conn)?;
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| nanoid!(RECOVERY_CODE_LENGTH, &RECOVERY_CODE_ALPHABET))
+        .collect::<Vec<_>>();
+    let hashes = codes
+        .iter()
+        .map(|code| hash_token(code))
+        .collect::<Vec<_>>();
+    diesel::insert_into(recovery_code::table)
+        .values(
+            hashes
+                .iter()
+                .map(|code_hash| NewRecoveryCode { user_id, code_hash })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    // split the codes in half to make them easier to read
+    Ok(codes
+        .into_iter()
+        .map(|code| {
+            let (start, end) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            format!("{}-{}", start, end)
+        })
+        .collect())
+}
+
+/// What still needs to happen (after the password has been checked) for somebody to log in.
+#[derive(Debug, Copy, Clone)]
+pub(super) enum SecondFactor {
+    /// The user doesn't use two-factor authentication (and doesn't have to).
+    NotNeeded,
+    /// The code which was provided is correct.
+    Verified,
+    /// The user uses two-factor authentication, but no code was provided.
+    Missing,
+    /// The code which was provided is not correct.
+    NotValid,
+    /// Too many incorrect codes have been entered, so the user can't log in for a while.
+    LockedOut,
+    /// The user's institution requires two-factor authentication, but they haven't set it up.
+    SetupRequired,
+}
+
+pub(super) fn check_second_factor(
+    user_id: i32,
+    code: Option<&str>,
+    conn: &DatabaseConnection,
+) -> QueryResult<SecondFactor> {
+    if let Some(totp) = confirmed_totp(user_id, conn)? {
+        Ok(match code {
+            Some(code) => match verify_code(user_id, code, conn)? {
+                CodeCheck::Valid => SecondFactor::Verified,
+                CodeCheck::NotValid => SecondFactor::NotValid,
+                CodeCheck::LockedOut => SecondFactor::LockedOut,
+            },
+            None if is_locked(&totp) => SecondFactor::LockedOut,
+            None => SecondFactor::Missing,
+        })
+    } else if required_by_institution(user_id, conn)? {
+        Ok(SecondFactor::SetupRequired)
+    } else {
+        Ok(SecondFactor::NotNeeded)
+    }
+}
+
+/// Somebody who can set up two-factor authentication: either a logged in user, or somebody who has
+/// entered their password but needs to set up two-factor authentication before they can finish
+/// logging in.
+///
+/// This has the same requirements as `AuthCookie`.
+#[derive(Debug, Copy, Clone)]
+pub struct TwoFactorUser {
+    user_id: i32,
+    logged_in: bool,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for TwoFactorUser {
+    type Error = AuthError;
+
+    async fn from_request(
+        request: &'a rocket::Request<'r>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        if let Some(session) = current_session(request).await {
+            return rocket::request::Outcome::Success(TwoFactorUser {
+                user_id: session.user_id,
+                logged_in: true,
+            });
+        }
+        match pending_login(request.cookies()) {
+            Some(user_id) => rocket::request::Outcome::Success(TwoFactorUser {
+                user_id,
+                logged_in: false,
+            }),
+            None => rocket::request::Outcome::Forward(()),
+        }
+    }
+}
+
+#[derive(ThisError, Debug)]
+pub enum TwoFactorError {
+    #[error("no login in progress")]
+    NoPendingLogin,
+    #[error("two-factor authentication is already turned on")]
+    AlreadyEnabled,
+    #[error("two-factor authentication is not turned on")]
+    NotEnabled,
+    #[error("two-factor authentication setup hasn't been started")]
+    NotStarted,
+    #[error("invalid code")]
+    InvalidCode,
+    #[error("too many incorrect codes have been entered")]
+    LockedOut,
+    #[error("the user's institution requires two-factor authentication")]
+    Required,
+    #[error("database error")]
+    DatabaseError,
+}
+
+impl From<diesel::result::Error> for TwoFactorError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+impl TwoFactorError {
+    fn reason(&self) -> &'static str {
+        match self {
+            TwoFactorError::NoPendingLogin => {
+                "Your login has expired (or you haven't logged in yet). Please log in again."
+            }
+            TwoFactorError::AlreadyEnabled => {
+                "Two-factor authentication is already turned on. If you want to use a different \
+                authenticator, please turn it off first."
+            }
+            TwoFactorError::NotEnabled => "Two-factor authentication isn't turned on.",
+            TwoFactorError::NotStarted => {
+                "Please start setting up two-factor authentication before confirming it."
+            }
+            TwoFactorError::InvalidCode => "That code isn't correct.",
+            TwoFactorError::LockedOut => {
+                "Too many incorrect codes have been entered, so your account has been locked for \
+                a few minutes. Please try logging in again later."
+            }
+            TwoFactorError::Required => {
+                "Your institution requires two-factor authentication, so you can't turn it off."
+            }
+            TwoFactorError::DatabaseError => {
+                "Encountered a database error while undertaking this operation."
+            }
+        }
+    }
+}
+
+/// Whether somebody uses two-factor authentication (and whether they have to).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwoFactorStatus {
+    enabled: bool,
+    required: bool,
+    recovery_codes_left: i64,
+}
+
+async fn load_status(user_id: i32, conn: &Database) -> Result<TwoFactorStatus, TwoFactorError> {
+    conn.run(move |c| {
+        Ok(TwoFactorStatus {
+            enabled: confirmed_totp(user_id, c)?.is_some(),
+            required: required_by_institution(user_id, c)?,
+            recovery_codes_left: recovery_code::table
+                .filter(recovery_code::user_id.eq(user_id))
+                .count()
+                .get_result(c)?,
+        })
+    })
+    .await
+}
+
+/// The details which people need to add Lovelace to their authenticator app.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Enrolment {
+    secret: String,
+    provisioning_uri: String,
+}
+
+/// Creates a new TOTP secret for the user (replacing any which they haven't confirmed yet).
+async fn begin_enrolment(user_id: i32, conn: &Database) -> Result<Enrolment, TwoFactorError> {
+    conn.run(move |c| {
+        if confirmed_totp(user_id, c)?.is_some() {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+        let username = users::table
+            .find(user_id)
+            .select(users::username)
+            .get_result::<String>(c)?;
+        let secret = generate_secret();
+        c.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(totp::table.filter(totp::user_id.eq(user_id))).execute(c)?;
+            diesel::insert_into(totp::table)
+                .values(NewTotp {
+                    user_id,
+                    secret: &secret,
+                    confirmed: false,
+                    created: Utc::now().naive_utc(),
+                })
+                .execute(c)
+        })?;
+        Ok(Enrolment {
+            provisioning_uri: provisioning_uri(&username, &secret),
+            secret,
+        })
+    })
+    .await
+}
+
+/// Turns two-factor authentication on (if the code from the user's authenticator app is correct),
+/// returning the user's recovery codes.
+async fn confirm_enrolment(
+    user_id: i32,
+    code: String,
+    conn: &Database,
+) -> Result<Vec<String>, TwoFactorError> {
+    conn.run(move |c| {
+        let totp = totp::table
+            .filter(totp::user_id.eq(user_id))
+            .filter(totp::confirmed.eq(false))
+            .first::<Totp>(c)
+            .optional()?
+            .ok_or(TwoFactorError::NotStarted)?;
+        let step = check_totp(&totp.secret, &normalise_code(&code), Utc::now().timestamp())
+            .ok_or(TwoFactorError::InvalidCode)?;
+        Ok(c.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::update(totp::table.find(totp.id))
+                .set((totp::confirmed.eq(true), totp::last_used_step.eq(step)))
+                .execute(c)?;
+            replace_recovery_codes(user_id, c)
+        })?)
+    })
+    .await
+}
+
+async fn regenerate_recovery_codes(
+    user_id: i32,
+    conn: &Database,
+) -> Result<Vec<String>, TwoFactorError> {
+    conn.run(move |c| {
+        if confirmed_totp(user_id, c)?.is_none() {
+            return Err(TwoFactorError::NotEnabled);
+        }
+        Ok(replace_recovery_codes(user_id, c)?)
+    })
+    .await
+}
+
+async fn disable_two_factor(
+    user_id: i32,
+    code: String,
+    conn: &Database,
+) -> Result<(), TwoFactorError> {
+    conn.run(move |c| {
+        if confirmed_totp(user_id, c)?.is_none() {
+            return Err(TwoFactorError::NotEnabled);
+        }
+        if required_by_institution(user_id, c)? {
+            return Err(TwoFactorError::Required);
+        }
+        match verify_code(user_id, &code, c)? {
+            CodeCheck::Valid => {}
+            CodeCheck::NotValid => return Err(TwoFactorError::InvalidCode),
+            CodeCheck::LockedOut => return Err(TwoFactorError::LockedOut),
+        }
+        c.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(totp::table.filter(totp::user_id.eq(user_id))).execute(c)?;
+            diesel::delete(recovery_code::table.filter(recovery_code::user_id.eq(user_id)))
+                .execute(c)
+        })?;
+        Ok(())
+    })
+    .await
+}
+
+/// Finishes logging in somebody who has entered their password (and now a code).
+async fn login_with_code(
+    cookies: &CookieJar<'_>,
+    code: String,
+    user_agent: UserAgent,
+    conn: &Database,
+) -> Result<(), TwoFactorError> {
+    let user_id = pending_login(cookies).ok_or(TwoFactorError::NoPendingLogin)?;
+    match conn.run(move |c| verify_code(user_id, &code, c)).await? {
+        CodeCheck::Valid => {}
+        CodeCheck::NotValid => return Err(TwoFactorError::InvalidCode),
+        CodeCheck::LockedOut => {
+            // the user has to start again (with their password) once the lock has expired
+            end_pending_login(cookies);
+            return Err(TwoFactorError::LockedOut);
+        }
+    }
+    finish_login(cookies, user_id, user_agent, conn)
+        .await
+        .map_err(|_| TwoFactorError::DatabaseError)?;
+    end_pending_login(cookies);
+    Ok(())
+}
+
+fn code_form(action: &'static str, submit: &'static str) -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new(action))
+        .child(
+            Input::new()
+                .attribute(Type::Text)
+                .attribute(Name::new("code"))
+                .attribute(Placeholder::new("Code")),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new(submit)),
+        )
+}
+
+fn button_form(action: &'static str, submit: &'static str) -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new(action))
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new(submit)),
+        )
+}
+
+fn error_page(e: TwoFactorError) -> Html {
+    match e {
+        TwoFactorError::DatabaseError => database_error(),
+        e => Html::new()
+            .status(400)
+            .head(default_head("Two-factor authentication"))
+            .body(
+                Body::new()
+                    .child(H1::new("Two-factor authentication"))
+                    .child(P::with_text(e.reason()))
+                    .child(A::new().href("/auth/two_factor").text("Go back")),
+            ),
+    }
+}
+
+/// Asks somebody who has entered their password for their two-factor authentication code.
+pub(super) fn code_page(message: Option<&str>) -> Html {
+    Html::new()
+        .head(default_head("Two-factor authentication"))
+        .body(
+            Body::new()
+                .child(H1::new("Two-factor authentication"))
+                .child(P::with_text(
+                    "Please enter the code from your authenticator app (or one of your recovery \
+                    codes).",
+                ))
+                .map(|body| match message {
+                    Some(message) => body.child(P::with_text(message)),
+                    None => body,
+                })
+                .child(code_form("/auth/login/two_factor", "Log in")),
+        )
+}
+
+/// Tells somebody who has entered their password that they need to set up two-factor
+/// authentication before they can log in.
+pub(super) fn setup_required_page() -> Html {
+    Html::new()
+        .head(default_head("Set up two-factor authentication"))
+        .body(
+            Body::new()
+                .child(H1::new("Set up two-factor authentication"))
+                .child(P::with_text(
+                    "Your institution requires everybody to use two-factor authentication, so you \
+                    need to set it up before you can log in.",
+                ))
+                .child(button_form(
+                    "/auth/two_factor/enrol",
+                    "Set up two-factor authentication",
+                )),
+        )
+}
+
+fn recovery_codes_page(title: &'static str, text: &'static str, codes: Vec<String>) -> Html {
+    Html::new().head(default_head(title)).body(
+        Body::new()
+            .child(H1::new(title))
+            .child(P::with_text(text))
+            .child(P::with_text(
+                "These are your recovery codes. Each of them can be used once (instead of a \
+                    code from your authenticator app) to log in. Please store them somewhere safe \
+                    – you won't be able to see them again.",
+            ))
+            .children(codes.into_iter().map(P::with_text))
+            .child(A::new().href("/auth/two_factor").text("Done")),
+    )
+}
+
+#[get("/two_factor")]
+pub async fn html_two_factor_settings(user: TwoFactorUser, conn: Database) -> Html {
+    let status = match load_status(user.user_id, &conn).await {
+        Ok(status) => status,
+        Err(e) => return error_page(e),
+    };
+    let body = Body::new().child(H1::new("Two-factor authentication"));
+    let body = if status.enabled {
+        body.child(P::with_text(format!(
+            "Two-factor authentication is turned on. You have {} recovery codes left.",
+            status.recovery_codes_left
+        )))
+        .child(button_form(
+            "/auth/two_factor/recovery_codes",
+            "Get new recovery codes",
+        ))
+        .map(|body| {
+            if status.required {
+                body.child(P::with_text(
+                    "Your institution requires two-factor authentication, so you can't turn it \
+                    off.",
+                ))
+            } else {
+                body.child(code_form(
+                    "/auth/two_factor/disable",
+                    "Turn off two-factor authentication",
+                ))
+            }
+        })
+    } else {
+        body.child(P::with_text(if status.required {
+            "Your institution requires you to use two-factor authentication, but you haven't set \
+            it up yet."
+        } else {
+            "Two-factor authentication makes it much harder for anybody else to log in to your \
+            account, even if they find out your password."
+        }))
+        .child(button_form(
+            "/auth/two_factor/enrol",
+            "Set up two-factor authentication",
+        ))
+    };
+    Html::new()
+        .head(default_head("Two-factor authentication"))
+        .body(body)
+}
+
+#[post("/two_factor/enrol")]
+pub async fn html_begin_enrolment(user: TwoFactorUser, conn: Database) -> Html {
+    let enrolment = match begin_enrolment(user.user_id, &conn).await {
+        Ok(enrolment) => enrolment,
+        Err(e) => return error_page(e),
+    };
+    let qr_code = match QrCode::new(enrolment.provisioning_uri.as_bytes()) {
+        Ok(code) => code.render::<svg::Color>().min_dimensions(200, 200).build(),
+        Err(e) => {
+            error!("{:#?}", e);
+            return database_error();
+        }
+    };
+    Html::new()
+        .head(default_head("Set up two-factor authentication"))
+        .body(
+            Body::new()
+                .child(H1::new("Set up two-factor authentication"))
+                .child(P::with_text(
+                    "Scan this QR code with your authenticator app, and then enter the code which \
+                    it shows you.",
+                ))
+                .child(
+                    Img::new()
+                        .attribute(Src::new(format!(
+                            "data:image/svg+xml;base64,{}",
+                            base64::encode(qr_code)
+                        )))
+                        .attribute(Alt::new("A QR code to scan with your authenticator app")),
+                )
+                .child(P::with_text(format!(
+                    "If you can't scan the code, you can type this key into your app instead: {}",
+                    enrolment.secret
+                )))
+                .child(
+                    A::new()
+                        .href(enrolment.provisioning_uri)
+                        .text("Or open it in your authenticator app directly"),
+                )
+                .child(code_form(
+                    "/auth/two_factor/confirm",
+                    "Turn on two-factor authentication",
+                )),
+        )
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactorCodeForm {
+    code: String,
+}
+
+#[post("/two_factor/confirm", data = "<form>")]
+pub async fn html_confirm_enrolment(
+    user: TwoFactorUser,
+    cookies: &CookieJar<'_>,
+    user_agent: UserAgent,
+    conn: Database,
+    form: rocket::form::Form<TwoFactorCodeForm>,
+) -> Html {
+    let codes = match confirm_enrolment(user.user_id, form.into_inner().code, &conn).await {
+        Ok(codes) => codes,
+        Err(e) => return error_page(e),
+    };
+    if !user.logged_in {
+        if finish_login(cookies, user.user_id, user_agent, &conn)
+            .await
+            .is_err()
+        {
+            return database_error();
+        }
+        end_pending_login(cookies);
+    }
+    recovery_codes_page(
+        "Two-factor authentication is on",
+        "From now on you'll need a code from your authenticator app to log in.",
+        codes,
+    )
+}
+
+#[post("/two_factor/recovery_codes")]
+pub async fn html_regenerate_recovery_codes(current: CurrentSession, conn: Database) -> Html {
+    match regenerate_recovery_codes(current.0.user_id, &conn).await {
+        Ok(codes) => recovery_codes_page(
+            "New recovery codes",
+            "Your old recovery codes don't work any more.",
+            codes,
+        ),
+        Err(e) => error_page(e),
+    }
+}
+
+#[post("/two_factor/disable", data = "<form>")]
+pub async fn html_disable_two_factor(
+    current: CurrentSession,
+    conn: Database,
+    form: rocket::form::Form<TwoFactorCodeForm>,
+) -> Html {
+    match disable_two_factor(current.0.user_id, form.into_inner().code, &conn).await {
+        Ok(()) => Html::new()
+            .head(default_head("Two-factor authentication is off"))
+            .body(
+                Body::new()
+                    .child(H1::new("Two-factor authentication is off"))
+                    .child(P::with_text(
+                        "You now only need your password to log in. Your recovery codes have been \
+                        deleted.",
+                    )),
+            ),
+        Err(e) => error_page(e),
+    }
+}
+
+#[post("/login/two_factor", data = "<form>")]
+pub async fn html_two_factor_login(
+    cookies: &CookieJar<'_>,
+    user_agent: UserAgent,
+    conn: Database,
+    form: rocket::form::Form<TwoFactorCodeForm>,
+) -> Html {
+    match login_with_code(cookies, form.into_inner().code, user_agent, &conn).await {
+        Ok(()) => logged_in_page(),
+        Err(TwoFactorError::InvalidCode) => code_page(Some(TwoFactorError::InvalidCode.reason())),
+        Err(e) => error_page(e),
+    }
+}
+
+#[get("/two_factor")]
+pub async fn api_two_factor_settings(
+    user: TwoFactorUser,
+    conn: Database,
+) -> Json<ApiResponse<TwoFactorStatus>> {
+    Json(match load_status(user.user_id, &conn).await {
+        Ok(status) => ApiResponse::new_ok(status),
+        Err(e) => ApiResponse::new_err(e.reason()),
+    })
+}
+
+#[post("/two_factor/enrol")]
+pub async fn api_begin_enrolment(
+    user: TwoFactorUser,
+    conn: Database,
+) -> Json<ApiResponse<Enrolment>> {
+    Json(match begin_enrolment(user.user_id, &conn).await {
+        Ok(enrolment) => ApiResponse::new_ok(enrolment),
+        Err(e) => ApiResponse::new_err(e.reason()),
+    })
+}
+
+#[post("/two_factor/confirm", data = "<form>")]
+pub async fn api_confirm_enrolment(
+    user: TwoFactorUser,
+    cookies: &CookieJar<'_>,
+    user_agent: UserAgent,
+    conn: Database,
+    form: Json<TwoFactorCodeForm>,
+) -> Json<ApiResponse<Vec<String>>> {
+    let codes = match confirm_enrolment(user.user_id, form.into_inner().code, &conn).await {
+        Ok(codes) => codes,
+        Err(e) => return Json(ApiResponse::new_err(e.reason())),
+    };
+    if !user.logged_in {
+        if finish_login(cookies, user.user_id, user_agent, &conn)
+            .await
+            .is_err()
+        {
+            return Json(ApiResponse::new_err(TwoFactorError::DatabaseError.reason()));
+        }
+        end_pending_login(cookies);
+    }
+    Json(ApiResponse::new_ok(codes))
+}
+
+#[post("/two_factor/recovery_codes")]
+pub async fn api_regenerate_recovery_codes(
+    current: CurrentSession,
+    conn: Database,
+) -> Json<ApiResponse<Vec<String>>> {
+    Json(
+        match regenerate_recovery_codes(current.0.user_id, &conn).await {
+            Ok(codes) => ApiResponse::new_ok(codes),
+            Err(e) => ApiResponse::new_err(e.reason()),
+        },
+    )
+}
+
+#[post("/two_factor/disable", data = "<form>")]
+pub async fn api_disable_two_factor(
+    current: CurrentSession,
+    conn: Database,
+    form: Json<TwoFactorCodeForm>,
+) -> Json<ApiResponse<()>> {
+    Json(
+        match disable_two_factor(current.0.user_id, form.into_inner().code, &conn).await {
+            Ok(()) => ApiResponse::new_ok(()),
+            Err(e) => ApiResponse::new_err(e.reason()),
+        },
+    )
+}
+
+#[post("/login/two_factor", data = "<form>")]
+pub async fn api_two_factor_login(
+    cookies: &CookieJar<'_>,
+    user_agent: UserAgent,
+    conn: Database,
+    form: Json<TwoFactorCodeForm>,
+) -> Json<ApiResponse<()>> {
+    Json(
+        match login_with_code(cookies, form.into_inner().code, user_agent, &conn).await {
+            Ok(()) => ApiResponse::new_ok(()),
+            Err(e) => ApiResponse::new_err(e.reason()),
+        },
+    )
+}
+
+/// The code which an authenticator app would show at `time` (a Unix timestamp).
+#[cfg(test)]
+pub(super) fn code_at(secret: &str, time: i64) -> String {
+    let secret = base32::decode(BASE32, secret).expect("invalid secret");
+    format_code(hotp(&secret, (time / TOTP_STEP_SECONDS) as u64))
+}
+
+#[cfg(test)]
+mod test_totp {
+    use super::{check_totp, code_at, hotp, BASE32};
+
+    #[test]
+    fn test_rfc_vectors() {
+        // from appendix D of RFC 4226
+        let secret = b"12345678901234567890";
+        let expected = [755224, 287082, 359152, 969429, 338314];
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(secret, counter as u64), *value);
+        }
+        let secret = base32::encode(BASE32, secret);
+        assert_eq!(code_at(&secret, 59), "287082");
+        // codes are accepted for a little while either side of when they were shown
+        assert_eq!(check_totp(&secret, "287082", 59), Some(1));
+        assert_eq!(check_totp(&secret, "287082", 89), Some(1));
+        assert_eq!(check_totp(&secret, "287082", 150), None);
+        assert_eq!(check_totp(&secret, "000000", 59), None);
+    }
+}
@@ -1,8 +1,12 @@
 use std::ops::Add;
 
-use super::{calendar_already_connected, check_user_does_not_already_have_calendar_connected};
+use super::{
+    calendar_already_connected, check_user_does_not_already_have_calendar_connected,
+    create_study_plan_calendar, user_timezone,
+};
 use crate::{
     auth::AuthCookie,
+    calendar::scheduler::ScheduleQueue,
     catch_database_error,
     db::Database,
     models::calendar::{CalendarType, NewCalDav, NewCalendar},
@@ -11,11 +15,15 @@ use crate::{
 use chrono::{Duration, Utc};
 use diesel::prelude::*;
 use malvolio::prelude::*;
-use prospero::client::DavClient;
-use rocket::FromForm;
+use prospero::{client::DavClient, discovery::DiscoveredCalendar};
+use rocket::{FromForm, State};
 
+/// Asks for the details of the user's CalDAV server, so that we can find their calendars (which
+/// they then pick from in `calendar_picker`).
 fn caldav_form() -> Form {
     Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/calendar/caldav/discover"))
         .child(
             Input::new()
                 .attribute(Name::new("username"))
@@ -34,6 +42,63 @@ fn caldav_form() -> Form {
                 .attribute(Placeholder::new("URL for the CalDAV server."))
                 .attribute(Type::Text),
         )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Find my calendars")),
+        )
+}
+
+/// Lets the user pick which of their calendars we should read their free time from, and which one
+/// we should add their study plan to.
+fn calendar_picker(form: &DiscoverCaldavForm, calendars: &[DiscoveredCalendar]) -> Form {
+    let options = |calendars: &[DiscoveredCalendar]| {
+        calendars
+            .iter()
+            .map(|calendar| {
+                let url = calendar.calendar.url().to_string();
+                SelectOption::new()
+                    .attribute(Value::new(url.clone()))
+                    .text(calendar.display_name.clone().unwrap_or(url))
+            })
+            .collect::<Vec<_>>()
+    };
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/calendar/caldav/link"))
+        .child(
+            Input::new()
+                .attribute(Type::Hidden)
+                .attribute(Name::new("username"))
+                .attribute(Value::new(form.username.clone())),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Hidden)
+                .attribute(Name::new("password"))
+                .attribute(Value::new(form.password.clone())),
+        )
+        .child(Label::new(
+            "Which calendar should we read your free time from?",
+        ))
+        .child(
+            Select::new()
+                .attribute(Name::new("url"))
+                .children(options(calendars)),
+        )
+        .child(Label::new(
+            "Which (separate) calendar should we add your study plan to?",
+        ))
+        .child(
+            Select::new()
+                .attribute(Name::new("lovelace_url"))
+                .child(
+                    SelectOption::new()
+                        .attribute(Value::new(""))
+                        .text("Create a new calendar for me"),
+                )
+                .children(options(calendars)),
+        )
         .child(
             Input::new()
                 .attribute(Type::Submit)
@@ -58,18 +123,78 @@ pub async fn link_caldav_page(auth: AuthCookie, conn: Database) -> Html {
         )
 }
 
+#[derive(FromForm, Debug, Clone)]
+pub struct DiscoverCaldavForm {
+    username: String,
+    password: String,
+    url: String,
+}
+
+#[post("/discover", data = "<form>")]
+pub async fn discover_caldav_calendars(
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<DiscoverCaldavForm>,
+) -> Html {
+    if check_user_does_not_already_have_calendar_connected(auth.0, &conn)
+        .await
+        .is_some()
+    {
+        return calendar_already_connected(caldav_form());
+    }
+    let client = DavClient::new_username_password(&form.username, &form.password, &form.url);
+    let calendars = match client.calendars().await {
+        Ok(calendars) => calendars
+            .into_iter()
+            // we can only schedule around (and into) calendars which can contain events
+            .filter(DiscoveredCalendar::supports_events)
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            warn!("could not find the calendars on a CalDAV server: {:?}", e);
+            return error_page(
+                "Error: we tried to find your calendars on the provided server, but couldn't. \
+                Please check your username, password and the server's URL.",
+            );
+        }
+    };
+    if calendars.is_empty() {
+        return error_page(
+            "Error: we couldn't find any calendars which can contain events on the provided \
+            server.",
+        );
+    }
+    Html::new()
+        .head(default_head("Link a CalDAV client".to_string()))
+        .body(
+            Body::new()
+                .child(H1::new("Pick your calendars"))
+                .child(calendar_picker(&form, &calendars)),
+        )
+}
+
 #[derive(FromForm, Debug, Clone)]
 pub struct CaldavCalendarForm {
     username: String,
     password: String,
     url: String,
+    lovelace_url: Option<String>,
+}
+
+fn error_page(message: &'static str) -> Html {
+    Html::new().head(default_head("Error".to_string())).body(
+        Body::new()
+            .child(H1::new("Error"))
+            .child(P::with_text(message))
+            .child(caldav_form()),
+    )
 }
 
 #[post("/link", data = "<form>")]
 pub async fn connect_caldav_calendar(
+    auth: AuthCookie,
     conn: Database,
     form: rocket::form::Form<CaldavCalendarForm>,
-    auth: AuthCookie,
+    queue: State<'_, ScheduleQueue>,
 ) -> Html {
     use crate::schema::caldav;
     use crate::schema::calendar;
@@ -81,93 +206,201 @@ pub async fn connect_caldav_calendar(
     }
     let client = DavClient::new_username_password(&form.username, &form.password, &form.url);
 
-    match client
+    if client
         .calendar()
         .date_search(Utc::now(), Utc::now().add(Duration::days(14)))
         .await
+        .is_err()
     {
-        Ok(_) => match conn
-            .run(move |c| {
-                diesel::insert_into(calendar::table)
-                    .values(NewCalendar {
-                        calendar_type: CalendarType::CalDav.into(),
-                        user_id: auth.0,
-                    })
-                    .returning(calendar::id)
-                    .get_result::<i32>(c)
-            })
-            .await
-        {
-            Ok(res) => {
-                catch_database_error!(
-                    conn.run(move |c| diesel::insert_into(caldav::table)
-                        .values(NewCalDav {
-                            calendar_id: res,
-                            username: &form.username,
-                            password: &form.password,
-                            url: &form.url
-                        })
-                        .execute(c))
-                        .await
-                );
-                Html::new().head(default_head("Success".to_string())).body(
-                    Body::new()
-                        .child(H1::new("Added that calendar."))
-                        .child(P::with_text(
-                            "We will start scheduling things into it soon.",
-                        )),
-                )
+        return error_page(
+            "Error: we tried to contact the provided server, but the response was invalid.",
+        );
+    }
+
+    let lovelace_url = match form.lovelace_url.as_deref().filter(|url| !url.is_empty()) {
+        Some(url) => url.to_string(),
+        None => {
+            let timezone = user_timezone(auth.0, &conn).await;
+            match create_study_plan_calendar(&client, timezone).await {
+                Ok(url) => url,
+                Err(_) => {
+                    return error_page(
+                        "Error: we couldn't create a calendar for your study plan on the \
+                        provided server. Please create one yourself and provide its URL.",
+                    )
+                }
             }
-            Err(_) => database_error(),
-        },
-        Err(_) => Html::new().head(default_head("Error".to_string())).body(
-            Body::new()
-                .child(H1::new("Error"))
-                .child(P::with_text(
-                    "Error: we tried to contact the provided server, but the response was invalid.",
-                ))
-                .child(caldav_form()),
-        ),
+        }
+    };
+
+    match conn
+        .run(move |c| {
+            diesel::insert_into(calendar::table)
+                .values(NewCalendar {
+                    calendar_type: CalendarType::CalDav.into(),
+                    user_id: auth.0,
+                })
+                .returning(calendar::id)
+                .get_result::<i32>(c)
+        })
+        .await
+    {
+        Ok(res) => {
+            catch_database_error!(
+                conn.run(move |c| diesel::insert_into(caldav::table)
+                    .values(NewCalDav {
+                        calendar_id: res,
+                        username: &form.username,
+                        password: &form.password,
+                        url: &form.url,
+                        lovelace_url: Some(&lovelace_url)
+                    })
+                    .execute(c))
+                    .await
+            );
+            queue.enqueue(auth.0);
+            Html::new().head(default_head("Success".to_string())).body(
+                Body::new()
+                    .child(H1::new("Added that calendar."))
+                    .child(P::with_text(
+                        "We will start scheduling things into it soon.",
+                    )),
+            )
+        }
+        Err(_) => database_error(),
     }
 }
 
 #[cfg(test)]
-#[cfg(feature = "caldav_server")]
 mod test_connect_caldav {
     use crate::{
+        calendar::scheduler::jobs::RescheduledUsers,
         db::{Database, DatabaseConnection},
         models::NewUser,
-        utils::client,
+        schema::{caldav, calendar},
+        utils::{client, login_user},
     };
+    use bcrypt::DEFAULT_COST;
     use chrono::Utc;
     use diesel::prelude::*;
+    use rocket::http::ContentType;
 
     const USERNAME: &str = "someuser";
     const EMAIL: &str = "someuser@example.com";
     const PASSWORD: &str = "arand0mishpassw0rd";
     const TIMEZONE: &str = "Africa/Abidjan";
 
-    fn setup_env(conn: &DatabaseConnection) {
+    fn setup_env(conn: &DatabaseConnection) -> i32 {
         use crate::schema::users;
         diesel::insert_into(users::table)
             .values(NewUser {
                 username: USERNAME,
                 email: EMAIL,
-                password: PASSWORD,
+                password: &bcrypt::hash(PASSWORD, DEFAULT_COST).unwrap(),
                 created: Utc::now().naive_utc(),
                 email_verified: true,
                 timezone: TIMEZONE,
             })
-            .execute(conn)
-            .expect("failed to create user");
+            .returning(users::id)
+            .get_result::<i32>(conn)
+            .expect("failed to create user")
     }
+
     #[rocket::async_test]
     async fn test_can_connect_caldav() {
+        let server = ariel::TestServer::start().await;
+        let client = client().await;
+        let user_id = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| setup_env(c))
+            .await;
+        login_user(USERNAME, PASSWORD, &client).await;
+
+        // no study plan calendar is provided, so one should be created
+        let res = client
+            .post("/calendar/caldav/link")
+            .header(ContentType::Form)
+            .body(format!(
+                "username={}&password={}&url={}&lovelace_url=",
+                USERNAME,
+                PASSWORD,
+                server.calendar_url()
+            ))
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .unwrap()
+            .contains("Added that calendar."));
+
+        let lovelace_url = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(move |c| {
+                calendar::table
+                    .filter(calendar::user_id.eq(user_id))
+                    .inner_join(caldav::table)
+                    .select(caldav::lovelace_url)
+                    .first::<Option<String>>(c)
+            })
+            .await
+            .unwrap()
+            .expect("a study plan calendar should have been created");
+        assert!(lovelace_url.starts_with(&server.home_set_url()));
+        assert_ne!(lovelace_url, server.calendar_url());
+        let calendars = prospero::client::DavClient::new_unauthenticated(server.url())
+            .calendars()
+            .await
+            .unwrap();
+        let study_plan = calendars
+            .iter()
+            .find(|calendar| calendar.calendar.url() == lovelace_url)
+            .expect("the study plan calendar should be on the server");
+        assert_eq!(
+            study_plan.display_name.as_deref(),
+            Some("Lovelace study plan")
+        );
+
+        // linking a calendar changes the user's free time, so they should be rescheduled
+        let rescheduled = client
+            .rocket()
+            .state::<RescheduledUsers>()
+            .unwrap()
+            .drain()
+            .await;
+        assert!(rescheduled.contains(&user_id));
+    }
+    #[rocket::async_test]
+    async fn test_can_pick_discovered_calendar() {
+        let server = ariel::TestServer::start().await;
+        let work = server.add_calendar("work");
         let client = client().await;
         Database::get_one(client.rocket())
             .await
             .unwrap()
             .run(|c| setup_env(c))
             .await;
+        login_user(USERNAME, PASSWORD, &client).await;
+
+        let page = client
+            .post("/calendar/caldav/discover")
+            .header(ContentType::Form)
+            .body(format!(
+                "username={}&password={}&url={}",
+                USERNAME,
+                PASSWORD,
+                server.url()
+            ))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(page.contains("Pick your calendars"));
+        assert!(page.contains(&server.calendar_url()));
+        assert!(page.contains(&work));
+        assert!(page.contains("Create a new calendar for me"));
     }
 }
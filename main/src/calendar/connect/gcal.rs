@@ -1,29 +1,82 @@
 //! Google calendar authentication.
 
+use chrono::{Duration, Utc};
 use malvolio::prelude::*;
-use rocket::tokio::sync::RwLock;
 use rocket::{response::Redirect, State};
-use std::collections::HashMap;
 
 use diesel::prelude::*;
 
 use crate::{
     auth::AuthCookie,
+    calendar::{
+        connect::token::{expiry_from, token_url},
+        scheduler::ScheduleQueue,
+    },
     catch_database_error,
     db::Database,
-    models::calendar::{CalendarType, NewCalendar, NewGoogleCalendar},
-    utils::{default_head, html_or_redirect::HtmlOrRedirect},
+    models::calendar::{CalendarType, NewCalendar, NewGoogleCalendar, NewOAuthState, OAuthState},
+    schema::oauth_state,
+    utils::{default_head, error_messages::database_error, html_or_redirect::HtmlOrRedirect},
 };
 
-#[derive(Debug, Clone)]
-pub struct StateValueEntry {
-    pub user_id: i32,
-    pub lovelace_calendar_id: String,
+/// How long (in minutes) somebody has to finish linking their calendar before the `state` value
+/// which we gave them stops working.
+const STATE_LIFETIME: i64 = 15;
+
+/// The URL of the Google calendar with the provided id. This can be changed with the
+/// `GOOGLE_CALDAV_URL` environment variable (in which `{calendar}` is replaced with the id), which
+/// lets us point Lovelace at a test server.
+pub fn calendar_url(calendar_id: &str) -> String {
+    std::env::var("GOOGLE_CALDAV_URL")
+        .unwrap_or_else(|_| {
+            "https://apidata.googleusercontent.com/caldav/v2/{calendar}/events".to_string()
+        })
+        .replace("{calendar}", calendar_id)
 }
 
-#[derive(Debug)]
-pub struct StateValues {
-    pub map: RwLock<HashMap<String, StateValueEntry>>,
+/// Creates (and stores) a new `state` value for the user. Any values which have expired are
+/// cleaned up at the same time.
+async fn create_oauth_state(
+    user_id: i32,
+    lovelace_calendar_id: String,
+    conn: &Database,
+) -> Result<String, diesel::result::Error> {
+    let state = uuid::Uuid::new_v4().to_string();
+    let move_state = state.clone();
+    let now = Utc::now().naive_utc();
+    conn.run(move |c| {
+        diesel::delete(oauth_state::table.filter(oauth_state::expires.le(now))).execute(c)?;
+        diesel::insert_into(oauth_state::table)
+            .values(NewOAuthState {
+                state: &move_state,
+                user_id,
+                lovelace_calendar_id: &lovelace_calendar_id,
+                created: now,
+                expires: now + Duration::minutes(STATE_LIFETIME),
+            })
+            .execute(c)
+    })
+    .await?;
+    Ok(state)
+}
+
+/// Removes (and returns) the entry for the provided `state` value, as long as it has not expired.
+/// Because the entry is removed, each `state` value can only be used once.
+async fn take_oauth_state(
+    state: String,
+    conn: &Database,
+) -> Result<Option<OAuthState>, diesel::result::Error> {
+    let now = Utc::now().naive_utc();
+    conn.run(move |c| {
+        diesel::delete(
+            oauth_state::table
+                .filter(oauth_state::state.eq(state))
+                .filter(oauth_state::expires.gt(now)),
+        )
+        .get_result::<OAuthState>(c)
+        .optional()
+    })
+    .await
 }
 
 #[get("/link")]
@@ -58,18 +111,16 @@ pub struct LinkCalendarForm {
 #[post("/link", data = "<form>")]
 pub async fn link_calendar(
     auth: AuthCookie,
-    oauth_state_values: State<'_, StateValues>,
+    conn: Database,
     form: rocket::form::Form<LinkCalendarForm>,
 ) -> HtmlOrRedirect {
-    let uuid = uuid::Uuid::new_v4().to_string();
-
-    oauth_state_values.map.write().await.insert(
-        uuid.clone(),
-        StateValueEntry {
-            user_id: auth.0,
-            lovelace_calendar_id: form.url.clone(),
-        },
-    );
+    let uuid = match create_oauth_state(auth.0, form.url.clone(), &conn).await {
+        Ok(uuid) => uuid,
+        Err(e) => {
+            error!("{:#?}", e);
+            return HtmlOrRedirect::Html(database_error());
+        }
+    };
     let res = format!(
         "{}?state={}&?scope=https%3A//www.googleapis.com/auth/calendar?redirect_uri={}?client_id={}",
         std::env::var("OAUTH_TEST_SERVER")
@@ -99,7 +150,7 @@ pub async fn gcal_callback(
     code: Option<String>,
     error: Option<String>,
     state: Option<String>,
-    oauth_state_values: State<'_, StateValues>,
+    queue: State<'_, ScheduleQueue>,
     conn: Database,
 ) -> Html {
     use crate::schema::calendar;
@@ -108,32 +159,25 @@ pub async fn gcal_callback(
         return Html::new();
     }
 
-    let lock = oauth_state_values.map.read().await;
     if let Some(code) = code {
         if let Some(state) = state {
-            if let Some(entry) = lock.get(&state) {
-                let entry = entry.clone();
-                // we drop the lock here because we want to read from it later.
-                std::mem::drop(lock);
-                let access_token_response: AccessTokenResponse = ureq::post(
-                    &std::env::var("TOKEN_URL")
-                        .unwrap_or_else(|_| "https://oauth2.googleapis.com/token".to_string()),
-                )
-                .set("Content-Type", "application/x-www-form-urlencoded")
-                .send_string(&format!(
-                    "code={}?
+            if let Some(entry) = catch_database_error!(take_oauth_state(state, &conn).await) {
+                let access_token_response: AccessTokenResponse = ureq::post(&token_url())
+                    .set("Content-Type", "application/x-www-form-urlencoded")
+                    .send_string(&format!(
+                        "code={}?
                         client_id={}?
                         client_secret={}?
                         redirect_uri={}?
                         grant_type=authorization_code",
-                    code,
-                    std::env::var("CLIENT_ID").unwrap(),
-                    std::env::var("CLIENT_SECRET").unwrap(),
-                    std::env::var("REDIRECT_URI").unwrap()
-                ))
-                .unwrap()
-                .into_json()
-                .unwrap();
+                        code,
+                        std::env::var("CLIENT_ID").unwrap(),
+                        std::env::var("CLIENT_SECRET").unwrap(),
+                        std::env::var("REDIRECT_URI").unwrap()
+                    ))
+                    .unwrap()
+                    .into_json()
+                    .unwrap();
                 let move_entry_user_id = entry.user_id;
                 let calendar_id = catch_database_error!(
                     conn.run(move |c| diesel::insert_into(calendar::table)
@@ -145,31 +189,20 @@ pub async fn gcal_callback(
                         .get_result::<i32>(c))
                         .await
                 );
-                cfg_if! {
-                    if #[cfg(test)] {
-                        let to_update = format!(
-                            "http://localhost:8080/user/calendars/{}",
-                            entry.lovelace_calendar_id
-                        );
-                    } else {
-                        let to_update = format!(
-                            "https://apidata.googleusercontent.com/caldav/v2/{}/events",
-                            entry.lovelace_calendar_id
-                        );
-                    }
-                };
+                let to_update = calendar_url(&entry.lovelace_calendar_id);
                 catch_database_error!(
                     conn.run(move |c| diesel::insert_into(google_calendar::table)
                         .values(NewGoogleCalendar {
                             refresh_token: &access_token_response.refresh_token,
                             access_token: &access_token_response.access_token,
                             calendar_id,
-                            lovelace_calendar_id: &to_update
+                            lovelace_calendar_id: &to_update,
+                            access_token_expires_at: expiry_from(access_token_response.expires_in),
                         })
                         .execute(c))
                         .await
                 );
-                oauth_state_values.map.write().await.remove(&state);
+                queue.enqueue(entry.user_id);
                 Html::new()
                     .head(default_head("Head".to_string()))
                     .body(Body::new().child(H1::new("Connected your calendar")))
@@ -193,3 +226,70 @@ pub async fn gcal_callback(
             .body(Body::new().child(P::with_text("Did not get a valid code to process.")))
     }
 }
+
+#[cfg(test)]
+mod test_oauth_state {
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+
+    use super::{create_oauth_state, take_oauth_state};
+    use crate::{
+        db::Database,
+        models::NewUser,
+        schema::{oauth_state, users},
+        utils::client,
+    };
+
+    async fn setup_user(conn: &Database) -> i32 {
+        conn.run(|c| {
+            diesel::insert_into(users::table)
+                .values(NewUser {
+                    username: "oauth-user",
+                    email: "oauth-user@example.com",
+                    password: "not-a-real-hash",
+                    created: Utc::now().naive_utc(),
+                    email_verified: true,
+                    timezone: "Africa/Abidjan",
+                })
+                .returning(users::id)
+                .get_result::<i32>(c)
+                .unwrap()
+        })
+        .await
+    }
+
+    #[rocket::async_test]
+    async fn test_state_can_only_be_used_once() {
+        let client = client().await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let user_id = setup_user(&conn).await;
+        let state = create_oauth_state(user_id, "lovelace".to_string(), &conn)
+            .await
+            .unwrap();
+        let entry = take_oauth_state(state.clone(), &conn)
+            .await
+            .unwrap()
+            .expect("the state value should be valid");
+        assert_eq!(entry.user_id, user_id);
+        assert_eq!(entry.lovelace_calendar_id, "lovelace");
+        assert!(take_oauth_state(state, &conn).await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_expired_state_is_rejected() {
+        let client = client().await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let user_id = setup_user(&conn).await;
+        let state = create_oauth_state(user_id, "lovelace".to_string(), &conn)
+            .await
+            .unwrap();
+        conn.run(|c| {
+            diesel::update(oauth_state::table)
+                .set(oauth_state::expires.eq((Utc::now() - Duration::minutes(1)).naive_utc()))
+                .execute(c)
+                .unwrap()
+        })
+        .await;
+        assert!(take_oauth_state(state, &conn).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,574 @@
+//! Lets users see which calendar they have connected, check that we can still reach it and
+//! disconnect it (after which they are free to connect a different one).
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket_contrib::json::Json;
+use thiserror::Error as ThisError;
+
+use crate::{
+    auth::AuthCookie,
+    calendar::scheduler::{calendar_clients, SchedulingError},
+    db::Database,
+    models::calendar::{
+        parse_calendar_type, CalDav, CalDavUnauthenticated, Calendar, CalendarType, GoogleCalendar,
+        ScheduledWorkBlock,
+    },
+    schema::{
+        caldav, caldav_unauthenticated, calendar, google_calendar, scheduled_work_block,
+        synced_event,
+    },
+    utils::{default_head, error_messages::database_error, json_response::ApiResponse},
+};
+
+/// A summary of the calendar which the user has connected. Passwords and access tokens are never
+/// included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectedCalendar {
+    /// One of `google`, `caldav` or `caldav_unauthenticated`.
+    pub provider: String,
+    /// The calendar which we read the user's events from (we don't store this for Google
+    /// calendars).
+    pub url: Option<String>,
+    /// The calendar which we add the user's study plan to.
+    pub lovelace_url: Option<String>,
+}
+
+#[derive(ThisError, Debug)]
+pub enum ManageCalendarError {
+    #[error("database error")]
+    DatabaseError,
+    #[error("no calendar is connected")]
+    NotConnected,
+    #[error("could not connect to the calendar")]
+    ConnectionFailed,
+}
+
+impl ManageCalendarError {
+    /// A message explaining what went wrong.
+    fn message(&self) -> &'static str {
+        match self {
+            ManageCalendarError::DatabaseError => {
+                "We ran into a database error when trying to load your calendar."
+            }
+            ManageCalendarError::NotConnected => "You haven't connected a calendar yet.",
+            ManageCalendarError::ConnectionFailed => {
+                "We couldn't connect to your calendar. If you've changed your password (or the \
+                calendar has moved) then you'll need to disconnect it and connect it again."
+            }
+        }
+    }
+}
+
+impl From<diesel::result::Error> for ManageCalendarError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+impl From<SchedulingError> for ManageCalendarError {
+    fn from(e: SchedulingError) -> Self {
+        match e {
+            SchedulingError::DatabaseError(e) => e.into(),
+            e => {
+                warn!("{:?}", e);
+                Self::ConnectionFailed
+            }
+        }
+    }
+}
+
+async fn load_calendar(user_id: i32, conn: &Database) -> Result<Calendar, ManageCalendarError> {
+    conn.run(move |c| {
+        calendar::table
+            .filter(calendar::user_id.eq(user_id))
+            .first::<Calendar>(c)
+            .optional()
+    })
+    .await?
+    .ok_or(ManageCalendarError::NotConnected)
+}
+
+async fn connected_calendar(
+    user_id: i32,
+    conn: &Database,
+) -> Result<ConnectedCalendar, ManageCalendarError> {
+    let calendar = load_calendar(user_id, conn).await?;
+    let calendar_id = calendar.id;
+    Ok(match parse_calendar_type(calendar.calendar_type) {
+        CalendarType::GoogleCalendar => {
+            let gcal = conn
+                .run(move |c| {
+                    google_calendar::table
+                        .filter(google_calendar::calendar_id.eq(calendar_id))
+                        .first::<GoogleCalendar>(c)
+                })
+                .await?;
+            ConnectedCalendar {
+                provider: "google".to_string(),
+                url: None,
+                lovelace_url: Some(gcal.lovelace_calendar_id),
+            }
+        }
+        CalendarType::CalDav => {
+            let caldav = conn
+                .run(move |c| {
+                    caldav::table
+                        .filter(caldav::calendar_id.eq(calendar_id))
+                        .first::<CalDav>(c)
+                })
+                .await?;
+            ConnectedCalendar {
+                provider: "caldav".to_string(),
+                url: Some(caldav.url),
+                lovelace_url: caldav.lovelace_url,
+            }
+        }
+        CalendarType::CalDavUnauthenticated => {
+            let caldav = conn
+                .run(move |c| {
+                    caldav_unauthenticated::table
+                        .filter(caldav_unauthenticated::calendar_id.eq(calendar_id))
+                        .first::<CalDavUnauthenticated>(c)
+                })
+                .await?;
+            ConnectedCalendar {
+                provider: "caldav_unauthenticated".to_string(),
+                url: Some(caldav.url),
+                lovelace_url: caldav.lovelace_url,
+            }
+        }
+    })
+}
+
+/// Checks that we can read from both the user's calendar and their Lovelace calendar.
+async fn test_connection(user_id: i32, conn: &Database) -> Result<(), ManageCalendarError> {
+    let calendar = load_calendar(user_id, conn).await?;
+    let (lovelace_client, user_client) = calendar_clients(&calendar, conn, false).await?;
+    let now = Utc::now();
+    for client in [lovelace_client, user_client].iter() {
+        client
+            .calendar()
+            .date_search(now, now + Duration::days(1))
+            .await
+            .map_err(SchedulingError::from)?;
+    }
+    Ok(())
+}
+
+/// Deletes the events which we added to the user's Lovelace calendar. Any other events in the
+/// calendar are left alone.
+async fn remove_lovelace_events(
+    calendar: &Calendar,
+    conn: &Database,
+) -> Result<(), ManageCalendarError> {
+    let user_id = calendar.user_id;
+    let records = conn
+        .run(move |c| {
+            scheduled_work_block::table
+                .filter(scheduled_work_block::user_id.eq(user_id))
+                .load::<ScheduledWorkBlock>(c)
+        })
+        .await?;
+    let (start, end) = match (
+        records.iter().map(|record| record.start_time).min(),
+        records.iter().map(|record| record.end_time).max(),
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Ok(()),
+    };
+    let uids = records
+        .into_iter()
+        .map(|record| record.uid)
+        .collect::<HashSet<_>>();
+    let (lovelace_client, _) = calendar_clients(calendar, conn, false).await?;
+    let events = lovelace_client
+        .calendar()
+        .date_search(DateTime::from_utc(start, Utc), DateTime::from_utc(end, Utc))
+        .await
+        .map_err(SchedulingError::from)?;
+    for event in events {
+        if matches!(event.uid().await, Ok(uid) if uids.contains(&uid)) {
+            event.delete().await.map_err(SchedulingError::from)?;
+        }
+    }
+    Ok(())
+}
+
+/// Disconnects the user's calendar, optionally deleting the events which we added to it first.
+///
+/// Removing the events is best-effort: people often disconnect a calendar because we can't reach
+/// it (e.g. they linked the wrong URL), and that shouldn't stop them from disconnecting it.
+async fn disconnect_calendar(
+    user_id: i32,
+    remove_events: bool,
+    conn: &Database,
+) -> Result<(), ManageCalendarError> {
+    let calendar = load_calendar(user_id, conn).await?;
+    if remove_events {
+        if let Err(e) = remove_lovelace_events(&calendar, conn).await {
+            warn!(
+                "could not remove the events from the calendar of user {}: {:?}",
+                user_id, e
+            );
+        }
+    }
+    let calendar_id = calendar.id;
+    conn.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(
+                google_calendar::table.filter(google_calendar::calendar_id.eq(calendar_id)),
+            )
+            .execute(c)?;
+            diesel::delete(caldav::table.filter(caldav::calendar_id.eq(calendar_id))).execute(c)?;
+            diesel::delete(
+                caldav_unauthenticated::table
+                    .filter(caldav_unauthenticated::calendar_id.eq(calendar_id)),
+            )
+            .execute(c)?;
+            // these records refer to events in the calendar which is being disconnected (so they
+            // would only confuse the scheduler if the user connects another calendar)
+            diesel::delete(
+                scheduled_work_block::table.filter(scheduled_work_block::user_id.eq(user_id)),
+            )
+            .execute(c)?;
+            diesel::delete(synced_event::table.filter(synced_event::calendar_id.eq(calendar_id)))
+                .execute(c)?;
+            diesel::delete(calendar::table.filter(calendar::id.eq(calendar_id))).execute(c)?;
+            Ok(())
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Links to the pages from which a calendar can be connected.
+fn link_calendar_options() -> Div {
+    Div::new()
+        .child(P::with_text("You can connect one of these calendars:"))
+        .child(A::new().href("/calendar/gcal/link").text("Google Calendar"))
+        .child(
+            A::new()
+                .href("/calendar/caldav/link")
+                .text("A CalDAV calendar (with a username and password)"),
+        )
+        .child(
+            A::new()
+                .href("/calendar/unauthenticated_caldav/link")
+                .text("A CalDAV calendar (without a password)"),
+        )
+}
+
+fn disconnect_form() -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/calendar/connected/disconnect"))
+        .child(Label::new(
+            "Also delete the study plan events which Lovelace added to your calendar",
+        ))
+        .child(
+            Input::new()
+                .attribute(Type::Checkbox)
+                .attribute(Name::new("remove_events")),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Disconnect calendar")),
+        )
+}
+
+fn error_page(e: ManageCalendarError) -> Html {
+    match e {
+        ManageCalendarError::DatabaseError => database_error(),
+        ManageCalendarError::NotConnected => Html::new()
+            .head(default_head("No calendar connected"))
+            .body(
+                Body::new()
+                    .child(H1::new("No calendar connected"))
+                    .child(P::with_text(e.message()))
+                    .child(link_calendar_options()),
+            ),
+        ManageCalendarError::ConnectionFailed => Html::new()
+            .head(default_head("Could not connect to your calendar"))
+            .body(
+                Body::new()
+                    .child(H1::new("Could not connect to your calendar"))
+                    .child(P::with_text(e.message()))
+                    .child(disconnect_form()),
+            ),
+    }
+}
+
+fn api_error<T>(e: ManageCalendarError) -> ApiResponse<T> {
+    ApiResponse::new_err(match e {
+        ManageCalendarError::DatabaseError => "database error",
+        ManageCalendarError::NotConnected => "no calendar connected",
+        ManageCalendarError::ConnectionFailed => "could not connect to calendar",
+    })
+}
+
+#[get("/connected")]
+pub async fn html_view_connected_calendar(auth: AuthCookie, conn: Database) -> Html {
+    match connected_calendar(auth.0, &conn).await {
+        Ok(calendar) => Html::new().head(default_head("Your calendar")).body(
+            Body::new()
+                .child(H1::new("Your calendar"))
+                .child(P::with_text(format!(
+                    "Connected calendar type: {}",
+                    calendar.provider
+                )))
+                .map(|body| {
+                    if let Some(url) = calendar.url {
+                        body.child(P::with_text(format!("Reading your events from: {}", url)))
+                    } else {
+                        body
+                    }
+                })
+                .map(|body| {
+                    if let Some(url) = calendar.lovelace_url {
+                        body.child(P::with_text(format!("Adding your study plan to: {}", url)))
+                    } else {
+                        body
+                    }
+                })
+                .child(
+                    Form::new()
+                        .attribute(Method::Post)
+                        .attribute(Action::new("/calendar/connected/test"))
+                        .child(
+                            Input::new()
+                                .attribute(Type::Submit)
+                                .attribute(Value::new("Test connection")),
+                        ),
+                )
+                .child(P::with_text(
+                    "To switch to a different calendar, disconnect this one first.",
+                ))
+                .child(disconnect_form()),
+        ),
+        Err(e) => error_page(e),
+    }
+}
+
+#[get("/connected")]
+pub async fn api_view_connected_calendar(
+    auth: AuthCookie,
+    conn: Database,
+) -> Json<ApiResponse<ConnectedCalendar>> {
+    Json(match connected_calendar(auth.0, &conn).await {
+        Ok(calendar) => ApiResponse::new_ok(calendar),
+        Err(e) => api_error(e),
+    })
+}
+
+#[post("/connected/test")]
+pub async fn html_test_connected_calendar(auth: AuthCookie, conn: Database) -> Html {
+    match test_connection(auth.0, &conn).await {
+        Ok(()) => Html::new().head(default_head("Connection works")).body(
+            Body::new()
+                .child(H1::new("Connection works"))
+                .child(P::with_text("We were able to connect to your calendar.")),
+        ),
+        Err(e) => error_page(e),
+    }
+}
+
+#[post("/connected/test")]
+pub async fn api_test_connected_calendar(
+    auth: AuthCookie,
+    conn: Database,
+) -> Json<ApiResponse<()>> {
+    Json(match test_connection(auth.0, &conn).await {
+        Ok(()) => ApiResponse::new_ok(()),
+        Err(e) => api_error(e),
+    })
+}
+
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DisconnectCalendarForm {
+    /// Whether to delete the events which we added to the user's Lovelace calendar.
+    #[serde(default)]
+    remove_events: bool,
+}
+
+#[post("/connected/disconnect", data = "<form>")]
+pub async fn html_disconnect_calendar(
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<DisconnectCalendarForm>,
+) -> Html {
+    match disconnect_calendar(auth.0, form.remove_events, &conn).await {
+        Ok(()) => Html::new()
+            .head(default_head("Calendar disconnected"))
+            .body(
+                Body::new()
+                    .child(H1::new("Calendar disconnected"))
+                    .child(link_calendar_options()),
+            ),
+        Err(e) => error_page(e),
+    }
+}
+
+#[post("/connected/disconnect", data = "<form>")]
+pub async fn api_disconnect_calendar(
+    auth: AuthCookie,
+    conn: Database,
+    form: Json<DisconnectCalendarForm>,
+) -> Json<ApiResponse<()>> {
+    Json(
+        match disconnect_calendar(auth.0, form.remove_events, &conn).await {
+            Ok(()) => ApiResponse::new_ok(()),
+            Err(e) => api_error(e),
+        },
+    )
+}
+
+#[cfg(test)]
+mod test_manage_calendar {
+    use bcrypt::DEFAULT_COST;
+    use chrono::Utc;
+    use diesel::prelude::*;
+    use rocket::http::ContentType;
+
+    use crate::{
+        db::Database,
+        models::NewUser,
+        schema::{caldav_unauthenticated, calendar, users},
+        utils::{client, login_user},
+    };
+
+    const USERNAME: &str = "calendar-switcher";
+    const EMAIL: &str = "calendar-switcher@example.com";
+    const PASSWORD: &str = "s3cure-PASSWORD-which-passes-criteria";
+    const TIMEZONE: &str = "Africa/Abidjan";
+
+    async fn setup_user(client: &rocket::local::asynchronous::Client) -> i32 {
+        Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                diesel::insert_into(users::table)
+                    .values(NewUser {
+                        username: USERNAME,
+                        email: EMAIL,
+                        password: &bcrypt::hash(PASSWORD, DEFAULT_COST).unwrap(),
+                        created: Utc::now().naive_utc(),
+                        email_verified: true,
+                        timezone: TIMEZONE,
+                    })
+                    .returning(users::id)
+                    .get_result::<i32>(c)
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn link(
+        client: &rocket::local::asynchronous::Client,
+        server: &ariel::TestServer,
+        url: &str,
+    ) {
+        let res = client
+            .post("/calendar/unauthenticated_caldav/link")
+            .header(ContentType::Form)
+            .body(format!(
+                "url={}&lovelace_url={}",
+                url,
+                server.add_calendar("lovelace")
+            ))
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .unwrap()
+            .contains("That calendar has been added."));
+    }
+
+    async fn calendar_count(client: &rocket::local::asynchronous::Client, user_id: i32) -> i64 {
+        Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(move |c| {
+                calendar::table
+                    .filter(calendar::user_id.eq(user_id))
+                    .count()
+                    .get_result::<i64>(c)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_disconnect_and_relink_calendar() {
+        let server = ariel::TestServer::start().await;
+        let client = client().await;
+        let user_id = setup_user(&client).await;
+        login_user(USERNAME, PASSWORD, &client).await;
+
+        let wrong = server.add_calendar("wrong");
+        link(&client, &server, &wrong).await;
+        let res = client.get("/api/calendar/connected").dispatch().await;
+        let string = res.into_string().await.unwrap();
+        assert!(string.contains("caldav_unauthenticated"));
+        assert!(string.contains(&wrong));
+
+        let res = client
+            .post("/calendar/connected/disconnect")
+            .header(ContentType::Form)
+            .body("remove_events=true")
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .unwrap()
+            .contains("Calendar disconnected"));
+        assert_eq!(calendar_count(&client, user_id).await, 0);
+        assert_eq!(
+            Database::get_one(client.rocket())
+                .await
+                .unwrap()
+                .run(|c| caldav_unauthenticated::table.count().get_result::<i64>(c))
+                .await
+                .unwrap(),
+            0
+        );
+
+        let res = client.get("/api/calendar/connected").dispatch().await;
+        assert!(res
+            .into_string()
+            .await
+            .unwrap()
+            .contains("no calendar connected"));
+
+        let right = server.add_calendar("right");
+        link(&client, &server, &right).await;
+        assert_eq!(calendar_count(&client, user_id).await, 1);
+        let res = client.get("/calendar/connected").dispatch().await;
+        assert!(res.into_string().await.unwrap().contains(&right));
+    }
+
+    #[rocket::async_test]
+    async fn test_disconnect_without_calendar() {
+        let client = client().await;
+        setup_user(&client).await;
+        login_user(USERNAME, PASSWORD, &client).await;
+        let res = client
+            .post("/api/calendar/connected/disconnect")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        assert!(res
+            .into_string()
+            .await
+            .unwrap()
+            .contains("no calendar connected"));
+    }
+}
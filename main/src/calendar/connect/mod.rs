@@ -1,13 +1,22 @@
 //! Calendar authentication.
 
 use crate::{db::Database, models::calendar::Calendar, utils::default_head};
+use chrono_tz::Tz;
 use diesel::prelude::*;
 use malvolio::prelude::*;
+use prospero::{
+    client::{DavClient, MakeCalendar},
+    error::CalDavResult,
+};
 
 /// Authenticated username/password CalDAV integration.
 pub mod caldav;
 /// Google Calendar integration.
 pub mod gcal;
+/// Viewing, testing and disconnecting the user's calendar.
+pub mod manage;
+/// Google Calendar access tokens.
+pub mod token;
 /// *Very* unwise unauthenticated CalDAV integration. Possibly something to remove in the future.
 pub mod unauthenticated_caldav;
 
@@ -39,3 +48,43 @@ pub(crate) fn calendar_already_connected(form: Form) -> Html {
             .child(form),
     )
 }
+
+/// The name of the calendar which we create (if the user doesn't provide one) to add the user's
+/// study plan to.
+const STUDY_PLAN_CALENDAR_NAME: &str = "Lovelace study plan";
+const STUDY_PLAN_CALENDAR_DESCRIPTION: &str =
+    "The times which Lovelace has set aside for you to work on your tasks.";
+const STUDY_PLAN_CALENDAR_COLOUR: &str = "#1b998bff";
+
+/// Returns the user's timezone (if it is valid).
+pub(crate) async fn user_timezone(user_id: i32, conn: &Database) -> Option<Tz> {
+    use crate::schema::users;
+    conn.run(move |c| {
+        users::table
+            .filter(users::id.eq(user_id))
+            .select(users::timezone)
+            .first::<String>(c)
+    })
+    .await
+    .ok()
+    .and_then(|timezone| timezone.parse::<Tz>().ok())
+}
+
+/// Creates a new calendar (in the user's calendar home set) for Lovelace to add the user's study
+/// plan to, and returns its URL. `client` can point to any URL on the user's CalDAV server.
+pub(crate) async fn create_study_plan_calendar(
+    client: &DavClient,
+    timezone: Option<Tz>,
+) -> CalDavResult<String> {
+    let home_set = client.calendar_home_set().await?;
+    let mut calendar = MakeCalendar::new()
+        .name(STUDY_PLAN_CALENDAR_NAME.to_string())
+        .id(format!("lovelace-{}", uuid::Uuid::new_v4()))
+        .description(STUDY_PLAN_CALENDAR_DESCRIPTION.to_string())
+        .colour(STUDY_PLAN_CALENDAR_COLOUR.to_string());
+    if let Some(timezone) = timezone {
+        calendar = calendar.timezone(timezone);
+    }
+    let created = client.with_url(home_set).make_calendar(calendar).await?;
+    Ok(created.url().to_string())
+}
@@ -0,0 +1,284 @@
+//! Keeps the access tokens for Google calendars up to date.
+//!
+//! Google's access tokens only last for an hour, after which a new one has to be requested using
+//! the refresh token which we were given when the user linked their calendar. We refresh tokens
+//! shortly before they are due to expire, and also whenever Google tells us that a token is no
+//! longer valid (tokens can be revoked early).
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use thiserror::Error as ThisError;
+
+use crate::{db::Database, models::calendar::GoogleCalendar, schema::google_calendar};
+
+/// We refresh access tokens which are due to expire within this many seconds (so that they don't
+/// expire halfway through scheduling).
+const EXPIRY_MARGIN: i64 = 120;
+
+/// The URL of the endpoint which issues access tokens.
+pub fn token_url() -> String {
+    std::env::var("TOKEN_URL").unwrap_or_else(|_| "https://oauth2.googleapis.com/token".to_string())
+}
+
+/// Works out when an access token which lasts for `expires_in` seconds (from now) expires.
+pub fn expiry_from(expires_in: Option<i32>) -> Option<NaiveDateTime> {
+    expires_in.map(|seconds| (Utc::now() + Duration::seconds(seconds as i64)).naive_utc())
+}
+
+#[derive(ThisError, Debug)]
+pub enum TokenError {
+    #[error("database error")]
+    DatabaseError(diesel::result::Error),
+    #[error("could not reach the token endpoint")]
+    RequestError(reqwest::Error),
+    #[error("the token endpoint refused to issue a new access token (status {0})")]
+    Rejected(u16),
+}
+
+impl From<diesel::result::Error> for TokenError {
+    fn from(e: diesel::result::Error) -> Self {
+        TokenError::DatabaseError(e)
+    }
+}
+
+impl From<reqwest::Error> for TokenError {
+    fn from(e: reqwest::Error) -> Self {
+        TokenError::RequestError(e)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RefreshTokenResponse {
+    access_token: String,
+    expires_in: Option<i32>,
+    /// Google doesn't usually issue a new refresh token, but if it does then we have to use the
+    /// new one from now on.
+    refresh_token: Option<String>,
+}
+
+/// Hands out valid access tokens for Google calendars.
+#[derive(Debug, Clone)]
+pub struct TokenManager {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    client: reqwest::Client,
+}
+
+impl TokenManager {
+    pub fn new<S1, S2, S3>(token_url: S1, client_id: S2, client_secret: S3) -> Self
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        S3: Into<String>,
+    {
+        Self {
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Constructs a token manager using the `TOKEN_URL`, `CLIENT_ID` and `CLIENT_SECRET`
+    /// environment variables.
+    pub fn from_env() -> Self {
+        Self::new(
+            token_url(),
+            std::env::var("CLIENT_ID").unwrap_or_default(),
+            std::env::var("CLIENT_SECRET").unwrap_or_default(),
+        )
+    }
+
+    /// Returns an access token for the calendar, refreshing the stored one if it has expired (or
+    /// is about to).
+    pub async fn access_token(
+        &self,
+        calendar: &GoogleCalendar,
+        conn: &Database,
+    ) -> Result<String, TokenError> {
+        match calendar.access_token_expires_at {
+            Some(expires_at)
+                if expires_at <= (Utc::now() + Duration::seconds(EXPIRY_MARGIN)).naive_utc() =>
+            {
+                self.refresh(calendar, conn).await
+            }
+            _ => Ok(calendar.access_token.clone()),
+        }
+    }
+
+    /// Requests a new access token for the calendar, and saves it in the database.
+    pub async fn refresh(
+        &self,
+        calendar: &GoogleCalendar,
+        conn: &Database,
+    ) -> Result<String, TokenError> {
+        let res = self
+            .client
+            .post(&self.token_url)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("refresh_token", calendar.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ])
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(TokenError::Rejected(res.status().as_u16()));
+        }
+        let response = res.json::<RefreshTokenResponse>().await?;
+        let id = calendar.id;
+        let access_token = response.access_token.clone();
+        let expires_at = expiry_from(response.expires_in);
+        let refresh_token = response
+            .refresh_token
+            .unwrap_or_else(|| calendar.refresh_token.clone());
+        conn.run(move |c| {
+            diesel::update(google_calendar::table.filter(google_calendar::id.eq(id)))
+                .set((
+                    google_calendar::access_token.eq(access_token),
+                    google_calendar::access_token_expires_at.eq(expires_at),
+                    google_calendar::refresh_token.eq(refresh_token),
+                ))
+                .execute(c)
+        })
+        .await?;
+        Ok(response.access_token)
+    }
+}
+
+#[cfg(test)]
+mod test_token_manager {
+    use chrono::{Duration, NaiveDateTime, Utc};
+    use diesel::prelude::*;
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::TokenManager;
+    use crate::{
+        db::Database,
+        models::{
+            calendar::{CalendarType, GoogleCalendar, NewCalendar, NewGoogleCalendar},
+            NewUser,
+        },
+        schema::{calendar, google_calendar, users},
+        utils::client,
+    };
+
+    async fn setup(conn: &Database, expires_at: Option<NaiveDateTime>) -> GoogleCalendar {
+        conn.run(move |c| {
+            let user_id = diesel::insert_into(users::table)
+                .values(NewUser {
+                    username: "gcal-user",
+                    email: "gcal-user@example.com",
+                    password: "not-a-real-hash",
+                    created: Utc::now().naive_utc(),
+                    email_verified: true,
+                    timezone: "Africa/Abidjan",
+                })
+                .returning(users::id)
+                .get_result::<i32>(c)
+                .unwrap();
+            let calendar_id = diesel::insert_into(calendar::table)
+                .values(NewCalendar {
+                    calendar_type: CalendarType::GoogleCalendar.into(),
+                    user_id,
+                })
+                .returning(calendar::id)
+                .get_result::<i32>(c)
+                .unwrap();
+            diesel::insert_into(google_calendar::table)
+                .values(NewGoogleCalendar {
+                    refresh_token: "the-refresh-token",
+                    access_token: "old-access-token",
+                    calendar_id,
+                    lovelace_calendar_id: "lovelace",
+                    access_token_expires_at: expires_at,
+                })
+                .get_result::<GoogleCalendar>(c)
+                .unwrap()
+        })
+        .await
+    }
+
+    async fn token_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("refresh_token=the-refresh-token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{
+                    "access_token": "new-access-token",
+                    "expires_in": 3599,
+                    "token_type": "Bearer"
+                }"#,
+            ))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[rocket::async_test]
+    async fn test_expired_token_is_refreshed() {
+        let server = token_server().await;
+        let client = client().await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let calendar = setup(&conn, Some((Utc::now() - Duration::minutes(5)).naive_utc())).await;
+        let manager = TokenManager::new(format!("{}/token", server.uri()), "id", "secret");
+        assert_eq!(
+            manager.access_token(&calendar, &conn).await.unwrap(),
+            "new-access-token"
+        );
+        let id = calendar.id;
+        let saved = conn
+            .run(move |c| {
+                google_calendar::table
+                    .filter(google_calendar::id.eq(id))
+                    .first::<GoogleCalendar>(c)
+            })
+            .await
+            .unwrap();
+        assert_eq!(saved.access_token, "new-access-token");
+        assert_eq!(saved.refresh_token, "the-refresh-token");
+        assert!(saved.access_token_expires_at.unwrap() > Utc::now().naive_utc());
+    }
+
+    #[rocket::async_test]
+    async fn test_valid_token_is_not_refreshed() {
+        let server = token_server().await;
+        let client = client().await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let calendar = setup(
+            &conn,
+            Some((Utc::now() + Duration::minutes(30)).naive_utc()),
+        )
+        .await;
+        let manager = TokenManager::new(format!("{}/token", server.uri()), "id", "secret");
+        assert_eq!(
+            manager.access_token(&calendar, &conn).await.unwrap(),
+            "old-access-token"
+        );
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_rejected_refresh_is_an_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        let client = client().await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let calendar = setup(&conn, None).await;
+        let manager = TokenManager::new(format!("{}/token", server.uri()), "id", "secret");
+        assert!(matches!(
+            manager.refresh(&calendar, &conn).await,
+            Err(super::TokenError::Rejected(400))
+        ));
+    }
+}
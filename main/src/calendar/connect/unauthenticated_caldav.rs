@@ -1,7 +1,11 @@
-use super::{calendar_already_connected, check_user_does_not_already_have_calendar_connected};
+use super::{
+    calendar_already_connected, check_user_does_not_already_have_calendar_connected,
+    create_study_plan_calendar, user_timezone,
+};
 use crate::schema::{caldav_unauthenticated, calendar};
 use crate::{
     auth::AuthCookie,
+    calendar::scheduler::ScheduleQueue,
     catch_database_error,
     db::Database,
     models::calendar::{CalendarType, NewCalDavUnauthenticated, NewCalendar},
@@ -9,6 +13,8 @@ use crate::{
 };
 use diesel::prelude::*;
 use malvolio::prelude::*;
+use prospero::client::DavClient;
+use rocket::State;
 
 fn caldav_form() -> Form {
     Form::new()
@@ -17,6 +23,14 @@ fn caldav_form() -> Form {
                 .attribute(Name::new("url"))
                 .attribute(Placeholder::new("The URL of the calendar")),
         )
+        .child(
+            Input::new()
+                .attribute(Name::new("lovelace_url"))
+                .attribute(Placeholder::new(
+                    "The URL of the (separate) calendar to add your study plan to (leave this \
+                    blank and we will create one for you)",
+                )),
+        )
         .child(
             Input::new()
                 .attribute(Type::Submit)
@@ -44,6 +58,7 @@ pub async fn view_link_unauthenticated_caldav(auth: AuthCookie, conn: Database)
 #[derive(FromForm, Debug)]
 pub struct Unauthenticated {
     url: String,
+    lovelace_url: Option<String>,
 }
 
 #[post("/link", data = "<form>")]
@@ -51,6 +66,7 @@ pub async fn link_unauthenticated_caldav(
     form: rocket::form::Form<Unauthenticated>,
     auth: AuthCookie,
     conn: Database,
+    queue: State<'_, ScheduleQueue>,
 ) -> Html {
     if check_user_does_not_already_have_calendar_connected(auth.0, &conn)
         .await
@@ -58,6 +74,28 @@ pub async fn link_unauthenticated_caldav(
     {
         return calendar_already_connected(caldav_form());
     }
+    let lovelace_url = match form.lovelace_url.as_deref().filter(|url| !url.is_empty()) {
+        Some(url) => url.to_string(),
+        None => {
+            let client = DavClient::new_unauthenticated(&form.url);
+            let timezone = user_timezone(auth.0, &conn).await;
+            match create_study_plan_calendar(&client, timezone).await {
+                Ok(url) => url,
+                Err(_) => {
+                    return Html::new().head(default_head("Error".to_string())).body(
+                        Body::new()
+                            .child(H1::new("Error"))
+                            .child(P::with_text(
+                                "Error: we couldn't create a calendar for your study plan on \
+                                the provided server. Please create one yourself and provide its \
+                                URL.",
+                            ))
+                            .child(caldav_form()),
+                    )
+                }
+            }
+        }
+    };
     let calendar_id = catch_database_error!({
         let user_id = auth.0;
         conn.run(move |c| {
@@ -75,11 +113,13 @@ pub async fn link_unauthenticated_caldav(
         conn.run(move |c| diesel::insert_into(caldav_unauthenticated::table)
             .values(NewCalDavUnauthenticated {
                 calendar_id,
-                url: &form.url
+                url: &form.url,
+                lovelace_url: Some(&lovelace_url)
             })
             .execute(c))
             .await
     );
+    queue.enqueue(auth.0);
     Html::new()
         .head(default_head("Added that calendar".to_string()))
         .body(
@@ -3,6 +3,9 @@
 /// Connects calendars to the application.
 pub mod connect;
 
+/// Lets users choose when they are happy to study.
+pub mod preferences;
+
 /// Schedules events. Currently we're just recomputing the entire schedule every time something
 /// changes. If this is too expensive then we may need to look at doing this incrementally.
 pub mod scheduler;
@@ -0,0 +1,491 @@
+//! Lets users choose when (and how much) they are happy to study. The scheduler only ever places
+//! work inside the hours that the user picks here.
+
+use chrono::NaiveTime;
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket::State;
+use rocket_contrib::json::Json;
+use thiserror::Error as ThisError;
+
+use crate::{
+    auth::AuthCookie,
+    calendar::scheduler::{
+        ScheduleQueue, SchedulingConstraints, DEFAULT_MAX_MINUTES_PER_DAY, DEFAULT_MIN_BREAK,
+    },
+    db::Database,
+    models::calendar::{NewSchedulePreferences, NewWorkingHours},
+    schema::{schedule_preferences, working_hours},
+    utils::{default_head, error_messages::database_error, json_response::ApiResponse},
+};
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+/// The format in which times are sent to (and received from) the user.
+const TIME_FORMAT: &str = "%H:%M";
+
+/// The user's preferences. Times should be in the form `HH:MM` (and are in the user's timezone). If
+/// both the start and end time for a day are left out then that day is a day off.
+#[derive(FromForm, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PreferencesForm {
+    monday_start: Option<String>,
+    monday_end: Option<String>,
+    tuesday_start: Option<String>,
+    tuesday_end: Option<String>,
+    wednesday_start: Option<String>,
+    wednesday_end: Option<String>,
+    thursday_start: Option<String>,
+    thursday_end: Option<String>,
+    friday_start: Option<String>,
+    friday_end: Option<String>,
+    saturday_start: Option<String>,
+    saturday_end: Option<String>,
+    sunday_start: Option<String>,
+    sunday_end: Option<String>,
+    /// The shortest break (in minutes) to leave between two blocks of work.
+    min_break: Option<i32>,
+    /// The most time (in minutes) to study for in one day.
+    max_minutes_per_day: Option<i32>,
+}
+
+impl PreferencesForm {
+    /// The start and end times for each day of the week (starting with Monday).
+    fn days(&self) -> [(&Option<String>, &Option<String>); 7] {
+        [
+            (&self.monday_start, &self.monday_end),
+            (&self.tuesday_start, &self.tuesday_end),
+            (&self.wednesday_start, &self.wednesday_end),
+            (&self.thursday_start, &self.thursday_end),
+            (&self.friday_start, &self.friday_end),
+            (&self.saturday_start, &self.saturday_end),
+            (&self.sunday_start, &self.sunday_end),
+        ]
+    }
+}
+
+impl From<&SchedulingConstraints> for PreferencesForm {
+    fn from(constraints: &SchedulingConstraints) -> Self {
+        let format = |index: usize| {
+            let hours = constraints.working_hours[index];
+            (
+                hours.map(|(start, _)| start.format(TIME_FORMAT).to_string()),
+                hours.map(|(_, end)| end.format(TIME_FORMAT).to_string()),
+            )
+        };
+        let (monday_start, monday_end) = format(0);
+        let (tuesday_start, tuesday_end) = format(1);
+        let (wednesday_start, wednesday_end) = format(2);
+        let (thursday_start, thursday_end) = format(3);
+        let (friday_start, friday_end) = format(4);
+        let (saturday_start, saturday_end) = format(5);
+        let (sunday_start, sunday_end) = format(6);
+        Self {
+            monday_start,
+            monday_end,
+            tuesday_start,
+            tuesday_end,
+            wednesday_start,
+            wednesday_end,
+            thursday_start,
+            thursday_end,
+            friday_start,
+            friday_end,
+            saturday_start,
+            saturday_end,
+            sunday_start,
+            sunday_end,
+            min_break: Some(constraints.min_break.num_minutes() as i32),
+            max_minutes_per_day: Some(constraints.max_per_day.num_minutes() as i32),
+        }
+    }
+}
+
+pub fn preferences_form(form: &PreferencesForm) -> Form {
+    let number_input = |name: &'static str, value: Option<i32>, placeholder: &'static str| {
+        Input::new()
+            .attribute(Type::Number)
+            .attribute(Name::new(name))
+            .attribute(Placeholder::new(placeholder))
+            .map(|item| {
+                if let Some(value) = value {
+                    item.attribute(Value::new(value.to_string()))
+                } else {
+                    item
+                }
+            })
+    };
+    let time_input = |name: String, value: &Option<String>| {
+        Input::new()
+            .attribute(Type::Time)
+            .attribute(Name::new(name))
+            .map(|item| {
+                if let Some(value) = value {
+                    item.attribute(Value::new(value.clone()))
+                } else {
+                    item
+                }
+            })
+    };
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new("/calendar/preferences"))
+        .children(
+            WEEKDAYS
+                .iter()
+                .zip(form.days().iter())
+                .map(|(weekday, (start, end))| {
+                    let name = weekday.to_lowercase();
+                    Div::new()
+                        .child(Label::new(format!(
+                            "{} (leave both times blank to take the day off)",
+                            weekday
+                        )))
+                        .child(time_input(format!("{}_start", name), *start))
+                        .child(time_input(format!("{}_end", name), *end))
+                }),
+        )
+        .child(number_input(
+            "min_break",
+            form.min_break,
+            "The shortest break (in minutes) to leave between blocks of work",
+        ))
+        .child(number_input(
+            "max_minutes_per_day",
+            form.max_minutes_per_day,
+            "The most time (in minutes) to study for in one day",
+        ))
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Save preferences")),
+        )
+}
+
+#[derive(ThisError, Debug)]
+pub enum PreferencesError {
+    #[error("database error")]
+    DatabaseError,
+    #[error("invalid working hours for {0}")]
+    InvalidHours(&'static str),
+    #[error("invalid break length")]
+    InvalidBreak,
+    #[error("invalid daily limit")]
+    InvalidDailyLimit,
+}
+
+impl PreferencesError {
+    /// A message explaining what went wrong (and how to fix it).
+    fn message(&self) -> &'static str {
+        match self {
+            PreferencesError::DatabaseError => {
+                "We ran into a database error when trying to save your preferences."
+            }
+            PreferencesError::InvalidHours(_) => {
+                "Please check the hours you supplied – each day needs both a start and an end time \
+                (and the start time should come before the end time), or neither."
+            }
+            PreferencesError::InvalidBreak => "The length of a break can't be negative.",
+            PreferencesError::InvalidDailyLimit => {
+                "The most time to study for in a day should be between 1 and 1440 minutes."
+            }
+        }
+    }
+}
+
+impl From<diesel::result::Error> for PreferencesError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT)
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+        .ok()
+}
+
+/// Parses the working hours provided in the form, returning a list of `(weekday, start, end)`.
+fn parse_working_hours(
+    form: &PreferencesForm,
+) -> Result<Vec<(i16, NaiveTime, NaiveTime)>, PreferencesError> {
+    let mut hours = vec![];
+    for (weekday, (start, end)) in form.days().iter().enumerate() {
+        let start = start.as_deref().filter(|time| !time.trim().is_empty());
+        let end = end.as_deref().filter(|time| !time.trim().is_empty());
+        match (start, end) {
+            (None, None) => {}
+            (Some(start), Some(end)) => match (parse_time(start.trim()), parse_time(end.trim())) {
+                (Some(start), Some(end)) if start < end => {
+                    hours.push((weekday as i16, start, end));
+                }
+                _ => return Err(PreferencesError::InvalidHours(WEEKDAYS[weekday])),
+            },
+            _ => return Err(PreferencesError::InvalidHours(WEEKDAYS[weekday])),
+        }
+    }
+    Ok(hours)
+}
+
+async fn update_preferences(
+    auth: AuthCookie,
+    conn: &Database,
+    form: &PreferencesForm,
+    queue: &ScheduleQueue,
+) -> Result<PreferencesForm, PreferencesError> {
+    let hours = parse_working_hours(form)?;
+    let min_break = form.min_break.unwrap_or(DEFAULT_MIN_BREAK);
+    if min_break < 0 {
+        return Err(PreferencesError::InvalidBreak);
+    }
+    let max_minutes_per_day = form
+        .max_minutes_per_day
+        .unwrap_or(DEFAULT_MAX_MINUTES_PER_DAY);
+    if max_minutes_per_day <= 0 || max_minutes_per_day > 24 * 60 {
+        return Err(PreferencesError::InvalidDailyLimit);
+    }
+    let user_id = auth.0;
+    conn.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_into(schedule_preferences::table)
+                .values(NewSchedulePreferences {
+                    user_id,
+                    min_break,
+                    max_minutes_per_day,
+                })
+                .on_conflict(schedule_preferences::user_id)
+                .do_update()
+                .set((
+                    schedule_preferences::min_break.eq(min_break),
+                    schedule_preferences::max_minutes_per_day.eq(max_minutes_per_day),
+                ))
+                .execute(c)?;
+            diesel::delete(working_hours::table.filter(working_hours::user_id.eq(user_id)))
+                .execute(c)?;
+            diesel::insert_into(working_hours::table)
+                .values(
+                    hours
+                        .into_iter()
+                        .map(|(weekday, start_time, end_time)| NewWorkingHours {
+                            user_id,
+                            weekday,
+                            start_time,
+                            end_time,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .execute(c)?;
+            Ok(())
+        })
+    })
+    .await?;
+    queue.enqueue(user_id);
+    let constraints = SchedulingConstraints::load(user_id, conn).await?;
+    Ok(PreferencesForm::from(&constraints))
+}
+
+fn preferences_page(title: &str, message: Option<&str>, form: &PreferencesForm) -> Html {
+    Html::new().head(default_head(title)).body(
+        Body::new()
+            .child(H1::new(title))
+            .map(|body| {
+                if let Some(message) = message {
+                    body.child(P::with_text(message))
+                } else {
+                    body
+                }
+            })
+            .child(P::with_text(
+                "We'll only ever ask you to study during these hours (in your timezone).",
+            ))
+            .child(preferences_form(form)),
+    )
+}
+
+#[get("/preferences")]
+pub async fn html_view_preferences(auth: AuthCookie, conn: Database) -> Html {
+    match SchedulingConstraints::load(auth.0, &conn).await {
+        Ok(constraints) => preferences_page(
+            "Study preferences",
+            None,
+            &PreferencesForm::from(&constraints),
+        ),
+        Err(e) => {
+            error!("{:#?}", e);
+            database_error()
+        }
+    }
+}
+
+#[get("/preferences")]
+pub async fn api_view_preferences(
+    auth: AuthCookie,
+    conn: Database,
+) -> Json<ApiResponse<PreferencesForm>> {
+    Json(match SchedulingConstraints::load(auth.0, &conn).await {
+        Ok(constraints) => ApiResponse::new_ok(PreferencesForm::from(&constraints)),
+        Err(e) => {
+            error!("{:#?}", e);
+            ApiResponse::new_err("database error")
+        }
+    })
+}
+
+#[post("/preferences", data = "<form>")]
+pub async fn html_update_preferences(
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<PreferencesForm>,
+    queue: State<'_, ScheduleQueue>,
+) -> Html {
+    match update_preferences(auth, &conn, &form, &queue).await {
+        Ok(preferences) => preferences_page(
+            "Saved your preferences",
+            Some("We'll update your study plan to match these shortly."),
+            &preferences,
+        ),
+        Err(PreferencesError::DatabaseError) => database_error(),
+        Err(e) => preferences_page("Invalid preferences", Some(e.message()), &form).status(400),
+    }
+}
+
+#[post("/preferences", data = "<form>")]
+pub async fn api_update_preferences(
+    auth: AuthCookie,
+    conn: Database,
+    form: Json<PreferencesForm>,
+    queue: State<'_, ScheduleQueue>,
+) -> Json<ApiResponse<PreferencesForm>> {
+    Json(match update_preferences(auth, &conn, &form, &queue).await {
+        Ok(preferences) => ApiResponse::new_ok(preferences),
+        Err(e) => ApiResponse::new_err(match e {
+            PreferencesError::DatabaseError => "database error",
+            PreferencesError::InvalidHours(_) => "invalid working hours",
+            PreferencesError::InvalidBreak => "invalid break length",
+            PreferencesError::InvalidDailyLimit => "invalid daily limit",
+        }),
+    })
+}
+
+#[cfg(test)]
+mod test_preferences {
+    use bcrypt::DEFAULT_COST;
+    use chrono::{NaiveTime, Utc};
+    use diesel::prelude::*;
+    use rocket::http::ContentType;
+
+    use crate::{
+        db::Database,
+        models::{
+            calendar::{SchedulePreferences, WorkingHours},
+            NewUser,
+        },
+        schema::{schedule_preferences, users, working_hours},
+        utils::{client, login_user},
+    };
+
+    const USERNAME: &str = "studious-student";
+    const EMAIL: &str = "studious-student@example.com";
+    const PASSWORD: &str = "s3cure-PASSWORD-which-passes-criteria";
+    const TIMEZONE: &str = "Europe/London";
+
+    async fn setup_user(client: &rocket::local::asynchronous::Client) -> i32 {
+        Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                diesel::insert_into(users::table)
+                    .values(NewUser {
+                        username: USERNAME,
+                        email: EMAIL,
+                        password: &bcrypt::hash(PASSWORD, DEFAULT_COST).unwrap(),
+                        created: Utc::now().naive_utc(),
+                        email_verified: true,
+                        timezone: TIMEZONE,
+                    })
+                    .returning(users::id)
+                    .get_result::<i32>(c)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_can_update_preferences() {
+        let client = client().await;
+        let user_id = setup_user(&client).await;
+        login_user(EMAIL, PASSWORD, &client).await;
+
+        let res = client
+            .post("/calendar/preferences")
+            .header(ContentType::Form)
+            .body(
+                "monday_start=10:00&monday_end=16:30&wednesday_start=09:00&wednesday_end=12:00\
+                &min_break=20&max_minutes_per_day=120",
+            )
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Saved your preferences"));
+
+        let (preferences, hours) = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(move |c| {
+                let preferences = schedule_preferences::table
+                    .filter(schedule_preferences::user_id.eq(user_id))
+                    .first::<SchedulePreferences>(c)
+                    .unwrap();
+                let hours = working_hours::table
+                    .filter(working_hours::user_id.eq(user_id))
+                    .order_by(working_hours::weekday.asc())
+                    .load::<WorkingHours>(c)
+                    .unwrap();
+                (preferences, hours)
+            })
+            .await;
+        assert_eq!(preferences.min_break, 20);
+        assert_eq!(preferences.max_minutes_per_day, 120);
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[0].weekday, 0);
+        assert_eq!(hours[0].start_time, NaiveTime::from_hms(10, 0, 0));
+        assert_eq!(hours[0].end_time, NaiveTime::from_hms(16, 30, 0));
+        assert_eq!(hours[1].weekday, 2);
+    }
+
+    #[rocket::async_test]
+    async fn test_invalid_hours_are_rejected() {
+        let client = client().await;
+        let user_id = setup_user(&client).await;
+        login_user(EMAIL, PASSWORD, &client).await;
+
+        let res = client
+            .post("/calendar/preferences")
+            .header(ContentType::Form)
+            .body("monday_start=16:00&monday_end=10:00")
+            .dispatch()
+            .await;
+        assert_eq!(res.status().code, 400);
+
+        let saved = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(move |c| {
+                diesel::select(diesel::dsl::exists(
+                    schedule_preferences::table.filter(schedule_preferences::user_id.eq(user_id)),
+                ))
+                .get_result::<bool>(c)
+                .unwrap()
+            })
+            .await;
+        assert!(!saved);
+    }
+}
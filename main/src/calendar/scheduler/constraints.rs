@@ -0,0 +1,196 @@
+//! The limits which the user has placed on when (and how much) we ask them to study.
+
+use std::cmp::{max, min};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+
+use super::FreeSlot;
+use crate::{
+    db::Database,
+    models::calendar::{SchedulePreferences, WorkingHours},
+    schema::{schedule_preferences, users, working_hours},
+};
+
+/// The shortest break (in minutes) which we leave between two blocks of work if the user hasn't
+/// told us otherwise.
+pub const DEFAULT_MIN_BREAK: i32 = 10;
+/// The most time (in minutes) which we ask somebody to study for in one day if they haven't told us
+/// otherwise.
+pub const DEFAULT_MAX_MINUTES_PER_DAY: i32 = 240;
+
+/// The hours during which we schedule work if the user hasn't told us otherwise.
+pub fn default_working_hours() -> (NaiveTime, NaiveTime) {
+    (NaiveTime::from_hms(9, 0, 0), NaiveTime::from_hms(21, 0, 0))
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulingConstraints {
+    /// The times of day (in `timezone`) during which the user is happy to study, indexed by day of
+    /// the week (Monday is 0). Days which are `None` are days off.
+    pub working_hours: [Option<(NaiveTime, NaiveTime)>; 7],
+    /// The shortest break to leave between two blocks of work.
+    pub min_break: Duration,
+    /// The most time which the user should be asked to study for in a single day.
+    pub max_per_day: Duration,
+    pub timezone: Tz,
+}
+
+impl Default for SchedulingConstraints {
+    fn default() -> Self {
+        Self {
+            working_hours: [Some(default_working_hours()); 7],
+            min_break: Duration::minutes(DEFAULT_MIN_BREAK as i64),
+            max_per_day: Duration::minutes(DEFAULT_MAX_MINUTES_PER_DAY as i64),
+            timezone: chrono_tz::UTC,
+        }
+    }
+}
+
+impl SchedulingConstraints {
+    /// Constructs the constraints from the rows stored in the database. If the user has not saved
+    /// their preferences the defaults are used in their place.
+    pub fn from_preferences(
+        preferences: Option<SchedulePreferences>,
+        hours: Vec<WorkingHours>,
+        timezone: Tz,
+    ) -> Self {
+        match preferences {
+            Some(preferences) => {
+                let mut working_hours = [None; 7];
+                for day in hours {
+                    if let Some(slot) = working_hours.get_mut(day.weekday as usize) {
+                        *slot = Some((day.start_time, day.end_time));
+                    }
+                }
+                Self {
+                    working_hours,
+                    min_break: Duration::minutes(preferences.min_break as i64),
+                    max_per_day: Duration::minutes(preferences.max_minutes_per_day as i64),
+                    timezone,
+                }
+            }
+            None => Self {
+                timezone,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Loads the constraints for the given user from the database.
+    pub async fn load(user_id: i32, conn: &Database) -> Result<Self, diesel::result::Error> {
+        let (timezone, preferences, hours) = conn
+            .run(move |c| {
+                let timezone = users::table
+                    .filter(users::id.eq(user_id))
+                    .select(users::timezone)
+                    .first::<String>(c)?;
+                let preferences = schedule_preferences::table
+                    .filter(schedule_preferences::user_id.eq(user_id))
+                    .first::<SchedulePreferences>(c)
+                    .optional()?;
+                let hours = working_hours::table
+                    .filter(working_hours::user_id.eq(user_id))
+                    .load::<WorkingHours>(c)?;
+                Ok((timezone, preferences, hours))
+            })
+            .await?;
+        Ok(Self::from_preferences(
+            preferences,
+            hours,
+            timezone.parse::<Tz>().unwrap_or(chrono_tz::UTC),
+        ))
+    }
+
+    /// The day (in the user's timezone) on which the provided time falls.
+    pub(super) fn local_date(&self, time: DateTime<Utc>) -> NaiveDate {
+        time.with_timezone(&self.timezone).date().naive_local()
+    }
+
+    /// Cuts the provided free slots down so that they only cover the user's working hours. Each of
+    /// the returned slots falls on a single day (in the user's timezone).
+    pub(super) fn restrict(&self, slots: Vec<FreeSlot>) -> Vec<FreeSlot> {
+        let mut restricted = vec![];
+        for slot in slots {
+            let mut date = self.local_date(slot.start);
+            while date <= self.local_date(slot.end) {
+                let hours = self.working_hours[date.weekday().num_days_from_monday() as usize];
+                if let Some((start, end)) = hours {
+                    // around daylight saving time changes some local times happen twice (and some
+                    // never happen at all) – here we pick the widest window that we can
+                    let window_start = self
+                        .timezone
+                        .from_local_datetime(&date.and_time(start))
+                        .earliest();
+                    let window_end = self
+                        .timezone
+                        .from_local_datetime(&date.and_time(end))
+                        .latest();
+                    if let (Some(window_start), Some(window_end)) = (window_start, window_end) {
+                        let start = max(slot.start, window_start.with_timezone(&Utc));
+                        let end = min(slot.end, window_end.with_timezone(&Utc));
+                        if start < end {
+                            restricted.push(FreeSlot { start, end });
+                        }
+                    }
+                }
+                date = date.succ();
+            }
+        }
+        restricted
+    }
+}
+
+#[cfg(test)]
+mod test_constraints {
+    use chrono::{Duration, NaiveTime, TimeZone, Utc};
+
+    use super::SchedulingConstraints;
+    use crate::calendar::scheduler::{test_ctx::start, FreeSlot};
+
+    #[test]
+    fn test_free_time_is_restricted_to_working_hours() {
+        let constraints = SchedulingConstraints::default();
+        let restricted = constraints.restrict(vec![FreeSlot {
+            start: start() - Duration::hours(9),
+            end: start() + Duration::days(1) + Duration::hours(3),
+        }]);
+        assert_eq!(
+            restricted,
+            vec![
+                FreeSlot {
+                    start: start(),
+                    end: start() + Duration::hours(12),
+                },
+                FreeSlot {
+                    start: start() + Duration::days(1),
+                    end: start() + Duration::days(1) + Duration::hours(3),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_working_hours_use_the_users_timezone() {
+        let mut working_hours = [None; 7];
+        // 2021-03-01 is a Monday
+        working_hours[0] = Some((NaiveTime::from_hms(9, 0, 0), NaiveTime::from_hms(17, 0, 0)));
+        let constraints = SchedulingConstraints {
+            working_hours,
+            timezone: chrono_tz::America::New_York,
+            ..Default::default()
+        };
+        let restricted = constraints.restrict(vec![FreeSlot {
+            start: Utc.ymd(2021, 2, 28).and_hms(0, 0, 0),
+            end: Utc.ymd(2021, 3, 3).and_hms(0, 0, 0),
+        }]);
+        assert_eq!(
+            restricted,
+            vec![FreeSlot {
+                start: Utc.ymd(2021, 3, 1).and_hms(14, 0, 0),
+                end: Utc.ymd(2021, 3, 1).and_hms(22, 0, 0),
+            }]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Works out when the user is free from the events in their own calendar.
+//!
+//! Calendars contain all sorts of events – some happen at a specific time, some happen at the same
+//! local time wherever you are ("floating" times) and some last all day. They also aren't returned
+//! in any particular order, and can overlap. Events which we can't make sense of are skipped (with
+//! a warning) rather than stopping the user from being scheduled at all.
+//!
+//! If the user's calendar server supports free/busy queries then none of this is needed, because
+//! the server tells us when the user is busy.
+
+use std::cmp::{max, min};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use prospero::{
+    calendar::Calendar as DavCalendar,
+    error::CalDavError,
+    event::{EventPointer, EventTime},
+    properties::{EventStatus, Transparency},
+};
+
+use super::{sync, FreeSlot, SchedulingError};
+use crate::{db::Database, models::calendar::Calendar};
+
+/// Converts a time from the user's calendar into UTC. Floating times (and the days on which all-day
+/// events happen) are taken to be in the user's timezone.
+fn to_utc(time: EventTime, timezone: Tz) -> Result<DateTime<Utc>, SchedulingError> {
+    let local = match time {
+        EventTime::Utc(time) => return Ok(time),
+        EventTime::Floating(time) => time,
+        EventTime::Date(date) => date.and_hms(0, 0, 0),
+    };
+    // around daylight saving time changes some local times happen twice (in which case we take the
+    // earlier one) and some never happen at all (in which case we move forward by an hour)
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or(SchedulingError::InvalidEventTime)
+}
+
+/// Works out the period of time which an event takes up.
+pub(super) fn busy_period(
+    start: Option<EventTime>,
+    end: Option<EventTime>,
+    timezone: Tz,
+) -> Result<FreeSlot, SchedulingError> {
+    let start = start.ok_or(SchedulingError::MissingEventTime)?;
+    let end = match (start, end) {
+        (_, Some(end)) => to_utc(end, timezone)?,
+        // events without an end time last for the whole day if they are all-day events, and
+        // otherwise finish as soon as they start (RFC 5545, section 3.6.1)
+        (EventTime::Date(date), None) => to_utc(EventTime::Date(date.succ()), timezone)?,
+        (start, None) => to_utc(start, timezone)?,
+    };
+    let start = to_utc(start, timezone)?;
+    if end < start {
+        return Err(SchedulingError::InvalidEventTime);
+    }
+    Ok(FreeSlot { start, end })
+}
+
+/// Computes the times between `from` and `until` which are not covered by any of the busy periods.
+/// The busy periods can be supplied in any order, and are allowed to overlap.
+pub(super) fn free_time(
+    mut busy: Vec<FreeSlot>,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Vec<FreeSlot> {
+    busy.sort_by_key(|period| period.start);
+    let mut free = vec![];
+    let mut cursor = from;
+    for period in busy {
+        if cursor >= until {
+            break;
+        }
+        if period.start > cursor {
+            free.push(FreeSlot {
+                start: cursor,
+                end: min(period.start, until),
+            });
+        }
+        cursor = max(cursor, period.end);
+    }
+    if cursor < until {
+        free.push(FreeSlot {
+            start: cursor,
+            end: until,
+        });
+    }
+    free
+}
+
+/// Works out the periods of time which each occurrence of an event takes up (recurring events
+/// happen more than once).
+async fn read_busy_periods(
+    event: &EventPointer,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    timezone: Tz,
+) -> Result<Vec<FreeSlot>, SchedulingError> {
+    // transparent events (and events which aren't happening) don't stop the user from working
+    if event.transparency().await? == Transparency::Transparent
+        || event.status().await? == Some(EventStatus::Cancelled)
+    {
+        return Ok(vec![]);
+    }
+    event
+        .occurrences(from, until)
+        .await?
+        .into_iter()
+        .map(|occurrence| busy_period(Some(occurrence.start), occurrence.end, timezone))
+        .collect()
+}
+
+/// Maps the events in the user's calendar to the times between `from` and `until` during which
+/// they are free.
+async fn map_user_events_to_free_time(
+    events: Vec<EventPointer>,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    timezone: Tz,
+) -> Result<Vec<FreeSlot>, SchedulingError> {
+    let mut busy = vec![];
+    for event in &events {
+        match read_busy_periods(event, from, until, timezone).await {
+            Ok(periods) => busy.extend(periods),
+            // if we can't reach the calendar then we can't tell when the user is free
+            Err(e @ SchedulingError::SchedulingError(CalDavError::RequestError(_))) => {
+                return Err(e)
+            }
+            Err(e) => warn!(
+                "Skipping an event in the user's calendar which could not be read: {:?}",
+                e
+            ),
+        }
+    }
+    Ok(free_time(busy, from, until))
+}
+
+/// Works out when the user is free between `from` and `until`. If their calendar server supports
+/// free/busy queries then we use those (so we never see the details of the user's events),
+/// otherwise we work it out from the events in their calendar.
+pub(super) async fn user_free_time(
+    calendar: &Calendar,
+    user_calendar: &DavCalendar,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    timezone: Tz,
+    conn: &Database,
+) -> Result<Vec<FreeSlot>, SchedulingError> {
+    match user_calendar.free_busy_query(from, until).await {
+        Ok(busy) => Ok(free_time(
+            busy.into_iter()
+                .map(|period| FreeSlot {
+                    start: period.start,
+                    end: period.end,
+                })
+                .collect(),
+            from,
+            until,
+        )),
+        // the server doesn't support free/busy queries
+        Err(CalDavError::UnexpectedStatus { .. }) => {
+            let events = sync::user_events(calendar, user_calendar, from, until, conn).await?;
+            map_user_events_to_free_time(events, from, until, timezone).await
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test_free_time {
+    use chrono::{Duration, NaiveDate, TimeZone, Utc};
+    use prospero::event::EventTime;
+
+    use super::{busy_period, free_time};
+    use crate::calendar::scheduler::{test_ctx::start, FreeSlot, SchedulingError};
+
+    fn period(from_hours: i64, until_hours: i64) -> FreeSlot {
+        FreeSlot {
+            start: start() + Duration::hours(from_hours),
+            end: start() + Duration::hours(until_hours),
+        }
+    }
+
+    #[test]
+    fn test_no_events_means_always_free() {
+        assert_eq!(
+            free_time(vec![], start(), start() + Duration::days(14)),
+            vec![FreeSlot {
+                start: start(),
+                end: start() + Duration::days(14),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_and_unsorted_events_are_merged() {
+        let busy = vec![period(5, 6), period(1, 3), period(2, 4), period(2, 3)];
+        assert_eq!(
+            free_time(busy, start(), start() + Duration::hours(8)),
+            vec![period(0, 1), period(4, 5), period(6, 8)]
+        );
+    }
+
+    #[test]
+    fn test_events_outside_the_window_are_ignored() {
+        let busy = vec![period(-3, 1), period(7, 10), period(12, 13)];
+        assert_eq!(
+            free_time(busy, start(), start() + Duration::hours(8)),
+            vec![period(1, 7)]
+        );
+    }
+
+    #[test]
+    fn test_all_day_events_use_the_users_timezone() {
+        let period = busy_period(
+            Some(EventTime::parse("20210301").unwrap()),
+            Some(EventTime::parse("20210302").unwrap()),
+            chrono_tz::America::New_York,
+        )
+        .unwrap();
+        assert_eq!(period.start, Utc.ymd(2021, 3, 1).and_hms(5, 0, 0));
+        assert_eq!(period.end, Utc.ymd(2021, 3, 2).and_hms(5, 0, 0));
+    }
+
+    #[test]
+    fn test_all_day_events_without_an_end_last_one_day() {
+        let period = busy_period(
+            Some(EventTime::Date(NaiveDate::from_ymd(2021, 3, 1))),
+            None,
+            chrono_tz::UTC,
+        )
+        .unwrap();
+        assert_eq!(period.end - period.start, Duration::days(1));
+    }
+
+    #[test]
+    fn test_floating_times_use_the_users_timezone() {
+        let period = busy_period(
+            Some(EventTime::parse("20210301T090000").unwrap()),
+            Some(EventTime::parse("20210301T100000").unwrap()),
+            chrono_tz::Europe::Paris,
+        )
+        .unwrap();
+        assert_eq!(period.start, Utc.ymd(2021, 3, 1).and_hms(8, 0, 0));
+        assert_eq!(period.end, Utc.ymd(2021, 3, 1).and_hms(9, 0, 0));
+    }
+
+    #[test]
+    fn test_utc_times_are_not_converted() {
+        let period = busy_period(
+            Some(EventTime::parse("20210301T090000Z").unwrap()),
+            Some(EventTime::parse("20210301T100000Z").unwrap()),
+            chrono_tz::Europe::Paris,
+        )
+        .unwrap();
+        assert_eq!(period.start, Utc.ymd(2021, 3, 1).and_hms(9, 0, 0));
+    }
+
+    #[test]
+    fn test_times_which_do_not_exist_are_moved_forward() {
+        // the clocks went forward from 02:00 to 03:00 on this day
+        let period = busy_period(
+            Some(EventTime::parse("20210314T023000").unwrap()),
+            None,
+            chrono_tz::America::New_York,
+        )
+        .unwrap();
+        assert_eq!(period.start, Utc.ymd(2021, 3, 14).and_hms(7, 30, 0));
+    }
+
+    #[test]
+    fn test_malformed_times_are_rejected() {
+        assert!(EventTime::parse("not a time").is_err());
+        assert!(EventTime::parse("20211345T090000Z").is_err());
+    }
+
+    #[test]
+    fn test_events_without_a_start_are_rejected() {
+        assert!(matches!(
+            busy_period(None, None, chrono_tz::UTC),
+            Err(SchedulingError::MissingEventTime)
+        ));
+    }
+
+    #[test]
+    fn test_events_which_end_before_they_start_are_rejected() {
+        assert!(matches!(
+            busy_period(
+                Some(EventTime::parse("20210301T100000Z").unwrap()),
+                Some(EventTime::parse("20210301T090000Z").unwrap()),
+                chrono_tz::UTC,
+            ),
+            Err(SchedulingError::InvalidEventTime)
+        ));
+    }
+}
@@ -0,0 +1,356 @@
+//! Reschedules users in the background whenever something which affects their schedule changes
+//! (e.g. a task is created, edited or deleted, they join a class or they link a calendar).
+//!
+//! Route handlers add users to the `ScheduleQueue` (which is stored in Rocket's managed state).
+//! Changes tend to come in bursts (a teacher might edit a task several times in quick succession)
+//! so rather than rescheduling straight away we wait until nothing has happened for
+//! `Delays::debounce` before running the scheduler, which means that each user is rescheduled once
+//! per burst. So that a steady stream of changes can't put off rescheduling forever, nobody waits
+//! for longer than `Delays::max_delay`.
+
+use std::{collections::HashMap, future::Future};
+#[cfg(test)]
+use std::{collections::HashSet, sync::Arc};
+
+use diesel::prelude::*;
+#[cfg(test)]
+use rocket::tokio::sync::Mutex;
+use rocket::{
+    tokio::{
+        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        time::{self, Duration, Instant},
+    },
+    Rocket,
+};
+
+#[cfg(not(test))]
+use super::two_week_schedule;
+#[cfg(not(test))]
+use crate::db::DatabasePool;
+use crate::{db::Database, schema::class_student};
+
+/// How long the job runner waits before rescheduling people.
+#[derive(Debug, Copy, Clone)]
+pub struct Delays {
+    /// How long to wait after the last change to a user's schedule before rescheduling them.
+    pub debounce: Duration,
+    /// The longest that we will wait before rescheduling a user (however many changes there are).
+    pub max_delay: Duration,
+}
+
+pub const DELAYS: Delays = Delays {
+    debounce: Duration::from_secs(10),
+    max_delay: Duration::from_secs(120),
+};
+
+/// The tests use much shorter delays, so that they don't have to wait long for users to be
+/// rescheduled.
+#[cfg(test)]
+pub const TEST_DELAYS: Delays = Delays {
+    debounce: Duration::from_millis(20),
+    max_delay: Duration::from_millis(100),
+};
+
+/// The handle which is used to ask for users to be rescheduled.
+#[derive(Debug, Clone)]
+pub struct ScheduleQueue {
+    sender: UnboundedSender<i32>,
+}
+
+impl ScheduleQueue {
+    /// Asks for the schedule of the provided user to be recomputed.
+    pub fn enqueue(&self, user_id: i32) {
+        if self.sender.send(user_id).is_err() {
+            warn!(
+                "could not reschedule user {} because the scheduler is not running",
+                user_id
+            );
+        }
+    }
+
+    /// Asks for the schedules of all the students in the provided class to be recomputed.
+    pub async fn enqueue_class(&self, class_id: i32, conn: &Database) {
+        match conn
+            .run(move |c| {
+                class_student::table
+                    .filter(class_student::class_id.eq(class_id))
+                    .select(class_student::user_id)
+                    .load::<i32>(c)
+            })
+            .await
+        {
+            Ok(students) => {
+                for user_id in students {
+                    self.enqueue(user_id);
+                }
+            }
+            Err(e) => error!("{:#?}", e),
+        }
+    }
+}
+
+/// Keeps track of the users who are waiting to be rescheduled.
+#[derive(Debug)]
+struct Debouncer {
+    delays: Delays,
+    /// Maps each user to the time at which they were first added to the queue, and the time at
+    /// which they should be rescheduled.
+    pending: HashMap<i32, (Instant, Instant)>,
+}
+
+impl Debouncer {
+    fn new(delays: Delays) -> Self {
+        Self {
+            delays,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, user_id: i32, now: Instant) {
+        let (first, deadline) = self.pending.entry(user_id).or_insert((now, now));
+        *deadline = std::cmp::min(now + self.delays.debounce, *first + self.delays.max_delay);
+    }
+
+    /// The next time at which somebody needs to be rescheduled.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, deadline)| *deadline).min()
+    }
+
+    /// Removes (and returns) the users who should be rescheduled at (or before) `now`.
+    fn take_due(&mut self, now: Instant) -> Vec<i32> {
+        let due = self
+            .pending
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(user_id, _)| *user_id)
+            .collect::<Vec<_>>();
+        for user_id in &due {
+            self.pending.remove(user_id);
+        }
+        due
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Reschedules the provided users. A connection is only taken from the pool while there are
+/// people to reschedule, so that the job runner doesn't keep one to itself.
+#[cfg(not(test))]
+async fn reschedule(users: Vec<i32>, pool: DatabasePool) {
+    let conn = match pool.get().await {
+        Some(conn) => conn,
+        None => {
+            error!(
+                "couldn't get a database connection to reschedule users {:?}",
+                users
+            );
+            return;
+        }
+    };
+    for user_id in users {
+        match two_week_schedule(user_id, &conn).await {
+            Ok(unschedulable) => {
+                for task in unschedulable {
+                    info!(
+                        "user {} doesn't have enough time to finish task {}",
+                        user_id, task.task_id
+                    );
+                }
+            }
+            Err(e) => warn!("could not reschedule user {}: {:?}", user_id, e),
+        }
+    }
+}
+
+/// Receives users from the queue and passes them to `reschedule` (once they are due). This runs
+/// until every `ScheduleQueue` has been dropped.
+async fn run<F, Fut>(mut receiver: UnboundedReceiver<i32>, delays: Delays, mut reschedule: F)
+where
+    F: FnMut(Vec<i32>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut debouncer = Debouncer::new(delays);
+    loop {
+        let received = match debouncer.next_deadline() {
+            Some(deadline) => time::timeout_at(deadline, receiver.recv()).await.ok(),
+            None => Some(receiver.recv().await),
+        };
+        match received {
+            Some(Some(user_id)) => debouncer.push(user_id, Instant::now()),
+            // every `ScheduleQueue` has been dropped
+            Some(None) => break,
+            // somebody is due to be rescheduled
+            None => {}
+        }
+        let due = debouncer.take_due(Instant::now());
+        if !due.is_empty() {
+            reschedule(due).await;
+        }
+    }
+    // nobody else can be added to the queue, so we don't need to wait any longer
+    let due = debouncer.take_due(Instant::now() + delays.max_delay);
+    if !due.is_empty() {
+        reschedule(due).await;
+    }
+}
+
+/// The users who have been rescheduled. When testing the job runner doesn't run the scheduler
+/// (which needs the calendar test servers, and would compete with the tests for their only
+/// database connection) and instead the users are stored here, so that tests can check that the
+/// right people were rescheduled.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct RescheduledUsers(Arc<Mutex<HashSet<i32>>>);
+
+#[cfg(test)]
+impl RescheduledUsers {
+    async fn record(&self, users: Vec<i32>) {
+        self.0.lock().await.extend(users);
+    }
+
+    /// Waits for everybody who has been added to the queue so far to be rescheduled, and then
+    /// removes (and returns) all the users who have been rescheduled.
+    pub async fn drain(&self) -> HashSet<i32> {
+        time::sleep(TEST_DELAYS.max_delay * 2).await;
+        std::mem::take(&mut *self.0.lock().await)
+    }
+}
+
+/// Starts the job runner, and adds a `ScheduleQueue` to Rocket's managed state. This should be
+/// attached after the database fairing.
+pub async fn start(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (sender, receiver) = unbounded_channel();
+    cfg_if! {
+        if #[cfg(test)] {
+            let rescheduled = RescheduledUsers::default();
+            let record = rescheduled.clone();
+            rocket::tokio::spawn(run(receiver, TEST_DELAYS, move |users| {
+                let record = record.clone();
+                async move { record.record(users).await }
+            }));
+            let rocket = rocket.manage(rescheduled);
+        } else {
+            let pool = match DatabasePool::from_rocket(&rocket).await {
+                Some(pool) => pool,
+                None => {
+                    error!("Couldn't find the database pool for the scheduler.");
+                    return Err(rocket);
+                }
+            };
+            rocket::tokio::spawn(run(receiver, DELAYS, move |users| {
+                reschedule(users, pool.clone())
+            }));
+        }
+    }
+    Ok(rocket.manage(ScheduleQueue { sender }))
+}
+
+#[cfg(test)]
+mod test_debouncer {
+    use std::sync::Arc;
+
+    use rocket::tokio::{
+        self,
+        sync::{mpsc::unbounded_channel, Mutex},
+        time::{self, Duration, Instant},
+    };
+
+    use super::{run, Debouncer, DELAYS, TEST_DELAYS};
+
+    const DEBOUNCE: Duration = DELAYS.debounce;
+    const MAX_DELAY: Duration = DELAYS.max_delay;
+
+    #[test]
+    fn test_burst_of_changes_is_rescheduled_once() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DELAYS);
+        debouncer.push(1, start);
+        debouncer.push(1, start + Duration::from_secs(1));
+        debouncer.push(1, start + Duration::from_secs(2));
+        assert!(debouncer.take_due(start + DEBOUNCE).is_empty());
+        assert_eq!(
+            debouncer.next_deadline(),
+            Some(start + Duration::from_secs(2) + DEBOUNCE)
+        );
+        assert_eq!(
+            debouncer.take_due(start + Duration::from_secs(2) + DEBOUNCE),
+            vec![1]
+        );
+        assert!(debouncer.is_empty());
+    }
+
+    #[test]
+    fn test_users_are_rescheduled_independently() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DELAYS);
+        debouncer.push(1, start);
+        debouncer.push(2, start + Duration::from_secs(5));
+        assert_eq!(debouncer.take_due(start + DEBOUNCE), vec![1]);
+        assert_eq!(
+            debouncer.take_due(start + Duration::from_secs(5) + DEBOUNCE),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_rescheduling_is_not_delayed_forever() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DELAYS);
+        let mut now = start;
+        while now < start + MAX_DELAY + DEBOUNCE {
+            debouncer.push(1, now);
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(debouncer.next_deadline(), Some(start + MAX_DELAY));
+    }
+
+    /// Returns a job runner (with the test delays) and the batches of users which it has
+    /// rescheduled.
+    #[allow(clippy::type_complexity)]
+    fn runner() -> (
+        tokio::sync::mpsc::UnboundedSender<i32>,
+        tokio::task::JoinHandle<()>,
+        Arc<Mutex<Vec<Vec<i32>>>>,
+    ) {
+        let (sender, receiver) = unbounded_channel();
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let record = batches.clone();
+        let handle = tokio::spawn(run(receiver, TEST_DELAYS, move |mut users| {
+            let record = record.clone();
+            async move {
+                users.sort_unstable();
+                record.lock().await.push(users)
+            }
+        }));
+        (sender, handle, batches)
+    }
+
+    #[rocket::async_test]
+    async fn test_runner_reschedules_each_burst_once() {
+        let (sender, handle, batches) = runner();
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        sender.send(1).unwrap();
+        time::sleep(TEST_DELAYS.debounce / 4).await;
+        sender.send(1).unwrap();
+        time::sleep(TEST_DELAYS.max_delay * 2).await;
+        let mut rescheduled = batches.lock().await.concat();
+        rescheduled.sort_unstable();
+        assert_eq!(rescheduled, vec![1, 2]);
+        drop(sender);
+        handle.await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_runner_reschedules_pending_users_when_stopped() {
+        let (sender, handle, batches) = runner();
+        sender.send(3).unwrap();
+        sender.send(4).unwrap();
+        drop(sender);
+        handle.await.unwrap();
+        assert_eq!(*batches.lock().await, vec![vec![3, 4]]);
+    }
+}
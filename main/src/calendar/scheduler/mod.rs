@@ -2,37 +2,77 @@
 //!
 //! The algorithm works as follows:
 //!   1. Pick out all the events which are happening over the next two weeks
-//!   2. Work out all the times during which the user is busy
+//!   2. Work out all the times during which the user is free (and happy to study – see
+//!      `SchedulingConstraints`)
 //!   3. Work out all the tasks that the user has
-//!   4. Make sure that there is actually enough time to do all the work
-//!   5. Start filling in the tasks (currently we're using a shortest-task first system)
+//!   4. Start filling in the tasks, in the order chosen by a `SchedulingStrategy` (by default
+//!      the tasks which are due soonest go first)
+//!   5. Make sure that there was actually enough time to do all the work – if there wasn't, we
+//!      send the student a notification telling them which tasks they won't be able to finish
 //!
-//! NOTE: (because there's only one of me and thousands of lines of code) we are currently assuming
-//! that all tasks take 25 minutes, which is obviously not correct
+//! Each task takes as long as the student (or, if they haven't said, the teacher) thinks that it
+//! will. Tasks which take longer than `MAX_BLOCK_LENGTH` minutes are split into a number of "work
+//! blocks," which are spread out over the free time before the task is due. We leave a break
+//! between blocks, and make sure that nobody is asked to study for more than they want to in a
+//! single day.
+//!
+//! Rather than clearing out the user's calendar every time that we recompute their schedule, we
+//! work out which events need to change (see the `reconcile` module) and only touch those. Any
+//! blocks which the user has moved themselves are left where they are.
+//!
+//! Where possible we ask the user's calendar server when they are busy, rather than reading their
+//! events. Otherwise, we keep a copy of the user's calendar in the database (see the `sync`
+//! module), so that each time we only have to fetch the events which have changed.
+//!
+//! Schedules are recomputed in the background (see the `jobs` module) whenever something which
+//! affects them changes.
 
-use crate::models::{ClassAsynchronousTask, StudentClassAsynchronousTask};
+mod constraints;
+mod free_time;
+pub mod jobs;
+mod reconcile;
+mod strategy;
+mod sync;
+#[cfg(test)]
+mod test_ctx;
+
+pub use constraints::{SchedulingConstraints, DEFAULT_MAX_MINUTES_PER_DAY, DEFAULT_MIN_BREAK};
+pub use jobs::ScheduleQueue;
+pub use strategy::{EarliestDeadlineFirst, SchedulingStrategy, ShortestTaskFirst, StudentTask};
+
+use crate::models::ClassAsynchronousTask;
 use crate::{
+    calendar::connect::{
+        gcal::calendar_url,
+        token::{TokenError, TokenManager},
+    },
     db::Database,
     models::{
-        calendar::{parse_calendar_type, GoogleCalendar},
+        calendar::{
+            parse_calendar_type, CalDav, CalDavUnauthenticated, CalendarType, GoogleCalendar,
+            NewScheduledWorkBlock, ScheduledWorkBlock,
+        },
         User,
     },
+    notifications::{NotificationPriority, NotifyBuilder},
     schema::{
-        calendar, class, class_asynchronous_task, class_student, google_calendar,
-        student_class_asynchronous_task, users,
+        caldav, caldav_unauthenticated, calendar, class_asynchronous_task, class_student,
+        google_calendar, notifications, scheduled_work_block, student_class_asynchronous_task,
+        users,
     },
 };
-use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use diesel::prelude::*;
 use prospero::{
-    client::DavClient,
-    error::CalDavError,
-    event::EventPointer,
-    icalendar::{Component, Event},
+    builder::EventBuilder, client::DavClient, error::CalDavError, event::EventPointer,
+    properties::Alarm,
 };
-use std::ops::Add;
 use thiserror::Error as ThisError;
-use uuid::Uuid;
+
+use free_time::user_free_time;
+use reconcile::{block_uid, reconcile, remove_busy_time, ExistingEvent, PreviousSchedule};
 
 #[derive(ThisError, Debug)]
 pub enum SchedulingError {
@@ -40,6 +80,14 @@ pub enum SchedulingError {
     DatabaseError(diesel::result::Error),
     #[error("scheduling error")]
     SchedulingError(prospero::error::CalDavError),
+    #[error("could not get an access token for the user's calendar")]
+    TokenError(TokenError),
+    #[error("the user has not told us which calendar to add events to")]
+    NoLovelaceCalendar,
+    #[error("an event does not have a start time")]
+    MissingEventTime,
+    #[error("an event has a start or end time which could not be understood")]
+    InvalidEventTime,
 }
 
 impl From<CalDavError> for SchedulingError {
@@ -48,47 +96,168 @@ impl From<CalDavError> for SchedulingError {
     }
 }
 
+impl From<TokenError> for SchedulingError {
+    fn from(e: TokenError) -> Self {
+        SchedulingError::TokenError(e)
+    }
+}
+
 impl From<diesel::result::Error> for SchedulingError {
     fn from(e: diesel::result::Error) -> Self {
         SchedulingError::DatabaseError(e)
     }
 }
 
+/// The longest period of time (in minutes) which we ask somebody to spend on a task in one go.
+const MAX_BLOCK_LENGTH: i64 = 50;
+/// The shortest period of time (in minutes) which we will schedule a block of work for (unless
+/// that's all the time that is needed to finish the task).
+const MIN_BLOCK_LENGTH: i64 = 15;
+/// How long (in minutes) before a block of work starts the student is reminded about it.
+const REMINDER_MINUTES: i64 = 10;
+/// The category which is given to all the events that we create.
+const EVENT_CATEGORY: &str = "Lovelace";
+
+#[derive(Debug, Clone, PartialEq)]
 struct FreeSlot {
     start: DateTime<Utc>,
     end: DateTime<Utc>,
 }
 
-/// Maps use events to free time.
-async fn map_user_events_to_free_time(events: Vec<EventPointer>) -> Vec<FreeSlot> {
-    // this shouldn't really ever happen (because otherwise people are going to start getting
-    // scheduled to do things in the middle of the night)
-    if events.is_empty() {
-        return vec![FreeSlot {
-            start: Utc::now(),
-            end: Utc::now() + Duration::days(14),
-        }];
+/// A period of time during which the user should work on a specific task. Tasks which take a long
+/// time are split up into a number of blocks (numbered from zero with `block_index`).
+#[derive(Debug, Clone, PartialEq)]
+struct WorkBlock {
+    task_id: i32,
+    block_index: usize,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/// A task which we could not find enough free time for before it is due.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnschedulableTask {
+    pub task_id: i32,
+    pub title: String,
+    pub due_date: NaiveDateTime,
+    /// How much more time would be needed to finish the task.
+    pub shortfall: Duration,
+}
+
+/// The time from which we start scheduling work (this is the start of the next minute, so that all
+/// the blocks that we schedule start and end on a whole minute).
+fn schedule_start() -> DateTime<Utc> {
+    Utc.timestamp((Utc::now().timestamp() / 60 + 1) * 60, 0)
+}
+
+/// Creates a client for a Google calendar. When testing we talk to the local test server instead
+/// (which doesn't understand OAuth).
+#[cfg_attr(test, allow(unused_variables))]
+fn google_client(url: String, access_token: String) -> DavClient {
+    cfg_if! {
+        if #[cfg(test)] {
+            DavClient::new_unauthenticated(url)
+        } else {
+            DavClient::new_oauth(url, access_token)
+        }
     }
-    let pad_start = Utc::now();
-    let mut free_slots = vec![FreeSlot {
-        start: pad_start,
-        end: events.get(0).unwrap().start_time().await.unwrap(),
-    }];
-    for (i, event) in events.iter().enumerate() {
-        if i == (events.len() - 1) {
-            break;
+}
+
+/// Constructs a pair of clients in the form `(lovelace_client, user_client)` for the provided
+/// calendar. The first client points at the calendar that Lovelace writes events into, and the
+/// second at the user's own calendar (which we only ever read from).
+///
+/// If `refresh_token` is true then a new access token is requested for Google calendars (even if
+/// the one we have hasn't expired yet).
+pub(crate) async fn calendar_clients(
+    calendar: &crate::models::calendar::Calendar,
+    conn: &Database,
+    refresh_token: bool,
+) -> Result<(DavClient, DavClient), SchedulingError> {
+    let calendar_id = calendar.id;
+    match parse_calendar_type(calendar.calendar_type) {
+        CalendarType::GoogleCalendar => {
+            let gcal = conn
+                .run(move |c| {
+                    google_calendar::table
+                        .filter(google_calendar::calendar_id.eq(calendar_id))
+                        .first::<GoogleCalendar>(c)
+                })
+                .await?;
+
+            let user_calendar_url = calendar_url(&gcal.calendar_id.to_string());
+
+            let tokens = TokenManager::from_env();
+            let access_token = if refresh_token {
+                tokens.refresh(&gcal, conn).await?
+            } else {
+                tokens.access_token(&gcal, conn).await?
+            };
+            Ok((
+                google_client(gcal.lovelace_calendar_id, access_token.clone()),
+                google_client(user_calendar_url, access_token),
+            ))
+        }
+        CalendarType::CalDav => {
+            let caldav = conn
+                .run(move |c| {
+                    caldav::table
+                        .filter(caldav::calendar_id.eq(calendar_id))
+                        .first::<CalDav>(c)
+                })
+                .await?;
+            let lovelace_url = caldav
+                .lovelace_url
+                .ok_or(SchedulingError::NoLovelaceCalendar)?;
+            Ok((
+                DavClient::new_username_password(
+                    caldav.username.clone(),
+                    caldav.password.clone(),
+                    lovelace_url,
+                ),
+                DavClient::new_username_password(caldav.username, caldav.password, caldav.url),
+            ))
+        }
+        CalendarType::CalDavUnauthenticated => {
+            let caldav = conn
+                .run(move |c| {
+                    caldav_unauthenticated::table
+                        .filter(caldav_unauthenticated::calendar_id.eq(calendar_id))
+                        .first::<CalDavUnauthenticated>(c)
+                })
+                .await?;
+            let lovelace_url = caldav
+                .lovelace_url
+                .ok_or(SchedulingError::NoLovelaceCalendar)?;
+            Ok((
+                DavClient::new_unauthenticated(lovelace_url),
+                DavClient::new_unauthenticated(caldav.url),
+            ))
         }
-        let start = event.end_time().await.unwrap();
-        let end = events.get(i + 1).unwrap().start_time().await.unwrap();
-        free_slots.push(FreeSlot { start, end })
     }
-    free_slots
 }
 
-/// Creates a schedule for the next two weeks.
+/// Creates a schedule for the next two weeks, scheduling the tasks which are due first before any
+/// others.
 ///
 /// Schedules cannot be created for people who have not connected a calendar.
-pub async fn two_week_schedule(user_id: i32, conn: &Database) -> Result<(), SchedulingError> {
+pub async fn two_week_schedule(
+    user_id: i32,
+    conn: &Database,
+) -> Result<Vec<UnschedulableTask>, SchedulingError> {
+    two_week_schedule_with_strategy(user_id, conn, &EarliestDeadlineFirst).await
+}
+
+/// Creates a schedule for the next two weeks, using the provided strategy to decide which tasks
+/// should be scheduled first.
+///
+/// Returns the tasks which could not be finished before they are due (the user is also sent a
+/// notification about these).
+pub async fn two_week_schedule_with_strategy(
+    user_id: i32,
+    conn: &Database,
+    strategy: &dyn SchedulingStrategy,
+) -> Result<Vec<UnschedulableTask>, SchedulingError> {
     let (_user, calendar) = conn
         .run(move |c| {
             users::table
@@ -98,168 +267,667 @@ pub async fn two_week_schedule(user_id: i32, conn: &Database) -> Result<(), Sche
                 .first::<(User, crate::models::calendar::Calendar)>(c)
         })
         .await?;
-    let r#type = calendar.calendar_type;
-    let (lovelace_client, user_client) = match parse_calendar_type(r#type) {
-        crate::models::calendar::CalendarType::GoogleCalendar => {
-            let gcal = conn
-                .run(move |c| {
-                    google_calendar::table
-                        .filter(google_calendar::id.eq(calendar.id))
-                        .first::<GoogleCalendar>(c)
-                })
-                .await?;
-
-            cfg_if! {
-                if #[cfg(test)] {
-                    let user_calendar_url = "http://localhost:8080/user/calendars/calendar".to_string();
-                } else {
-                    let user_calendar_url = format!("https://apidata.googleusercontent.com/caldav/v2/{}/events", gcal.calendar_id);
-                }
-            };
-
-            cfg_if! {
-                if #[cfg(test)] {
-                    (
-                        DavClient::new_unauthenticated(
-                            gcal.lovelace_calendar_id,
-                        ),
-                        DavClient::new_unauthenticated(
-                            user_calendar_url,
-                        ),
-                    )
-                } else {
-                    (
-                        DavClient::new_oauth(
-                            gcal.lovelace_calendar_id,
-                            gcal.access_token.clone(),
-                        ),
-                        DavClient::new_oauth(
-                            user_calendar_url,
-                            gcal.access_token,
-                        ),
-                    )
-                }
-            }
-        }
-        crate::models::calendar::CalendarType::CalDav => {
-            todo!()
+    match schedule_into_calendar(user_id, &calendar, conn, strategy, false).await {
+        // Google sometimes revokes access tokens before they are due to expire, in which case we
+        // get a new one and try again (this is safe because rescheduling only changes the events
+        // which need to be changed)
+        Err(SchedulingError::SchedulingError(CalDavError::Unauthorized))
+            if matches!(
+                parse_calendar_type(calendar.calendar_type),
+                CalendarType::GoogleCalendar
+            ) =>
+        {
+            schedule_into_calendar(user_id, &calendar, conn, strategy, true).await
         }
-        crate::models::calendar::CalendarType::CalDavUnauthenticated => {
-            todo!()
-        }
-    };
+        result => result,
+    }
+}
+
+/// Schedules the user's tasks into the provided calendar (which should belong to them).
+async fn schedule_into_calendar(
+    user_id: i32,
+    calendar: &crate::models::calendar::Calendar,
+    conn: &Database,
+    strategy: &dyn SchedulingStrategy,
+    refresh_token: bool,
+) -> Result<Vec<UnschedulableTask>, SchedulingError> {
+    let (lovelace_client, user_client) = calendar_clients(calendar, conn, refresh_token).await?;
 
+    let now = schedule_start();
     let lovelace_controller = lovelace_client.calendar();
     let user_controller = user_client.calendar();
-    let user_events = user_controller
-        .date_search(
-            chrono::Utc::now(),
-            chrono::Utc::now() + chrono::Duration::days(14),
-        )
-        .await
-        .unwrap();
     let set_events = lovelace_controller
-        .date_search(Utc::now(), Utc::now() + Duration::days(14))
+        .date_search(now, now + Duration::days(14))
         .await?;
-    let mut tasks = conn
+    let (mut tasks, records) = conn
         .run(move |c| {
-            student_class_asynchronous_task::table
+            let tasks = student_class_asynchronous_task::table
                 .inner_join(class_student::table.inner_join(users::table))
                 .inner_join(class_asynchronous_task::table)
                 .filter(users::id.eq(user_id))
-                .filter(class_asynchronous_task::due_date.ge(chrono::Utc::now().naive_utc()))
+                .filter(student_class_asynchronous_task::completed.eq(false))
+                .filter(class_asynchronous_task::due_date.ge(now.naive_utc()))
                 .filter(
-                    class_asynchronous_task::due_date
-                        .le((chrono::Utc::now() + Duration::days(14)).naive_utc()),
+                    class_asynchronous_task::due_date.le((now + Duration::days(14)).naive_utc()),
                 )
                 .select((
                     class_asynchronous_task::all_columns,
                     student_class_asynchronous_task::all_columns,
                 ))
-                .load::<(ClassAsynchronousTask, StudentClassAsynchronousTask)>(c)
+                .load::<StudentTask>(c)?;
+            let records = scheduled_work_block::table
+                .filter(scheduled_work_block::user_id.eq(user_id))
+                .load::<ScheduledWorkBlock>(c)?;
+            Ok::<_, diesel::result::Error>((tasks, records))
         })
-        .await
-        .unwrap();
-    let free_slots = map_user_events_to_free_time(user_events).await;
+        .await?;
+    strategy.order(&mut tasks);
+
+    let mut pointers = HashMap::new();
+    let mut existing = vec![];
+    for pointer in set_events {
+        match read_existing_event(&pointer).await {
+            Ok(event) => {
+                pointers.insert(event.uid.clone(), pointer);
+                existing.push(event);
+            }
+            Err(e) => warn!(
+                "Skipping an event in the Lovelace calendar which could not be read: {:?}",
+                e
+            ),
+        }
+    }
+    let outstanding = tasks
+        .iter()
+        .map(|(task, _)| task.id)
+        .collect::<HashSet<_>>();
+    let mut reconciliation = reconcile(records, existing, &outstanding, now);
+
+    let constraints = SchedulingConstraints::load(user_id, conn).await?;
+    let free_time = user_free_time(
+        calendar,
+        &user_controller,
+        now,
+        now + Duration::days(14),
+        constraints.timezone,
+        conn,
+    )
+    .await?;
+    let free_slots = remove_busy_time(constraints.restrict(free_time), &reconciliation.busy);
 
-    let mut events_to_add = vec![];
+    let blocks = allocate_blocks(free_slots, &tasks, &constraints, &reconciliation.previous);
+    let unschedulable = check_feasibility(
+        &tasks,
+        &blocks
+            .iter()
+            .chain(reconciliation.previous.pinned.iter())
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+
+    let stale = std::mem::take(&mut reconciliation.stale);
+    conn.run(move |c| {
+        diesel::delete(
+            scheduled_work_block::table
+                .filter(scheduled_work_block::user_id.eq(user_id))
+                .filter(scheduled_work_block::uid.eq_any(stale)),
+        )
+        .execute(c)
+    })
+    .await?;
+
+    for block in &blocks {
+        let task = match tasks.iter().find(|(task, _)| task.id == block.task_id) {
+            Some((task, _)) => task,
+            None => continue,
+        };
+        let uid = block_uid(user_id, block);
+        let event = block_to_event(&uid, block, task);
+        match reconciliation.managed.remove(&uid) {
+            Some(existing) => {
+                if existing.start == block.start
+                    && existing.end == block.end
+                    && existing.summary.as_deref() == Some(block_summary(task).as_str())
+                    && existing.description.as_deref().unwrap_or_default() == task.description
+                {
+                    continue;
+                }
+                if let Some(pointer) = pointers.get(&uid) {
+                    match pointer.update(event).await {
+                        Ok(()) => {}
+                        // the user changed this event while we were scheduling, so we leave it
+                        // where it is (next time it will be treated as a block they have moved)
+                        Err(CalDavError::Conflict { .. }) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                let (start, end) = (block.start.naive_utc(), block.end.naive_utc());
+                conn.run(move |c| {
+                    diesel::update(
+                        scheduled_work_block::table
+                            .filter(scheduled_work_block::user_id.eq(user_id))
+                            .filter(scheduled_work_block::uid.eq(uid)),
+                    )
+                    .set((
+                        scheduled_work_block::start_time.eq(start),
+                        scheduled_work_block::end_time.eq(end),
+                    ))
+                    .execute(c)
+                })
+                .await?;
+            }
+            None => {
+                lovelace_controller.save_event(event).await?;
+                let block = block.clone();
+                conn.run(move |c| {
+                    diesel::insert_into(scheduled_work_block::table)
+                        .values(NewScheduledWorkBlock {
+                            user_id,
+                            class_asynchronous_task_id: block.task_id,
+                            block_index: block.block_index as i32,
+                            uid: &uid,
+                            start_time: block.start.naive_utc(),
+                            end_time: block.end.naive_utc(),
+                        })
+                        .execute(c)
+                })
+                .await?;
+            }
+        }
+    }
 
-    for slot in free_slots {
-        if tasks.is_empty() {
-            break;
+    // these are blocks which are no longer needed
+    for (uid, _) in reconciliation.managed {
+        if let Some(pointer) = pointers.remove(&uid) {
+            pointer.delete().await?;
         }
-        fill_slot(slot, &mut tasks, &mut events_to_add);
+        conn.run(move |c| {
+            diesel::delete(
+                scheduled_work_block::table
+                    .filter(scheduled_work_block::user_id.eq(user_id))
+                    .filter(scheduled_work_block::uid.eq(uid)),
+            )
+            .execute(c)
+        })
+        .await?;
     }
 
-    for event in set_events {
-        event.delete().await?;
+    if !unschedulable.is_empty() {
+        notify_unschedulable(user_id, &constraints, &unschedulable, conn).await?;
     }
 
-    for event in events_to_add {
-        lovelace_controller.save_event(event).await?;
+    Ok(unschedulable)
+}
+
+/// Reads the parts of an event in the Lovelace calendar which we need to reconcile it with the new
+/// schedule.
+async fn read_existing_event(pointer: &EventPointer) -> Result<ExistingEvent, CalDavError> {
+    Ok(ExistingEvent {
+        uid: pointer.uid().await?,
+        start: pointer.start_time().await?,
+        end: pointer.end_time().await?,
+        summary: pointer.summary().await.ok(),
+        description: pointer.description().await?,
+    })
+}
+
+/// Works out which tasks were not given enough time (before they are due) by `allocate_blocks`.
+fn check_feasibility(tasks: &[StudentTask], blocks: &[WorkBlock]) -> Vec<UnschedulableTask> {
+    tasks
+        .iter()
+        .filter_map(|(task, student_task)| {
+            let allocated = blocks
+                .iter()
+                .filter(|block| block.task_id == task.id)
+                .fold(Duration::zero(), |acc, block| {
+                    acc + (block.end - block.start)
+                });
+            let shortfall =
+                Duration::minutes(student_task.minutes_to_complete(task) as i64) - allocated;
+            if shortfall > Duration::zero() {
+                Some(UnschedulableTask {
+                    task_id: task.id,
+                    title: task.title.clone(),
+                    due_date: task.due_date,
+                    shortfall,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Warns the user that some of their tasks cannot be finished before they are due. If the user
+/// still has an unread warning from an earlier reschedule, that warning is brought up to date
+/// instead of sending another one (the details change every time, so otherwise every reschedule
+/// would add a new warning).
+async fn notify_unschedulable(
+    user_id: i32,
+    constraints: &SchedulingConstraints,
+    unschedulable: &[UnschedulableTask],
+    conn: &Database,
+) -> Result<(), SchedulingError> {
+    let title = "You might not have enough time to finish some tasks".to_string();
+    let message = format!(
+        "We couldn't find enough free time in your calendar to finish these tasks before they \
+        are due: {}.",
+        unschedulable
+            .iter()
+            .map(|task| format!(
+                "\"{}\" (due {}, about {} more minutes needed)",
+                task.title,
+                DateTime::<Utc>::from_utc(task.due_date, Utc)
+                    .with_timezone(&constraints.timezone)
+                    .format("%A %-d %B at %H:%M"),
+                task.shortfall.num_minutes()
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    conn.run(move |c| {
+        let updated = diesel::update(
+            notifications::table
+                .filter(notifications::user_id.eq(user_id))
+                .filter(notifications::read.eq(false))
+                .filter(notifications::title.eq(&title)),
+        )
+        .set(notifications::contents.eq(&message))
+        .execute(c)?;
+        if updated > 0 {
+            return Ok(());
+        }
+        NotifyBuilder::default()
+            .intended_for(user_id)
+            .title(title.as_str())
+            .message(message.as_str())
+            .priority(NotificationPriority::Warning)
+            .build()
+            .expect("all the fields of `Notify` have been set")
+            .create(c)
+    })
+    .await
+    .map_err(From::from)
+}
+
+/// Splits every task into work blocks and places these into the provided free slots (which
+/// should be sorted by start time and not overlap). Tasks are scheduled in the order in which they
+/// are supplied, and no work block is ever placed after the time at which its task is due.
+///
+/// The free slots should already have been restricted to the user's working hours; this function
+/// makes sure that the other constraints (breaks and the daily limit) are respected.
+fn allocate_blocks(
+    mut free_slots: Vec<FreeSlot>,
+    tasks: &[StudentTask],
+    constraints: &SchedulingConstraints,
+    previous: &PreviousSchedule,
+) -> Vec<WorkBlock> {
+    let mut blocks = vec![];
+    let mut studied = HashMap::<NaiveDate, Duration>::new();
+    for block in &previous.pinned {
+        let studied_that_day = studied
+            .entry(constraints.local_date(block.start))
+            .or_insert_with(Duration::zero);
+        *studied_that_day = *studied_that_day + (block.end - block.start);
     }
+    for (task, student_task) in tasks {
+        let due = DateTime::<Utc>::from_utc(task.due_date, Utc);
+        let mut remaining = Duration::minutes(student_task.minutes_to_complete(task) as i64)
+            - previous.time_for_task(task.id);
+        // blocks which we aren't allowed to move keep their indices
+        let mut block_indices =
+            (0..).filter(|index| !previous.reserved.contains(&(task.id, *index)));
+        for slot in free_slots.iter_mut() {
+            if slot.start >= due {
+                break;
+            }
+            while remaining > Duration::zero() {
+                let day = constraints.local_date(slot.start);
+                let studied_today = studied.get(&day).copied().unwrap_or_else(Duration::zero);
+                let available = std::cmp::min(slot.end, due) - slot.start;
+                let length = std::cmp::min(
+                    std::cmp::min(available, remaining),
+                    std::cmp::min(
+                        Duration::minutes(MAX_BLOCK_LENGTH),
+                        constraints.max_per_day - studied_today,
+                    ),
+                );
+                // we don't want to ask somebody to work on something for only five minutes
+                // (unless that's all they need to finish it off)
+                if length <= Duration::zero()
+                    || (length < remaining && length < Duration::minutes(MIN_BLOCK_LENGTH))
+                {
+                    break;
+                }
+                blocks.push(WorkBlock {
+                    task_id: task.id,
+                    block_index: block_indices
+                        .next()
+                        .expect("there are infinitely many block indices"),
+                    start: slot.start,
+                    end: slot.start + length,
+                });
+                studied.insert(day, studied_today + length);
+                slot.start = slot.start + length + constraints.min_break;
+                remaining = remaining - length;
+            }
+            if remaining <= Duration::zero() {
+                break;
+            }
+        }
+    }
+    blocks
+}
 
-    Ok(())
+/// The summary of the calendar events for a task.
+fn block_summary(task: &ClassAsynchronousTask) -> String {
+    format!("Task: {}", task.title)
 }
 
-/// Recursively occupies the provided time slot with as many events as possible.
-fn fill_slot(
-    slot: FreeSlot,
-    tasks: &mut Vec<(ClassAsynchronousTask, StudentClassAsynchronousTask)>,
-    events_to_add: &mut Vec<Event>,
-) {
-    if tasks.is_empty() {
-        return;
+/// Creates the calendar event for a given block of work. The event links back to the task's page
+/// (if we know where Lovelace is hosted) and reminds the student shortly before it starts.
+fn block_to_event(uid: &str, block: &WorkBlock, task: &ClassAsynchronousTask) -> EventBuilder {
+    let event = EventBuilder::new(block.start, block.end)
+        .uid(uid)
+        .summary(&block_summary(task))
+        .description(&task.description)
+        .category(EVENT_CATEGORY)
+        .alarm(Alarm::reminder(
+            Duration::minutes(REMINDER_MINUTES),
+            &block_summary(task),
+        ));
+    match std::env::var("HOSTNAME") {
+        Ok(hostname) => event.link(&format!(
+            "{}/class/{}/task/async/{}/view",
+            hostname, task.class_id, task.id
+        )),
+        Err(_) => event,
     }
-    let len = slot.end - slot.start;
-    if len > Duration::minutes(25) {
-        let (task, _) = tasks.pop().unwrap();
-        events_to_add.push(
-            Event::new()
-                .uid(&Uuid::new_v4().to_string())
-                .starts(slot.start)
-                .ends(slot.start.add(Duration::minutes(25)))
-                .summary(
-                    &format!(
-                        "Task title: {} Task description: {}",
-                        task.title, task.description
-                    )
-                    .chars()
-                    .map(|char| if char == '\n' { ' ' } else { char })
-                    .collect::<String>()
-                    .as_str(),
-                )
-                .done(),
+}
+
+#[cfg(test)]
+mod test_allocate_blocks {
+    use chrono::Duration;
+
+    use super::{
+        allocate_blocks, check_feasibility,
+        test_ctx::{start, task, unconstrained},
+        FreeSlot, PreviousSchedule, SchedulingConstraints, WorkBlock, MAX_BLOCK_LENGTH,
+    };
+
+    #[test]
+    fn test_long_task_is_split_into_blocks() {
+        let slots = vec![
+            FreeSlot {
+                start: start(),
+                end: start() + Duration::minutes(60),
+            },
+            FreeSlot {
+                start: start() + Duration::hours(3),
+                end: start() + Duration::hours(6),
+            },
+        ];
+        let blocks = allocate_blocks(
+            slots,
+            &[task(1, start() + Duration::days(1), 120, None)],
+            &unconstrained(),
+            &PreviousSchedule::default(),
+        );
+        let total = blocks.iter().fold(Duration::zero(), |acc, block| {
+            acc + (block.end - block.start)
+        });
+        assert_eq!(total, Duration::minutes(120));
+        assert!(blocks
+            .iter()
+            .all(|block| block.end - block.start <= Duration::minutes(MAX_BLOCK_LENGTH)));
+        assert_eq!(
+            blocks.iter().map(|b| b.block_index).collect::<Vec<_>>(),
+            (0..blocks.len()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_student_estimate_overrides_teacher_estimate() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(5),
+        }];
+        let blocks = allocate_blocks(
+            slots,
+            &[task(1, start() + Duration::days(1), 120, Some(30))],
+            &unconstrained(),
+            &PreviousSchedule::default(),
+        );
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].end - blocks[0].start, Duration::minutes(30));
+    }
+
+    #[test]
+    fn test_blocks_are_not_placed_after_due_date() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(5),
+        }];
+        let due = start() + Duration::minutes(40);
+        let blocks = allocate_blocks(
+            slots,
+            &[task(1, due, 120, None)],
+            &unconstrained(),
+            &PreviousSchedule::default(),
         );
-        fill_slot(
+        assert!(blocks.iter().all(|block| block.end <= due));
+    }
+
+    #[test]
+    fn test_tasks_share_slots() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(2),
+        }];
+        let blocks = allocate_blocks(
+            slots,
+            &[
+                task(1, start() + Duration::days(1), 30, None),
+                task(2, start() + Duration::days(1), 30, None),
+            ],
+            &unconstrained(),
+            &PreviousSchedule::default(),
+        );
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].end, blocks[1].start);
+        assert_ne!(blocks[0].task_id, blocks[1].task_id);
+    }
+
+    #[test]
+    fn test_feasibility_check_reports_tasks_without_enough_time() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::minutes(60),
+        }];
+        let tasks = [
+            task(1, start() + Duration::days(1), 40, None),
+            task(2, start() + Duration::days(1), 40, None),
+        ];
+        let blocks = allocate_blocks(
+            slots,
+            &tasks,
+            &unconstrained(),
+            &PreviousSchedule::default(),
+        );
+        let unschedulable = check_feasibility(&tasks, &blocks);
+        assert_eq!(unschedulable.len(), 1);
+        assert_eq!(unschedulable[0].task_id, 2);
+        assert_eq!(unschedulable[0].shortfall, Duration::minutes(20));
+    }
+
+    #[test]
+    fn test_feasibility_check_accepts_feasible_schedule() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(3),
+        }];
+        let tasks = [
+            task(1, start() + Duration::days(1), 40, None),
+            task(2, start() + Duration::days(1), 90, None),
+        ];
+        let blocks = allocate_blocks(
+            slots,
+            &tasks,
+            &unconstrained(),
+            &PreviousSchedule::default(),
+        );
+        assert!(check_feasibility(&tasks, &blocks).is_empty());
+    }
+
+    #[test]
+    fn test_breaks_are_left_between_blocks() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(3),
+        }];
+        let constraints = SchedulingConstraints {
+            min_break: Duration::minutes(15),
+            ..unconstrained()
+        };
+        let blocks = allocate_blocks(
+            slots,
+            &[
+                task(1, start() + Duration::days(1), 30, None),
+                task(2, start() + Duration::days(1), 30, None),
+            ],
+            &constraints,
+            &PreviousSchedule::default(),
+        );
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].start - blocks[0].end, Duration::minutes(15));
+    }
+
+    #[test]
+    fn test_daily_limit_is_respected() {
+        let slots = vec![
+            FreeSlot {
+                start: start(),
+                end: start() + Duration::hours(8),
+            },
             FreeSlot {
-                start: slot.start + Duration::minutes(25),
-                end: slot.end,
+                start: start() + Duration::days(1),
+                end: start() + Duration::days(1) + Duration::hours(8),
             },
-            tasks,
-            events_to_add,
+        ];
+        let constraints = SchedulingConstraints {
+            max_per_day: Duration::minutes(60),
+            ..unconstrained()
+        };
+        let blocks = allocate_blocks(
+            slots,
+            &[task(1, start() + Duration::days(3), 100, None)],
+            &constraints,
+            &PreviousSchedule::default(),
+        );
+        let on_first_day = blocks
+            .iter()
+            .filter(|block| block.start < start() + Duration::days(1))
+            .fold(Duration::zero(), |acc, block| {
+                acc + (block.end - block.start)
+            });
+        assert!(on_first_day <= Duration::minutes(60));
+        assert_eq!(
+            blocks.iter().fold(Duration::zero(), |acc, block| acc
+                + (block.end - block.start)),
+            Duration::minutes(100)
+        );
+    }
+
+    #[test]
+    fn test_pinned_blocks_are_respected() {
+        let slots = vec![FreeSlot {
+            start: start(),
+            end: start() + Duration::hours(3),
+        }];
+        let previous = PreviousSchedule {
+            pinned: vec![WorkBlock {
+                task_id: 1,
+                block_index: 0,
+                start: start() - Duration::hours(1),
+                end: start() - Duration::minutes(20),
+            }],
+            reserved: vec![(1, 0), (1, 1)].into_iter().collect(),
+        };
+        let blocks = allocate_blocks(
+            slots,
+            &[task(1, start() + Duration::days(1), 60, None)],
+            &unconstrained(),
+            &previous,
         );
+        // 40 minutes have already been set aside for this task
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].end - blocks[0].start, Duration::minutes(20));
+        assert_eq!(blocks[0].block_index, 2);
     }
 }
 
-/// Computes the schedule for all users in a class.
-pub async fn schedule_class(class_id: i32, conn: &Database) -> Result<(), SchedulingError> {
-    match conn
-        .run(move |c| {
-            class::table
-                .filter(class::id.eq(class_id))
-                .inner_join(class_student::table.inner_join(users::table))
-                .select(users::id)
-                .load::<i32>(c)
-        })
-        .await
-    {
-        Ok(users) => {
-            for user_id in users {
-                two_week_schedule(user_id, &conn).await?;
-            }
-            Ok(())
-        }
-        Err(e) => Err(SchedulingError::DatabaseError(e)),
+#[cfg(test)]
+mod test_notify_unschedulable {
+    use chrono::Duration;
+    use diesel::prelude::*;
+
+    use super::{
+        notify_unschedulable,
+        test_ctx::{start, unconstrained},
+        UnschedulableTask,
+    };
+    use crate::{
+        db::Database,
+        schema::{notifications, users},
+        utils::{client, create_user},
+    };
+
+    #[rocket::async_test]
+    async fn test_warning_is_updated_instead_of_repeated() {
+        let client = client().await;
+        create_user(
+            "student",
+            "student@example.com",
+            "Etc/UTC",
+            "password",
+            &client,
+        )
+        .await;
+        let conn = Database::get_one(client.rocket()).await.unwrap();
+        let user_id = conn
+            .run(|c| {
+                users::table
+                    .filter(users::username.eq("student"))
+                    .select(users::id)
+                    .get_result::<i32>(c)
+            })
+            .await
+            .unwrap();
+        let unschedulable = |shortfall| {
+            vec![UnschedulableTask {
+                task_id: 1,
+                title: "Essay".to_string(),
+                due_date: (start() + Duration::days(1)).naive_utc(),
+                shortfall: Duration::minutes(shortfall),
+            }]
+        };
+        notify_unschedulable(user_id, &unconstrained(), &unschedulable(30), &conn)
+            .await
+            .unwrap();
+        // the shortfall changes every time that the user is rescheduled
+        notify_unschedulable(user_id, &unconstrained(), &unschedulable(20), &conn)
+            .await
+            .unwrap();
+        let warnings = conn
+            .run(move |c| {
+                notifications::table
+                    .filter(notifications::user_id.eq(user_id))
+                    .select(notifications::contents)
+                    .load::<String>(c)
+            })
+            .await
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("about 20 more minutes needed"));
     }
 }
@@ -0,0 +1,334 @@
+//! Works out how the events which are already in the user's Lovelace calendar relate to the
+//! schedule which we are about to compute.
+//!
+//! Every block of work has a stable UID (derived from the task it belongs to and its position in
+//! that task), and we remember when we scheduled each block for. This lets us sort the existing
+//! events into:
+//! * blocks which we can freely move (or delete) – these are events which are exactly where we put
+//!   them
+//! * blocks which we must leave alone – these are blocks which have already started, or which the
+//!   user has moved (or deleted) themselves
+//! * events which we didn't create – these are also left alone
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::{FreeSlot, WorkBlock};
+use crate::models::calendar::ScheduledWorkBlock;
+
+/// An event which is already in the user's Lovelace calendar.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct ExistingEvent {
+    pub uid: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+}
+
+/// The parts of the previous schedule which must be kept.
+#[derive(Debug, Clone, Default)]
+pub(super) struct PreviousSchedule {
+    /// Blocks which will not be moved. The time spent on these counts towards their task.
+    pub pinned: Vec<WorkBlock>,
+    /// The `(task_id, block_index)` pairs which are already in use, and cannot be given to new
+    /// blocks (otherwise their UIDs would clash).
+    pub reserved: HashSet<(i32, usize)>,
+}
+
+impl PreviousSchedule {
+    /// The amount of time which has already been set aside for the given task.
+    pub fn time_for_task(&self, task_id: i32) -> Duration {
+        self.pinned
+            .iter()
+            .filter(|block| block.task_id == task_id)
+            .fold(Duration::zero(), |acc, block| {
+                acc + (block.end - block.start)
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Reconciliation {
+    pub previous: PreviousSchedule,
+    /// Times during which the Lovelace calendar already contains an event that we're keeping.
+    pub busy: Vec<FreeSlot>,
+    /// Events which are exactly where we put them (and can therefore be moved or deleted), indexed
+    /// by UID.
+    pub managed: HashMap<String, ExistingEvent>,
+    /// The UIDs of the records which are no longer needed.
+    pub stale: Vec<String>,
+}
+
+/// The UID of the calendar event for the given block.
+pub(super) fn block_uid(user_id: i32, block: &WorkBlock) -> String {
+    format!(
+        "lovelace-{}-{}-{}",
+        user_id, block.task_id, block.block_index
+    )
+}
+
+/// Sorts the existing events in the Lovelace calendar (which should be all the events from `now`
+/// onwards) using our records of what we last scheduled. `outstanding` should contain the ids of
+/// the tasks which are still being scheduled.
+pub(super) fn reconcile(
+    records: Vec<ScheduledWorkBlock>,
+    events: Vec<ExistingEvent>,
+    outstanding: &HashSet<i32>,
+    now: DateTime<Utc>,
+) -> Reconciliation {
+    let mut reconciliation = Reconciliation::default();
+    let mut events = events
+        .into_iter()
+        .map(|event| (event.uid.clone(), event))
+        .collect::<HashMap<_, _>>();
+    for record in records {
+        let event = events.remove(&record.uid);
+        let scheduled_start = DateTime::<Utc>::from_utc(record.start_time, Utc);
+        let scheduled_end = DateTime::<Utc>::from_utc(record.end_time, Utc);
+        let task_id = record.class_asynchronous_task_id;
+        let block_index = record.block_index as usize;
+        let is_outstanding = outstanding.contains(&task_id);
+        let mut pin = |start, end| {
+            reconciliation.busy.push(FreeSlot { start, end });
+            if is_outstanding {
+                reconciliation.previous.pinned.push(WorkBlock {
+                    task_id,
+                    block_index,
+                    start,
+                    end,
+                });
+            }
+        };
+        if scheduled_start < now {
+            // this block has already started, so it's too late to change it
+            let (start, end) = event
+                .map(|event| (event.start, event.end))
+                .unwrap_or((scheduled_start, scheduled_end));
+            pin(start, end);
+            if !is_outstanding {
+                reconciliation.stale.push(record.uid);
+                continue;
+            }
+        } else {
+            match event {
+                Some(event) if event.start == scheduled_start && event.end == scheduled_end => {
+                    reconciliation.managed.insert(record.uid, event);
+                    continue;
+                }
+                // the user has moved this block, so we leave it where they put it
+                Some(event) => pin(event.start, event.end),
+                // the user has deleted this block – we don't put it back, but we do make sure
+                // that any time the task still needs is scheduled (under a new UID)
+                None => {
+                    if !is_outstanding {
+                        reconciliation.stale.push(record.uid);
+                        continue;
+                    }
+                }
+            }
+        }
+        reconciliation
+            .previous
+            .reserved
+            .insert((task_id, block_index));
+    }
+    // events which we didn't create
+    reconciliation
+        .busy
+        .extend(events.values().map(|event| FreeSlot {
+            start: event.start,
+            end: event.end,
+        }));
+    reconciliation
+}
+
+/// Removes the busy periods from the list of free slots (which should be sorted by start time).
+pub(super) fn remove_busy_time(slots: Vec<FreeSlot>, busy: &[FreeSlot]) -> Vec<FreeSlot> {
+    busy.iter().fold(slots, |slots, busy| {
+        slots
+            .into_iter()
+            .flat_map(|slot| {
+                if busy.end <= slot.start || busy.start >= slot.end {
+                    return vec![slot];
+                }
+                let mut remaining = vec![];
+                if slot.start < busy.start {
+                    remaining.push(FreeSlot {
+                        start: slot.start,
+                        end: busy.start,
+                    });
+                }
+                if busy.end < slot.end {
+                    remaining.push(FreeSlot {
+                        start: busy.end,
+                        end: slot.end,
+                    });
+                }
+                remaining
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test_reconcile {
+    use std::collections::HashSet;
+
+    use chrono::{DateTime, Duration, Utc};
+
+    use super::{reconcile, remove_busy_time, ExistingEvent};
+    use crate::{
+        calendar::scheduler::{test_ctx::start, FreeSlot},
+        models::calendar::ScheduledWorkBlock,
+    };
+
+    fn record(task_id: i32, block_index: i32, start: DateTime<Utc>) -> ScheduledWorkBlock {
+        ScheduledWorkBlock {
+            id: 1,
+            user_id: 1,
+            class_asynchronous_task_id: task_id,
+            block_index,
+            uid: format!("lovelace-1-{}-{}", task_id, block_index),
+            start_time: start.naive_utc(),
+            end_time: (start + Duration::minutes(30)).naive_utc(),
+        }
+    }
+
+    fn event(uid: &str, start: DateTime<Utc>) -> ExistingEvent {
+        ExistingEvent {
+            uid: uid.to_string(),
+            start,
+            end: start + Duration::minutes(30),
+            summary: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_untouched_events_are_managed() {
+        let now = start();
+        let reconciliation = reconcile(
+            vec![record(1, 0, now + Duration::hours(1))],
+            vec![event("lovelace-1-1-0", now + Duration::hours(1))],
+            &vec![1].into_iter().collect(),
+            now,
+        );
+        assert!(reconciliation.managed.contains_key("lovelace-1-1-0"));
+        assert!(reconciliation.previous.pinned.is_empty());
+        assert!(reconciliation.previous.reserved.is_empty());
+        assert!(reconciliation.busy.is_empty());
+    }
+
+    #[test]
+    fn test_moved_events_are_left_alone() {
+        let now = start();
+        let reconciliation = reconcile(
+            vec![record(1, 0, now + Duration::hours(1))],
+            vec![event("lovelace-1-1-0", now + Duration::hours(5))],
+            &vec![1].into_iter().collect(),
+            now,
+        );
+        assert!(reconciliation.managed.is_empty());
+        assert_eq!(reconciliation.previous.pinned.len(), 1);
+        assert_eq!(
+            reconciliation.previous.pinned[0].start,
+            now + Duration::hours(5)
+        );
+        assert!(reconciliation.previous.reserved.contains(&(1, 0)));
+        assert_eq!(
+            reconciliation.previous.time_for_task(1),
+            Duration::minutes(30)
+        );
+        assert_eq!(reconciliation.busy.len(), 1);
+    }
+
+    #[test]
+    fn test_deleted_events_are_not_recreated() {
+        let now = start();
+        let reconciliation = reconcile(
+            vec![record(1, 0, now + Duration::hours(1))],
+            vec![],
+            &vec![1].into_iter().collect(),
+            now,
+        );
+        assert!(reconciliation.previous.pinned.is_empty());
+        assert!(reconciliation.previous.reserved.contains(&(1, 0)));
+        assert!(reconciliation.stale.is_empty());
+    }
+
+    #[test]
+    fn test_blocks_for_finished_tasks_are_cleaned_up() {
+        let now = start();
+        let reconciliation = reconcile(
+            vec![
+                record(1, 0, now - Duration::hours(1)),
+                record(1, 1, now + Duration::hours(1)),
+            ],
+            vec![event("lovelace-1-1-1", now + Duration::hours(1))],
+            &HashSet::new(),
+            now,
+        );
+        assert_eq!(reconciliation.stale, vec!["lovelace-1-1-0".to_string()]);
+        // this will be deleted (because the task no longer needs to be scheduled)
+        assert!(reconciliation.managed.contains_key("lovelace-1-1-1"));
+        assert!(reconciliation.previous.pinned.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_events_are_busy() {
+        let now = start();
+        let reconciliation = reconcile(
+            vec![],
+            vec![event("some-other-event", now + Duration::hours(1))],
+            &HashSet::new(),
+            now,
+        );
+        assert!(reconciliation.managed.is_empty());
+        assert_eq!(
+            reconciliation.busy,
+            vec![FreeSlot {
+                start: now + Duration::hours(1),
+                end: now + Duration::hours(1) + Duration::minutes(30),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_remove_busy_time() {
+        let slots = vec![
+            FreeSlot {
+                start: start(),
+                end: start() + Duration::hours(2),
+            },
+            FreeSlot {
+                start: start() + Duration::hours(3),
+                end: start() + Duration::hours(4),
+            },
+        ];
+        let busy = vec![
+            FreeSlot {
+                start: start() + Duration::hours(1),
+                end: start() + Duration::minutes(90),
+            },
+            FreeSlot {
+                start: start() + Duration::minutes(170),
+                end: start() + Duration::hours(5),
+            },
+        ];
+        assert_eq!(
+            remove_busy_time(slots, &busy),
+            vec![
+                FreeSlot {
+                    start: start(),
+                    end: start() + Duration::hours(1),
+                },
+                FreeSlot {
+                    start: start() + Duration::minutes(90),
+                    end: start() + Duration::hours(2),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Strategies which decide the order in which tasks are fitted into a student's free time.
+//!
+//! Tasks which come earlier in the order get the first pick of the free time, so the strategy has
+//! a large effect on whether or not everything can be finished before it is due.
+
+use crate::models::{ClassAsynchronousTask, StudentClassAsynchronousTask};
+
+/// A task, together with the student's own record of it.
+pub type StudentTask = (ClassAsynchronousTask, StudentClassAsynchronousTask);
+
+/// Something which decides the order in which tasks should be scheduled.
+pub trait SchedulingStrategy: Send + Sync {
+    /// Sorts the tasks into the order in which they should be scheduled (the first task will be
+    /// scheduled first).
+    fn order(&self, tasks: &mut [StudentTask]);
+}
+
+/// Schedules the tasks which are due first before any others (and, of the tasks which are due at
+/// the same time, the shortest first). This is a good heuristic for getting everything finished on
+/// time, but it isn't guaranteed to find a schedule which does so whenever one exists – free time
+/// is limited to the user's working hours and blocks have a minimum length, so a task which is due
+/// later can sometimes use a gap which an earlier task couldn't.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct EarliestDeadlineFirst;
+
+impl SchedulingStrategy for EarliestDeadlineFirst {
+    fn order(&self, tasks: &mut [StudentTask]) {
+        tasks.sort_by(|(a, a_student), (b, b_student)| {
+            a.due_date.cmp(&b.due_date).then_with(|| {
+                a_student
+                    .minutes_to_complete(a)
+                    .cmp(&b_student.minutes_to_complete(b))
+            })
+        });
+    }
+}
+
+/// Schedules the tasks which take the least time before any others. This gets the largest number
+/// of tasks done as soon as possible, but can leave a long task without enough time before its
+/// due date.
+#[derive(Debug, Default, Copy, Clone)]
+#[allow(unused)]
+pub struct ShortestTaskFirst;
+
+impl SchedulingStrategy for ShortestTaskFirst {
+    fn order(&self, tasks: &mut [StudentTask]) {
+        tasks.sort_by(|(a, a_student), (b, b_student)| {
+            a_student
+                .minutes_to_complete(a)
+                .cmp(&b_student.minutes_to_complete(b))
+                .then_with(|| a.due_date.cmp(&b.due_date))
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_strategy {
+    use chrono::Duration;
+
+    use super::{EarliestDeadlineFirst, SchedulingStrategy, ShortestTaskFirst};
+    use crate::calendar::scheduler::test_ctx::{start, task};
+
+    #[test]
+    fn test_earliest_deadline_first() {
+        let mut tasks = vec![
+            task(1, start() + Duration::days(3), 10, None),
+            task(2, start() + Duration::days(1), 60, None),
+            task(3, start() + Duration::days(2), 30, None),
+        ];
+        EarliestDeadlineFirst.order(&mut tasks);
+        assert_eq!(
+            tasks.iter().map(|(task, _)| task.id).collect::<Vec<_>>(),
+            vec![2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_shortest_task_first() {
+        let mut tasks = vec![
+            task(1, start() + Duration::days(3), 10, None),
+            task(2, start() + Duration::days(1), 60, None),
+            task(3, start() + Duration::days(2), 30, Some(5)),
+        ];
+        ShortestTaskFirst.order(&mut tasks);
+        assert_eq!(
+            tasks.iter().map(|(task, _)| task.id).collect::<Vec<_>>(),
+            vec![3, 1, 2]
+        );
+    }
+}
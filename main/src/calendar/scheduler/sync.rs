@@ -0,0 +1,133 @@
+//! Keeps a copy of the user's calendar in the database, so that we only have to fetch the events
+//! which have changed since the last time we scheduled them.
+//!
+//! If the calendar's ctag hasn't changed since the last sync then nothing is fetched at all.
+//! Otherwise we ask the server for the changes since the last sync (using the sync token which it
+//! gave us last time). Servers which don't support this are searched for the events in the
+//! scheduling window every time instead.
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use prospero::{
+    calendar::{Calendar as DavCalendar, Etag},
+    error::CalDavError,
+    event::EventPointer,
+    sync::{SyncResult, SyncedResource},
+};
+
+use super::SchedulingError;
+use crate::{
+    db::Database,
+    models::calendar::{Calendar, NewSyncedEvent, SyncedEvent},
+    schema::{calendar, synced_event},
+};
+
+/// Saves the changes to the user's calendar in the database. If `from_scratch` is true then the
+/// result contains the entire calendar (so anything which we had stored before is removed).
+async fn save_changes(
+    calendar_id: i32,
+    result: SyncResult,
+    from_scratch: bool,
+    ctag: Option<String>,
+    conn: &Database,
+) -> Result<(), diesel::result::Error> {
+    conn.run(move |c| {
+        c.transaction::<_, diesel::result::Error, _>(|| {
+            let calendar_events =
+                synced_event::table.filter(synced_event::calendar_id.eq(calendar_id));
+            if from_scratch {
+                diesel::delete(calendar_events).execute(c)?;
+            } else {
+                diesel::delete(calendar_events.filter(synced_event::href.eq_any(&result.removed)))
+                    .execute(c)?;
+            }
+            for resource in &result.changed {
+                let etag = resource.etag.as_ref().map(Etag::as_str);
+                diesel::insert_into(synced_event::table)
+                    .values(NewSyncedEvent {
+                        calendar_id,
+                        href: &resource.href,
+                        etag,
+                        data: &resource.data,
+                    })
+                    .on_conflict((synced_event::calendar_id, synced_event::href))
+                    .do_update()
+                    .set((
+                        synced_event::etag.eq(etag),
+                        synced_event::data.eq(&resource.data),
+                    ))
+                    .execute(c)?;
+            }
+            diesel::update(calendar::table.filter(calendar::id.eq(calendar_id)))
+                .set((
+                    calendar::sync_token.eq(&result.token),
+                    calendar::ctag.eq(ctag),
+                ))
+                .execute(c)?;
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Reads the events from our copy of the user's calendar.
+async fn stored_events(
+    calendar_id: i32,
+    user_calendar: &DavCalendar,
+    conn: &Database,
+) -> Result<Vec<EventPointer>, SchedulingError> {
+    let stored = conn
+        .run(move |c| {
+            synced_event::table
+                .filter(synced_event::calendar_id.eq(calendar_id))
+                .load::<SyncedEvent>(c)
+        })
+        .await?;
+    let mut events = vec![];
+    for event in stored {
+        let resource = SyncedResource {
+            href: event.href,
+            etag: event.etag.map(Etag::new),
+            data: event.data,
+        };
+        match user_calendar.events_from_data(&resource) {
+            Ok(pointers) => events.extend(pointers),
+            Err(e) => warn!(
+                "Skipping a resource in the user's calendar which could not be read: {}",
+                e
+            ),
+        }
+    }
+    Ok(events)
+}
+
+/// Returns the events in the user's calendar. This includes (at least) all the events between
+/// `from` and `until`.
+pub(super) async fn user_events(
+    calendar: &Calendar,
+    user_calendar: &DavCalendar,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    conn: &Database,
+) -> Result<Vec<EventPointer>, SchedulingError> {
+    let ctag = match user_calendar.ctag().await {
+        Ok(ctag) => ctag,
+        Err(CalDavError::Unauthorized) => return Err(CalDavError::Unauthorized.into()),
+        // not every server supports ctags, in which case we always have to ask for the changes
+        Err(_) => None,
+    };
+    if calendar.sync_token.is_some() && ctag.is_some() && ctag == calendar.ctag {
+        return stored_events(calendar.id, user_calendar, conn).await;
+    }
+    let (result, from_scratch) = match user_calendar.sync(calendar.sync_token.as_deref()).await {
+        Ok(result) => (result, calendar.sync_token.is_none()),
+        Err(CalDavError::InvalidSyncToken) => (user_calendar.sync(None).await?, true),
+        // the server doesn't support `sync-collection`
+        Err(CalDavError::UnexpectedStatus { .. }) => {
+            return Ok(user_calendar.date_search(from, until).await?)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    save_changes(calendar.id, result, from_scratch, ctag, conn).await?;
+    stored_events(calendar.id, user_calendar, conn).await
+}
@@ -0,0 +1,50 @@
+//! Helpers for the scheduler's unit tests.
+
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+
+use super::{SchedulingConstraints, StudentTask};
+use crate::models::{ClassAsynchronousTask, StudentClassAsynchronousTask};
+
+pub fn start() -> DateTime<Utc> {
+    Utc.ymd(2021, 3, 1).and_hms(9, 0, 0)
+}
+
+pub fn task(
+    id: i32,
+    due: DateTime<Utc>,
+    estimated_duration: i32,
+    student_estimate: Option<i32>,
+) -> StudentTask {
+    (
+        ClassAsynchronousTask {
+            id,
+            title: format!("task-{}", id),
+            description: "description".to_string(),
+            created: start().naive_utc(),
+            due_date: due.naive_utc(),
+            class_teacher_id: 1,
+            class_id: 1,
+            estimated_duration,
+        },
+        StudentClassAsynchronousTask {
+            id,
+            class_student_id: 1,
+            class_asynchronous_task_id: id,
+            completed: false,
+            estimated_duration: student_estimate,
+        },
+    )
+}
+
+/// Constraints which allow work to be scheduled at any time.
+pub fn unconstrained() -> SchedulingConstraints {
+    SchedulingConstraints {
+        working_hours: [Some((
+            NaiveTime::from_hms(0, 0, 0),
+            NaiveTime::from_hms(23, 59, 59),
+        )); 7],
+        min_break: Duration::zero(),
+        max_per_day: Duration::days(1),
+        timezone: chrono_tz::UTC,
+    }
+}
@@ -1,8 +1,9 @@
 //! Integration tests for calendaring.
 
 use crate::{
+    calendar::scheduler::{jobs::RescheduledUsers, two_week_schedule},
     models::calendar::{Calendar, GoogleCalendar},
-    schema::{calendar, google_calendar},
+    schema::{calendar, google_calendar, oauth_state},
     utils::{launch, login_user, logout},
 };
 use chrono::{Duration, Utc};
@@ -16,8 +17,6 @@ use crate::{
     models::{NewClass, NewClassStudent, NewClassTeacher, NewUser},
 };
 
-use super::connect::gcal::StateValues;
-
 const NEW_TASK_TITLE: &str = "new-task-title";
 const NEW_TASK_DESCRIPTION: &str = "new-task-description";
 
@@ -128,6 +127,13 @@ async fn google_oauth_caldav_integration_test() {
 
     let test_server = MockServer::start().await;
     std::env::set_var("TOKEN_URL", format!("{}/token", test_server.uri()));
+    // the test server stands in for Google's CalDAV server
+    let caldav_server = ariel::TestServer::start().await;
+    caldav_server.add_calendar(LOVELACE_CALENDAR);
+    std::env::set_var(
+        "GOOGLE_CALDAV_URL",
+        caldav_server.calendar_url_of("{calendar}"),
+    );
 
     let client = rocket::local::asynchronous::Client::tracked(launch())
         .await
@@ -139,7 +145,8 @@ async fn google_oauth_caldav_integration_test() {
             r#"
             {
                 "access_token": "some-token",
-                "refresh_token": "some-refresh-token"
+                "refresh_token": "some-refresh-token",
+                "expires_in": 3599
             }"#,
         ))
         .mount(&test_server)
@@ -156,31 +163,38 @@ async fn google_oauth_caldav_integration_test() {
     let add_calendar_response = client
         .post("/calendar/gcal/link")
         .header(ContentType::Form)
-        .body("url=lovelace")
+        .body(format!("url={}", LOVELACE_CALENDAR))
         .dispatch()
         .await;
     assert_eq!(add_calendar_response.status().code, 303);
 
-    let (state_token, _) = client
-        .rocket()
-        .state::<StateValues>()
+    let state_token = Database::get_one(client.rocket())
+        .await
         .unwrap()
-        .map
-        .read()
+        .run(move |c| {
+            oauth_state::table
+                .filter(oauth_state::user_id.eq(student_id))
+                .select(oauth_state::state)
+                .first::<String>(c)
+        })
         .await
-        .iter()
-        .find(|(_, b)| b.user_id == student_id)
-        .map(|(a, b)| (a.clone(), b.clone()))
         .unwrap();
 
     let inp = format!(
         "/calendar/gcal/callback?state={}&code={}",
         state_token, AUTH_CODE
     );
-    let res = client.get(inp).dispatch().await;
+    let res = client.get(&inp).dispatch().await;
     let string = res.into_string().await.unwrap();
     assert!(string.contains("Connected your calendar"));
-    let (_, google_calendar) = Database::get_one(&client.rocket())
+    // the state value can only be used once
+    let res = client.get(&inp).dispatch().await;
+    assert!(!res
+        .into_string()
+        .await
+        .unwrap()
+        .contains("Connected your calendar"));
+    let (calendar, google_calendar) = Database::get_one(&client.rocket())
         .await
         .unwrap()
         .run(move |c| {
@@ -193,12 +207,20 @@ async fn google_oauth_caldav_integration_test() {
         .unwrap();
     assert_eq!(&google_calendar.access_token, "some-token");
     assert_eq!(&google_calendar.refresh_token, "some-refresh-token");
+    assert!(google_calendar.access_token_expires_at.unwrap() > Utc::now().naive_utc());
+    assert_eq!(
+        google_calendar.lovelace_calendar_id,
+        caldav_server.calendar_url_of(LOVELACE_CALENDAR)
+    );
+    // the scheduler reads the user's own calendar (which is also on the test server)
+    caldav_server.add_calendar(&calendar.id.to_string());
 
-    sequence(&client, class_id).await;
+    sequence(&client, class_id, &google_calendar.lovelace_calendar_id).await;
 }
 
-/// Runs the test sequence (adds a number of events and checks that they are added to the calendar)
-async fn sequence(client: &Client, class_id: i32) {
+/// Runs the test sequence (adds a number of events and checks that they are added to the calendar
+/// at `lovelace_url`)
+async fn sequence(client: &Client, class_id: i32, lovelace_url: &str) {
     logout(&client).await;
     login_user(TEACHER_USERNAME, TEACHER_PASSWORD, client).await;
     let res = client
@@ -217,8 +239,20 @@ async fn sequence(client: &Client, class_id: i32) {
         .await;
     let string = res.into_string().await.expect("invalid body string");
     assert!(string.contains("created"));
-    rocket::tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-    let client = DavClient::new_unauthenticated("http://localhost:8080/user/calendars/lovelace");
+    // the scheduler doesn't run in the background when testing, so we run it here instead
+    let conn = Database::get_one(client.rocket()).await.unwrap();
+    for user_id in client
+        .rocket()
+        .state::<RescheduledUsers>()
+        .unwrap()
+        .drain()
+        .await
+    {
+        two_week_schedule(user_id, &conn)
+            .await
+            .expect("failed to schedule tasks");
+    }
+    let client = DavClient::new_unauthenticated(lovelace_url);
     let calendar = client.calendar();
     let results = calendar
         .date_search(
@@ -229,3 +263,222 @@ async fn sequence(client: &Client, class_id: i32) {
         .unwrap();
     assert_eq!(results.len(), 1);
 }
+
+/// The name of the calendar (on the test server) which Lovelace adds the study plan to.
+const LOVELACE_CALENDAR: &str = "lovelace";
+
+/// Sets a task (due in a week's time) to every student in the class, returning the student's id.
+fn setup_env_with_task(conn: &DatabaseConnection) -> i32 {
+    use crate::{
+        models::{
+            NewClassAsynchronousTask, NewStudentClassAsynchronousTask, DEFAULT_ESTIMATED_DURATION,
+        },
+        schema::{
+            class_asynchronous_task, class_student, class_teacher, student_class_asynchronous_task,
+        },
+    };
+    let (class_id, student_id, teacher_id) = setup_env(conn);
+    let class_teacher_id = class_teacher::table
+        .filter(class_teacher::user_id.eq(teacher_id))
+        .filter(class_teacher::class_id.eq(class_id))
+        .select(class_teacher::id)
+        .first::<i32>(conn)
+        .unwrap();
+    let task_id = diesel::insert_into(class_asynchronous_task::table)
+        .values(NewClassAsynchronousTask {
+            title: NEW_TASK_TITLE,
+            description: NEW_TASK_DESCRIPTION,
+            created: Utc::now().naive_utc(),
+            due_date: (Utc::now() + Duration::days(7)).naive_utc(),
+            class_teacher_id,
+            class_id,
+            estimated_duration: DEFAULT_ESTIMATED_DURATION,
+        })
+        .returning(class_asynchronous_task::id)
+        .get_result::<i32>(conn)
+        .unwrap();
+    let class_student_id = class_student::table
+        .filter(class_student::user_id.eq(student_id))
+        .select(class_student::id)
+        .first::<i32>(conn)
+        .unwrap();
+    diesel::insert_into(student_class_asynchronous_task::table)
+        .values(NewStudentClassAsynchronousTask {
+            class_student_id,
+            class_asynchronous_task_id: task_id,
+            completed: false,
+        })
+        .execute(conn)
+        .unwrap();
+    student_id
+}
+
+/// Checks that the Lovelace calendar contains an event for the task created in
+/// `setup_env_with_task`.
+async fn lovelace_calendar_contains_task(lovelace_url: &str) -> bool {
+    let events = DavClient::new_unauthenticated(lovelace_url)
+        .calendar()
+        .date_search(
+            Utc::now() - Duration::days(1),
+            Utc::now() + Duration::days(14),
+        )
+        .await
+        .unwrap();
+    for event in events {
+        if event.summary().await.unwrap().contains(NEW_TASK_TITLE) {
+            return true;
+        }
+    }
+    false
+}
+
+#[rocket::async_test]
+async fn test_schedule_caldav_calendar() {
+    use crate::{
+        models::calendar::{CalendarType, NewCalDav, NewCalendar},
+        schema::caldav,
+        utils::client,
+    };
+    let server = ariel::TestServer::start().await;
+    let user_url = server.calendar_url();
+    let lovelace_url = server.add_calendar(LOVELACE_CALENDAR);
+    let client = client().await;
+    let conn = Database::get_one(client.rocket()).await.unwrap();
+    let database_lovelace_url = lovelace_url.clone();
+    let student_id = conn
+        .run(move |c| {
+            let student_id = setup_env_with_task(c);
+            let calendar_id = diesel::insert_into(calendar::table)
+                .values(NewCalendar {
+                    calendar_type: CalendarType::CalDav.into(),
+                    user_id: student_id,
+                })
+                .returning(calendar::id)
+                .get_result::<i32>(c)
+                .unwrap();
+            diesel::insert_into(caldav::table)
+                .values(NewCalDav {
+                    calendar_id,
+                    username: STUDENT_USERNAME,
+                    password: STUDENT_PASSWORD,
+                    url: &user_url,
+                    lovelace_url: Some(&database_lovelace_url),
+                })
+                .execute(c)
+                .unwrap();
+            student_id
+        })
+        .await;
+    two_week_schedule(student_id, &conn)
+        .await
+        .expect("failed to schedule tasks");
+    assert!(lovelace_calendar_contains_task(&lovelace_url).await);
+}
+
+#[rocket::async_test]
+async fn test_schedule_unauthenticated_caldav_calendar() {
+    use crate::{
+        models::calendar::{CalendarType, NewCalDavUnauthenticated, NewCalendar},
+        schema::caldav_unauthenticated,
+        utils::client,
+    };
+    let server = ariel::TestServer::start().await;
+    let user_url = server.calendar_url();
+    let lovelace_url = server.add_calendar(LOVELACE_CALENDAR);
+    let client = client().await;
+    let conn = Database::get_one(client.rocket()).await.unwrap();
+    let database_lovelace_url = lovelace_url.clone();
+    let student_id = conn
+        .run(move |c| {
+            let student_id = setup_env_with_task(c);
+            let calendar_id = diesel::insert_into(calendar::table)
+                .values(NewCalendar {
+                    calendar_type: CalendarType::CalDavUnauthenticated.into(),
+                    user_id: student_id,
+                })
+                .returning(calendar::id)
+                .get_result::<i32>(c)
+                .unwrap();
+            diesel::insert_into(caldav_unauthenticated::table)
+                .values(NewCalDavUnauthenticated {
+                    calendar_id,
+                    url: &user_url,
+                    lovelace_url: Some(&database_lovelace_url),
+                })
+                .execute(c)
+                .unwrap();
+            student_id
+        })
+        .await;
+    two_week_schedule(student_id, &conn)
+        .await
+        .expect("failed to schedule tasks");
+    assert!(lovelace_calendar_contains_task(&lovelace_url).await);
+}
+
+/// Rescheduling should leave events which don't need to change alone (rather than deleting and
+/// recreating them).
+#[rocket::async_test]
+async fn test_rescheduling_does_not_duplicate_events() {
+    use crate::{
+        models::calendar::{CalendarType, NewCalDavUnauthenticated, NewCalendar},
+        schema::caldav_unauthenticated,
+        utils::client,
+    };
+    let server = ariel::TestServer::start().await;
+    let user_url = server.calendar_url();
+    let lovelace_url = server.add_calendar(LOVELACE_CALENDAR);
+    let client = client().await;
+    let conn = Database::get_one(client.rocket()).await.unwrap();
+    let database_lovelace_url = lovelace_url.clone();
+    let student_id = conn
+        .run(move |c| {
+            let student_id = setup_env_with_task(c);
+            let calendar_id = diesel::insert_into(calendar::table)
+                .values(NewCalendar {
+                    calendar_type: CalendarType::CalDavUnauthenticated.into(),
+                    user_id: student_id,
+                })
+                .returning(calendar::id)
+                .get_result::<i32>(c)
+                .unwrap();
+            diesel::insert_into(caldav_unauthenticated::table)
+                .values(NewCalDavUnauthenticated {
+                    calendar_id,
+                    url: &user_url,
+                    lovelace_url: Some(&database_lovelace_url),
+                })
+                .execute(c)
+                .unwrap();
+            student_id
+        })
+        .await;
+    let lovelace_url = &lovelace_url;
+    let student_events = || async move {
+        let mut uids = vec![];
+        for event in DavClient::new_unauthenticated(lovelace_url)
+            .calendar()
+            .date_search(Utc::now(), Utc::now() + Duration::days(14))
+            .await
+            .unwrap()
+        {
+            let uid = event.uid().await.unwrap();
+            if uid.starts_with(&format!("lovelace-{}-", student_id)) {
+                uids.push(uid);
+            }
+        }
+        uids.sort();
+        uids
+    };
+
+    two_week_schedule(student_id, &conn)
+        .await
+        .expect("failed to schedule tasks");
+    let first = student_events().await;
+    assert!(!first.is_empty());
+
+    two_week_schedule(student_id, &conn)
+        .await
+        .expect("failed to schedule tasks");
+    assert_eq!(first, student_events().await);
+}
@@ -5,19 +5,26 @@ A copy of this license can be found in the `licenses` directory at the root of t
 
 use diesel::prelude::*;
 use malvolio::prelude::*;
+use rocket::State;
 use rocket_contrib::json::Json;
 
 use crate::utils::default_head;
 use crate::utils::error_message;
 use crate::{
     auth::AuthCookie,
+    calendar::scheduler::ScheduleQueue,
     db::Database,
     models::{Class, ClassStudent, NewClassStudent},
     utils::{error::LovelaceError, json_response::ApiResponse},
 };
 
 #[get("/join/<join_code>")]
-pub async fn html_join_class(join_code: String, user_id: AuthCookie, conn: Database) -> Html {
+pub async fn html_join_class(
+    join_code: String,
+    user_id: AuthCookie,
+    conn: Database,
+    queue: State<'_, ScheduleQueue>,
+) -> Html {
     use crate::schema::class::dsl as class;
     let class_id = match conn
         .run(|c| {
@@ -52,13 +59,16 @@ pub async fn html_join_class(join_code: String, user_id: AuthCookie, conn: Datab
         })
         .await
     {
-        Ok(_) => Html::default()
-            .head(default_head("Joined".to_string()))
-            .body(
-                Body::default()
-                    .child(H1::new("Class joined!"))
-                    .child(P::with_text("You have sucessfully joined this class.")),
-            ),
+        Ok(_) => {
+            queue.enqueue(user_id.0);
+            Html::default()
+                .head(default_head("Joined".to_string()))
+                .body(
+                    Body::default()
+                        .child(H1::new("Class joined!"))
+                        .child(P::with_text("You have sucessfully joined this class.")),
+                )
+        }
         Err(_) => error_message(
             "Internal server error".to_string(),
             "Something's up with our database – fear not, we're fixing it.".to_string(),
@@ -71,6 +81,7 @@ pub async fn api_join_class(
     join_code: String,
     user_id: AuthCookie,
     conn: Database,
+    queue: State<'_, ScheduleQueue>,
 ) -> Json<ApiResponse<crate::models::Class>> {
     use crate::schema::class::dsl as class;
     let class_instance = match conn
@@ -102,7 +113,10 @@ pub async fn api_join_class(
             })
             .await
         {
-            Ok(_) => ApiResponse::new_ok(class_instance),
+            Ok(_) => {
+                queue.enqueue(user_id.0);
+                ApiResponse::new_ok(class_instance)
+            }
             Err(_) => LovelaceError::DatabaseError.into(),
         },
     )
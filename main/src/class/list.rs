@@ -101,8 +101,8 @@ pub struct ViewAllClasses {
 
 #[get("/class")]
 pub async fn api_view_all_classes(
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Json<ApiResponse<ViewAllClasses>> {
     Json(match view_all_classes(auth, conn).await {
         Ok((student, teacher)) => ApiResponse::new_ok(ViewAllClasses { teacher, student }),
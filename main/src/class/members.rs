@@ -14,8 +14,8 @@ use rocket_contrib::json::Json;
 #[get("/class/<id>/members")]
 pub async fn html_view_class_members_page(
     id: usize,
-    conn: Database,
     auth_cookie: AuthCookie,
+    conn: Database,
 ) -> Html {
     use crate::schema::class::dsl as class;
     use crate::schema::class_student::dsl as class_student;
@@ -77,8 +77,8 @@ pub struct ViewClassMembers {
 #[get("/class/<id>/members")]
 pub async fn api_view_class_members_page(
     id: usize,
-    conn: Database,
     auth_cookie: AuthCookie,
+    conn: Database,
 ) -> Json<ApiResponse<ViewClassMembers>> {
     use crate::schema::class::dsl as class;
     use crate::schema::class_student::dsl as class_student;
@@ -44,8 +44,8 @@ fn edit_message_form(msg: &ClassMessage) -> Form {
 pub async fn edit_message_page(
     _class_id: i32,
     message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Html {
     use crate::schema::class_message::dsl as class_message;
     match conn
@@ -126,8 +126,8 @@ pub struct EditMessageForm {
 pub async fn api_apply_message_edit(
     _class_id: i32,
     message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: rocket::form::Form<EditMessageForm>,
 ) -> Json<ApiResponse<ClassMessage>> {
     Json(
@@ -144,8 +144,8 @@ pub async fn api_apply_message_edit(
 pub async fn html_apply_message_edit(
     class_id: i32,
     message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: rocket::form::Form<EditMessageForm>,
 ) -> HtmlOrRedirect {
     match apply_message_edit_base(message_id, conn, auth, &form).await {
@@ -45,8 +45,8 @@ pub async fn edit_message_reply(
     class_id: i32,
     message_reply_id: i32,
     _message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Html {
     use crate::schema::class_message_reply::dsl as class_message_reply;
     match conn
@@ -116,8 +116,8 @@ pub async fn html_apply_message_reply_edit(
     class_id: i32,
     message_reply_id: i32,
     message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: rocket::form::Form<ApplyMessageReplyEditForm>,
 ) -> HtmlOrRedirect {
     match apply_message_reply_edit_base(class_id, message_reply_id, conn, auth, &form).await {
@@ -137,8 +137,8 @@ pub async fn api_apply_message_reply_edit(
     class_id: i32,
     message_reply_id: i32,
     _message_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: Json<ApplyMessageReplyEditForm>,
 ) -> Json<ApiResponse<ClassMessageReply>> {
     Json(
@@ -61,7 +61,7 @@ async fn list_all_messages_base(
 
 #[get("/<id>/message")]
 
-pub async fn html_list_all_messages(id: i32, conn: Database, auth: AuthCookie) -> Html {
+pub async fn html_list_all_messages(id: i32, auth: AuthCookie, conn: Database) -> Html {
     match list_all_messages_base(id, conn, auth).await {
         Ok((class, messages)) => Html::default()
             .head(default_head(format!("Messages in class {}", class.name)))
@@ -104,8 +104,8 @@ pub struct ListAllClassMessages {
 #[get("/<id>/message")]
 pub async fn api_list_all_messages(
     id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Json<ApiResponse<ListAllClassMessages>> {
     Json(match list_all_messages_base(id, conn, auth).await {
         Ok((class, messages)) => ApiResponse::new_ok(ListAllClassMessages {
@@ -323,6 +323,18 @@ mod class_tests {
             .unwrap()
             .run(move |c| add_message_reply(message_id_1, student_id, class_id, c))
             .await;
+        let edit_page = client
+            .get(format!(
+                "/class/{}/message/{}/reply/{}/edit",
+                class_id, message_id_1, message_reply_id
+            ))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .expect("invalid body response");
+        assert!(edit_page.contains("Edit your reply"));
+        assert!(edit_page.contains(CLASS_MESSAGE_REPLY_ORIGINAL_CONTENTS));
         let edit_message_res = client
             .post(format!(
                 "/class/{}/message/{}/reply/{}/edit",
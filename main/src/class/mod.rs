@@ -86,7 +86,10 @@ mod test_class_routes {
     use regex::Regex;
     use rocket::http::ContentType;
 
-    use crate::utils::{create_user, login_user, logout};
+    use crate::{
+        calendar::scheduler::jobs::RescheduledUsers,
+        utils::{create_user, login_user, logout},
+    };
 
     const TIMEZONE: &str = "Africa/Abidjan";
     const TEACHER_USERNAME: &str = "some_teacher";
@@ -215,6 +218,17 @@ mod test_class_routes {
         let valid_join_attempt = client.get(format!("/join/{}", join_code)).dispatch().await;
         let string = valid_join_attempt.into_string().await.unwrap();
         assert!(string.contains("joined this class"));
+        // the student's schedule should be updated to include the work for this class
+        assert_eq!(
+            client
+                .rocket()
+                .state::<RescheduledUsers>()
+                .unwrap()
+                .drain()
+                .await
+                .len(),
+            1
+        );
 
         // test joined classes show up on student class list
 
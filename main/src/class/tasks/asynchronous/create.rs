@@ -1,21 +1,23 @@
-use crate::calendar::scheduler::schedule_class;
+use crate::calendar::scheduler::ScheduleQueue;
 use crate::class::user_is_teacher;
 use crate::models::ClassAsynchronousTask;
 use crate::models::NewClassAsynchronousTask;
 use crate::models::NewStudentClassAsynchronousTask;
+use crate::models::DEFAULT_ESTIMATED_DURATION;
 use crate::utils::default_head;
 use crate::utils::error_messages::database_error;
 use crate::utils::error_messages::invalid_date;
+use crate::utils::error_messages::invalid_duration;
 use crate::utils::permission_error::permission_error;
 use crate::{auth::AuthCookie, db::Database};
 use crate::{class::get_user_role_in_class, utils::json_response::ApiResponse};
 
-use chrono::Duration;
-use chrono::{NaiveDateTime, Utc};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use malvolio::prelude::*;
 use mercutio::Apply;
 use portia::form::FormStyle;
+use rocket::State;
 use rocket_contrib::json::Json;
 use thiserror::Error as ThisError;
 
@@ -38,6 +40,14 @@ fn create_new_async_task_form() -> Form {
                 .attribute(Name::new("due_date"))
                 .attribute(Type::Text),
         )
+        .child(
+            Input::new()
+                .attribute(Name::new("estimated_duration"))
+                .attribute(Placeholder::new(
+                    "How long (in minutes) you expect this task to take.",
+                ))
+                .attribute(Type::Number),
+        )
         .child(Input::new().attribute(Type::Submit))
 }
 
@@ -49,6 +59,9 @@ pub struct CreateNewAsyncTask {
     title: String,
     description: String,
     due_date: String,
+    /// The number of minutes the task is expected to take. If this is not supplied then
+    /// `DEFAULT_ESTIMATED_DURATION` is used.
+    estimated_duration: Option<i32>,
 }
 
 #[get("/<class_id>/task/async/create")]
@@ -75,6 +88,8 @@ pub enum CreateAsyncTaskError {
     PermissionError,
     #[error("invalid date")]
     InvalidDate,
+    #[error("invalid duration")]
+    InvalidDuration,
 }
 
 async fn new_async_task(
@@ -82,6 +97,7 @@ async fn new_async_task(
     class_id: i32,
     auth: AuthCookie,
     form: &CreateNewAsyncTask,
+    queue: &ScheduleQueue,
 ) -> Result<ClassAsynchronousTask, CreateAsyncTaskError> {
     use crate::schema::class_teacher::dsl as class_teacher;
     match get_user_role_in_class(auth.0, class_id, &conn).await {
@@ -94,6 +110,12 @@ async fn new_async_task(
         Ok(date) => date,
         Err(_) => return Err(CreateAsyncTaskError::InvalidDate),
     };
+    let estimated_duration = form
+        .estimated_duration
+        .unwrap_or(DEFAULT_ESTIMATED_DURATION);
+    if estimated_duration <= 0 {
+        return Err(CreateAsyncTaskError::InvalidDuration);
+    }
     let title = form.title.clone();
     let description = form.description.clone();
     match conn
@@ -110,6 +132,7 @@ async fn new_async_task(
                         .first::<i32>(c)
                         .unwrap(),
                     class_id,
+                    estimated_duration,
                 })
                 .returning(crate::schema::class_asynchronous_task::all_columns)
                 .get_result::<ClassAsynchronousTask>(c)
@@ -148,11 +171,7 @@ async fn new_async_task(
                 .await
             {
                 Ok(_) => {
-                    if due_date < Utc::now().naive_utc() + Duration::days(14) {
-                        rocket::tokio::spawn(async move {
-                            let _ = schedule_class(class_id, &conn).await;
-                        });
-                    }
+                    queue.enqueue_class(class_id, &conn).await;
                     Ok(async_task)
                 }
                 Err(e) => {
@@ -170,12 +189,13 @@ async fn new_async_task(
 
 #[post("/<class_id>/task/async/create", data = "<form>")]
 pub async fn html_create_new_async_task(
+    auth: AuthCookie,
     conn: Database,
     class_id: i32,
-    auth: AuthCookie,
     form: rocket::form::Form<CreateNewAsyncTask>,
+    queue: State<'_, ScheduleQueue>,
 ) -> Html {
-    match new_async_task(conn, class_id, auth, &form).await {
+    match new_async_task(conn, class_id, auth, &form, &queue).await {
         Ok(_) => Html::new()
             .head(default_head("Created that task".to_string()))
             .body(
@@ -189,27 +209,38 @@ pub async fn html_create_new_async_task(
             CreateAsyncTaskError::InvalidDate => {
                 return invalid_date(Some(create_new_async_task_form()))
             }
+            CreateAsyncTaskError::InvalidDuration => {
+                return invalid_duration(Some(create_new_async_task_form()))
+            }
         },
     }
 }
 
 #[post("/<class_id>/task/async/create", data = "<form>")]
 pub async fn api_create_new_async_task(
+    auth: AuthCookie,
     conn: Database,
     class_id: i32,
-    auth: AuthCookie,
     form: Json<CreateNewAsyncTask>,
+    queue: State<'_, ScheduleQueue>,
 ) -> Json<ApiResponse<ClassAsynchronousTask>> {
-    Json(match new_async_task(conn, class_id, auth, &form).await {
-        Ok(task) => ApiResponse::new_ok(task),
-        Err(e) => ApiResponse::new_err(match e {
-            CreateAsyncTaskError::DatabaseError => {
-                "Encountered a database error when trying to fulfill this operation."
-            }
-            CreateAsyncTaskError::PermissionError => {
-                "You don't have permissions to create tasks in this class."
-            }
-            CreateAsyncTaskError::InvalidDate => "The date you provided is not in a valid format.",
-        }),
-    })
+    Json(
+        match new_async_task(conn, class_id, auth, &form, &queue).await {
+            Ok(task) => ApiResponse::new_ok(task),
+            Err(e) => ApiResponse::new_err(match e {
+                CreateAsyncTaskError::DatabaseError => {
+                    "Encountered a database error when trying to fulfill this operation."
+                }
+                CreateAsyncTaskError::PermissionError => {
+                    "You don't have permissions to create tasks in this class."
+                }
+                CreateAsyncTaskError::InvalidDate => {
+                    "The date you provided is not in a valid format."
+                }
+                CreateAsyncTaskError::InvalidDuration => {
+                    "The estimated duration must be a positive number of minutes."
+                }
+            }),
+        },
+    )
 }
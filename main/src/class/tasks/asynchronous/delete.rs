@@ -1,10 +1,12 @@
 use diesel::prelude::*;
 use malvolio::prelude::*;
+use rocket::State;
 use rocket_contrib::json::Json;
 use thiserror::Error as ThisError;
 
 use crate::{
     auth::AuthCookie,
+    calendar::scheduler::ScheduleQueue,
     class::{get_user_role_in_class, ClassMemberRole},
     db::Database,
     utils::{default_head, error_messages::database_error, json_response::ApiResponse},
@@ -23,6 +25,7 @@ async fn delete_task(
     task_id: i32,
     auth: AuthCookie,
     conn: Database,
+    queue: &ScheduleQueue,
 ) -> Result<(), DeleteTaskError> {
     use crate::schema::class_asynchronous_task::dsl as class_asynchronous_task;
     if let Some(role) = get_user_role_in_class(auth.0, class_id, &conn).await {
@@ -44,8 +47,9 @@ async fn delete_task(
     .map_err(|e| {
         error!("{:#?}", e);
         DeleteTaskError::DatabaseError
-    })
-    .map(drop)
+    })?;
+    queue.enqueue_class(class_id, &conn).await;
+    Ok(())
 }
 
 #[get("/<class_id>/task/async/<task_id>/delete")]
@@ -54,8 +58,9 @@ pub async fn html_delete_task(
     task_id: i32,
     auth: AuthCookie,
     conn: Database,
+    queue: State<'_, ScheduleQueue>,
 ) -> Html {
-    match delete_task(class_id, task_id, auth, conn).await {
+    match delete_task(class_id, task_id, auth, conn, &queue).await {
         Ok(_) => Html::new()
             .head(default_head("Successfully deleted that task".to_string()))
             .body(Body::new().child(H1::new("Successfully deleted that task."))),
@@ -69,9 +74,12 @@ pub async fn api_delete_task(
     task_id: i32,
     auth: AuthCookie,
     conn: Database,
+    queue: State<'_, ScheduleQueue>,
 ) -> Json<ApiResponse<()>> {
-    Json(match delete_task(class_id, task_id, auth, conn).await {
-        Ok(_) => ApiResponse::new_ok(()),
-        Err(_) => ApiResponse::new_err("database error"),
-    })
+    Json(
+        match delete_task(class_id, task_id, auth, conn, &queue).await {
+            Ok(_) => ApiResponse::new_ok(()),
+            Err(_) => ApiResponse::new_err("database error"),
+        },
+    )
 }
@@ -1,11 +1,12 @@
 use crate::{
+    calendar::scheduler::ScheduleQueue,
     catch_database_error,
     class::get_user_role_in_class,
     class::ClassMemberRole,
     models::{ClassAsynchronousTask, UpdateClassAsynchronousTask},
     utils::{
         default_head,
-        error_messages::{database_error, invalid_date},
+        error_messages::{database_error, invalid_date, invalid_duration},
         json_response::ApiResponse,
         permission_error::permission_error,
     },
@@ -15,6 +16,7 @@ use diesel::prelude::*;
 use malvolio::prelude::*;
 use mercutio::Apply;
 use portia::form::{FormStyle, FormSubmitInputStyle, FormTextInputStyle};
+use rocket::State;
 use rocket_contrib::json::Json;
 use thiserror::Error as ThisError;
 
@@ -24,6 +26,7 @@ fn edit_task_form(
     title: Option<String>,
     description: Option<String>,
     due_date: Option<String>,
+    estimated_duration: Option<i32>,
 ) -> Form {
     Form::new()
         .apply(FormStyle)
@@ -66,6 +69,19 @@ fn edit_task_form(
                 })
                 .attribute(Name::new("due_date")),
         )
+        .child(
+            Input::new()
+                .attribute(Type::Number)
+                .apply(FormTextInputStyle)
+                .map(|item| {
+                    if let Some(estimated_duration) = estimated_duration {
+                        item.attribute(Value::new(estimated_duration.to_string()))
+                    } else {
+                        item
+                    }
+                })
+                .attribute(Name::new("estimated_duration")),
+        )
 }
 
 #[get("/<class_id>/task/async/<task_id>/edit")]
@@ -95,6 +111,7 @@ pub async fn view_edit_task_page(
                         Some(res.title),
                         Some(res.description),
                         Some(res.due_date.format("%Y-%m-%dT%H:%M").to_string()),
+                        Some(res.estimated_duration),
                     )),
             )
     } else {
@@ -107,6 +124,7 @@ pub struct EditTaskForm {
     title: String,
     description: String,
     due_date: String,
+    estimated_duration: Option<i32>,
 }
 
 #[derive(ThisError, Debug)]
@@ -117,6 +135,8 @@ pub enum EditTaskError {
     PermissionError,
     #[error("invalid date")]
     InvalidDate,
+    #[error("invalid duration")]
+    InvalidDuration,
 }
 
 pub async fn apply_edit_task(
@@ -125,36 +145,45 @@ pub async fn apply_edit_task(
     auth: AuthCookie,
     conn: Database,
     form: &EditTaskForm,
+    queue: &ScheduleQueue,
 ) -> Result<ClassAsynchronousTask, EditTaskError> {
     use crate::schema::class_asynchronous_task::dsl as class_asynchronous_task;
     let due_date = NaiveDateTime::parse_from_str(&form.due_date, "%Y-%m-%dT%H:%M")
         .map_err(|_| EditTaskError::InvalidDate)?;
+    if form.estimated_duration.map(|d| d <= 0).unwrap_or(false) {
+        return Err(EditTaskError::InvalidDuration);
+    }
     if let Some(role) = get_user_role_in_class(auth.0, class_id, &conn).await {
         if role != ClassMemberRole::Teacher {
             return Err(EditTaskError::DatabaseError);
         }
         let title = Some(form.title.clone());
         let description = Some(form.description.clone());
-        conn.run(move |c| {
-            diesel::update(
-                class_asynchronous_task::class_asynchronous_task
-                    .filter(class_asynchronous_task::id.eq(task_id))
-                    .filter(class_asynchronous_task::class_id.eq(class_id)),
-            )
-            .set(UpdateClassAsynchronousTask {
-                title,
-                description,
-                due_date: Some(due_date),
-                ..Default::default()
+        let estimated_duration = form.estimated_duration;
+        let task = conn
+            .run(move |c| {
+                diesel::update(
+                    class_asynchronous_task::class_asynchronous_task
+                        .filter(class_asynchronous_task::id.eq(task_id))
+                        .filter(class_asynchronous_task::class_id.eq(class_id)),
+                )
+                .set(UpdateClassAsynchronousTask {
+                    title,
+                    description,
+                    due_date: Some(due_date),
+                    estimated_duration,
+                    ..Default::default()
+                })
+                .returning(crate::schema::class_asynchronous_task::all_columns)
+                .get_result(c)
             })
-            .returning(crate::schema::class_asynchronous_task::all_columns)
-            .get_result(c)
-        })
-        .await
-        .map_err(|e| {
-            error!("{:#?}", e);
-            EditTaskError::DatabaseError
-        })
+            .await
+            .map_err(|e| {
+                error!("{:#?}", e);
+                EditTaskError::DatabaseError
+            })?;
+        queue.enqueue_class(class_id, &conn).await;
+        Ok(task)
     } else {
         Err(EditTaskError::PermissionError)
     }
@@ -167,8 +196,9 @@ pub async fn html_apply_edit_task(
     auth: AuthCookie,
     conn: Database,
     form: rocket::form::Form<EditTaskForm>,
+    queue: State<'_, ScheduleQueue>,
 ) -> Html {
-    match apply_edit_task(class_id, task_id, auth, conn, &form).await {
+    match apply_edit_task(class_id, task_id, auth, conn, &form, &queue).await {
         Ok(_) => Html::new()
             .head(default_head("Successfully updated".to_string()))
             .body(Body::new().child(H1::new("Successfully updated that task."))),
@@ -179,6 +209,13 @@ pub async fn html_apply_edit_task(
                 Some(form.title.clone()),
                 Some(form.description.clone()),
                 Some(form.due_date.clone()),
+                form.estimated_duration,
+            ))),
+            EditTaskError::InvalidDuration => invalid_duration(Some(edit_task_form(
+                Some(form.title.clone()),
+                Some(form.description.clone()),
+                Some(form.due_date.clone()),
+                form.estimated_duration,
             ))),
         },
     }
@@ -191,14 +228,16 @@ pub async fn api_apply_edit_task(
     auth: AuthCookie,
     conn: Database,
     form: Json<EditTaskForm>,
+    queue: State<'_, ScheduleQueue>,
 ) -> Json<ApiResponse<ClassAsynchronousTask>> {
     Json(
-        match apply_edit_task(class_id, task_id, auth, conn, &form).await {
+        match apply_edit_task(class_id, task_id, auth, conn, &form, &queue).await {
             Ok(task) => ApiResponse::new_ok(task),
             Err(e) => ApiResponse::new_err(match e {
                 EditTaskError::DatabaseError => "database error",
                 EditTaskError::PermissionError => "permission error",
                 EditTaskError::InvalidDate => "invalid date",
+                EditTaskError::InvalidDuration => "invalid duration",
             }),
         },
     )
@@ -0,0 +1,167 @@
+//! Lets students override the teacher's estimate of how long a task will take (this is the
+//! estimate that the scheduler uses when it places the task into their calendar).
+
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use rocket::State;
+use rocket_contrib::json::Json;
+use thiserror::Error as ThisError;
+
+use crate::{
+    auth::AuthCookie,
+    calendar::scheduler::ScheduleQueue,
+    class::{get_user_role_in_class, ClassMemberRole},
+    db::Database,
+    models::StudentClassAsynchronousTask,
+    utils::{
+        default_head,
+        error_messages::{database_error, invalid_duration},
+        json_response::ApiResponse,
+        permission_error::permission_error,
+    },
+};
+
+/// The form which students use to supply their own estimate of how long a task will take.
+pub fn estimate_form(class_id: i32, task_id: i32, estimated_duration: Option<i32>) -> Form {
+    Form::new()
+        .attribute(Method::Post)
+        .attribute(Action::new(format!(
+            "/class/{}/task/async/{}/estimate",
+            class_id, task_id
+        )))
+        .child(
+            Input::new()
+                .attribute(Type::Number)
+                .attribute(Name::new("estimated_duration"))
+                .attribute(Placeholder::new(
+                    "How long (in minutes) you think this task will take you.",
+                ))
+                .map(|item| {
+                    if let Some(estimated_duration) = estimated_duration {
+                        item.attribute(Value::new(estimated_duration.to_string()))
+                    } else {
+                        item
+                    }
+                }),
+        )
+        .child(
+            Input::new()
+                .attribute(Type::Submit)
+                .attribute(Value::new("Update estimate")),
+        )
+}
+
+#[derive(FromForm, Debug, Clone, Serialize, Deserialize)]
+pub struct EstimateForm {
+    /// The student's estimate (in minutes). If this is not supplied then the student's estimate is
+    /// cleared, and the teacher's estimate is used in its place.
+    estimated_duration: Option<i32>,
+}
+
+#[derive(ThisError, Debug)]
+pub enum EstimateError {
+    #[error("database error")]
+    DatabaseError,
+    #[error("permission error")]
+    PermissionError,
+    #[error("invalid duration")]
+    InvalidDuration,
+}
+
+async fn set_estimate(
+    class_id: i32,
+    task_id: i32,
+    auth: AuthCookie,
+    conn: &Database,
+    form: &EstimateForm,
+    queue: &ScheduleQueue,
+) -> Result<StudentClassAsynchronousTask, EstimateError> {
+    use crate::schema::class_student::dsl as class_student;
+    use crate::schema::student_class_asynchronous_task::dsl as student_class_asynchronous_task;
+    if form.estimated_duration.map(|d| d <= 0).unwrap_or(false) {
+        return Err(EstimateError::InvalidDuration);
+    }
+    match get_user_role_in_class(auth.0, class_id, conn).await {
+        Some(ClassMemberRole::Student) => {}
+        None | Some(ClassMemberRole::Teacher) => return Err(EstimateError::PermissionError),
+    }
+    let estimated_duration = form.estimated_duration;
+    let estimate = conn
+        .run(move |c| {
+            let class_student_id = class_student::class_student
+                .filter(class_student::user_id.eq(auth.0))
+                .filter(class_student::class_id.eq(class_id))
+                .select(class_student::id)
+                .first::<i32>(c)?;
+            diesel::update(
+                student_class_asynchronous_task::student_class_asynchronous_task
+                    .filter(student_class_asynchronous_task::class_student_id.eq(class_student_id))
+                    .filter(
+                        student_class_asynchronous_task::class_asynchronous_task_id.eq(task_id),
+                    ),
+            )
+            .set(student_class_asynchronous_task::estimated_duration.eq(estimated_duration))
+            .returning(crate::schema::student_class_asynchronous_task::all_columns)
+            .get_result::<StudentClassAsynchronousTask>(c)
+        })
+        .await
+        .map_err(|e| {
+            error!("{:#?}", e);
+            EstimateError::DatabaseError
+        })?;
+    queue.enqueue(auth.0);
+    Ok(estimate)
+}
+
+#[post("/<class_id>/task/async/<task_id>/estimate", data = "<form>")]
+pub async fn html_set_estimate(
+    class_id: i32,
+    task_id: i32,
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<EstimateForm>,
+    queue: State<'_, ScheduleQueue>,
+) -> Html {
+    match set_estimate(class_id, task_id, auth, &conn, &form, &queue).await {
+        Ok(_) => Html::new()
+            .head(default_head("Updated your estimate"))
+            .body(
+                Body::new()
+                    .child(H1::new("Updated your estimate"))
+                    .child(P::with_text(
+                        "We'll use your estimate when we next schedule this task into your \
+                        calendar.",
+                    )),
+            ),
+        Err(e) => match e {
+            EstimateError::DatabaseError => database_error(),
+            EstimateError::PermissionError => permission_error(),
+            EstimateError::InvalidDuration => invalid_duration(Some(estimate_form(
+                class_id,
+                task_id,
+                form.estimated_duration,
+            ))),
+        },
+    }
+}
+
+#[post("/<class_id>/task/async/<task_id>/estimate", data = "<form>")]
+pub async fn api_set_estimate(
+    class_id: i32,
+    task_id: i32,
+    auth: AuthCookie,
+    conn: Database,
+    form: Json<EstimateForm>,
+    queue: State<'_, ScheduleQueue>,
+) -> Json<ApiResponse<StudentClassAsynchronousTask>> {
+    Json(
+        match set_estimate(class_id, task_id, auth, &conn, &form, &queue).await {
+            Ok(task) => ApiResponse::new_ok(task),
+            Err(e) => ApiResponse::new_err(match e {
+                EstimateError::DatabaseError => "database error",
+                EstimateError::PermissionError => "permission error",
+                EstimateError::InvalidDuration => "invalid duration",
+            }),
+        },
+    )
+}
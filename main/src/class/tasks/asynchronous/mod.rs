@@ -7,6 +7,7 @@ A copy of this license can be found in the `licenses` directory at the root of t
 mod create;
 mod delete;
 mod edit;
+mod estimate;
 mod summary;
 mod view;
 
@@ -15,6 +16,7 @@ pub use create::{
 };
 pub use delete::{api_delete_task, html_delete_task};
 pub use edit::{api_apply_edit_task, html_apply_edit_task, view_edit_task_page};
+pub use estimate::{api_set_estimate, html_set_estimate};
 pub use summary::{api_view_all_async_tasks_in_class, html_view_all_async_tasks_in_class};
 pub use view::{api_view_specific_asynchronous_task, html_view_specific_asynchronous_task};
 
@@ -23,10 +25,12 @@ mod async_task_tests {
     use std::ops::Add;
 
     use crate::{
+        calendar::scheduler::jobs::RescheduledUsers,
         db::{Database, DatabaseConnection},
         models::{
             ClassAsynchronousTask, NewClassAsynchronousTask, NewClassStudent, NewClassTeacher,
             NewStudentClassAsynchronousTask, StudentClassAsynchronousTask,
+            DEFAULT_ESTIMATED_DURATION,
         },
         utils::{client, login_user},
     };
@@ -141,6 +145,7 @@ mod async_task_tests {
                     .naive_utc(),
                 class_teacher_id,
                 class_id,
+                estimated_duration: DEFAULT_ESTIMATED_DURATION,
             })
             .returning(crate::schema::class_asynchronous_task::id)
             .get_result::<i32>(conn)
@@ -171,6 +176,7 @@ mod async_task_tests {
                     .naive_utc(),
                 class_teacher_id,
                 class_id,
+                estimated_duration: DEFAULT_ESTIMATED_DURATION,
             })
             .returning(crate::schema::class_asynchronous_task::id)
             .get_result::<i32>(conn)
@@ -361,11 +367,18 @@ mod async_task_tests {
             .await;
         let string = res.into_string().await.expect("invalid body response");
         assert!(!string.contains("updated that task"));
+        assert!(client
+            .rocket()
+            .state::<RescheduledUsers>()
+            .unwrap()
+            .drain()
+            .await
+            .is_empty());
     }
     #[rocket::async_test]
     async fn test_teacher_can_delete_asynchronous_task() {
         let client = client().await;
-        let (class_id, _, _, tasks) = Database::get_one(&client.rocket())
+        let (class_id, _, student_id, tasks) = Database::get_one(&client.rocket())
             .await
             .unwrap()
             .run(|c| populate_database(c))
@@ -380,5 +393,157 @@ mod async_task_tests {
             .await;
         let string = res.into_string().await.expect("invalid body response");
         assert!(string.contains("deleted that task"));
+        let enqueued = client
+            .rocket()
+            .state::<RescheduledUsers>()
+            .unwrap()
+            .drain()
+            .await;
+        assert_eq!(enqueued.len(), 2);
+        assert!(enqueued.contains(&student_id));
+    }
+    #[rocket::async_test]
+    async fn test_teacher_can_set_estimated_duration() {
+        const NEW_TASK_TITLE: &str = "new-task-title-with-estimate";
+        let client = client().await;
+        let (class_id, _, _, _) = Database::get_one(&client.rocket())
+            .await
+            .unwrap()
+            .run(|c| populate_database(c))
+            .await;
+        login_user(TEACHER_EMAIL, TEACHER_PASSWORD, &client).await;
+
+        let res = client
+            .post(format!("/class/{}/task/async/create", class_id))
+            .header(ContentType::Form)
+            .body(format!(
+                "title={}&description={}&due_date={}&estimated_duration=120",
+                NEW_TASK_TITLE,
+                TASK_1_DESCRIPTION,
+                (chrono::Utc::now() + chrono::Duration::days(7))
+                    .naive_utc()
+                    .format("%Y-%m-%dT%H:%M")
+                    .to_string(),
+            ))
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Created that task"));
+        let task = Database::get_one(&client.rocket())
+            .await
+            .unwrap()
+            .run(|c| {
+                use crate::schema::class_asynchronous_task::dsl as class_asynchronous_task;
+                class_asynchronous_task::class_asynchronous_task
+                    .filter(class_asynchronous_task::title.eq(NEW_TASK_TITLE))
+                    .first::<ClassAsynchronousTask>(c)
+            })
+            .await
+            .unwrap();
+        assert_eq!(task.estimated_duration, 120);
+
+        let res = client
+            .post(format!("/class/{}/task/async/create", class_id))
+            .header(ContentType::Form)
+            .body(format!(
+                "title={}&description={}&due_date={}&estimated_duration=-5",
+                NEW_TASK_TITLE,
+                TASK_1_DESCRIPTION,
+                (chrono::Utc::now() + chrono::Duration::days(7))
+                    .naive_utc()
+                    .format("%Y-%m-%dT%H:%M")
+                    .to_string(),
+            ))
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Invalid duration"));
+    }
+    /// Retrieves the student's copy of the provided task.
+    async fn student_task(
+        client: &rocket::local::asynchronous::Client,
+        student_id: i32,
+        task_id: i32,
+    ) -> StudentClassAsynchronousTask {
+        use crate::schema::class_student::dsl as class_student;
+        use crate::schema::student_class_asynchronous_task::dsl as student_class_asynchronous_task;
+        Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(move |c| {
+                student_class_asynchronous_task::student_class_asynchronous_task
+                    .inner_join(class_student::class_student)
+                    .filter(class_student::user_id.eq(student_id))
+                    .filter(student_class_asynchronous_task::class_asynchronous_task_id.eq(task_id))
+                    .select(crate::schema::student_class_asynchronous_task::all_columns)
+                    .first::<StudentClassAsynchronousTask>(c)
+            })
+            .await
+            .unwrap()
+    }
+    #[rocket::async_test]
+    async fn test_student_can_override_estimated_duration() {
+        let client = client().await;
+        let (class_id, _, student_id, tasks) = Database::get_one(&client.rocket())
+            .await
+            .unwrap()
+            .run(|c| populate_database(c))
+            .await;
+        login_user(TEACHER_USERNAME, TEACHER_PASSWORD, &client).await;
+        let res = client
+            .post(format!(
+                "/class/{}/task/async/{}/estimate",
+                class_id, tasks[1]
+            ))
+            .header(ContentType::Form)
+            .body("estimated_duration=90")
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(!string.contains("Updated your estimate"));
+
+        login_user(STUDENT_1_USERNAME, STUDENT_1_PASSWORD, &client).await;
+        let res = client
+            .post(format!(
+                "/class/{}/task/async/{}/estimate",
+                class_id, tasks[1]
+            ))
+            .header(ContentType::Form)
+            .body("estimated_duration=90")
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Updated your estimate"));
+        assert_eq!(
+            student_task(&client, student_id, tasks[1])
+                .await
+                .estimated_duration,
+            Some(90)
+        );
+
+        let res = client
+            .get(format!("/class/{}/task/async/{}/view", class_id, tasks[1]))
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("take you 90 minutes"));
+
+        let res = client
+            .post(format!(
+                "/class/{}/task/async/{}/estimate",
+                class_id, tasks[1]
+            ))
+            .header(ContentType::Form)
+            .body("")
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Updated your estimate"));
+        assert_eq!(
+            student_task(&client, student_id, tasks[1])
+                .await
+                .estimated_duration,
+            None
+        );
     }
 }
@@ -7,7 +7,7 @@ use crate::{
     utils::default_head,
 };
 
-use super::ViewAsyncTaskSummaryError;
+use super::{super::estimate::estimate_form, ViewAsyncTaskSummaryError};
 
 pub async fn get_student_async_task_summary(
     task_id: i32,
@@ -53,6 +53,15 @@ pub fn render_student_task_summary(
                 "You have not marked this task as done"
             } else {
                 "You have marked this task as done."
-            })),
+            }))
+            .child(P::with_text(format!(
+                "We expect this task to take you {} minutes.",
+                student_task.minutes_to_complete(&class_task)
+            )))
+            .child(estimate_form(
+                class_task.class_id,
+                class_task.id,
+                student_task.estimated_duration,
+            )),
     )
 }
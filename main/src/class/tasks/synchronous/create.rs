@@ -159,9 +159,9 @@ async fn create_new_sync_task(
 
 #[post("/<class_id>/task/sync/create", data = "<form>")]
 pub async fn html_create_new_sync_task(
+    auth: AuthCookie,
     conn: Database,
     class_id: i32,
-    auth: AuthCookie,
     form: rocket::form::Form<CreateNewSyncTask>,
 ) -> Html {
     match create_new_sync_task(conn, class_id, auth, &form).await {
@@ -183,9 +183,9 @@ pub async fn html_create_new_sync_task(
 
 #[post("/<class_id>/task/sync/create", data = "<form>")]
 pub async fn api_create_new_async_task(
+    auth: AuthCookie,
     conn: Database,
     class_id: i32,
-    auth: AuthCookie,
     form: Json<CreateNewSyncTask>,
 ) -> Json<ApiResponse<ClassSynchronousTask>> {
     Json(
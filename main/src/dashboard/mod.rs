@@ -280,7 +280,7 @@ impl Render<Div> for SyncTaskCard {
 }
 
 #[get("/")]
-pub async fn html_dashboard(conn: Database, auth: AuthCookie) -> Html {
+pub async fn html_dashboard(auth: AuthCookie, conn: Database) -> Html {
     match Dashboard::query(auth, conn).await {
         Ok(t) => t,
         Err(e) => {
@@ -292,7 +292,7 @@ pub async fn html_dashboard(conn: Database, auth: AuthCookie) -> Html {
 }
 
 #[get("/")]
-pub async fn api_dashboard(conn: Database, auth: AuthCookie) -> Json<ApiResponse<Dashboard>> {
+pub async fn api_dashboard(auth: AuthCookie, conn: Database) -> Json<ApiResponse<Dashboard>> {
     Json(match Dashboard::query(auth, conn).await {
         Ok(res) => ApiResponse::new_ok(res),
         Err(e) => {
@@ -317,7 +317,7 @@ mod test_dashboard {
         },
         models::{
             NewClass, NewClassAsynchronousTask, NewClassStudent, NewClassSynchronousTask,
-            NewClassTeacher, NewStudentClassAsynchronousTask,
+            NewClassTeacher, NewStudentClassAsynchronousTask, DEFAULT_ESTIMATED_DURATION,
         },
         schema::{
             class, class_asynchronous_task, class_student, class_synchronous_task, class_teacher,
@@ -390,6 +390,7 @@ mod test_dashboard {
                     due_date: Utc::now().add(Duration::days(5)).naive_utc(),
                     class_teacher_id,
                     class_id,
+                    estimated_duration: DEFAULT_ESTIMATED_DURATION,
                 })
                 .returning(class_asynchronous_task::id)
                 .get_result(c)
@@ -11,6 +11,8 @@ use diesel::r2d2::CustomizeConnection;
 use diesel::sql_types::HasSqlType;
 use diesel::{Connection, ConnectionResult, PgConnection, QueryResult, Queryable};
 use rocket::Rocket;
+#[cfg(not(test))]
+use rocket_contrib::databases::ConnectionPool;
 use rocket_contrib::databases::{diesel, Config, PoolResult, Poolable};
 
 embed_migrations!("../migrations/");
@@ -106,6 +108,26 @@ impl Poolable for TestPgConnection {
 #[database("postgres")]
 pub struct Database(TestPgConnection);
 
+/// A handle to the connection pool, for code which runs outside of a request (such as the
+/// scheduler's job runner) and should only hold a connection while it is using it.
+#[cfg(not(test))]
+#[derive(Clone)]
+pub struct DatabasePool(ConnectionPool<Database, DatabaseConnection>);
+
+#[cfg(not(test))]
+impl DatabasePool {
+    /// Returns the pool which is attached to the provided `Rocket` (this should be called after the
+    /// database fairing has been attached).
+    pub async fn from_rocket(rocket: &Rocket) -> Option<Self> {
+        ConnectionPool::get_pool(rocket).await.map(Self)
+    }
+
+    /// Takes a connection from the pool (waiting if all of them are in use).
+    pub async fn get(&self) -> Option<Database> {
+        self.0.get().await.map(Database)
+    }
+}
+
 pub async fn run_migrations(rocket: Rocket) -> Result<Rocket, Rocket> {
     let conn = Database::get_one(&rocket)
         .await
@@ -76,8 +76,8 @@ fn create_institution_class_form(student_groups: Vec<StudentGroup>) -> Form {
 
 #[get("/class/create")]
 pub async fn pick_which_institution_to_create_class_as_part_of(
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Html {
     let institutions = match conn
         .run(
@@ -295,8 +295,8 @@ async fn apply_create_institution_class(
 
 #[post("/<institution_id>/class/create", data = "<data>")]
 pub async fn html_create_institution_class(
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     data: rocket::form::Form<CreateClassForm>,
     institution_id: i32,
 ) -> HtmlOrRedirect {
@@ -308,8 +308,8 @@ pub async fn html_create_institution_class(
 
 #[post("/<institution_id>/class/create", data = "<data>")]
 pub async fn api_create_institution_class(
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     data: Json<CreateClassForm>,
     institution_id: i32,
 ) -> Json<ApiResponse<crate::models::Class>> {
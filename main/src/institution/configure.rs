@@ -21,6 +21,9 @@ pub struct ConfigureInstitutionForm {
     name: Option<String>,
     domain: Option<String>,
     enforce_same_domain: Option<bool>,
+    /// Whether everybody in the institution has to use two-factor authentication. People who
+    /// haven't set it up yet will have to the next time that they log in.
+    require_two_factor: Option<bool>,
 }
 
 async fn apply_configure_institution(
@@ -50,6 +53,7 @@ async fn apply_configure_institution(
     let name = data.name.clone();
     let domain = data.domain.clone();
     let enforce_same_domain = data.enforce_same_domain;
+    let require_two_factor = data.require_two_factor;
     let res = conn
         .run(move |c| {
             diesel::update(institution::table.filter(institution::id.eq(institution_id)))
@@ -58,6 +62,7 @@ async fn apply_configure_institution(
                     domain,
                     created: None,
                     enforce_same_domain,
+                    require_two_factor,
                 })
                 .returning(institution::all_columns)
                 .get_result::<Institution>(c)
@@ -70,11 +75,11 @@ async fn apply_configure_institution(
     Ok(res)
 }
 
-struct ConfigureInstitutionFormProducer(String, String, bool);
+struct ConfigureInstitutionFormProducer(String, String, bool, bool);
 
 impl FormProducer for ConfigureInstitutionFormProducer {
     fn produce(self) -> Form {
-        let Self(name, domain, enforce_same_domain) = self;
+        let Self(name, domain, enforce_same_domain, require_two_factor) = self;
         Form::new()
             .child(
                 Input::new()
@@ -97,14 +102,30 @@ impl FormProducer for ConfigureInstitutionFormProducer {
                     .attribute(Type::Checkbox)
                     .attribute(Value::new(enforce_same_domain.to_string())),
             )
+            .child(Label::new(
+                "Require everybody in the institution to use two-factor authentication",
+            ))
+            .child(
+                Input::new()
+                    .attribute(Name::new("require_two_factor"))
+                    .attribute(Type::Checkbox)
+                    .attribute(Value::new("true"))
+                    .map(|input| {
+                        if require_two_factor {
+                            input.attribute(Checked)
+                        } else {
+                            input
+                        }
+                    }),
+            )
     }
 }
 
 #[get("/<institution_id>/configure")]
 pub async fn configure_institution_page(
     institution_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
 ) -> Html {
     let institution = match conn
         .run(move |c| {
@@ -131,8 +152,14 @@ pub async fn configure_institution_page(
                         institution.name,
                         institution.domain,
                         institution.enforce_same_domain,
+                        institution.require_two_factor,
                     )
                     .produce(),
+                )
+                .child(
+                    A::new()
+                        .href(format!("/institution/{}/sso", institution_id))
+                        .text("Set up single sign-on"),
                 ),
         )
 }
@@ -150,6 +177,13 @@ impl Render<Div> for Institution {
                 does not belong to your institution's domain may join (given that they have an \
                 invite)."
             }))
+            .child(P::with_text(if self.require_two_factor {
+                "Two-factor authentication: required. Everybody in your institution has to set up \
+                two-factor authentication before they can log in."
+            } else {
+                "Two-factor authentication: optional. People in your institution can choose \
+                whether to use two-factor authentication."
+            }))
             .into_div()
     }
 }
@@ -157,8 +191,8 @@ impl Render<Div> for Institution {
 #[post("/<institution_id>/configure", data = "<form>")]
 pub async fn html_configure_institution(
     institution_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: rocket::form::Form<ConfigureInstitutionForm>,
 ) -> Html {
     match apply_configure_institution(conn, &form, auth, institution_id).await {
@@ -178,6 +212,7 @@ pub async fn html_configure_institution(
                 form.name.clone().unwrap_or_else(|| "".to_string()),
                 form.domain.clone().unwrap_or_else(|| "".to_string()),
                 form.enforce_same_domain.unwrap_or(false),
+                form.require_two_factor.unwrap_or(false),
             ),
         )
         .render(),
@@ -187,8 +222,8 @@ pub async fn html_configure_institution(
 #[post("/<institution_id>/configure", data = "<form>")]
 pub async fn api_configure_institution(
     institution_id: i32,
-    conn: Database,
     auth: AuthCookie,
+    conn: Database,
     form: Json<ConfigureInstitutionForm>,
 ) -> Json<ApiResponse<Institution>> {
     Json(
@@ -208,10 +243,13 @@ mod test_configure_institution {
 
     use crate::{
         db::Database,
-        institution::test_ctx::{ADMIN_EMAIL, ADMIN_PASSWORD, NAME, TIMEZONE, WEBSITE},
+        institution::test_ctx::{
+            ADMIN_EMAIL, ADMIN_PASSWORD, NAME, STUDENT_PASSWORD, STUDENT_USERNAME, TIMEZONE,
+            WEBSITE,
+        },
         models::{institution::Institution, NewUser},
         schema::{institution, users},
-        utils::{client, login_user},
+        utils::{client, login_user, logout},
     };
 
     use super::super::test_ctx::setup_env;
@@ -299,4 +337,49 @@ mod test_configure_institution {
         assert_eq!(institution.enforce_same_domain, false);
         assert_eq!(institution.name, NAME);
     }
+
+    #[rocket::async_test]
+    async fn test_admin_can_require_two_factor() {
+        let client = client().await;
+        let (_, _, _, institution_id, _) = Database::get_one(client.rocket())
+            .await
+            .unwrap()
+            .run(|c| setup_env(c))
+            .await;
+        login_user(ADMIN_EMAIL, ADMIN_PASSWORD, &client).await;
+        let res = client
+            .post(format!("/institution/{}/configure", institution_id))
+            .header(ContentType::Form)
+            .body("require_two_factor=true")
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("Two-factor authentication: required"));
+        // the setting is ticked when the admin comes back to the form
+        let res = client
+            .get(format!("/institution/{}/configure", institution_id))
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("checked=\"checked\""));
+        logout(&client).await;
+
+        // students have to set up two-factor authentication before they can log in
+        let res = client
+            .post("/auth/login")
+            .header(ContentType::Form)
+            .body(format!(
+                "identifier={}&password={}",
+                STUDENT_USERNAME, STUDENT_PASSWORD
+            ))
+            .dispatch()
+            .await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("need to set it up before you can log in"));
+        let res = client.get("/api/class").dispatch().await;
+        assert_ne!(res.status(), rocket::http::Status::Ok);
+        let res = client.post("/api/auth/two_factor/enrol").dispatch().await;
+        let string = res.into_string().await.expect("invalid body response");
+        assert!(string.contains("otpauth://totp/Lovelace:student-username"));
+    }
 }
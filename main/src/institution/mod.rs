@@ -2,6 +2,7 @@ pub mod class;
 pub mod configure;
 pub mod delete;
 pub mod register;
+pub mod sso;
 
 #[cfg(test)]
 pub mod test_ctx;
@@ -0,0 +1,391 @@
+//! Lets administrators set up single sign-on (with an OpenID Connect provider) for their
+//! institution. The login itself happens in `auth::sso`.
+//!
+//! Whoever controls the provider can log in as anybody with an email address at the institution's
+//! domain, so administrators can only set up single sign-on for the domain of their own (verified)
+//! email address, and only one institution can use each domain.
+
+use crate::utils::form::FormProducer;
+use diesel::prelude::*;
+use malvolio::prelude::*;
+use portia::{levels::Level, render::Render};
+use rocket::FromForm;
+use rocket_contrib::json::Json;
+use thiserror::Error as ThisError;
+
+use crate::{
+    auth::AuthCookie,
+    db::{Database, DatabaseConnection},
+    models::{
+        institution::oidc::{InstitutionOidc, NewInstitutionOidc},
+        User,
+    },
+    schema::{administrator, institution, institution_oidc, users},
+    utils::{
+        default_head,
+        error::{LovelaceError, LovelaceResult},
+        error_messages::database_error,
+        json_response::ApiResponse,
+    },
+};
+
+#[derive(FromForm, Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigureSsoForm {
+    /// The issuer URL of the provider.
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    /// The claim which contains people's roles (e.g. `roles` or `groups`).
+    role_claim: String,
+    /// The value of the role claim which teachers have.
+    teacher_value: String,
+    /// The value of the role claim which students have.
+    student_value: String,
+}
+
+#[derive(ThisError, Debug)]
+pub enum ConfigureSsoError {
+    #[error("permission error")]
+    PermissionError,
+    #[error("the administrator's email address doesn't belong to the institution's domain")]
+    UnverifiedDomain,
+    #[error("another institution already uses this domain for single sign-on")]
+    DomainTaken,
+    #[error("database error")]
+    DatabaseError,
+}
+
+impl From<diesel::result::Error> for ConfigureSsoError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("{:#?}", e);
+        Self::DatabaseError
+    }
+}
+
+impl ConfigureSsoError {
+    fn reason(&self) -> &'static str {
+        match self {
+            ConfigureSsoError::PermissionError => "You don't have permission to do this.",
+            ConfigureSsoError::UnverifiedDomain => {
+                "You can only set up single sign-on for your institution's domain if your own \
+                (verified) email address belongs to it."
+            }
+            ConfigureSsoError::DomainTaken => {
+                "Another institution already uses single sign-on for your institution's domain."
+            }
+            ConfigureSsoError::DatabaseError => {
+                "Encountered a database error while undertaking this operation."
+            }
+        }
+    }
+}
+
+/// Institution domains are stored as they were entered (e.g. `https://www.example.com/`), so we
+/// strip them down before comparing them with email addresses.
+pub(crate) fn normalise_domain(domain: &str) -> String {
+    let domain = domain.trim().to_lowercase();
+    let domain = domain
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    domain.split('/').next().unwrap_or_default().to_string()
+}
+
+pub(crate) fn email_domain(email: &str) -> Option<String> {
+    email
+        .rfind('@')
+        .map(|at| email[at + 1..].trim().to_lowercase())
+}
+
+fn is_admin(institution_id: i32, auth: AuthCookie, c: &DatabaseConnection) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        institution::table
+            .filter(institution::id.eq(institution_id))
+            .inner_join(administrator::table.inner_join(users::table))
+            .filter(users::id.eq(auth.0)),
+    ))
+    .get_result::<bool>(c)
+}
+
+async fn apply_configure_sso(
+    conn: Database,
+    data: ConfigureSsoForm,
+    auth: AuthCookie,
+    institution_id: i32,
+) -> Result<InstitutionOidc, ConfigureSsoError> {
+    conn.run(move |c| {
+        if !is_admin(institution_id, auth, c)? {
+            return Err(ConfigureSsoError::PermissionError);
+        }
+        let domain = institution::table
+            .find(institution_id)
+            .select(institution::domain)
+            .first::<String>(c)
+            .map(|domain| normalise_domain(&domain))?;
+        // the administrator's (verified) email address shows that the institution uses the domain
+        let admin = users::table.find(auth.0).first::<User>(c)?;
+        if domain.is_empty()
+            || !admin.email_verified
+            || email_domain(&admin.email).as_ref() != Some(&domain)
+        {
+            return Err(ConfigureSsoError::UnverifiedDomain);
+        }
+        let taken = diesel::select(diesel::dsl::exists(
+            institution_oidc::table
+                .filter(institution_oidc::domain.eq(&domain))
+                .filter(institution_oidc::institution_id.ne(institution_id)),
+        ))
+        .get_result::<bool>(c)?;
+        if taken {
+            return Err(ConfigureSsoError::DomainTaken);
+        }
+        // the settings page doesn't show the client secret, so leaving it empty keeps the old one
+        let client_secret = match data.client_secret.trim() {
+            "" => institution_oidc::table
+                .filter(institution_oidc::institution_id.eq(institution_id))
+                .select(institution_oidc::client_secret)
+                .first::<String>(c)
+                .optional()?
+                .unwrap_or_default(),
+            secret => secret.to_string(),
+        };
+        let config = NewInstitutionOidc {
+            institution_id,
+            issuer: data.issuer.trim().trim_end_matches('/'),
+            client_id: data.client_id.trim(),
+            client_secret: &client_secret,
+            role_claim: data.role_claim.trim(),
+            teacher_value: data.teacher_value.trim(),
+            student_value: data.student_value.trim(),
+            domain: &domain,
+        };
+        Ok(diesel::insert_into(institution_oidc::table)
+            .values(&config)
+            .on_conflict(institution_oidc::institution_id)
+            .do_update()
+            .set(&config)
+            .returning(institution_oidc::all_columns)
+            .get_result::<InstitutionOidc>(c)?)
+    })
+    .await
+}
+
+async fn apply_remove_sso(
+    conn: Database,
+    auth: AuthCookie,
+    institution_id: i32,
+) -> LovelaceResult<()> {
+    conn.run(move |c| {
+        if !is_admin(institution_id, auth, c)? {
+            return Err(LovelaceError::PermissionError);
+        }
+        diesel::delete(
+            institution_oidc::table.filter(institution_oidc::institution_id.eq(institution_id)),
+        )
+        .execute(c)?;
+        Ok(())
+    })
+    .await
+}
+
+struct ConfigureSsoFormProducer(i32, Option<InstitutionOidc>);
+
+impl FormProducer for ConfigureSsoFormProducer {
+    fn produce(self) -> Form {
+        let Self(institution_id, config) = self;
+        let field = |name: &'static str, placeholder: &'static str, value: Option<String>| {
+            Input::new()
+                .attribute(Type::Text)
+                .attribute(Name::new(name))
+                .attribute(Placeholder::new(placeholder))
+                .attribute(Value::new(value.unwrap_or_default()))
+        };
+        Form::new()
+            .attribute(Method::Post)
+            .attribute(Action::new(format!("/institution/{}/sso", institution_id)))
+            .child(field(
+                "issuer",
+                "The issuer URL of your provider",
+                config.as_ref().map(|c| c.issuer.clone()),
+            ))
+            .child(field(
+                "client_id",
+                "Client ID",
+                config.as_ref().map(|c| c.client_id.clone()),
+            ))
+            .child(
+                Input::new()
+                    .attribute(Type::Password)
+                    .attribute(Name::new("client_secret"))
+                    .attribute(Placeholder::new("Client secret")),
+            )
+            .child(field(
+                "role_claim",
+                "The claim which contains people's roles",
+                config.as_ref().map(|c| c.role_claim.clone()),
+            ))
+            .child(field(
+                "teacher_value",
+                "The role which teachers have",
+                config.as_ref().map(|c| c.teacher_value.clone()),
+            ))
+            .child(field(
+                "student_value",
+                "The role which students have",
+                config.map(|c| c.student_value),
+            ))
+            .child(
+                Input::new()
+                    .attribute(Type::Submit)
+                    .attribute(Value::new("Save")),
+            )
+    }
+}
+
+impl Render<Div> for InstitutionOidc {
+    fn render(self) -> Div {
+        Level::new()
+            .child(H3::new("Single sign-on is set up"))
+            .child(P::with_text(format!(
+                "People with email addresses at {} log in with {}. People whose \"{}\" claim is \
+                \"{}\" are added to your institution as teachers, and those for whom it is \"{}\" \
+                are added as students.",
+                self.domain.unwrap_or_default(),
+                self.issuer,
+                self.role_claim,
+                self.teacher_value,
+                self.student_value
+            )))
+            .into_div()
+    }
+}
+
+#[get("/<institution_id>/sso")]
+pub async fn configure_sso_page(institution_id: i32, auth: AuthCookie, conn: Database) -> Html {
+    let config = match conn
+        .run(move |c| {
+            if !is_admin(institution_id, auth, c)? {
+                return Err(LovelaceError::PermissionError);
+            }
+            institution_oidc::table
+                .filter(institution_oidc::institution_id.eq(institution_id))
+                .first::<InstitutionOidc>(c)
+                .optional()
+                .map_err(|e| {
+                    error!("{:#?}", e);
+                    LovelaceError::DatabaseError
+                })
+        })
+        .await
+    {
+        Ok(config) => config,
+        Err(e) => return e.render(),
+    };
+    Html::new()
+        .status(200)
+        .head(default_head("Single sign-on"))
+        .body(
+            Body::new()
+                .child(Level::new().child(H1::new("Single sign-on")))
+                .child(P::with_text(
+                    "Let people from your institution log in with your OpenID Connect provider. \
+                    Accounts are created for them (and they are added to your institution) the \
+                    first time that they log in.",
+                ))
+                .child(P::with_text(
+                    "Use this as the redirect URI when you register Lovelace with your provider: \
+                    <this site>/auth/sso/callback",
+                ))
+                .child(ConfigureSsoFormProducer(institution_id, config).produce())
+                .child(
+                    Form::new()
+                        .attribute(Method::Post)
+                        .attribute(Action::new(format!(
+                            "/institution/{}/sso/remove",
+                            institution_id
+                        )))
+                        .child(
+                            Input::new()
+                                .attribute(Type::Submit)
+                                .attribute(Value::new("Turn off single sign-on")),
+                        ),
+                ),
+        )
+}
+
+#[post("/<institution_id>/sso", data = "<form>")]
+pub async fn html_configure_sso(
+    institution_id: i32,
+    auth: AuthCookie,
+    conn: Database,
+    form: rocket::form::Form<ConfigureSsoForm>,
+) -> Html {
+    match apply_configure_sso(conn, form.into_inner(), auth, institution_id).await {
+        Ok(config) => Html::new()
+            .status(200)
+            .head(default_head("Succesfully updated"))
+            .body(
+                Body::new().child(
+                    Level::new()
+                        .child(H1::new("Succesfully updated"))
+                        .child(Render::<Div>::render(config)),
+                ),
+            ),
+        Err(ConfigureSsoError::DatabaseError) => database_error(),
+        Err(e) => Html::new()
+            .status(400)
+            .head(default_head("Single sign-on"))
+            .body(
+                Body::new()
+                    .child(H1::new("Single sign-on"))
+                    .child(P::with_text(e.reason()))
+                    .child(ConfigureSsoFormProducer(institution_id, None).produce()),
+            ),
+    }
+}
+
+#[post("/<institution_id>/sso", data = "<form>")]
+pub async fn api_configure_sso(
+    institution_id: i32,
+    auth: AuthCookie,
+    conn: Database,
+    form: Json<ConfigureSsoForm>,
+) -> Json<ApiResponse<InstitutionOidc>> {
+    Json(
+        match apply_configure_sso(conn, form.into_inner(), auth, institution_id).await {
+            Ok(config) => ApiResponse::new_ok(config),
+            Err(e) => ApiResponse::new_err(e.reason()),
+        },
+    )
+}
+
+#[post("/<institution_id>/sso/remove")]
+pub async fn html_remove_sso(institution_id: i32, auth: AuthCookie, conn: Database) -> Html {
+    match apply_remove_sso(conn, auth, institution_id).await {
+        Ok(()) => Html::new()
+            .status(200)
+            .head(default_head("Single sign-on turned off"))
+            .body(
+                Body::new().child(
+                    Level::new()
+                        .child(H1::new("Single sign-on turned off"))
+                        .child(P::with_text(
+                            "People from your institution can no longer log in with your provider.",
+                        )),
+                ),
+            ),
+        Err(e) => e.render(),
+    }
+}
+
+#[post("/<institution_id>/sso/remove")]
+pub async fn api_remove_sso(
+    institution_id: i32,
+    auth: AuthCookie,
+    conn: Database,
+) -> Json<ApiResponse<()>> {
+    Json(match apply_remove_sso(conn, auth, institution_id).await {
+        Ok(()) => ApiResponse::new_ok(()),
+        Err(e) => From::from(e),
+    })
+}
@@ -0,0 +1,30 @@
+use chrono::NaiveDateTime;
+
+use crate::schema::access_token;
+
+/// A personal access token, which can be used to call the API without logging in.
+#[derive(Queryable, Identifiable, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "access_token"]
+pub struct AccessToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// The hash of the token (the token itself is never stored).
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+    pub expires: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "access_token"]
+pub struct NewAccessToken<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub token_hash: &'a str,
+    pub scopes: Vec<String>,
+    pub created: NaiveDateTime,
+    pub expires: Option<NaiveDateTime>,
+}
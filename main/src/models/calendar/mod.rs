@@ -1,7 +1,14 @@
+use chrono::{NaiveDateTime, NaiveTime};
+
 use crate::schema::caldav;
 use crate::schema::caldav_unauthenticated;
 use crate::schema::calendar;
 use crate::schema::google_calendar;
+use crate::schema::oauth_state;
+use crate::schema::schedule_preferences;
+use crate::schema::scheduled_work_block;
+use crate::schema::synced_event;
+use crate::schema::working_hours;
 
 #[derive(Debug, Queryable, Identifiable)]
 #[table_name = "calendar"]
@@ -9,6 +16,10 @@ pub struct Calendar {
     pub id: i32,
     pub calendar_type: i32,
     pub user_id: i32,
+    /// The sync token which the server gave us the last time we synced the user's calendar.
+    pub sync_token: Option<String>,
+    /// The ctag of the user's calendar the last time we synced it.
+    pub ctag: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
@@ -53,6 +64,8 @@ pub struct GoogleCalendar {
     pub refresh_token: String,
     pub access_token: String,
     pub lovelace_calendar_id: String,
+    /// When the access token expires (if we know).
+    pub access_token_expires_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Insertable)]
@@ -62,6 +75,7 @@ pub struct NewGoogleCalendar<'a> {
     pub access_token: &'a str,
     pub calendar_id: i32,
     pub lovelace_calendar_id: &'a str,
+    pub access_token_expires_at: Option<NaiveDateTime>,
 }
 
 #[derive(Queryable, Identifiable, Debug)]
@@ -72,6 +86,7 @@ pub struct CalDav {
     pub username: String,
     pub password: String,
     pub url: String,
+    pub lovelace_url: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -81,6 +96,7 @@ pub struct NewCalDav<'a> {
     pub username: &'a str,
     pub password: &'a str,
     pub url: &'a str,
+    pub lovelace_url: Option<&'a str>,
 }
 
 #[derive(Queryable, Identifiable, Debug)]
@@ -89,6 +105,7 @@ pub struct CalDavUnauthenticated {
     pub id: i32,
     pub calendar_id: i32,
     pub url: String,
+    pub lovelace_url: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -96,4 +113,116 @@ pub struct CalDavUnauthenticated {
 pub struct NewCalDavUnauthenticated<'a> {
     pub calendar_id: i32,
     pub url: &'a str,
+    pub lovelace_url: Option<&'a str>,
+}
+
+/// How the user would like their study plan to be laid out.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "schedule_preferences"]
+pub struct SchedulePreferences {
+    pub id: i32,
+    pub user_id: i32,
+    /// The shortest break (in minutes) to leave between two blocks of work.
+    pub min_break: i32,
+    /// The most time (in minutes) which the user wants to study for in a single day.
+    pub max_minutes_per_day: i32,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[table_name = "schedule_preferences"]
+pub struct NewSchedulePreferences {
+    pub user_id: i32,
+    pub min_break: i32,
+    pub max_minutes_per_day: i32,
+}
+
+/// The time of day (in the user's timezone) during which the user is happy to study on a given day
+/// of the week.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "working_hours"]
+pub struct WorkingHours {
+    pub id: i32,
+    pub user_id: i32,
+    /// Monday is 0 and Sunday is 6.
+    pub weekday: i16,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "working_hours"]
+pub struct NewWorkingHours {
+    pub user_id: i32,
+    pub weekday: i16,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+/// A block of work which the scheduler has added to the user's Lovelace calendar.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "scheduled_work_block"]
+pub struct ScheduledWorkBlock {
+    pub id: i32,
+    pub user_id: i32,
+    pub class_asynchronous_task_id: i32,
+    pub block_index: i32,
+    /// The UID of the calendar event.
+    pub uid: String,
+    /// When the block was scheduled to start (this is what we last wrote to the calendar – if the
+    /// event in the calendar has a different start time then the user has moved it).
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "scheduled_work_block"]
+pub struct NewScheduledWorkBlock<'a> {
+    pub user_id: i32,
+    pub class_asynchronous_task_id: i32,
+    pub block_index: i32,
+    pub uid: &'a str,
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+}
+
+/// A `state` value which has been handed out to someone who is linking a Google calendar.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "oauth_state"]
+pub struct OAuthState {
+    pub id: i32,
+    pub state: String,
+    pub user_id: i32,
+    pub lovelace_calendar_id: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "oauth_state"]
+pub struct NewOAuthState<'a> {
+    pub state: &'a str,
+    pub user_id: i32,
+    pub lovelace_calendar_id: &'a str,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+/// A copy of a resource (which contains one or more events) in the user's calendar.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "synced_event"]
+pub struct SyncedEvent {
+    pub id: i32,
+    pub calendar_id: i32,
+    pub href: String,
+    pub etag: Option<String>,
+    pub data: String,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "synced_event"]
+pub struct NewSyncedEvent<'a> {
+    pub calendar_id: i32,
+    pub href: &'a str,
+    pub etag: Option<&'a str>,
+    pub data: &'a str,
 }
@@ -4,6 +4,10 @@ use crate::models::ClassStudent;
 use crate::schema::class_asynchronous_task;
 use crate::schema::student_class_asynchronous_task;
 
+/// The amount of time (in minutes) that a task is assumed to take if the teacher does not provide
+/// an estimate.
+pub const DEFAULT_ESTIMATED_DURATION: i32 = 25;
+
 #[derive(Queryable, Identifiable, PartialEq, Debug, Clone, Serialize, Deserialize)]
 #[table_name = "class_asynchronous_task"]
 pub struct ClassAsynchronousTask {
@@ -14,6 +18,8 @@ pub struct ClassAsynchronousTask {
     pub due_date: NaiveDateTime,
     pub class_teacher_id: i32,
     pub class_id: i32,
+    /// The teacher's estimate of how long (in minutes) this task will take.
+    pub estimated_duration: i32,
 }
 
 impl ClassAsynchronousTask {
@@ -23,6 +29,10 @@ impl ClassAsynchronousTask {
             .child(H3::new(format!("Task: {}", self.title)))
             .child(P::with_text(format!("Description: {}", self.description)))
             .child(P::with_text(format!("Created at: {}", self.created)))
+            .child(P::with_text(format!(
+                "Estimated duration: {} minutes",
+                self.estimated_duration
+            )))
     }
 }
 
@@ -35,6 +45,7 @@ pub struct UpdateClassAsynchronousTask {
     pub due_date: Option<NaiveDateTime>,
     pub class_teacher_id: Option<i32>,
     pub class_id: Option<i32>,
+    pub estimated_duration: Option<i32>,
 }
 
 #[derive(Insertable, Debug, Clone)]
@@ -46,6 +57,7 @@ pub struct NewClassAsynchronousTask<'a> {
     pub due_date: NaiveDateTime,
     pub class_teacher_id: i32,
     pub class_id: i32,
+    pub estimated_duration: i32,
 }
 
 #[derive(Insertable, Debug, Clone)]
@@ -77,4 +89,15 @@ pub struct StudentClassAsynchronousTask {
     pub class_student_id: i32,
     pub class_asynchronous_task_id: i32,
     pub completed: bool,
+    /// The student's own estimate of how long (in minutes) this task will take them. If this is
+    /// `None` the teacher's estimate should be used instead.
+    pub estimated_duration: Option<i32>,
+}
+
+impl StudentClassAsynchronousTask {
+    /// The number of minutes which this student is expected to spend on the provided task (which
+    /// should be the task that this struct refers to).
+    pub fn minutes_to_complete(&self, task: &ClassAsynchronousTask) -> i32 {
+        self.estimated_duration.unwrap_or(task.estimated_duration)
+    }
 }
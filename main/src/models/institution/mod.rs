@@ -3,6 +3,7 @@ use chrono::NaiveDateTime;
 use crate::schema::institution;
 
 pub mod administrator;
+pub mod oidc;
 pub mod student;
 pub mod student_group;
 pub mod teacher;
@@ -18,6 +19,8 @@ pub struct Institution {
     pub let_teachers_create_classes: bool,
     pub let_all_users_create_classes: bool,
     pub let_teachers_add_sync_tasks: bool,
+    /// Whether everybody in the institution has to use two-factor authentication.
+    pub require_two_factor: bool,
 }
 
 #[derive(Insertable, Debug)]
@@ -39,4 +42,5 @@ pub struct UpdateInstitution {
     pub domain: Option<String>,
     pub created: Option<NaiveDateTime>,
     pub enforce_same_domain: Option<bool>,
+    pub require_two_factor: Option<bool>,
 }
@@ -0,0 +1,73 @@
+use chrono::NaiveDateTime;
+
+use crate::schema::{institution_oidc, oidc_identity, oidc_login};
+
+/// The OpenID Connect provider which people from an institution log in with.
+#[derive(Queryable, Identifiable, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "institution_oidc"]
+pub struct InstitutionOidc {
+    pub id: i32,
+    pub institution_id: i32,
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+    pub role_claim: String,
+    pub teacher_value: String,
+    pub student_value: String,
+    /// The email domain of the people who log in with the provider (which has been checked against
+    /// the email address of the administrator who set up single sign-on).
+    pub domain: Option<String>,
+}
+
+#[derive(Insertable, AsChangeset, Debug, Clone)]
+#[table_name = "institution_oidc"]
+pub struct NewInstitutionOidc<'a> {
+    pub institution_id: i32,
+    pub issuer: &'a str,
+    pub client_id: &'a str,
+    pub client_secret: &'a str,
+    pub role_claim: &'a str,
+    pub teacher_value: &'a str,
+    pub student_value: &'a str,
+    pub domain: &'a str,
+}
+
+/// A login which has been sent to an institution's provider.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "oidc_login"]
+pub struct OidcLogin {
+    pub id: i32,
+    pub state: String,
+    pub nonce: String,
+    pub institution_id: i32,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "oidc_login"]
+pub struct NewOidcLogin<'a> {
+    pub state: &'a str,
+    pub nonce: &'a str,
+    pub institution_id: i32,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "oidc_identity"]
+pub struct OidcIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub institution_id: i32,
+    pub subject: String,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "oidc_identity"]
+pub struct NewOidcIdentity<'a> {
+    pub user_id: i32,
+    pub institution_id: i32,
+    pub subject: &'a str,
+}
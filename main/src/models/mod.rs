@@ -3,12 +3,18 @@ This source code file is distributed subject to the terms of the GNU Affero Gene
 A copy of this license can be found in the `licenses` directory at the root of this project.
 */
 
+pub mod access_token;
 pub mod calendar;
 pub mod class;
 pub mod institution;
 pub mod notification;
+pub mod session;
+pub mod two_factor;
 pub mod user;
 
+pub use access_token::*;
 pub use class::*;
 pub use notification::*;
+pub use session::*;
+pub use two_factor::*;
 pub use user::*;
@@ -0,0 +1,29 @@
+use chrono::NaiveDateTime;
+
+use crate::schema::session;
+
+/// A place where somebody is logged in.
+#[derive(Queryable, Identifiable, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "session"]
+pub struct Session {
+    pub id: i32,
+    /// The hash of the token in the login cookie (the token itself is never stored).
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "session"]
+pub struct NewSession<'a> {
+    pub token_hash: &'a str,
+    pub user_id: i32,
+    pub created: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub user_agent: Option<&'a str>,
+}
@@ -0,0 +1,36 @@
+use chrono::NaiveDateTime;
+
+use crate::schema::{recovery_code, totp};
+
+/// The TOTP secret of somebody who has set up (or is setting up) two-factor authentication.
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[table_name = "totp"]
+pub struct Totp {
+    pub id: i32,
+    pub user_id: i32,
+    pub secret: String,
+    pub confirmed: bool,
+    pub last_used_step: Option<i64>,
+    pub created: NaiveDateTime,
+    /// How many incorrect codes have been entered in a row.
+    pub failed_attempts: i32,
+    /// Nobody can log in as the user until this time (because too many incorrect codes were
+    /// entered).
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "totp"]
+pub struct NewTotp<'a> {
+    pub user_id: i32,
+    pub secret: &'a str,
+    pub confirmed: bool,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "recovery_code"]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code_hash: &'a str,
+}
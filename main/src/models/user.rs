@@ -17,6 +17,8 @@ pub struct User {
     pub timezone: String,
     #[serde(skip_serializing)]
     pub email_verified: bool,
+    #[serde(skip_serializing)]
+    pub password_changed: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable, Debug, Clone)]
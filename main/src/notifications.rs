@@ -252,7 +252,6 @@ pub struct Notify<'a> {
 
 impl<'a> Notify<'a> {
     /// Add the current struct to the database.
-    #[allow(unused)]
     pub fn create(&self, conn: &DatabaseConnection) -> Result<(), diesel::result::Error> {
         use crate::schema::notifications::dsl as notifications;
         diesel::insert_into(notifications::notifications)
@@ -1,3 +1,16 @@
+table! {
+    access_token (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<Text>,
+        created -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+        expires -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     administrator (id) {
         id -> Int4,
@@ -23,6 +36,7 @@ table! {
         username -> Text,
         password -> Text,
         url -> Text,
+        lovelace_url -> Nullable<Text>,
     }
 }
 
@@ -31,6 +45,7 @@ table! {
         id -> Int4,
         calendar_id -> Int4,
         url -> Text,
+        lovelace_url -> Nullable<Text>,
     }
 }
 
@@ -39,6 +54,8 @@ table! {
         id -> Int4,
         calendar_type -> Int4,
         user_id -> Int4,
+        sync_token -> Nullable<Text>,
+        ctag -> Nullable<Text>,
     }
 }
 
@@ -63,6 +80,7 @@ table! {
         due_date -> Timestamp,
         class_teacher_id -> Int4,
         class_id -> Int4,
+        estimated_duration -> Int4,
     }
 }
 
@@ -136,6 +154,7 @@ table! {
         refresh_token -> Text,
         access_token -> Text,
         lovelace_calendar_id -> Text,
+        access_token_expires_at -> Nullable<Timestamp>,
     }
 }
 
@@ -149,6 +168,21 @@ table! {
         let_teachers_create_classes -> Bool,
         let_all_users_create_classes -> Bool,
         let_teachers_add_sync_tasks -> Bool,
+        require_two_factor -> Bool,
+    }
+}
+
+table! {
+    institution_oidc (id) {
+        id -> Int4,
+        institution_id -> Int4,
+        issuer -> Text,
+        client_id -> Text,
+        client_secret -> Text,
+        role_claim -> Text,
+        teacher_value -> Text,
+        student_value -> Text,
+        domain -> Nullable<Text>,
     }
 }
 
@@ -200,12 +234,85 @@ table! {
     }
 }
 
+table! {
+    oauth_state (id) {
+        id -> Int4,
+        state -> Text,
+        user_id -> Int4,
+        lovelace_calendar_id -> Text,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    oidc_identity (id) {
+        id -> Int4,
+        user_id -> Int4,
+        institution_id -> Int4,
+        subject -> Text,
+    }
+}
+
+table! {
+    oidc_login (id) {
+        id -> Int4,
+        state -> Text,
+        nonce -> Text,
+        institution_id -> Int4,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    recovery_code (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Text,
+    }
+}
+
+table! {
+    schedule_preferences (id) {
+        id -> Int4,
+        user_id -> Int4,
+        min_break -> Int4,
+        max_minutes_per_day -> Int4,
+    }
+}
+
+table! {
+    scheduled_work_block (id) {
+        id -> Int4,
+        user_id -> Int4,
+        class_asynchronous_task_id -> Int4,
+        block_index -> Int4,
+        uid -> Text,
+        start_time -> Timestamp,
+        end_time -> Timestamp,
+    }
+}
+
+table! {
+    session (id) {
+        id -> Int4,
+        token_hash -> Text,
+        user_id -> Int4,
+        created -> Timestamp,
+        last_seen -> Timestamp,
+        expires -> Timestamp,
+        user_agent -> Nullable<Text>,
+    }
+}
+
 table! {
     student_class_asynchronous_task (id) {
         id -> Int4,
         class_student_id -> Int4,
         class_asynchronous_task_id -> Int4,
         completed -> Bool,
+        estimated_duration -> Nullable<Int4>,
     }
 }
 
@@ -254,6 +361,29 @@ table! {
     }
 }
 
+table! {
+    synced_event (id) {
+        id -> Int4,
+        calendar_id -> Int4,
+        href -> Text,
+        etag -> Nullable<Text>,
+        data -> Text,
+    }
+}
+
+table! {
+    totp (id) {
+        id -> Int4,
+        user_id -> Int4,
+        secret -> Text,
+        confirmed -> Bool,
+        last_used_step -> Nullable<Int8>,
+        created -> Timestamp,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     users (id) {
         id -> Int4,
@@ -263,9 +393,21 @@ table! {
         created -> Timestamp,
         timezone -> Text,
         email_verified -> Bool,
+        password_changed -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    working_hours (id) {
+        id -> Int4,
+        user_id -> Int4,
+        weekday -> Int2,
+        start_time -> Time,
+        end_time -> Time,
     }
 }
 
+joinable!(access_token -> users (user_id));
 joinable!(administrator -> institution (institution_id));
 joinable!(administrator -> users (user_id));
 joinable!(administrator_invite -> institution (institution_id));
@@ -289,6 +431,7 @@ joinable!(class_teacher -> class (class_id));
 joinable!(class_teacher -> users (user_id));
 joinable!(class_teacher_invite -> class (class_id));
 joinable!(google_calendar -> calendar (calendar_id));
+joinable!(institution_oidc -> institution (institution_id));
 joinable!(institution_student -> institution (institution_id));
 joinable!(institution_student -> users (user_id));
 joinable!(institution_student_invite -> institution (institution_id));
@@ -296,6 +439,14 @@ joinable!(institution_teacher -> institution (institution_id));
 joinable!(institution_teacher -> users (user_id));
 joinable!(institution_teacher_invite -> institution (institution_id));
 joinable!(notifications -> users (user_id));
+joinable!(oauth_state -> users (user_id));
+joinable!(oidc_identity -> institution (institution_id));
+joinable!(oidc_identity -> users (user_id));
+joinable!(oidc_login -> institution (institution_id));
+joinable!(recovery_code -> users (user_id));
+joinable!(schedule_preferences -> users (user_id));
+joinable!(scheduled_work_block -> users (user_id));
+joinable!(session -> users (user_id));
 joinable!(student_class_asynchronous_task -> class_asynchronous_task (class_asynchronous_task_id));
 joinable!(student_class_asynchronous_task -> class_student (class_student_id));
 joinable!(student_class_synchronous_task -> class_student (class_student_id));
@@ -306,8 +457,12 @@ joinable!(student_group_student -> users (user_id));
 joinable!(student_group_teacher -> student_group (student_group_id));
 joinable!(student_group_teacher -> users (user_id));
 joinable!(student_group_teacher_invite -> student_group (student_group_id));
+joinable!(synced_event -> calendar (calendar_id));
+joinable!(totp -> users (user_id));
+joinable!(working_hours -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    access_token,
     administrator,
     administrator_invite,
     caldav,
@@ -323,16 +478,27 @@ allow_tables_to_appear_in_same_query!(
     class_teacher_invite,
     google_calendar,
     institution,
+    institution_oidc,
     institution_student,
     institution_student_invite,
     institution_teacher,
     institution_teacher_invite,
     notifications,
+    oauth_state,
+    oidc_identity,
+    oidc_login,
+    recovery_code,
+    schedule_preferences,
+    scheduled_work_block,
+    session,
     student_class_asynchronous_task,
     student_class_synchronous_task,
     student_group,
     student_group_student,
     student_group_teacher,
     student_group_teacher_invite,
+    synced_event,
+    totp,
     users,
+    working_hours,
 );
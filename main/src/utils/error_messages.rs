@@ -51,3 +51,23 @@ pub fn invalid_date(form: Option<Form>) -> Html {
                 }),
         )
 }
+
+pub fn invalid_duration(form: Option<Form>) -> Html {
+    Html::new()
+        .status(400)
+        .head(default_head("Invalid duration".to_string()))
+        .body(
+            Body::new()
+                .child(H1::new("Invalid duration"))
+                .child(P::with_text(
+                    "The estimated duration should be a positive number of minutes.",
+                ))
+                .map(|body| {
+                    if let Some(form) = form {
+                        body.child(form)
+                    } else {
+                        body
+                    }
+                }),
+        )
+}
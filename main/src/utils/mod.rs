@@ -4,7 +4,6 @@ A copy of this license can be found in the `licenses` directory at the root of t
 */
 #[cfg(test)]
 use crate::auth::LOGIN_COOKIE;
-use crate::calendar::connect::gcal::StateValues;
 #[cfg(test)]
 use crate::db::Database;
 #[cfg(test)]
@@ -19,11 +18,9 @@ use rocket::figment::{
     value::{Map, Value},
     Figment,
 };
-use rocket::tokio::sync::RwLock;
 use rocket::{fairing::AdHoc, Rocket};
 #[cfg(test)]
 use rocket::{http::ContentType, local::asynchronous::Client};
-use std::collections::HashMap;
 
 pub mod auto_database_error;
 pub mod error;
@@ -79,14 +76,15 @@ pub fn launch() -> Rocket {
         Figment::from(rocket::Config::default()).merge(("databases", map!["postgres" => db]))
     };
     rocket::custom(figment)
-        .manage(StateValues {
-            map: RwLock::new(HashMap::new()),
-        })
         .attach(crate::db::Database::fairing())
         .attach(AdHoc::on_attach(
             "Database Migrations",
             crate::db::run_migrations,
         ))
+        .attach(AdHoc::on_attach(
+            "Scheduler",
+            crate::calendar::scheduler::jobs::start,
+        ))
         .mount(
             "/api",
             routes![
@@ -121,7 +119,25 @@ pub fn launch() -> Rocket {
         .mount("/dashboard", routes![crate::dashboard::html_dashboard])
         .mount(
             "/api/auth",
-            routes![crate::auth::api_login, crate::auth::api_logout,],
+            routes![
+                crate::auth::api_login,
+                crate::auth::api_logout,
+                crate::auth::api_request_reset,
+                crate::auth::api_reset_password,
+                crate::auth::api_list_sessions,
+                crate::auth::api_revoke_session,
+                crate::auth::api_list_tokens,
+                crate::auth::api_create_token,
+                crate::auth::api_revoke_token,
+                crate::auth::api_issue_jwt,
+                crate::auth::api_two_factor_settings,
+                crate::auth::api_begin_enrolment,
+                crate::auth::api_confirm_enrolment,
+                crate::auth::api_regenerate_recovery_codes,
+                crate::auth::api_disable_two_factor,
+                crate::auth::api_two_factor_login,
+                crate::auth::api_start_sso
+            ],
         )
         .mount(
             "/auth",
@@ -130,7 +146,26 @@ pub fn launch() -> Rocket {
                 crate::auth::html_login,
                 crate::auth::register_page,
                 crate::auth::html_register,
-                crate::auth::verify_email
+                crate::auth::verify_email,
+                crate::auth::request_reset_page,
+                crate::auth::html_request_reset,
+                crate::auth::reset_password_page,
+                crate::auth::html_reset_password,
+                crate::auth::html_list_sessions,
+                crate::auth::html_revoke_session,
+                crate::auth::html_revoke_other_sessions,
+                crate::auth::html_list_tokens,
+                crate::auth::html_create_token,
+                crate::auth::html_revoke_token,
+                crate::auth::html_two_factor_settings,
+                crate::auth::html_begin_enrolment,
+                crate::auth::html_confirm_enrolment,
+                crate::auth::html_regenerate_recovery_codes,
+                crate::auth::html_disable_two_factor,
+                crate::auth::html_two_factor_login,
+                crate::auth::sso_page,
+                crate::auth::html_start_sso,
+                crate::auth::sso_callback
             ],
         )
         .mount(
@@ -139,6 +174,8 @@ pub fn launch() -> Rocket {
                 crate::institution::register::api_register_new_institution,
                 crate::institution::delete::api_delete_institution,
                 crate::institution::configure::api_configure_institution,
+                crate::institution::sso::api_configure_sso,
+                crate::institution::sso::api_remove_sso,
                 crate::institution::class::create::api_create_institution_class
             ],
         )
@@ -151,6 +188,9 @@ pub fn launch() -> Rocket {
                 crate::institution::delete::html_delete_institution,
                 crate::institution::configure::configure_institution_page,
                 crate::institution::configure::html_configure_institution,
+                crate::institution::sso::configure_sso_page,
+                crate::institution::sso::html_configure_sso,
+                crate::institution::sso::html_remove_sso,
                 crate::institution::class::create::pick_which_institution_to_create_class_as_part_of,
                 crate::institution::class::create::html_create_institution_class,
                 crate::institution::class::create::create_institution_class_page
@@ -177,6 +217,7 @@ pub fn launch() -> Rocket {
                 crate::class::tasks::asynchronous::api_view_specific_asynchronous_task,
                 crate::class::tasks::asynchronous::api_delete_task,
                 crate::class::tasks::asynchronous::api_view_all_async_tasks_in_class,
+                crate::class::tasks::asynchronous::api_set_estimate,
                 crate::class::tasks::synchronous::api_create_new_async_task,
                 crate::class::tasks::synchronous::api_delete_task,
                 crate::class::tasks::synchronous::api_apply_edit_task,
@@ -202,6 +243,7 @@ pub fn launch() -> Rocket {
                 crate::class::tasks::asynchronous::view_edit_task_page,
                 crate::class::tasks::asynchronous::html_apply_edit_task,
                 crate::class::tasks::asynchronous::html_delete_task,
+                crate::class::tasks::asynchronous::html_set_estimate,
                 crate::class::tasks::synchronous::html_view_all_sync_tasks_in_class,
                 crate::class::tasks::synchronous::html_create_new_sync_task,
                 crate::class::tasks::synchronous::get_create_new_sync_task,
@@ -211,6 +253,26 @@ pub fn launch() -> Rocket {
                 crate::class::tasks::synchronous::html_delete_task
             ],
         )
+        .mount(
+            "/calendar",
+            routes![
+                crate::calendar::preferences::html_view_preferences,
+                crate::calendar::preferences::html_update_preferences,
+                crate::calendar::connect::manage::html_view_connected_calendar,
+                crate::calendar::connect::manage::html_test_connected_calendar,
+                crate::calendar::connect::manage::html_disconnect_calendar
+            ],
+        )
+        .mount(
+            "/api/calendar",
+            routes![
+                crate::calendar::preferences::api_view_preferences,
+                crate::calendar::preferences::api_update_preferences,
+                crate::calendar::connect::manage::api_view_connected_calendar,
+                crate::calendar::connect::manage::api_test_connected_calendar,
+                crate::calendar::connect::manage::api_disconnect_calendar
+            ],
+        )
         .mount(
             "/calendar/gcal",
             routes![
@@ -219,6 +281,14 @@ pub fn launch() -> Rocket {
                 crate::calendar::connect::gcal::gcal_callback
             ],
         )
+        .mount(
+            "/calendar/caldav",
+            routes![
+                crate::calendar::connect::caldav::link_caldav_page,
+                crate::calendar::connect::caldav::discover_caldav_calendars,
+                crate::calendar::connect::caldav::connect_caldav_calendar
+            ],
+        )
         .mount(
             "/calendar/unauthenticated_caldav",
             routes![
@@ -0,0 +1,9 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The calendar into which Lovelace writes the events it schedules (kept separate from the user's own
+calendar so that we never delete events we did not create). */
+alter table caldav add column if not exists lovelace_url text;
+alter table caldav_unauthenticated add column if not exists lovelace_url text;
@@ -0,0 +1,6 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+alter table class_asynchronous_task drop column if exists estimated_duration;
+alter table student_class_asynchronous_task drop column if exists estimated_duration;
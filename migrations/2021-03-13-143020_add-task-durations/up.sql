@@ -0,0 +1,13 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The amount of time (in minutes) which the teacher expects the task to take. */
+alter table class_asynchronous_task
+    add column if not exists estimated_duration integer not null default 25;
+
+/* The amount of time (in minutes) which the student expects the task to take them. If this is null
+then the teacher's estimate is used. */
+alter table student_class_asynchronous_task
+    add column if not exists estimated_duration integer;
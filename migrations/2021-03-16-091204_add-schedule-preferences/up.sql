@@ -0,0 +1,28 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* How the user would like their study plan to be laid out. If a user has no row in this table then
+the scheduler's defaults are used. */
+create table if not exists schedule_preferences (
+    id serial primary key,
+    user_id integer not null unique references users (id) on delete cascade,
+    /* The shortest break (in minutes) which we leave between two blocks of work. */
+    min_break integer not null default 10 check (min_break >= 0),
+    /* The most time (in minutes) which we ask the user to study for in a single day. */
+    max_minutes_per_day integer not null default 240 check (max_minutes_per_day > 0)
+);
+
+/* The times of day (in the user's timezone) during which the user is happy to study. Once a user
+has saved their preferences, any day of the week without a row in this table is a day off. */
+create table if not exists working_hours (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    /* Monday is 0 and Sunday is 6. */
+    weekday smallint not null check (weekday >= 0 and weekday < 7),
+    start_time time not null,
+    end_time time not null,
+    check (start_time < end_time),
+    unique (user_id, weekday)
+);
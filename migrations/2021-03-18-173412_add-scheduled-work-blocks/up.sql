@@ -0,0 +1,21 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The blocks of work which we have added to each user's Lovelace calendar (and when we scheduled
+them for). When we recompute somebody's schedule we use this to work out which events need to be
+changed, and which events the user has moved themselves (and should therefore be left alone). */
+create table if not exists scheduled_work_block (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    /* This is deliberately not a foreign key – if a task is deleted we still need to know which
+    events belonged to it, so that we can remove them from the user's calendar. */
+    class_asynchronous_task_id integer not null,
+    block_index integer not null,
+    /* The UID of the calendar event. */
+    uid text not null,
+    start_time timestamp not null,
+    end_time timestamp not null,
+    unique (user_id, uid)
+);
@@ -0,0 +1,5 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+alter table google_calendar drop column if exists access_token_expires_at;
@@ -0,0 +1,7 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* When the access token stops working (this is null if Google didn't tell us). */
+alter table google_calendar add column if not exists access_token_expires_at timestamp;
@@ -0,0 +1,16 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The `state` values which we have handed out to people who are linking a Google calendar. Each one
+can only be used once, and stops working once it expires. */
+create table if not exists oauth_state (
+    id serial primary key,
+    state text not null unique,
+    user_id integer not null references users (id) on delete cascade,
+    /* The calendar into which Lovelace should write events. */
+    lovelace_calendar_id text not null,
+    created timestamp not null,
+    expires timestamp not null
+);
@@ -0,0 +1,20 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The state of the user's calendar the last time that we synced it (these are null if we have never
+synced it, or if the server doesn't support them). */
+alter table calendar add column if not exists sync_token text;
+alter table calendar add column if not exists ctag text;
+
+/* A copy of the resources in the user's calendar, so that we only have to fetch the ones which have
+changed. */
+create table if not exists synced_event (
+    id serial primary key,
+    calendar_id integer not null references calendar (id) on delete cascade,
+    href text not null,
+    etag text,
+    data text not null,
+    unique (calendar_id, href)
+);
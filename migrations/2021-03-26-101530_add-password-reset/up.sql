@@ -0,0 +1,8 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* When the user's password was last changed (null if it has never been changed). Logins and
+password reset links from before this time are no longer valid. */
+alter table users add column if not exists password_changed timestamp;
@@ -0,0 +1,19 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* Everywhere that people are logged in. The login cookie contains the session's (random) token, and
+logging out deletes the session. */
+create table if not exists session (
+    id serial primary key,
+    token text not null unique,
+    user_id integer not null references users (id) on delete cascade,
+    created timestamp not null,
+    /* Roughly when the session was last used (this is only updated every few minutes). */
+    last_seen timestamp not null,
+    expires timestamp not null,
+    /* The `User-Agent` header which was sent when the user logged in, so that people can tell their
+    sessions apart. */
+    user_agent text
+);
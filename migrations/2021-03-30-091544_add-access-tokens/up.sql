@@ -0,0 +1,21 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* Personal access tokens, which scripts (and apps) can use to call the API on somebody's behalf. */
+create table if not exists access_token (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    /* The name which the user gave the token, so that they can tell their tokens apart. */
+    name text not null,
+    /* The SHA-256 hash of the token (hex encoded). The token itself is only shown to the user once,
+    when it is created. */
+    token_hash text not null unique,
+    /* What the token can be used for (see `auth::tokens::Scope`). */
+    scopes text[] not null,
+    created timestamp not null,
+    last_used timestamp,
+    /* Tokens without an expiry date last until they are revoked. */
+    expires timestamp
+);
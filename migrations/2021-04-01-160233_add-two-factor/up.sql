@@ -0,0 +1,28 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The TOTP secrets of users who have set up two-factor authentication. */
+create table if not exists totp (
+    id serial primary key,
+    user_id integer not null unique references users (id) on delete cascade,
+    /* The shared secret (base32 encoded, as it appears in the provisioning URI). */
+    secret text not null,
+    /* Two-factor authentication is only turned on once the user has entered a code from their
+    authenticator app (which shows that they have set it up correctly). */
+    confirmed boolean not null default false,
+    /* The last time step which a code was accepted for, so that each code can only be used once. */
+    last_used_step bigint,
+    created timestamp not null
+);
+
+/* Single-use codes which people can log in with if they lose their authenticator. */
+create table if not exists recovery_code (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    /* The SHA-256 hash of the code (hex encoded). */
+    code_hash text not null
+);
+
+alter table institution add column if not exists require_two_factor boolean not null default false;
@@ -0,0 +1,39 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The OpenID Connect provider which people from an institution can log in with. */
+create table if not exists institution_oidc (
+    id serial primary key,
+    institution_id integer not null unique references institution (id) on delete cascade,
+    /* The provider's issuer URL (its configuration is discovered from
+    `<issuer>/.well-known/openid-configuration`). */
+    issuer text not null,
+    client_id text not null,
+    client_secret text not null,
+    /* The claim which says what somebody's role in the institution is, and the values of it which
+    make them a teacher or a student. */
+    role_claim text not null,
+    teacher_value text not null,
+    student_value text not null
+);
+
+/* Logins which have been sent to an institution's provider, but haven't come back yet. */
+create table if not exists oidc_login (
+    id serial primary key,
+    state text not null unique,
+    nonce text not null,
+    institution_id integer not null references institution (id) on delete cascade,
+    created timestamp not null,
+    expires timestamp not null
+);
+
+/* Links accounts at an institution's provider (identified by the `sub` claim) to users. */
+create table if not exists oidc_identity (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    institution_id integer not null references institution (id) on delete cascade,
+    subject text not null,
+    unique (institution_id, subject)
+);
@@ -0,0 +1,10 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* Sessions only store a hash of the token in the login cookie (so that the contents of the table
+can't be used to log in). The tokens of existing sessions can't be recovered, so everybody has to log
+in again. */
+delete from session;
+alter table session rename column token to token_hash;
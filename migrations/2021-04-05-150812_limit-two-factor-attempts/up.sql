@@ -0,0 +1,9 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* How many incorrect codes have been entered in a row (this is reset whenever a code is correct). */
+alter table totp add column if not exists failed_attempts integer not null default 0;
+/* After too many incorrect codes, nobody can log in as the user until this time. */
+alter table totp add column if not exists locked_until timestamp;
@@ -0,0 +1,10 @@
+/*
+This source code file is distributed subject to the terms of the GNU Affero General Public License.
+A copy of this license can be found in the `licenses` directory at the root of this project.
+*/
+
+/* The email domain of the people who log in with the provider. This is checked (against the email
+address of the administrator who sets up single sign-on) when the configuration is saved, and each
+domain can only be used by one institution. Configurations which were saved before this was added
+can't be used until they are saved again. */
+alter table institution_oidc add column if not exists domain text unique;
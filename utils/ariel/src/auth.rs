@@ -0,0 +1,295 @@
+//! Checks that requests to the server are authenticated.
+
+use std::collections::{HashMap, HashSet};
+
+use uuid::Uuid;
+
+/// The realm which the server uses in its challenges.
+pub(crate) const REALM: &str = "ariel";
+
+/// How clients have to authenticate with the server.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// Anybody can use the server.
+    None,
+    /// HTTP basic authentication with the provided username and password.
+    Basic { username: String, password: String },
+    /// HTTP digest authentication (RFC 2617) with the provided username and password.
+    Digest { username: String, password: String },
+    /// A bearer token (e.g. an OAuth access token) which has to be sent with every request.
+    Bearer(String),
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Whether a request may go ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allowed,
+    /// The request didn't have the right credentials. `stale` is true if the credentials were
+    /// right, but were for a digest nonce which has expired.
+    Denied {
+        stale: bool,
+    },
+}
+
+/// The nonces which the server has handed out in digest challenges.
+#[derive(Debug)]
+pub(crate) struct Nonces {
+    /// The nonce which is sent in new challenges (and is the only one which is accepted).
+    pub(crate) current: String,
+    expired: HashSet<String>,
+}
+
+impl Nonces {
+    pub(crate) fn new() -> Self {
+        Self {
+            current: Uuid::new_v4().to_simple().to_string(),
+            expired: HashSet::new(),
+        }
+    }
+
+    /// Replaces the current nonce with a new one.
+    pub(crate) fn expire(&mut self) {
+        let new = Uuid::new_v4().to_simple().to_string();
+        self.expired
+            .insert(std::mem::replace(&mut self.current, new));
+    }
+}
+
+impl Auth {
+    /// Checks the `Authorization` header of a request.
+    pub(crate) fn check(
+        &self,
+        method: &str,
+        authorization: Option<&str>,
+        nonces: &Nonces,
+    ) -> Verdict {
+        let verdict = |allowed: bool| {
+            if allowed {
+                Verdict::Allowed
+            } else {
+                Verdict::Denied { stale: false }
+            }
+        };
+        match self {
+            Auth::None => Verdict::Allowed,
+            Auth::Bearer(token) => verdict(
+                authorization
+                    .and_then(|header| strip_scheme(header, "Bearer"))
+                    .map_or(false, |provided| provided.trim() == token),
+            ),
+            Auth::Basic { username, password } => verdict(
+                authorization
+                    .and_then(|header| strip_scheme(header, "Basic"))
+                    .and_then(|encoded| base64::decode(encoded.trim()).ok())
+                    .map_or(false, |decoded| {
+                        decoded == format!("{}:{}", username, password).as_bytes()
+                    }),
+            ),
+            Auth::Digest { username, password } => {
+                let params = authorization
+                    .and_then(|header| strip_scheme(header, "Digest"))
+                    .map(parse_params)
+                    .unwrap_or_default();
+                if check_digest(username, password, method, &params, &nonces.current) {
+                    return Verdict::Allowed;
+                }
+                // a correct response to an old challenge means that the client should try again
+                // with the current nonce
+                let stale = params.get("nonce").map_or(false, |nonce| {
+                    nonces.expired.contains(nonce)
+                        && check_digest(username, password, method, &params, nonce)
+                });
+                Verdict::Denied { stale }
+            }
+        }
+    }
+
+    /// The `WWW-Authenticate` header which is sent with "401 Unauthorized" responses.
+    pub(crate) fn challenge(&self, nonce: &str, stale: bool) -> Option<String> {
+        match self {
+            Auth::None => None,
+            Auth::Bearer(_) => Some(format!("Bearer realm=\"{}\"", REALM)),
+            Auth::Basic { .. } => Some(format!("Basic realm=\"{}\"", REALM)),
+            Auth::Digest { .. } => Some(format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                REALM,
+                nonce,
+                if stale { ", stale=true" } else { "" }
+            )),
+        }
+    }
+}
+
+/// Removes the authentication scheme (which is case-insensitive) from the start of a header.
+fn strip_scheme<'a>(header: &'a str, scheme: &str) -> Option<&'a str> {
+    let header = header.trim_start();
+    match header.get(..scheme.len()) {
+        Some(start) if start.eq_ignore_ascii_case(scheme) => Some(&header[scheme.len()..]),
+        _ => None,
+    }
+}
+
+/// Parses the comma-separated `key=value` (or `key="value"`) parameters of a digest header.
+pub(crate) fn parse_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+    while let Some(equals) = rest.find('=') {
+        let key = rest[..equals]
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        rest = rest[equals + 1..].trim_start();
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or_else(|| quoted.len());
+            let value = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or_default();
+            value
+        } else {
+            let end = rest.find(',').unwrap_or_else(|| rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        parsed.insert(key, value.trim().to_string());
+    }
+    parsed
+}
+
+fn md5_hex(text: &str) -> String {
+    format!("{:x}", md5::compute(text))
+}
+
+/// Checks the response to a digest challenge.
+fn check_digest(
+    username: &str,
+    password: &str,
+    method: &str,
+    params: &HashMap<String, String>,
+    nonce: &str,
+) -> bool {
+    let param = |name: &'static str| params.get(name).map(String::as_str);
+    if param("username") != Some(username)
+        || param("realm") != Some(REALM)
+        || param("nonce") != Some(nonce)
+    {
+        return false;
+    }
+    let (uri, response) = match (param("uri"), param("response")) {
+        (Some(uri), Some(response)) => (uri, response),
+        _ => return false,
+    };
+    let ha1 = md5_hex(&format!("{}:{}:{}", username, REALM, password));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    let expected = match (param("qop"), param("nc"), param("cnonce")) {
+        (Some(qop), Some(nc), Some(cnonce)) => md5_hex(&format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1, nonce, nc, cnonce, qop, ha2
+        )),
+        (None, _, _) => md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)),
+        _ => return false,
+    };
+    expected.eq_ignore_ascii_case(response)
+}
+
+#[cfg(test)]
+mod test_auth {
+    use super::{check_digest, parse_params, Auth, Nonces, Verdict};
+
+    #[test]
+    fn test_digest_responses_are_checked() {
+        // adapted from the example in RFC 2617 (section 3.5), with our realm instead
+        let params = parse_params(
+            "username=\"Mufasa\", realm=\"ariel\", nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+            uri=\"/dir/index.html\", qop=auth, nc=00000001, cnonce=\"0a4f113b\", \
+            response=\"7875fc30fd2d47c0e8f186b32c1b09d8\"",
+        );
+        assert_eq!(params["uri"], "/dir/index.html");
+        assert_eq!(params["qop"], "auth");
+        let nonce = "dcd98b7102dd2f0e8b11d0f600bfb0c093";
+        assert!(check_digest(
+            "Mufasa",
+            "Circle Of Life",
+            "GET",
+            &params,
+            nonce
+        ));
+        assert!(!check_digest("Mufasa", "wrong", "GET", &params, nonce));
+        assert!(!check_digest(
+            "Mufasa",
+            "Circle Of Life",
+            "PUT",
+            &params,
+            nonce
+        ));
+        assert!(!check_digest(
+            "Mufasa",
+            "Circle Of Life",
+            "GET",
+            &params,
+            "another-nonce"
+        ));
+    }
+
+    #[test]
+    fn test_bearer_tokens_are_checked() {
+        let nonces = Nonces::new();
+        let auth = Auth::Bearer("some-token".to_string());
+        let allowed = |header| auth.check("GET", header, &nonces) == Verdict::Allowed;
+        assert!(allowed(Some("Bearer some-token")));
+        assert!(!allowed(Some("Bearer another-token")));
+        assert!(!allowed(None));
+        assert_eq!(Auth::None.check("GET", None, &nonces), Verdict::Allowed);
+    }
+
+    #[test]
+    fn test_basic_credentials_are_checked() {
+        let nonces = Nonces::new();
+        let auth = Auth::Basic {
+            username: "Aladdin".to_string(),
+            password: "open sesame".to_string(),
+        };
+        // the example from RFC 7617 (section 2)
+        assert_eq!(
+            auth.check("GET", Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="), &nonces),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            auth.check("GET", Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ"), &nonces),
+            Verdict::Denied { stale: false }
+        );
+    }
+
+    #[test]
+    fn test_expired_nonces_are_stale() {
+        let auth = Auth::Digest {
+            username: "Mufasa".to_string(),
+            password: "Circle Of Life".to_string(),
+        };
+        let mut nonces = Nonces::new();
+        nonces.current = "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string();
+        let header = "Digest username=\"Mufasa\", realm=\"ariel\", \
+            nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", qop=auth, \
+            nc=00000001, cnonce=\"0a4f113b\", response=\"7875fc30fd2d47c0e8f186b32c1b09d8\"";
+        assert_eq!(auth.check("GET", Some(header), &nonces), Verdict::Allowed);
+        nonces.expire();
+        assert_eq!(
+            auth.check("GET", Some(header), &nonces),
+            Verdict::Denied { stale: true }
+        );
+        assert_eq!(
+            auth.check("PUT", Some(header), &nonces),
+            Verdict::Denied { stale: false }
+        );
+        assert!(auth
+            .challenge(&nonces.current, true)
+            .unwrap()
+            .ends_with("stale=true"));
+    }
+}
@@ -0,0 +1,666 @@
+//! Responds to WebDAV (and CalDAV) requests.
+//!
+//! The server has the same layout as xandikos (with its `--defaults` option): a single user whose
+//! principal is at `/user/`, and whose calendars are inside `/user/calendars/`.
+
+use roxmltree::{Document, Node};
+
+use crate::{
+    filter::Filter,
+    store::{Collection, Store, HOME_SET, PRINCIPAL},
+    xml::{
+        child, element, error, escape, is, multistatus, APPLE_ICAL, CALDAV, CALENDARSERVER, DAV,
+    },
+};
+
+/// The parts of an HTTP request which the server looks at.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DavRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub depth: Option<&'a str>,
+    pub if_match: Option<&'a str>,
+    pub if_none_match: Option<&'a str>,
+    pub body: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DavResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl DavResponse {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    fn xml(status: u16, body: String) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/xml; charset=utf-8".to_string())],
+            body,
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// The properties which are returned when a client asks for all of them (`allprop`).
+const ALL_PROPERTIES: [(&str, &str); 11] = [
+    (DAV, "resourcetype"),
+    (DAV, "displayname"),
+    (DAV, "getetag"),
+    (DAV, "getcontenttype"),
+    (DAV, "sync-token"),
+    (DAV, "current-user-principal"),
+    (CALDAV, "calendar-home-set"),
+    (CALDAV, "supported-calendar-component-set"),
+    (CALDAV, "calendar-description"),
+    (CALENDARSERVER, "getctag"),
+    (APPLE_ICAL, "calendar-color"),
+];
+
+/// The properties which the client asked for (`None` means all of them).
+type Requested = Option<Vec<(String, String)>>;
+
+/// The resources on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Root,
+    /// `/.well-known/caldav` (RFC 6764), which redirects to the principal.
+    WellKnown,
+    Principal,
+    HomeSet,
+    Calendar(String),
+    Object(String, String),
+    Unknown,
+}
+
+impl Target {
+    fn parse(path: &str) -> Self {
+        // hrefs in REPORT requests can be full URLs
+        let path = match path.find("://") {
+            Some(scheme) => {
+                let rest = &path[scheme + 3..];
+                rest.find('/').map_or("/", |start| &rest[start..])
+            }
+            None => path,
+        };
+        let path = path
+            .split(|c| c == '?' || c == '#')
+            .next()
+            .unwrap_or_default();
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+        match segments.as_slice() {
+            [] => Target::Root,
+            [".well-known", "caldav"] => Target::WellKnown,
+            ["user"] => Target::Principal,
+            ["user", "calendars"] => Target::HomeSet,
+            ["user", "calendars", calendar] => Target::Calendar(calendar.to_string()),
+            ["user", "calendars", calendar, object] => {
+                Target::Object(calendar.to_string(), object.to_string())
+            }
+            _ => Target::Unknown,
+        }
+    }
+
+    fn href(&self) -> String {
+        match self {
+            Target::Root => "/".to_string(),
+            Target::WellKnown => "/.well-known/caldav".to_string(),
+            Target::Principal => PRINCIPAL.to_string(),
+            Target::HomeSet => HOME_SET.to_string(),
+            Target::Calendar(calendar) => format!("{}{}/", HOME_SET, calendar),
+            Target::Object(calendar, object) => format!("{}{}/{}", HOME_SET, calendar, object),
+            Target::Unknown => "/".to_string(),
+        }
+    }
+
+    fn exists(&self, store: &Store) -> bool {
+        match self {
+            Target::Root | Target::WellKnown | Target::Principal | Target::HomeSet => true,
+            Target::Calendar(calendar) => store.calendars.contains_key(calendar),
+            Target::Object(calendar, object) => store
+                .calendars
+                .get(calendar)
+                .map_or(false, |collection| collection.objects.contains_key(object)),
+            Target::Unknown => false,
+        }
+    }
+
+    /// The resources which are directly inside this one.
+    fn children(&self, store: &Store) -> Vec<Target> {
+        match self {
+            Target::Root => vec![Target::Principal],
+            Target::Principal => vec![Target::HomeSet],
+            Target::HomeSet => store
+                .calendars
+                .keys()
+                .map(|calendar| Target::Calendar(calendar.clone()))
+                .collect(),
+            Target::Calendar(calendar) => store
+                .calendars
+                .get(calendar)
+                .map(|collection| {
+                    collection
+                        .objects
+                        .keys()
+                        .map(|object| Target::Object(calendar.clone(), object.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Target::WellKnown | Target::Object(_, _) | Target::Unknown => vec![],
+        }
+    }
+}
+
+fn href(href: &str) -> String {
+    element(DAV, "href", &escape(href))
+}
+
+/// Returns the value of a property of a resource (as XML), or `None` if the resource doesn't have
+/// the property.
+fn property(store: &Store, target: &Target, namespace: &str, name: &str) -> Option<String> {
+    let collection = match target {
+        Target::Calendar(calendar) => store.calendars.get(calendar),
+        _ => None,
+    };
+    let object = match target {
+        Target::Object(calendar, object) => store
+            .calendars
+            .get(calendar)
+            .and_then(|collection| collection.objects.get(object)),
+        _ => None,
+    };
+    let collection_text = |value: fn(&Collection) -> &Option<String>| {
+        collection
+            .and_then(|collection| value(collection).as_deref())
+            .map(escape)
+    };
+    match (namespace, name) {
+        (DAV, "resourcetype") => Some(match target {
+            Target::Principal => element(DAV, "collection", "") + &element(DAV, "principal", ""),
+            Target::Calendar(_) => {
+                element(DAV, "collection", "") + &element(CALDAV, "calendar", "")
+            }
+            Target::Object(_, _) => String::new(),
+            _ => element(DAV, "collection", ""),
+        }),
+        (DAV, "current-user-principal") => Some(href(PRINCIPAL)),
+        (DAV, "displayname") => match target {
+            Target::Principal => Some("user".to_string()),
+            _ => collection_text(|collection| &collection.display_name),
+        },
+        (DAV, "getetag") => object.map(|object| escape(&object.etag())),
+        (DAV, "getcontenttype") => object.map(|_| "text/calendar; charset=utf-8".to_string()),
+        (DAV, "sync-token") => collection.map(|collection| escape(&collection.sync_token())),
+        (CALDAV, "calendar-home-set") if *target == Target::Principal => Some(href(HOME_SET)),
+        (CALDAV, "calendar-data") => object.map(|object| escape(&object.data)),
+        (CALDAV, "supported-calendar-component-set") => collection.map(|_| {
+            ["VEVENT", "VTODO"]
+                .iter()
+                .map(|component| format!("<C:comp name=\"{}\"/>", component))
+                .collect()
+        }),
+        (CALDAV, "calendar-description") => collection_text(|collection| &collection.description),
+        (CALDAV, "calendar-timezone") => collection_text(|collection| &collection.timezone),
+        (CALENDARSERVER, "getctag") => collection.map(|collection| escape(&collection.ctag())),
+        (APPLE_ICAL, "calendar-color") => collection_text(|collection| &collection.colour),
+        _ => None,
+    }
+}
+
+/// Writes out a `response` element which contains the requested properties of a resource.
+fn properties_response(store: &Store, target: &Target, requested: &Requested) -> String {
+    let (mut found, mut missing) = (String::new(), String::new());
+    match requested {
+        Some(requested) => {
+            for (namespace, name) in requested {
+                match property(store, target, namespace, name) {
+                    Some(value) => found.push_str(&element(namespace, name, &value)),
+                    None => missing.push_str(&element(namespace, name, "")),
+                }
+            }
+        }
+        None => {
+            for (namespace, name) in ALL_PROPERTIES.iter() {
+                if let Some(value) = property(store, target, namespace, name) {
+                    found.push_str(&element(namespace, name, &value));
+                }
+            }
+        }
+    }
+    let propstat = |props: &str, status: &str| {
+        element(
+            DAV,
+            "propstat",
+            &(element(DAV, "prop", props) + &element(DAV, "status", status)),
+        )
+    };
+    let mut content = href(&target.href());
+    if !found.is_empty() || missing.is_empty() {
+        content.push_str(&propstat(&found, "HTTP/1.1 200 OK"));
+    }
+    if !missing.is_empty() {
+        content.push_str(&propstat(&missing, "HTTP/1.1 404 Not Found"));
+    }
+    element(DAV, "response", &content)
+}
+
+/// Writes out a `response` element for a resource which doesn't exist.
+fn not_found_response(href_value: &str) -> String {
+    element(
+        DAV,
+        "response",
+        &(href(href_value) + &element(DAV, "status", "HTTP/1.1 404 Not Found")),
+    )
+}
+
+/// Reads the names of the properties inside a `prop` element.
+fn requested_properties(prop: Node) -> Vec<(String, String)> {
+    prop.children()
+        .filter(|node| node.is_element())
+        .map(|node| {
+            (
+                node.tag_name().namespace().unwrap_or_default().to_string(),
+                node.tag_name().name().to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Returns the text inside a node (including the text inside any of its children).
+fn text(node: Node) -> String {
+    node.descendants()
+        .filter(|node| node.is_text())
+        .filter_map(|node| node.text())
+        .collect()
+}
+
+fn bad_request() -> DavResponse {
+    DavResponse::new(400)
+}
+
+/// Responds to a request (which has already been authenticated).
+pub(crate) fn respond(store: &mut Store, request: &DavRequest) -> DavResponse {
+    let target = Target::parse(request.path);
+    match request.method {
+        "OPTIONS" => DavResponse::new(200)
+            .header("DAV", "1, 3, calendar-access".to_string())
+            .header(
+                "Allow",
+                "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT, MKCALENDAR".to_string(),
+            ),
+        "GET" | "HEAD" => get(store, &target),
+        "PROPFIND" => propfind(store, &target, request),
+        "REPORT" => report(store, &target, request),
+        "PUT" => put(store, &target, request),
+        "DELETE" => delete(store, &target, request),
+        "MKCALENDAR" => mkcalendar(store, &target, request),
+        _ => DavResponse::new(405),
+    }
+}
+
+fn get(store: &Store, target: &Target) -> DavResponse {
+    match target {
+        Target::WellKnown => DavResponse::new(301).header("Location", PRINCIPAL.to_string()),
+        Target::Object(calendar, object) => match store
+            .calendars
+            .get(calendar)
+            .and_then(|collection| collection.objects.get(object))
+        {
+            Some(object) => DavResponse {
+                status: 200,
+                headers: vec![
+                    ("Content-Type", "text/calendar; charset=utf-8".to_string()),
+                    ("ETag", object.etag()),
+                ],
+                body: object.data.clone(),
+            },
+            None => DavResponse::new(404),
+        },
+        target if target.exists(store) => DavResponse::new(200),
+        _ => DavResponse::new(404),
+    }
+}
+
+fn propfind(store: &Store, target: &Target, request: &DavRequest) -> DavResponse {
+    if !target.exists(store) {
+        return DavResponse::new(404);
+    }
+    let requested = if request.body.trim().is_empty() {
+        None
+    } else {
+        let document = match Document::parse(request.body) {
+            Ok(document) => document,
+            Err(_) => return bad_request(),
+        };
+        child(document.root_element(), DAV, "prop").map(requested_properties)
+    };
+    let mut targets = vec![target.clone()];
+    // we don't support infinite depth, so that is treated in the same way as a depth of one
+    if request.depth != Some("0") {
+        targets.extend(target.children(store));
+    }
+    let responses = targets
+        .iter()
+        .map(|target| properties_response(store, target, &requested))
+        .collect::<String>();
+    DavResponse::xml(207, multistatus(&responses, ""))
+}
+
+fn report(store: &Store, target: &Target, request: &DavRequest) -> DavResponse {
+    let document = match Document::parse(request.body) {
+        Ok(document) => document,
+        Err(_) => return bad_request(),
+    };
+    let root = document.root_element();
+    let requested = child(root, DAV, "prop").map(requested_properties);
+    if is(&root, CALDAV, "calendar-query") {
+        let filter = match child(root, CALDAV, "filter").map(Filter::parse) {
+            Some(Some(filter)) => filter,
+            Some(None) => return DavResponse::xml(403, error(CALDAV, "valid-filter")),
+            None => Filter::default(),
+        };
+        // clients don't always send a `Depth` header with this report, so we always search the
+        // objects in a calendar
+        let targets = match target {
+            Target::Calendar(_) => target.children(store),
+            Target::Object(_, _) if target.exists(store) => vec![target.clone()],
+            _ => return DavResponse::new(404),
+        };
+        let responses = targets
+            .iter()
+            .filter(|target| match target {
+                Target::Object(calendar, object) => {
+                    filter.matches(&store.calendars[calendar].objects[object].data)
+                }
+                _ => false,
+            })
+            .map(|target| properties_response(store, target, &requested))
+            .collect::<String>();
+        DavResponse::xml(207, multistatus(&responses, ""))
+    } else if is(&root, CALDAV, "calendar-multiget") {
+        let responses = root
+            .children()
+            .filter(|node| is(node, DAV, "href"))
+            .map(|node| {
+                let href_value = text(node);
+                let target = Target::parse(href_value.trim());
+                match target {
+                    Target::Object(_, _) if target.exists(store) => {
+                        properties_response(store, &target, &requested)
+                    }
+                    _ => not_found_response(href_value.trim()),
+                }
+            })
+            .collect::<String>();
+        DavResponse::xml(207, multistatus(&responses, ""))
+    } else if is(&root, DAV, "sync-collection") {
+        let (calendar, collection) = match target {
+            Target::Calendar(calendar) => match store.calendars.get(calendar) {
+                Some(collection) => (calendar, collection),
+                None => return DavResponse::new(404),
+            },
+            _ => return DavResponse::xml(403, error(DAV, "supported-report")),
+        };
+        let token = child(root, DAV, "sync-token").map(text).unwrap_or_default();
+        let changes = match collection.changes_since(token.trim()) {
+            Some(changes) => changes,
+            None => return DavResponse::xml(403, error(DAV, "valid-sync-token")),
+        };
+        let mut responses = changes
+            .changed
+            .iter()
+            .map(|(object, _)| {
+                let target = Target::Object(calendar.clone(), object.to_string());
+                properties_response(store, &target, &requested)
+            })
+            .collect::<String>();
+        for object in changes.removed {
+            responses.push_str(&not_found_response(
+                &Target::Object(calendar.clone(), object.clone()).href(),
+            ));
+        }
+        DavResponse::xml(
+            207,
+            multistatus(
+                &responses,
+                &element(DAV, "sync-token", &escape(&collection.sync_token())),
+            ),
+        )
+    } else {
+        DavResponse::xml(403, error(DAV, "supported-report"))
+    }
+}
+
+/// Checks the `If-Match` and `If-None-Match` headers against the current ETag of a resource
+/// (`None` if the resource doesn't exist).
+fn preconditions_hold(request: &DavRequest, etag: Option<&str>) -> bool {
+    let matches = |header: &str| {
+        header
+            .split(',')
+            .map(str::trim)
+            .any(|value| (value == "*" && etag.is_some()) || Some(value) == etag)
+    };
+    request.if_match.map_or(true, matches) && !request.if_none_match.map_or(false, matches)
+}
+
+fn put(store: &mut Store, target: &Target, request: &DavRequest) -> DavResponse {
+    let (calendar, object) = match target {
+        Target::Object(calendar, object) => (calendar, object),
+        Target::Unknown => return DavResponse::new(409),
+        _ => return DavResponse::new(405),
+    };
+    let collection = match store.calendars.get(calendar) {
+        Some(collection) => collection,
+        None => return DavResponse::new(409),
+    };
+    let existing = collection.objects.get(object).map(|object| object.etag());
+    if !preconditions_hold(request, existing.as_deref()) {
+        return DavResponse::new(412);
+    }
+    if !matches!(
+        ical::IcalParser::new(request.body.as_bytes()).next(),
+        Some(Ok(_))
+    ) {
+        return DavResponse::xml(403, error(CALDAV, "valid-calendar-data"));
+    }
+    match store.put_object(calendar, object, request.body.to_string()) {
+        Some(etag) => {
+            DavResponse::new(if existing.is_some() { 204 } else { 201 }).header("ETag", etag)
+        }
+        None => DavResponse::new(409),
+    }
+}
+
+fn delete(store: &mut Store, target: &Target, request: &DavRequest) -> DavResponse {
+    match target {
+        Target::Object(calendar, object) => {
+            let existing = store
+                .calendars
+                .get(calendar)
+                .and_then(|collection| collection.objects.get(object))
+                .map(|object| object.etag());
+            if existing.is_none() {
+                return DavResponse::new(404);
+            }
+            if !preconditions_hold(request, existing.as_deref()) {
+                return DavResponse::new(412);
+            }
+            store.delete_object(calendar, object);
+            DavResponse::new(204)
+        }
+        Target::Calendar(calendar) => {
+            if store.delete_calendar(calendar) {
+                DavResponse::new(204)
+            } else {
+                DavResponse::new(404)
+            }
+        }
+        Target::Unknown => DavResponse::new(404),
+        _ => DavResponse::new(405),
+    }
+}
+
+fn mkcalendar(store: &mut Store, target: &Target, request: &DavRequest) -> DavResponse {
+    let calendar = match target {
+        Target::Calendar(calendar) => calendar,
+        Target::Unknown => return DavResponse::new(409),
+        _ => return DavResponse::new(405),
+    };
+    if target.exists(store) {
+        return DavResponse::new(405);
+    }
+    let mut collection = Collection::default();
+    if !request.body.trim().is_empty() {
+        let document = match Document::parse(request.body) {
+            Ok(document) => document,
+            Err(_) => return bad_request(),
+        };
+        let props = child(document.root_element(), DAV, "set")
+            .and_then(|set| child(set, DAV, "prop"))
+            .into_iter()
+            .flat_map(|prop| prop.children())
+            .filter(|node| node.is_element());
+        for prop in props {
+            let value = Some(text(prop).trim().to_string());
+            if is(&prop, DAV, "displayname") {
+                collection.display_name = value;
+            } else if is(&prop, CALDAV, "calendar-description") {
+                collection.description = value;
+            } else if is(&prop, APPLE_ICAL, "calendar-color") {
+                collection.colour = value;
+            } else if is(&prop, CALDAV, "calendar-timezone") {
+                collection.timezone = value;
+            }
+        }
+    }
+    store.create_calendar(calendar, collection);
+    DavResponse::new(201)
+}
+
+#[cfg(test)]
+mod test_dav {
+    use super::{respond, DavRequest, Target};
+    use crate::store::Store;
+
+    fn request<'a>(method: &'a str, path: &'a str, body: &'a str) -> DavRequest<'a> {
+        DavRequest {
+            method,
+            path,
+            depth: Some("1"),
+            if_match: None,
+            if_none_match: None,
+            body,
+        }
+    }
+
+    const EVENT: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:some-uid\r\n\
+        DTSTART:20210302T090000Z\r\nDTEND:20210302T100000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(
+            Target::parse("http://localhost:1234/user/calendars/calendar//a.ics"),
+            Target::Object("calendar".to_string(), "a.ics".to_string())
+        );
+        assert_eq!(
+            Target::parse("/user/calendars/calendar"),
+            Target::Calendar("calendar".to_string())
+        );
+        assert_eq!(Target::parse("/somewhere/else"), Target::Unknown);
+    }
+
+    #[test]
+    fn test_put_respects_preconditions() {
+        let mut store = Store::new();
+        let path = "/user/calendars/calendar/some-uid.ics";
+        let created = respond(
+            &mut store,
+            &DavRequest {
+                if_none_match: Some("*"),
+                ..request("PUT", path, EVENT)
+            },
+        );
+        assert_eq!(created.status, 201);
+        let (_, etag) = created
+            .headers
+            .iter()
+            .find(|(name, _)| *name == "ETag")
+            .unwrap();
+        let again = DavRequest {
+            if_none_match: Some("*"),
+            ..request("PUT", path, EVENT)
+        };
+        assert_eq!(respond(&mut store, &again).status, 412);
+        let stale = DavRequest {
+            if_match: Some("\"not-the-etag\""),
+            ..request("PUT", path, EVENT)
+        };
+        assert_eq!(respond(&mut store, &stale).status, 412);
+        let update = DavRequest {
+            if_match: Some(etag.as_str()),
+            ..request("PUT", path, EVENT)
+        };
+        assert_eq!(respond(&mut store, &update).status, 204);
+        let invalid = request(
+            "PUT",
+            "/user/calendars/calendar/invalid.ics",
+            "not a calendar",
+        );
+        assert_eq!(respond(&mut store, &invalid).status, 403);
+        let no_calendar = request("PUT", "/user/calendars/missing/some-uid.ics", EVENT);
+        assert_eq!(respond(&mut store, &no_calendar).status, 409);
+    }
+
+    #[test]
+    fn test_calendar_query() {
+        let mut store = Store::new();
+        respond(
+            &mut store,
+            &request("PUT", "/user/calendars/calendar/some-uid.ics", EVENT),
+        );
+        let query = |start: &str, end: &str| {
+            format!(
+                r#"<?xml version="1.0" encoding="utf-8" ?>
+                <C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+                    <D:prop><D:getetag/><C:calendar-data/></D:prop>
+                    <C:filter>
+                        <C:comp-filter name="VCALENDAR">
+                            <C:comp-filter name="VEVENT">
+                                <C:time-range start="{}" end="{}"/>
+                            </C:comp-filter>
+                        </C:comp-filter>
+                    </C:filter>
+                </C:calendar-query>"#,
+                start, end
+            )
+        };
+        let body = query("20210301T000000Z", "20210303T000000Z");
+        let response = respond(
+            &mut store,
+            &request("REPORT", "/user/calendars/calendar", &body),
+        );
+        assert_eq!(response.status, 207);
+        assert!(response.body.contains("UID:some-uid"));
+        let body = query("20210303T000000Z", "20210304T000000Z");
+        let response = respond(
+            &mut store,
+            &request("REPORT", "/user/calendars/calendar", &body),
+        );
+        assert!(!response.body.contains("UID:some-uid"));
+    }
+}
@@ -0,0 +1,243 @@
+//! Decides which calendar objects match the filter in a `calendar-query` REPORT (RFC 4791,
+//! section 9.7).
+//!
+//! Only the parts of the filter which clients commonly use are supported: the type of component,
+//! a time range, and matching text in a property (e.g. looking an event up by its UID). Errors are
+//! made on the side of returning too much – recurring events always match a time range (clients
+//! have to work out when the occurrences happen anyway) and floating times match if they could be
+//! in the time range in any timezone.
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use ical::{parser::ical::component::IcalCalendar, property::Property};
+use roxmltree::Node;
+
+use crate::xml::{child, is, CALDAV};
+
+const DATETIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const LOCAL_DATETIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+const DATE_FORMAT: &str = "%Y%m%d";
+
+/// The largest difference between local time and UTC (so that floating times can be compared).
+fn largest_offset() -> Duration {
+    Duration::hours(14)
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filter {
+    /// The type of component (e.g. `VEVENT`) which has to match.
+    component: Option<String>,
+    time_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// Properties (and the text which they have to contain).
+    properties: Vec<(String, String)>,
+}
+
+fn parse_utc(value: &str) -> Option<DateTime<Utc>> {
+    Utc.datetime_from_str(value.trim(), DATETIME_FORMAT).ok()
+}
+
+impl Filter {
+    /// Reads a `filter` element. Returns `None` if the filter contains a time range which can't be
+    /// parsed.
+    pub fn parse(filter: Node) -> Option<Self> {
+        let mut parsed = Self::default();
+        // the filter we care about is the innermost `comp-filter`
+        let mut comp_filter = child(filter, CALDAV, "comp-filter");
+        while let Some(node) = comp_filter {
+            parsed.component = node.attribute("name").map(str::to_ascii_uppercase);
+            if let Some(range) = child(node, CALDAV, "time-range") {
+                let start = match range.attribute("start") {
+                    Some(start) => parse_utc(start)?,
+                    None => chrono::MIN_DATETIME,
+                };
+                let end = match range.attribute("end") {
+                    Some(end) => parse_utc(end)?,
+                    None => chrono::MAX_DATETIME,
+                };
+                parsed.time_range = Some((start, end));
+            }
+            parsed.properties = node
+                .children()
+                .filter(|node| is(node, CALDAV, "prop-filter"))
+                .filter_map(|prop_filter| {
+                    let name = prop_filter.attribute("name")?.to_ascii_uppercase();
+                    let text = child(prop_filter, CALDAV, "text-match")
+                        .and_then(|node| node.text())
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string();
+                    Some((name, text))
+                })
+                .collect();
+            comp_filter = child(node, CALDAV, "comp-filter");
+        }
+        Some(parsed)
+    }
+
+    /// Whether the iCalendar data matches the filter.
+    pub fn matches(&self, data: &str) -> bool {
+        let calendar = match ical::IcalParser::new(data.as_bytes()).next() {
+            Some(Ok(calendar)) => calendar,
+            _ => return false,
+        };
+        match self.component.as_deref() {
+            None | Some("VCALENDAR") => true,
+            Some(component) => components(&calendar, component)
+                .iter()
+                .any(|properties| self.component_matches(properties)),
+        }
+    }
+
+    fn component_matches(&self, properties: &[Property]) -> bool {
+        let has_text = |name: &str, text: &str| {
+            properties
+                .iter()
+                .filter(|property| property.name == name)
+                .filter_map(|property| property.value.as_deref())
+                .any(|value| value.contains(text))
+        };
+        self.properties
+            .iter()
+            .all(|(name, text)| has_text(name, text))
+            && self
+                .time_range
+                .map_or(true, |(start, end)| overlaps(properties, start, end))
+    }
+}
+
+/// Returns the properties of every component of the provided type.
+fn components<'a>(calendar: &'a IcalCalendar, component: &str) -> Vec<&'a [Property]> {
+    match component {
+        "VEVENT" => calendar
+            .events
+            .iter()
+            .map(|event| event.properties.as_slice())
+            .collect(),
+        "VTODO" => calendar
+            .todos
+            .iter()
+            .map(|todo| todo.properties.as_slice())
+            .collect(),
+        "VJOURNAL" => calendar
+            .journals
+            .iter()
+            .map(|journal| journal.properties.as_slice())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// Works out the earliest and latest points in time which a date or time could refer to.
+fn bounds(property: &Property) -> Option<(DateTime<Utc>, DateTime<Utc>, bool)> {
+    let value = property.value.as_deref()?.trim();
+    if let Some(time) = parse_utc(value) {
+        return Some((time, time, false));
+    }
+    let (local, is_date) = match NaiveDate::parse_from_str(value, DATE_FORMAT) {
+        Ok(date) => (date.and_hms(0, 0, 0), true),
+        Err(_) => (
+            NaiveDateTime::parse_from_str(value, LOCAL_DATETIME_FORMAT).ok()?,
+            false,
+        ),
+    };
+    let tz = param(property, "TZID").and_then(|tzid| tzid.parse::<Tz>().ok());
+    if let Some(time) = tz.and_then(|tz| tz.from_local_datetime(&local).earliest()) {
+        let time = time.with_timezone(&Utc);
+        return Some((time, time, is_date));
+    }
+    let time = Utc.from_utc_datetime(&local);
+    Some((time - largest_offset(), time + largest_offset(), is_date))
+}
+
+/// Whether the component could happen at some point between `start` and `end`.
+fn overlaps(properties: &[Property], start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    let property = |name: &'static str| properties.iter().find(|property| property.name == name);
+    if property("RRULE").is_some() || property("RDATE").is_some() {
+        return true;
+    }
+    let (earliest_start, latest_start, is_date) = match property("DTSTART").and_then(bounds) {
+        Some(bounds) => bounds,
+        // components without a start (e.g. some tasks) always match
+        None => return true,
+    };
+    let latest_end = match property("DTEND")
+        .or_else(|| property("DUE"))
+        .and_then(bounds)
+    {
+        Some((_, latest_end, _)) => latest_end,
+        // we don't parse durations, so we assume that the event could last for up to a week
+        None if property("DURATION").is_some() => latest_start + Duration::weeks(1),
+        None if is_date => latest_start + Duration::days(1),
+        None => latest_start,
+    };
+    earliest_start < end && (latest_end > start || latest_start >= start)
+}
+
+#[cfg(test)]
+mod test_filter {
+    use chrono::{TimeZone, Utc};
+
+    use super::Filter;
+
+    fn event(properties: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:some-uid\r\n{}\r\n\
+            END:VEVENT\r\nEND:VCALENDAR\r\n",
+            properties
+        )
+    }
+
+    fn filter(start: u32, end: u32) -> Filter {
+        Filter {
+            component: Some("VEVENT".to_string()),
+            time_range: Some((
+                Utc.ymd(2021, 3, start).and_hms(0, 0, 0),
+                Utc.ymd(2021, 3, end).and_hms(0, 0, 0),
+            )),
+            properties: vec![],
+        }
+    }
+
+    #[test]
+    fn test_time_ranges() {
+        let utc = event("DTSTART:20210302T090000Z\r\nDTEND:20210302T100000Z");
+        assert!(filter(1, 3).matches(&utc));
+        assert!(!filter(3, 4).matches(&utc));
+        let london = event(
+            "DTSTART;TZID=Europe/London:20210302T090000\r\n\
+            DTEND;TZID=Europe/London:20210302T100000",
+        );
+        assert!(filter(2, 3).matches(&london));
+        assert!(!filter(5, 6).matches(&london));
+        let all_day = event("DTSTART;VALUE=DATE:20210305");
+        assert!(filter(5, 6).matches(&all_day));
+        assert!(!filter(7, 8).matches(&all_day));
+        let recurring = event("DTSTART:20210101T090000Z\r\nRRULE:FREQ=WEEKLY");
+        assert!(filter(7, 8).matches(&recurring));
+    }
+
+    #[test]
+    fn test_text_matches() {
+        let filter = Filter {
+            component: Some("VEVENT".to_string()),
+            time_range: None,
+            properties: vec![("UID".to_string(), "some-uid".to_string())],
+        };
+        assert!(filter.matches(&event("DTSTART:20210302T090000Z")));
+        let filter = Filter {
+            properties: vec![("UID".to_string(), "another-uid".to_string())],
+            ..filter
+        };
+        assert!(!filter.matches(&event("DTSTART:20210302T090000Z")));
+    }
+}
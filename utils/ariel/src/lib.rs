@@ -0,0 +1,266 @@
+//! Ariel is a small, in-memory CalDAV server, which tests can use to check that calendar code
+//! works without needing a real CalDAV server (or a network connection).
+//!
+//! ```ignore
+//! let server = ariel::TestServer::start().await;
+//! let client = prospero::client::DavClient::new_unauthenticated(server.calendar_url());
+//! ```
+//!
+//! Each server listens on its own (ephemeral) port on localhost, so tests which run at the same
+//! time don't see each other's events, and is shut down when it is dropped. The server supports
+//! the parts of WebDAV and CalDAV which Lovelace uses – PROPFIND, the `calendar-query`,
+//! `calendar-multiget` and `sync-collection` REPORTs, GET, PUT, DELETE and MKCALENDAR (along with
+//! ETags, ctags and sync tokens) – and can require basic, digest or bearer authentication.
+
+#![deny(missing_debug_implementations)]
+
+mod auth;
+mod dav;
+mod filter;
+mod store;
+mod xml;
+
+pub use auth::Auth;
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    auth::{Nonces, Verdict},
+    dav::DavRequest,
+    store::{Collection, Store, DEFAULT_CALENDAR, HOME_SET, PRINCIPAL},
+};
+
+/// The state which is shared between the server and the `TestServer` which controls it.
+#[derive(Debug)]
+struct Shared {
+    store: Mutex<Store>,
+    auth: Auth,
+    nonces: Mutex<Nonces>,
+    /// How many "401 Unauthorized" responses have been sent.
+    challenges: AtomicUsize,
+    /// Whether responses to PUT requests leave out the new ETag.
+    hide_put_etags: AtomicBool,
+}
+
+/// A running CalDAV server.
+///
+/// When it starts, the server contains a single (empty) calendar – its URL is returned by
+/// `calendar_url`.
+#[derive(Debug)]
+pub struct TestServer {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    /// Starts a server which doesn't require authentication.
+    ///
+    /// Note that this has to be called from inside a Tokio runtime (e.g. in a `#[tokio::test]` or
+    /// a `#[rocket::async_test]`).
+    pub async fn start() -> Self {
+        Self::start_with_auth(Auth::None).await
+    }
+
+    /// Starts a server which requires clients to authenticate in the provided way.
+    pub async fn start_with_auth(auth: Auth) -> Self {
+        let shared = Arc::new(Shared {
+            store: Mutex::new(Store::new()),
+            auth,
+            nonces: Mutex::new(Nonces::new()),
+            challenges: AtomicUsize::new(0),
+            hide_put_etags: AtomicBool::new(false),
+        });
+        let service_state = shared.clone();
+        let make_service = make_service_fn(move |_| {
+            let shared = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(shared.clone(), request)))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        let (shutdown, receiver) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            receiver.await.ok();
+        });
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("the CalDAV test server failed: {}", e);
+            }
+        });
+        Self {
+            address,
+            shared,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    /// The URL of the server (e.g. `http://127.0.0.1:41235`).
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// The URL of the user's principal.
+    pub fn principal_url(&self) -> String {
+        format!("{}{}", self.url(), PRINCIPAL)
+    }
+
+    /// The URL of the collection which contains the user's calendars.
+    pub fn home_set_url(&self) -> String {
+        format!("{}{}", self.url(), HOME_SET)
+    }
+
+    /// The URL of the calendar which exists when the server starts.
+    pub fn calendar_url(&self) -> String {
+        self.calendar_url_of(DEFAULT_CALENDAR)
+    }
+
+    /// The URL of the calendar with the provided name.
+    pub fn calendar_url_of(&self, name: &str) -> String {
+        format!("{}{}{}", self.url(), HOME_SET, name)
+    }
+
+    /// Creates an empty calendar with the provided name (if there isn't already a calendar with
+    /// that name), and returns its URL.
+    pub fn add_calendar(&self, name: &str) -> String {
+        self.shared
+            .store
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .create_calendar(
+                name,
+                Collection {
+                    display_name: Some(name.to_string()),
+                    ..Default::default()
+                },
+            );
+        self.calendar_url_of(name)
+    }
+
+    /// Expires the nonce which is used in digest challenges, so that requests which use it are
+    /// rejected as "stale" (which is what servers do every so often).
+    pub fn expire_nonce(&self) {
+        self.shared
+            .nonces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .expire();
+    }
+
+    /// Stops telling clients the new ETag of the objects which they PUT (which servers do when
+    /// they change the object before storing it), so that they have to fetch it themselves.
+    pub fn hide_put_etags(&self) {
+        self.shared.hide_put_etags.store(true, Ordering::SeqCst);
+    }
+
+    /// The number of "401 Unauthorized" responses which the server has sent.
+    pub fn challenges(&self) -> usize {
+        self.shared.challenges.load(Ordering::SeqCst)
+    }
+
+    /// Returns the iCalendar data of every object in the calendar with the provided name.
+    pub fn objects(&self, name: &str) -> Vec<String> {
+        self.shared
+            .store
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .calendars
+            .get(name)
+            .map(|collection| {
+                collection
+                    .objects
+                    .values()
+                    .map(|object| object.data.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+async fn handle(shared: Arc<Shared>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let method = request.method().as_str().to_string();
+    let path = request.uri().path().to_string();
+    let authorization = header("Authorization");
+    let depth = header("Depth");
+    let if_match = header("If-Match");
+    let if_none_match = header("If-None-Match");
+
+    let (verdict, challenge) = {
+        let nonces = shared.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        let verdict = shared
+            .auth
+            .check(&method, authorization.as_deref(), &nonces);
+        let stale = verdict == Verdict::Denied { stale: true };
+        (verdict, shared.auth.challenge(&nonces.current, stale))
+    };
+    if verdict != Verdict::Allowed {
+        shared.challenges.fetch_add(1, Ordering::SeqCst);
+        let mut response = Response::builder().status(401);
+        if let Some(challenge) = challenge {
+            response = response.header("WWW-Authenticate", challenge);
+        }
+        return Ok(response
+            .body(Body::empty())
+            .expect("failed to build a response"));
+    }
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(_) => {
+            return Ok(Response::builder()
+                .status(400)
+                .body(Body::empty())
+                .expect("failed to build a response"))
+        }
+    };
+    let response = dav::respond(
+        &mut shared.store.lock().unwrap_or_else(PoisonError::into_inner),
+        &DavRequest {
+            method: &method,
+            path: &path,
+            depth: depth.as_deref(),
+            if_match: if_match.as_deref(),
+            if_none_match: if_none_match.as_deref(),
+            body: &body,
+        },
+    );
+    let hide_etag = method == "PUT" && shared.hide_put_etags.load(Ordering::SeqCst);
+    let mut builder = Response::builder().status(response.status);
+    for (name, value) in response.headers {
+        if hide_etag && name == "ETag" {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    Ok(builder
+        .body(Body::from(response.body))
+        .expect("failed to build a response"))
+}
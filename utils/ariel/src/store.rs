@@ -0,0 +1,196 @@
+//! The calendars (and the calendar objects inside them) which the server stores.
+//!
+//! Every change to the store is given a new version number, which is used for ETags, ctags and
+//! sync tokens.
+
+use std::collections::BTreeMap;
+
+/// The URL of the (only) user's principal.
+pub(crate) const PRINCIPAL: &str = "/user/";
+/// The URL of the collection which contains the user's calendars.
+pub(crate) const HOME_SET: &str = "/user/calendars/";
+/// The name of the calendar which exists when the server starts.
+pub(crate) const DEFAULT_CALENDAR: &str = "calendar";
+
+const SYNC_TOKEN_PREFIX: &str = "http://ariel/sync/";
+
+/// A resource inside a calendar (which usually contains a single event).
+#[derive(Debug, Clone)]
+pub(crate) struct Object {
+    pub data: String,
+    /// The version at which the object was last changed.
+    pub version: u64,
+}
+
+impl Object {
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
+}
+
+/// A calendar collection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Collection {
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub colour: Option<String>,
+    pub timezone: Option<String>,
+    pub objects: BTreeMap<String, Object>,
+    /// The objects which have been deleted, along with the version at which they were deleted
+    /// (so that they can be reported by `sync-collection`).
+    pub deleted: BTreeMap<String, u64>,
+    /// The version at which the collection was created.
+    pub created: u64,
+    /// The version at which the collection (or anything in it) was last changed.
+    pub version: u64,
+}
+
+/// The changes to a collection since a sync token.
+#[derive(Debug)]
+pub(crate) struct Changes<'a> {
+    pub changed: Vec<(&'a String, &'a Object)>,
+    pub removed: Vec<&'a String>,
+}
+
+impl Collection {
+    pub fn ctag(&self) -> String {
+        self.version.to_string()
+    }
+
+    pub fn sync_token(&self) -> String {
+        format!("{}{}", SYNC_TOKEN_PREFIX, self.version)
+    }
+
+    /// Returns the objects which have changed (and been removed) since the provided sync token
+    /// (an empty token means that every object is returned). Returns `None` if the token is not
+    /// valid for this collection.
+    pub fn changes_since(&self, token: &str) -> Option<Changes> {
+        let since = if token.is_empty() {
+            0
+        } else {
+            let since = token.strip_prefix(SYNC_TOKEN_PREFIX)?.parse::<u64>().ok()?;
+            if since < self.created || since > self.version {
+                return None;
+            }
+            since
+        };
+        Some(Changes {
+            changed: self
+                .objects
+                .iter()
+                .filter(|(_, object)| object.version > since)
+                .collect(),
+            removed: self
+                .deleted
+                .iter()
+                .filter(|(name, version)| **version > since && !self.objects.contains_key(*name))
+                .map(|(name, _)| name)
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    pub calendars: BTreeMap<String, Collection>,
+    version: u64,
+}
+
+impl Store {
+    /// Creates a store which contains an empty calendar called `DEFAULT_CALENDAR`.
+    pub fn new() -> Self {
+        let mut store = Self::default();
+        store.create_calendar(
+            DEFAULT_CALENDAR,
+            Collection {
+                display_name: Some(DEFAULT_CALENDAR.to_string()),
+                ..Default::default()
+            },
+        );
+        store
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    /// Adds a calendar to the store, returning `false` if there is already a calendar with the
+    /// provided name.
+    pub fn create_calendar(&mut self, name: &str, mut collection: Collection) -> bool {
+        if self.calendars.contains_key(name) {
+            return false;
+        }
+        let version = self.next_version();
+        collection.created = version;
+        collection.version = version;
+        self.calendars.insert(name.to_string(), collection);
+        true
+    }
+
+    pub fn delete_calendar(&mut self, name: &str) -> bool {
+        self.calendars.remove(name).is_some()
+    }
+
+    /// Creates (or replaces) an object, returning its new ETag. The calendar must exist.
+    pub fn put_object(&mut self, calendar: &str, name: &str, data: String) -> Option<String> {
+        if !self.calendars.contains_key(calendar) {
+            return None;
+        }
+        let version = self.next_version();
+        let collection = self.calendars.get_mut(calendar)?;
+        collection.version = version;
+        collection.deleted.remove(name);
+        let object = Object { data, version };
+        let etag = object.etag();
+        collection.objects.insert(name.to_string(), object);
+        Some(etag)
+    }
+
+    /// Deletes an object, returning `false` if it doesn't exist.
+    pub fn delete_object(&mut self, calendar: &str, name: &str) -> bool {
+        let exists = self
+            .calendars
+            .get(calendar)
+            .map_or(false, |collection| collection.objects.contains_key(name));
+        if !exists {
+            return false;
+        }
+        let version = self.next_version();
+        if let Some(collection) = self.calendars.get_mut(calendar) {
+            collection.objects.remove(name);
+            collection.deleted.insert(name.to_string(), version);
+            collection.version = version;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test_store {
+    use super::{Store, DEFAULT_CALENDAR};
+
+    #[test]
+    fn test_changes_since_sync_token() {
+        let mut store = Store::new();
+        store.put_object(DEFAULT_CALENDAR, "a.ics", "a".to_string());
+        store.put_object(DEFAULT_CALENDAR, "b.ics", "b".to_string());
+        let token = store.calendars[DEFAULT_CALENDAR].sync_token();
+        store.put_object(DEFAULT_CALENDAR, "b.ics", "changed".to_string());
+        store.delete_object(DEFAULT_CALENDAR, "a.ics");
+        store.put_object(DEFAULT_CALENDAR, "c.ics", "c".to_string());
+
+        let calendar = &store.calendars[DEFAULT_CALENDAR];
+        let changes = calendar.changes_since(&token).unwrap();
+        let changed = changes
+            .changed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(changed, vec!["b.ics", "c.ics"]);
+        assert_eq!(changes.removed, vec!["a.ics"]);
+        assert_eq!(calendar.changes_since("").unwrap().changed.len(), 2);
+        assert!(calendar.changes_since("http://ariel/sync/1000").is_none());
+        assert!(calendar.changes_since("not-a-token").is_none());
+    }
+}
@@ -0,0 +1,90 @@
+//! Helpers for reading (and writing) the XML in WebDAV requests and responses.
+
+use roxmltree::Node;
+
+pub(crate) const DAV: &str = "DAV:";
+pub(crate) const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+pub(crate) const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+pub(crate) const APPLE_ICAL: &str = "http://apple.com/ns/ical/";
+
+/// The namespaces (and the prefixes which are used for them) which are declared at the root of
+/// every multistatus response.
+pub(crate) const NAMESPACES: [(&str, &str); 4] = [
+    ("D", DAV),
+    ("C", CALDAV),
+    ("CS", CALENDARSERVER),
+    ("A", APPLE_ICAL),
+];
+
+/// Whether the node is an element with the provided name (in the provided namespace).
+pub(crate) fn is(node: &Node, namespace: &str, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().name() == name
+        && node.tag_name().namespace() == Some(namespace)
+}
+
+/// Returns the first child of the node with the provided name (in the provided namespace).
+pub(crate) fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| is(child, namespace, name))
+}
+
+/// Escapes text so that it can be included in an XML document.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// Writes out an element (a namespace which isn't declared at the root of the document is
+/// declared on the element itself).
+pub(crate) fn element(namespace: &str, name: &str, content: &str) -> String {
+    let (prefix, declaration) = match NAMESPACES.iter().find(|(_, ns)| *ns == namespace) {
+        Some((prefix, _)) => (*prefix, String::new()),
+        None => ("X", format!(" xmlns:X=\"{}\"", escape(namespace))),
+    };
+    if content.is_empty() {
+        format!("<{}:{}{}/>", prefix, name, declaration)
+    } else {
+        format!(
+            "<{}:{}{}>{}</{}:{}>",
+            prefix, name, declaration, content, prefix, name
+        )
+    }
+}
+
+/// Writes out a multistatus document, which contains the provided responses.
+pub(crate) fn multistatus(responses: &str, extra: &str) -> String {
+    let declarations = NAMESPACES
+        .iter()
+        .map(|(prefix, namespace)| format!(" xmlns:{}=\"{}\"", prefix, namespace))
+        .collect::<String>();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus{}>{}{}</D:multistatus>",
+        declarations, responses, extra
+    )
+}
+
+/// Writes out a WebDAV error document (RFC 4918, section 16) for a failed precondition.
+pub(crate) fn error(namespace: &str, precondition: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\" xmlns:C=\"{}\" \
+        xmlns:CS=\"{}\" xmlns:A=\"{}\">{}</D:error>",
+        CALDAV,
+        CALENDARSERVER,
+        APPLE_ICAL,
+        element(namespace, precondition, "")
+    )
+}
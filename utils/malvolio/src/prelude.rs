@@ -13,7 +13,7 @@ pub use crate::tags::{
     headings::{H1, H2, H3, H4, H5, H6},
     html::Html,
     img::{Alt, Img, Src},
-    input::{Input, Name, Placeholder, Type, Value},
+    input::{Checked, Input, Name, Placeholder, Type, Value},
     label::Label,
     meta::{Content, Meta, MetaName},
     option::SelectOption,
@@ -4,11 +4,12 @@ use std::{borrow::Cow, collections::HashMap, fmt::Display};
 #[cfg(feature = "with_yew")]
 use yew::virtual_dom::Listener;
 
+use super::body::body_node::BodyNode;
 use crate::{
     attributes::IntoAttribute, into_attribute_for_grouping_enum, into_grouping_union, utility_enum,
 };
 
-#[derive(Debug, Derivative)]
+#[derive(Debug, Clone, Derivative)]
 #[derivative(Default(new = "true"))]
 /// The `<img>` tag.
 pub struct Img {
@@ -28,7 +29,6 @@ impl Display for Img {
             attr.1.fmt(f)?;
             f.write_str("\"")?;
         }
-        f.write_str("\"")?;
         f.write_str("/>")
     }
 }
@@ -45,6 +45,8 @@ impl crate::into_vnode::IntoVNode for Img {
     }
 }
 
+into_grouping_union!(Img, BodyNode);
+
 impl Img {
     /// Attach an attribute to the <img> tag in question.
     pub fn attribute<A>(mut self, attribute: A) -> Self
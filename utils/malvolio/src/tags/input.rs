@@ -124,10 +124,21 @@ utility_enum!(
         Class(Class),
         Value(Value),
         Style(Style),
+        Checked(Checked),
     }
 );
 
-into_attribute_for_grouping_enum!(InputAttr, Type, Name, Placeholder, Id, Class, Value, Style);
+into_attribute_for_grouping_enum!(
+    InputAttr,
+    Type,
+    Name,
+    Placeholder,
+    Id,
+    Class,
+    Value,
+    Style,
+    Checked
+);
 
 into_grouping_union!(Id, InputAttr);
 into_grouping_union!(Class, InputAttr);
@@ -136,6 +147,7 @@ into_grouping_union!(Style, InputAttr);
 into_grouping_union!(Name, InputAttr);
 into_grouping_union!(Type, InputAttr);
 into_grouping_union!(Placeholder, InputAttr);
+into_grouping_union!(Checked, InputAttr);
 
 /// The `type` attribute for an input.
 ///
@@ -152,6 +164,8 @@ pub enum Type {
     Hidden,
     DateTimeLocal,
     Checkbox,
+    Number,
+    Time,
 }
 
 impl IntoAttribute for Type {
@@ -167,6 +181,8 @@ impl IntoAttribute for Type {
                 Type::Hidden => "hidden",
                 Type::DateTimeLocal => "datetime-local",
                 Type::Checkbox => "checkbox",
+                Type::Number => "number",
+                Type::Time => "time",
             }
             .into(),
         )
@@ -243,6 +259,19 @@ impl IntoAttribute for Value {
     }
 }
 
+/// The "checked" attribute for a checkbox, which makes it start off ticked.
+///
+/// See the [MDN Web Docs](https://developer.mozilla.org/en-US/docs/Web/HTML/Element/input/checkbox#attr-checked)
+/// for more info.
+#[derive(Debug, Clone)]
+pub struct Checked;
+
+impl IntoAttribute for Checked {
+    fn into_attribute(self) -> (&'static str, Cow<'static, str>) {
+        ("checked", "checked".into())
+    }
+}
+
 #[cfg(test)]
 #[cfg(feature = "with_yew")]
 #[cfg(not(tarpaulin))]
@@ -0,0 +1,191 @@
+//! Builds events to be saved in a calendar.
+//!
+//! `Calendar::save_event` and `EventPointer::update` accept anything which can be converted into
+//! a `NewEvent` – this is either an `icalendar::Event`, or an event built by `EventBuilder`
+//! (which, unlike `icalendar::Event`, can contain alarms).
+
+use chrono::{DateTime, Utc};
+use icalendar::Component;
+
+use crate::{
+    event::uid_of,
+    properties::{escape, Alarm, EventStatus, Transparency},
+};
+
+/// An event which has not been saved yet.
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+    event: icalendar::Event,
+    alarms: Vec<Alarm>,
+}
+
+impl From<icalendar::Event> for NewEvent {
+    fn from(event: icalendar::Event) -> Self {
+        Self {
+            event,
+            alarms: vec![],
+        }
+    }
+}
+
+impl NewEvent {
+    pub(crate) fn uid(&self) -> Option<String> {
+        uid_of(&self.event)
+    }
+
+    pub(crate) fn set_uid(&mut self, uid: &str) {
+        self.event.add_property("UID", uid);
+    }
+
+    /// Writes out a calendar which contains the event.
+    pub(crate) fn to_ical(&self) -> String {
+        let mut calendar = icalendar::Calendar::new();
+        calendar.push(self.event.clone());
+        let calendar = calendar.to_string();
+        // `icalendar` can't write components which are nested inside an event, so the alarms are
+        // added by hand
+        match calendar.rfind("END:VEVENT") {
+            Some(index) if !self.alarms.is_empty() => {
+                let alarms = self.alarms.iter().map(Alarm::to_ical).collect::<String>();
+                format!("{}{}{}", &calendar[..index], alarms, &calendar[index..])
+            }
+            _ => calendar,
+        }
+    }
+}
+
+/// Builds a `NewEvent`. Text is escaped, so it can contain any characters (including newlines).
+#[derive(Debug, Clone)]
+pub struct EventBuilder {
+    event: icalendar::Event,
+    categories: Vec<String>,
+    alarms: Vec<Alarm>,
+}
+
+impl EventBuilder {
+    /// Starts building an event which happens between `start` and `end`.
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        Self {
+            event: icalendar::Event::new().starts(start).ends(end).done(),
+            categories: vec![],
+            alarms: vec![],
+        }
+    }
+
+    fn text(mut self, name: &str, value: &str) -> Self {
+        self.event.add_property(name, &escape(value));
+        self
+    }
+
+    pub fn uid(mut self, uid: &str) -> Self {
+        self.event.add_property("UID", uid);
+        self
+    }
+
+    pub fn summary(self, summary: &str) -> Self {
+        self.text("SUMMARY", summary)
+    }
+
+    pub fn description(self, description: &str) -> Self {
+        self.text("DESCRIPTION", description)
+    }
+
+    pub fn location(self, location: &str) -> Self {
+        self.text("LOCATION", location)
+    }
+
+    /// Links the event to a web page (the `URL` property).
+    pub fn link(mut self, url: &str) -> Self {
+        self.event.add_property("URL", url);
+        self
+    }
+
+    /// Adds a category to the event (this can be called more than once).
+    pub fn category(mut self, category: &str) -> Self {
+        self.categories.push(escape(category));
+        self
+    }
+
+    pub fn status(mut self, status: EventStatus) -> Self {
+        self.event.add_property("STATUS", status.as_str());
+        self
+    }
+
+    pub fn transparency(mut self, transparency: Transparency) -> Self {
+        self.event.add_property("TRANSP", transparency.as_str());
+        self
+    }
+
+    /// Adds an alarm to the event (this can be called more than once).
+    pub fn alarm(mut self, alarm: Alarm) -> Self {
+        self.alarms.push(alarm);
+        self
+    }
+
+    pub fn build(mut self) -> NewEvent {
+        if !self.categories.is_empty() {
+            self.event
+                .add_property("CATEGORIES", &self.categories.join(","));
+        }
+        NewEvent {
+            event: self.event,
+            alarms: self.alarms,
+        }
+    }
+}
+
+impl From<EventBuilder> for NewEvent {
+    fn from(builder: EventBuilder) -> Self {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::EventBuilder;
+    use crate::properties::{Alarm, AlarmTrigger, Transparency};
+
+    #[test]
+    fn test_built_events_can_be_read_back() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(9, 0, 0);
+        let event = EventBuilder::new(start, start + Duration::hours(1))
+            .uid("some-uid")
+            .summary("Revision")
+            .description("Read chapter 1; then chapter 2,\nand make notes")
+            .link("https://example.com/class/1/task/async/2/view")
+            .category("Lovelace")
+            .category("Work, revision")
+            .transparency(Transparency::Opaque)
+            .alarm(Alarm::reminder(Duration::minutes(15), "Time to revise"))
+            .build();
+        assert_eq!(event.uid().as_deref(), Some("some-uid"));
+        let text = event.to_ical();
+        let calendar = ical::IcalParser::new(text.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let event = &calendar.events[0];
+        let value = |name: &'static str| {
+            event
+                .properties
+                .iter()
+                .find(|prop| prop.name == name)
+                .and_then(|prop| prop.value.clone())
+                .unwrap()
+        };
+        assert_eq!(
+            crate::properties::unescape(&value("DESCRIPTION")),
+            "Read chapter 1; then chapter 2,\nand make notes"
+        );
+        assert_eq!(
+            crate::properties::split_list(&value("CATEGORIES")),
+            vec!["Lovelace", "Work, revision"]
+        );
+        assert_eq!(event.alarms.len(), 1);
+        let alarm = Alarm::from_component(&event.alarms[0]).unwrap();
+        assert_eq!(alarm.trigger, AlarmTrigger::Start(Duration::minutes(-15)));
+        assert_eq!(alarm.description.as_deref(), Some("Time to revise"));
+    }
+}
@@ -3,14 +3,15 @@ use std::sync::Arc;
 use atomic_refcell::AtomicRefCell;
 
 use crate::{
+    builder::NewEvent,
     client::{DavClient, REPORT},
-    error::{CalDavError, CalDavResult},
-    event::{EventPointer, EventPointerData, DATETIME_FORMAT},
+    error::{check_status, parse_xml, CalDavError, CalDavResult},
+    event::{fetched_events, EventPointer, EventPointerData, DATETIME_FORMAT},
 };
 use chrono::{DateTime, Utc};
 use ical::parser::ical::component::IcalCalendar;
-use reqwest::Method;
-use roxmltree::{Descendants, Document};
+use reqwest::{Method, Response};
+use roxmltree::{Document, Node};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
@@ -19,29 +20,77 @@ pub struct Calendar {
     pub(crate) url: Arc<String>,
 }
 
+/// Identifies a specific version of a resource on the server (the ETag changes every time the
+/// resource is changed).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Etag(pub(crate) String);
+
+impl Etag {
+    pub fn new<S>(etag: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self(etag.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Reads the `ETag` header of the response (servers don't always send one).
+    pub(crate) fn from_response(response: &Response) -> Option<Self> {
+        response
+            .headers()
+            .get("ETag")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| Etag(value.to_string()))
+    }
+}
+
+/// The calendar data for one resource in a multistatus response.
 #[derive(Debug, Clone)]
-pub struct Etag(String);
+pub(crate) struct CalendarData {
+    pub calendar: IcalCalendar,
+    pub etag: Option<Etag>,
+    /// The `href` of the resource which the calendar data belongs to.
+    pub href: Option<String>,
+}
 
 impl Calendar {
+    /// The URL of the calendar.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
     /// Saves a new event in the calendar.
-    pub async fn save_event(&self, event: icalendar::Event) -> CalDavResult<EventPointer> {
-        let mut calendar = icalendar::Calendar::new();
-        calendar.push(event);
-        let uid = Uuid::new_v4().to_string();
+    ///
+    /// The event is stored under its UID (if the event does not have a UID a random one is
+    /// assigned). This fails if the calendar already contains an event with the same UID – use
+    /// `EventPointer::update` to change an existing event.
+    pub async fn save_event<E>(&self, event: E) -> CalDavResult<EventPointer>
+    where
+        E: Into<NewEvent>,
+    {
+        let mut event = event.into();
+        let uid = match event.uid() {
+            Some(uid) => uid,
+            None => {
+                let uid = Uuid::new_v4().to_string();
+                event.set_uid(&uid);
+                uid
+            }
+        };
         let req = self
             .client
-            .request(Method::PUT, format!("{}/{}.ics", self.url, &uid))
-            .await?
-            .header("If-None-Match", "Match")
+            .request(Method::PUT, format!("{}/{}.ics", self.url, &uid))?
+            .header("If-None-Match", "*")
             .header("Content-Type", "text/calendar")
-            .header("Content-Length", "xxxx")
-            .body(calendar.to_string());
-        let res = req.send().await?;
-        if res.status().as_u16() != 201 && res.status() != 200 && res.status().as_u16() != 207 {
-            return Err(CalDavError::OtherError);
-        }
+            .body(event.to_ical());
+        let res = check_status(self.client.send(req).await?).await?;
         Ok(EventPointer {
+            href: Some(format!("{}/{}.ics", self.url, &uid)),
             data: AtomicRefCell::new(EventPointerData::CreatedEventResponse { uid }),
+            etag: AtomicRefCell::new(Etag::from_response(&res)),
             url: self.url.clone(),
             client: self.client.clone(),
         })
@@ -74,23 +123,27 @@ impl Calendar {
             </C:calendar-query>
         }
         .to_string();
-        let res = self
+        let req = self
             .client
-            .request(Method::from_bytes(REPORT).unwrap(), self.url.as_str())
-            .await?
+            .request(Method::from_bytes(REPORT)?, self.url.as_str())?
             .body(body_string)
             .header("Content-Type", "application/xml; charset=\"utf-8\"")
-            .header("Depth", "1")
-            .send()
-            .await?;
-        let text = res.text().await.unwrap();
-        let tree = Document::parse(&text).unwrap();
-        let res = get_calendar_data(tree.descendants());
-        Ok(res
-            .map(|item| item.events)
-            .flatten()
-            .map(|event| EventPointer {
-                data: AtomicRefCell::new(EventPointerData::FetchedEvent(event)),
+            .header("Depth", "1");
+        let res = self.client.send(req).await?;
+        let text = check_status(res).await?.text().await?;
+        let tree = parse_xml(&self.url, &text)?;
+        Ok(get_calendar_data(&self.url, &tree)?
+            .into_iter()
+            .flat_map(|item| {
+                let (etag, href) = (item.etag, item.href);
+                fetched_events(item.calendar)
+                    .into_iter()
+                    .map(move |(event, timezones)| (event, timezones, etag.clone(), href.clone()))
+            })
+            .map(|(event, timezones, etag, href)| EventPointer {
+                href,
+                data: AtomicRefCell::new(EventPointerData::FetchedEvent { event, timezones }),
+                etag: AtomicRefCell::new(etag),
                 url: self.url.clone(),
                 client: self.client.clone(),
             })
@@ -98,24 +151,86 @@ impl Calendar {
     }
 }
 
-pub(crate) fn get_calendar_data<'a>(
-    tree: Descendants<'a, 'a>,
-) -> impl Iterator<Item = IcalCalendar> + 'a {
-    tree.filter(|node| node.tag_name().name() == "calendar-data")
-        // this is a bit hard to read (at least it is with my (@teymour-aldridge) editor colour
-        // scheme) – it just finds all the calendar events and tries to parse them (if it can't
-        // parse an event it just ignores it)
-        .filter_map(|node| {
-            node.first_child()
-                .map(|node| {
-                    node.text().map(|text| {
-                        ical::IcalParser::new(text.as_bytes())
-                            .next()
-                            .map(|x| x.map(Some).unwrap_or(None))
-                    })
-                })
-                .flatten()
-                .flatten()
-                .flatten()
+/// Returns the `href` of the resource which a node in a multistatus response belongs to.
+fn href_of(node: Node) -> Option<String> {
+    node.ancestors()
+        .find(|node| node.tag_name().name() == "response")
+        .and_then(|response| {
+            response
+                .children()
+                .find(|node| node.tag_name().name() == "href")
         })
+        .and_then(|href| href.text())
+        .map(|href| href.trim().to_string())
+}
+
+/// Works out which resource a node in a multistatus response belongs to (this is only used in
+/// errors).
+fn location_of(url: &str, node: Node) -> String {
+    href_of(node).unwrap_or_else(|| url.to_string())
+}
+
+/// Parses all the calendar data in a multistatus response (which was returned by the server at
+/// `url`).
+pub(crate) fn get_calendar_data(url: &str, document: &Document) -> CalDavResult<Vec<CalendarData>> {
+    document
+        .descendants()
+        .filter(|node| node.tag_name().name() == "calendar-data")
+        .filter_map(|node| node.text().map(|text| (node, text)))
+        .map(
+            |(node, text)| match ical::IcalParser::new(text.as_bytes()).next() {
+                Some(Ok(calendar)) => Ok(CalendarData {
+                    calendar,
+                    // the ETag is returned alongside the calendar data
+                    etag: node
+                        .parent()
+                        .and_then(|prop| {
+                            prop.children()
+                                .find(|node| node.tag_name().name() == "getetag")
+                        })
+                        .and_then(|etag| etag.text())
+                        .map(|etag| Etag(etag.trim().to_string())),
+                    href: href_of(node),
+                }),
+                Some(Err(e)) => Err(CalDavError::ICalError {
+                    location: location_of(url, node),
+                    message: format!("{:?}", e),
+                }),
+                None => Err(CalDavError::ICalError {
+                    location: location_of(url, node),
+                    message: "no calendar was found".to_string(),
+                }),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test_calendar_data {
+    use super::get_calendar_data;
+    use crate::error::{parse_xml, CalDavError};
+
+    #[test]
+    fn test_invalid_calendar_data_reports_its_location() {
+        let text = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:response>
+                    <d:href>/user/calendars/calendar/broken.ics</d:href>
+                    <d:propstat>
+                        <d:prop><c:calendar-data>BEGIN:VCALENDAR
+BEGIN:VEVENT
+END:VCALENDAR
+</c:calendar-data></d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+            </d:multistatus>"#;
+        let document = parse_xml("http://localhost/", text).unwrap();
+        match get_calendar_data("http://localhost/", &document) {
+            Err(CalDavError::ICalError { location, .. }) => {
+                assert_eq!(location, "/user/calendars/calendar/broken.ics")
+            }
+            other => panic!("expected an iCalendar error, got {:?}", other),
+        }
+    }
 }
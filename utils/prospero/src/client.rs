@@ -1,17 +1,28 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
 
-use atomic_refcell::AtomicRefCell;
-
-use digest_auth::AuthContext;
-use reqwest::{Client, Method, RequestBuilder};
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use digest_auth::{AuthContext, HttpMethod, WwwAuthenticateHeader};
+use reqwest::{
+    header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
 use uuid::Uuid;
 
 pub(crate) const MKCALENDAR: &[u8] = b"MKCALENDAR";
 pub(crate) const REPORT: &[u8] = b"REPORT";
+pub(crate) const PROPFIND: &[u8] = b"PROPFIND";
+
+/// How far ahead the `VTIMEZONE` which is sent when creating a calendar describes the timezone.
+const TIMEZONE_DAYS: i64 = 366 * 5;
+/// Identifies the program which wrote an iCalendar object.
+const PRODID: &str = "-//Lovelace//Prospero//EN";
 
 use crate::{
     calendar::Calendar,
-    error::{CalDavError, CalDavResult},
+    error::{check_status, snippet, CalDavError, CalDavResult},
+    timezone::vtimezone,
+    xml::escape,
 };
 
 /// The CalDAV client. This is the entry point to the application, and you will need one of these
@@ -19,53 +30,136 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct DavClient {
     auth_scheme: AuthScheme,
-    url: String,
+    pub(crate) url: String,
     client: Client,
-    /// This is not neat but it makes the API nicer.
-    auth_header: AtomicRefCell<Option<String>>,
+    /// The last challenge which the server sent us (which is shared between clones of the
+    /// client, so that they don't all have to be challenged).
+    challenge: Arc<Mutex<Option<Challenge>>>,
 }
 
 impl DavClient {
-    pub async fn request<S>(&self, method: Method, url: S) -> CalDavResult<RequestBuilder>
+    /// Creates a request to the provided URL. If the server has already challenged us then the
+    /// request includes our credentials.
+    ///
+    /// Requests should be sent with `DavClient::send`, which answers the server's challenges.
+    pub fn request<S>(&self, method: Method, url: S) -> CalDavResult<RequestBuilder>
     where
         S: AsRef<str>,
     {
-        self.authenticate_cache_http(self.client.request(method, url.as_ref()))
-            .await
-    }
-    async fn authenticate_fresh_http(
-        &self,
-        request: RequestBuilder,
-        username: &str,
-        password: &str,
-    ) -> CalDavResult<RequestBuilder> {
-        let res = self.client.get(&self.url).send().await?;
-        let headers = res.headers();
-        let wwwauth = headers["www-authenticate"].to_str()?;
-        let url = self.url.parse::<http::Uri>()?;
-        let context = AuthContext::new(username, password, url.path());
-        let mut prompt = digest_auth::parse(wwwauth)?;
-        let answer = prompt.respond(&context)?.to_header_string();
-        Ok(request.header("Authorization", answer))
-    }
-
-    async fn authenticate_cache_http(
-        &self,
-        request: RequestBuilder,
-    ) -> CalDavResult<RequestBuilder> {
-        match self.auth_scheme {
-            AuthScheme::UsernamePassword(ref username, ref password) => {
-                if let Some(ref auth) = *self.auth_header.borrow() {
-                    Ok(request.header("Authorization", auth))
-                } else {
-                    self.authenticate_fresh_http(request, username, password)
-                        .await
+        let url = Url::parse(url.as_ref())
+            .map_err(|_| CalDavError::InvalidUrl(url.as_ref().to_string()))?;
+        let request = self.client.request(method.clone(), url.clone());
+        Ok(match self.authorization(&method, &url)? {
+            Some(authorization) => request.header(AUTHORIZATION, authorization),
+            None => request,
+        })
+    }
+
+    /// Sends a request. If the server asks for credentials which it hasn't asked for before (or
+    /// our digest nonce has expired) then the request is sent again with the right credentials.
+    pub async fn send(&self, request: RequestBuilder) -> CalDavResult<Response> {
+        let request = request.build()?;
+        // requests with streaming bodies can't be sent twice, but we never create those
+        let retry = request.try_clone();
+        let res = self.client.execute(request).await?;
+        if res.status() != StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+        let mut retry = match retry {
+            Some(retry) => retry,
+            None => return Ok(res),
+        };
+        if !self.answer_challenge(&res, retry.headers().contains_key(AUTHORIZATION))? {
+            return Ok(res);
+        }
+        match self.authorization(retry.method(), retry.url())? {
+            Some(authorization) => {
+                let authorization = HeaderValue::from_str(&authorization).map_err(|_| {
+                    CalDavError::AuthenticationError("invalid credentials".to_string())
+                })?;
+                retry.headers_mut().insert(AUTHORIZATION, authorization);
+            }
+            None => return Ok(res),
+        }
+        Ok(self.client.execute(retry).await?)
+    }
+
+    /// Returns the `Authorization` header which should be sent with a request (if any).
+    fn authorization(&self, method: &Method, url: &Url) -> CalDavResult<Option<String>> {
+        let (username, password) = match self.auth_scheme {
+            AuthScheme::UsernamePassword(ref username, ref password) => (username, password),
+            AuthScheme::OAuth(ref access_token) => {
+                return Ok(Some(format!("Bearer {}", access_token)))
+            }
+            AuthScheme::None => return Ok(None),
+        };
+        let mut challenge = self
+            .challenge
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(match &mut *challenge {
+            Some(Challenge::Digest(prompt)) => {
+                let uri = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let context = AuthContext::new_with_method(
+                    username.as_str(),
+                    password.as_str(),
+                    uri,
+                    None,
+                    digest_method(method)?,
+                );
+                // this increments the nonce count, so every request has a different response
+                Some(prompt.respond(&context)?.to_header_string())
+            }
+            Some(Challenge::Basic) => Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )),
+            // the server hasn't asked for credentials yet (and might never do so, e.g. if it sits
+            // behind a reverse proxy on a trusted network)
+            None => None,
+        })
+    }
+
+    /// Remembers the challenge in a "401 Unauthorized" response, returning `true` if the request
+    /// should be sent again.
+    ///
+    /// If we already sent credentials then the request is only retried if they were rejected
+    /// because the digest nonce had expired (otherwise the credentials are wrong).
+    fn answer_challenge(&self, res: &Response, sent_credentials: bool) -> CalDavResult<bool> {
+        if !matches!(self.auth_scheme, AuthScheme::UsernamePassword(..)) {
+            return Ok(false);
+        }
+        let mut basic = false;
+        for header in res.headers().get_all(WWW_AUTHENTICATE) {
+            let header = header.to_str()?.trim_start();
+            if starts_with_scheme(header, "Digest") {
+                let prompt = digest_auth::parse(header)?;
+                if sent_credentials && !prompt.stale {
+                    return Ok(false);
                 }
+                *self
+                    .challenge
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(Challenge::Digest(prompt));
+                return Ok(true);
             }
-            AuthScheme::None => Ok(request),
-            AuthScheme::OAuth(ref access_token) => Ok(request.bearer_auth(access_token)),
+            basic |= starts_with_scheme(header, "Basic");
         }
+        // servers which support both schemes are sent digest responses, as these don't reveal
+        // the password
+        if basic && !sent_credentials {
+            *self
+                .challenge
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(Challenge::Basic);
+            return Ok(true);
+        }
+        Ok(false)
     }
+
     pub fn new_unauthenticated<S>(s: S) -> Self
     where
         S: Into<String>,
@@ -74,7 +168,7 @@ impl DavClient {
             auth_scheme: AuthScheme::None,
             url: s.into(),
             client: Client::new(),
-            auth_header: AtomicRefCell::new(None),
+            challenge: Arc::new(Mutex::new(None)),
         }
     }
     /// Construct a new CalDAV client which uses username/password authentication.
@@ -88,7 +182,7 @@ impl DavClient {
             auth_scheme: AuthScheme::new_username_password(username.into(), password.into()),
             url: url.into(),
             client: Client::new(),
-            auth_header: AtomicRefCell::new(None),
+            challenge: Arc::new(Mutex::new(None)),
         }
     }
     /// Construct a new CalDAV client which uses OAuth authentication.
@@ -104,30 +198,21 @@ impl DavClient {
             auth_scheme: AuthScheme::OAuth(access_token),
             url,
             client: Client::new(),
-            auth_header: AtomicRefCell::new(None),
+            challenge: Arc::new(Mutex::new(None)),
         }
     }
-    /// Returns a list of calendars.
-    ///
-    /// I think the spec discourages using this.
-    pub async fn calendars(&'_ self) -> CalDavResult<Vec<Calendar>> {
-        let body_string = xml! {
-            <?xml version="1.0" encoding="utf-8" ?>
-            <D:principal-match xmlns:D="DAV:">
-                <D:self/>
-                <D:prop>
-                    <C:calendar-home-set
-                    xmlns:C="urn:ietf:params:xml:ns:caldav"/>
-                </D:prop>
-            </D:principal-match>
+    /// Returns a client which uses the same credentials as this one, but a different URL (e.g.
+    /// the URL of the user's calendar home set).
+    pub fn with_url<S>(&self, url: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            auth_scheme: self.auth_scheme.clone(),
+            url: url.into(),
+            client: self.client.clone(),
+            challenge: Arc::new(Mutex::new(None)),
         }
-        .to_string();
-        self.client
-            .request(Method::from_bytes(REPORT).unwrap(), &self.url)
-            .body(body_string)
-            .send()
-            .await?;
-        todo!()
     }
     pub fn calendar(&'_ self) -> Calendar {
         Calendar {
@@ -135,41 +220,82 @@ impl DavClient {
             url: Arc::new(self.url.to_string()),
         }
     }
-    /// Creates a new calendar from the provided `MakeCalendar` struct. If any of the fields on the
-    /// `MkCalendar` struct are `None` a uuid will be used in their place.
+    /// Creates a new calendar (inside the collection at the URL of this client, which should
+    /// usually be the user's calendar home set) and returns it.
+    ///
+    /// If the `name` or `id` of the `MakeCalendar` struct are `None` a uuid will be used in their
+    /// place. If the server doesn't create the calendar (e.g. because one already exists at the
+    /// URL) then `CalDavError::UnexpectedStatus` is returned.
     pub async fn make_calendar(&'_ self, cal: MakeCalendar) -> CalDavResult<Calendar> {
         let url = format!(
             "{}/{}",
-            self.url,
+            self.url.trim_end_matches('/'),
             cal.id.unwrap_or_else(|| Uuid::new_v4().to_string())
         );
-        let name = if let Some(name) = cal.name {
-            name
-        } else {
-            Uuid::new_v4().to_string()
-        };
+        let name = escape(&cal.name.unwrap_or_else(|| Uuid::new_v4().to_string()));
+        // the optional properties are only included if they have been set
+        let description = cal.description.as_deref().map(|description| {
+            xml! {
+                <C:calendar-description>{escape(description)}</C:calendar-description>
+            }
+            .to_string()
+        });
+        let colour = cal.colour.as_deref().map(|colour| {
+            xml! {
+                <A:calendar-color>{escape(colour)}</A:calendar-color>
+            }
+            .to_string()
+        });
+        let timezone = cal.timezone.map(|tz| {
+            let now = Utc::now();
+            let calendar = format!(
+                "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:{}\r\n{}END:VCALENDAR\r\n",
+                PRODID,
+                vtimezone(
+                    tz,
+                    now - Duration::days(366),
+                    now + Duration::days(TIMEZONE_DAYS)
+                )
+            );
+            xml! {
+                <C:calendar-timezone>{escape(&calendar)}</C:calendar-timezone>
+            }
+            .to_string()
+        });
         let body_string = xml! {
             <?xml version="1.0" encoding="utf-8" ?>
-            <C:mkcalendar xmlns:D="DAV"
-                          xmlns:C="url:ietf:params:xml:ns:caldav">
+            <C:mkcalendar xmlns:D="DAV:"
+                          xmlns:C="urn:ietf:params:xml:ns:caldav"
+                          xmlns:A="http://apple.com/ns/ical/">
                 <D:set>
                     <D:prop>
                         <D:displayname>{name}</D:displayname>
+                        {description.unwrap_or_default()}
+                        {colour.unwrap_or_default()}
+                        {timezone.unwrap_or_default()}
                     </D:prop>
                 </D:set>
             </C:mkcalendar>
         }
         .to_string();
-        self.client
-            .request(Method::from_bytes(MKCALENDAR).unwrap(), &url)
-            .body(body_string)
-            .send()
-            .await
-            .map(|_| Calendar {
-                client: Arc::new(self.clone()),
-                url: Arc::new(self.url.to_string()),
-            })
-            .map_err(CalDavError::RequestError)
+        let req = self
+            .request(Method::from_bytes(MKCALENDAR)?, &url)?
+            .header("Content-Type", "application/xml; charset=\"utf-8\"")
+            .body(body_string);
+        let res = self.send(req).await?;
+        let res = check_status(res).await?;
+        // other successful statuses (e.g. "200 OK") don't mean that a new calendar was created
+        if res.status() != StatusCode::CREATED {
+            return Err(CalDavError::UnexpectedStatus {
+                url,
+                status: res.status().as_u16(),
+                body: snippet(&res.text().await.unwrap_or_default()),
+            });
+        }
+        Ok(Calendar {
+            client: Arc::new(self.clone()),
+            url: Arc::new(url),
+        })
     }
 }
 
@@ -178,6 +304,9 @@ impl DavClient {
 pub struct MakeCalendar {
     name: Option<String>,
     id: Option<String>,
+    description: Option<String>,
+    colour: Option<String>,
+    timezone: Option<Tz>,
 }
 
 impl MakeCalendar {
@@ -189,7 +318,7 @@ impl MakeCalendar {
         self.name = Some(name);
         self
     }
-    /// Set the `id` of the calendar to be created.
+    /// Set the `id` of the calendar to be created (this is the last part of its URL).
     ///
     /// Note that setting this field is optional and if you do not a uuid (a random string) will be
     /// used in place.
@@ -197,6 +326,23 @@ impl MakeCalendar {
         self.id = Some(id);
         self
     }
+    /// Set the description of the calendar to be created.
+    pub fn description(mut self, description: String) -> Self {
+        self.description = Some(description);
+        self
+    }
+    /// Set the colour of the calendar to be created (e.g. `#1b998bff`). This is an Apple
+    /// extension, but most calendar apps support it.
+    pub fn colour(mut self, colour: String) -> Self {
+        self.colour = Some(colour);
+        self
+    }
+    /// Set the timezone of the calendar to be created. Servers use this to work out when floating
+    /// times (and all-day events) in the calendar happen.
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -211,3 +357,39 @@ impl AuthScheme {
         Self::UsernamePassword(username, password)
     }
 }
+
+/// A challenge (i.e. a `WWW-Authenticate` header) which the server has sent us.
+#[derive(Debug, Clone)]
+enum Challenge {
+    Basic,
+    Digest(WwwAuthenticateHeader),
+}
+
+/// Checks whether a `WWW-Authenticate` header is for the provided (case-insensitive) scheme.
+fn starts_with_scheme(header: &str, scheme: &str) -> bool {
+    header
+        .get(..scheme.len())
+        .map_or(false, |start| start.eq_ignore_ascii_case(scheme))
+}
+
+/// The digest crate needs to know the method of each request, as the response depends on it.
+fn digest_method(method: &Method) -> CalDavResult<HttpMethod> {
+    Ok(match method.as_str() {
+        "GET" => HttpMethod::GET,
+        "POST" => HttpMethod::POST,
+        "HEAD" => HttpMethod::HEAD,
+        "PUT" => HttpMethod::OTHER("PUT"),
+        "DELETE" => HttpMethod::OTHER("DELETE"),
+        "OPTIONS" => HttpMethod::OTHER("OPTIONS"),
+        "PROPFIND" => HttpMethod::OTHER("PROPFIND"),
+        "PROPPATCH" => HttpMethod::OTHER("PROPPATCH"),
+        "REPORT" => HttpMethod::OTHER("REPORT"),
+        "MKCALENDAR" => HttpMethod::OTHER("MKCALENDAR"),
+        other => {
+            return Err(CalDavError::AuthenticationError(format!(
+                "digest authentication is not supported for {} requests",
+                other
+            )))
+        }
+    })
+}
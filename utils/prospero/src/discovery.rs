@@ -0,0 +1,346 @@
+//! Finds the calendars which belong to a user (RFC 4791 section 7.1 and RFC 6764).
+//!
+//! Discovery happens in three steps:
+//! 1. find the user's principal (using `current-user-principal`, falling back to the
+//!    `/.well-known/caldav` URL if the server doesn't tell us at the URL we were given)
+//! 2. find the collection which contains the user's calendars (the `calendar-home-set`)
+//! 3. list the calendars in that collection
+
+use std::sync::Arc;
+
+use reqwest::{Method, Url};
+
+use crate::{
+    calendar::Calendar,
+    client::{DavClient, PROPFIND},
+    error::{check_status, parse_xml, CalDavError, CalDavResult},
+    xml::{child, is, successful_props, text, CALDAV, DAV},
+};
+
+const APPLE_ICAL: &str = "http://apple.com/ns/ical/";
+const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// A calendar which was found on the server, along with some information about it.
+#[derive(Debug, Clone)]
+pub struct DiscoveredCalendar {
+    pub calendar: Calendar,
+    /// The name of the calendar (which is usually chosen by the user).
+    pub display_name: Option<String>,
+    /// The colour of the calendar (usually in the form `#RRGGBB` or `#RRGGBBAA`).
+    pub colour: Option<String>,
+    /// The types of component which the calendar can contain (e.g. `VEVENT` or `VTODO`). If this
+    /// is empty then the server didn't say, in which case any type of component is allowed.
+    pub components: Vec<String>,
+    /// Changes whenever anything in the calendar changes.
+    pub ctag: Option<String>,
+}
+
+impl DiscoveredCalendar {
+    /// Whether the calendar can contain events (some calendars can only contain tasks).
+    pub fn supports_events(&self) -> bool {
+        self.components.is_empty() || self.components.iter().any(|c| c == "VEVENT")
+    }
+}
+
+/// The properties of a collection which we read when listing calendars.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CollectionProperties {
+    pub href: String,
+    pub is_calendar: bool,
+    pub display_name: Option<String>,
+    pub colour: Option<String>,
+    pub components: Vec<String>,
+    pub ctag: Option<String>,
+}
+
+/// Reads the `href` inside the property with the provided name from a multistatus response (this
+/// is how servers tell us about the `current-user-principal` and the `calendar-home-set`).
+pub(crate) fn parse_href_property(
+    url: &str,
+    document: &str,
+    namespace: &str,
+    name: &str,
+) -> CalDavResult<Option<String>> {
+    let document = parse_xml(url, document)?;
+    Ok(document
+        .descendants()
+        .filter(|node| is(node, DAV, "response"))
+        .flat_map(successful_props)
+        .filter_map(|prop| child(prop, namespace, name))
+        .filter_map(|property| child(property, DAV, "href"))
+        .find_map(text))
+}
+
+/// Parses the response to a `Depth: 1` PROPFIND of a calendar home set.
+pub(crate) fn parse_collections(
+    url: &str,
+    document: &str,
+) -> CalDavResult<Vec<CollectionProperties>> {
+    let document = parse_xml(url, document)?;
+    Ok(document
+        .descendants()
+        .filter(|node| is(node, DAV, "response"))
+        .filter_map(|response| {
+            let href = child(response, DAV, "href").and_then(text)?;
+            let mut properties = CollectionProperties {
+                href,
+                ..Default::default()
+            };
+            for prop in successful_props(response) {
+                for property in prop.children().filter(|node| node.is_element()) {
+                    if is(&property, DAV, "resourcetype") {
+                        properties.is_calendar = child(property, CALDAV, "calendar").is_some();
+                    } else if is(&property, DAV, "displayname") {
+                        properties.display_name = text(property);
+                    } else if is(&property, APPLE_ICAL, "calendar-color") {
+                        properties.colour = text(property);
+                    } else if is(&property, CALDAV, "supported-calendar-component-set") {
+                        properties.components = property
+                            .children()
+                            .filter(|node| is(node, CALDAV, "comp"))
+                            .filter_map(|comp| comp.attribute("name"))
+                            .map(ToString::to_string)
+                            .collect();
+                    } else if is(&property, CALENDARSERVER, "getctag") {
+                        properties.ctag = text(property);
+                    }
+                }
+            }
+            Some(properties)
+        })
+        .collect())
+}
+
+impl DavClient {
+    /// Sends a PROPFIND request, returning the body of the response.
+    pub(crate) async fn propfind(
+        &self,
+        url: &str,
+        depth: &str,
+        body: String,
+    ) -> CalDavResult<String> {
+        let req = self
+            .request(Method::from_bytes(PROPFIND)?, url)?
+            .header("Content-Type", "application/xml; charset=\"utf-8\"")
+            .header("Depth", depth)
+            .body(body);
+        let res = self.send(req).await?;
+        Ok(check_status(res).await?.text().await?)
+    }
+
+    /// Resolves a (possibly relative) `href` against the provided URL.
+    pub(crate) fn resolve_href(base: &str, href: &str) -> CalDavResult<String> {
+        Url::parse(base)
+            .and_then(|base| base.join(href))
+            .map(String::from)
+            .map_err(|_| CalDavError::InvalidUrl(format!("{} (relative to {})", href, base)))
+    }
+
+    /// Asks the server at the provided URL for the user's principal.
+    async fn principal_at(&self, url: &str) -> CalDavResult<Option<String>> {
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:">
+                <D:prop>
+                    <D:current-user-principal/>
+                </D:prop>
+            </D:propfind>
+        }
+        .to_string();
+        let document = match self.propfind(url, "0", body).await {
+            Ok(document) => document,
+            // not every URL on the server knows about principals
+            Err(CalDavError::UnexpectedStatus { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        parse_href_property(url, &document, DAV, "current-user-principal")?
+            .map(|href| Self::resolve_href(url, &href))
+            .transpose()
+    }
+
+    /// Finds the URL of the user's principal.
+    pub async fn current_user_principal(&self) -> CalDavResult<String> {
+        if let Some(principal) = self.principal_at(&self.url).await? {
+            return Ok(principal);
+        }
+        // the server didn't tell us at the URL we were given, so we try the "well-known" URL
+        // instead (RFC 6764 section 5), which servers usually redirect to the right place
+        let well_known = Self::resolve_href(&self.url, "/.well-known/caldav")?;
+        let res = self.send(self.request(Method::GET, &well_known)?).await?;
+        let context_path = res.url().to_string();
+        // the GET request itself might fail (as the server might only respond to PROPFIND), but we
+        // only need to know where it was redirected to
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(CalDavError::Unauthorized);
+        }
+        self.principal_at(&context_path)
+            .await?
+            .ok_or(CalDavError::MissingResponseProperty {
+                url: context_path,
+                property: "current-user-principal",
+            })
+    }
+
+    /// Finds the URL of the collection which contains the user's calendars.
+    pub async fn calendar_home_set(&self) -> CalDavResult<String> {
+        let principal = self.current_user_principal().await?;
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+                <D:prop>
+                    <C:calendar-home-set/>
+                </D:prop>
+            </D:propfind>
+        }
+        .to_string();
+        let document = self.propfind(&principal, "0", body).await?;
+        let href = parse_href_property(&principal, &document, CALDAV, "calendar-home-set")?
+            .ok_or_else(|| CalDavError::MissingResponseProperty {
+                url: principal.clone(),
+                property: "calendar-home-set",
+            })?;
+        Self::resolve_href(&principal, &href)
+    }
+
+    /// Returns a list of the user's calendars.
+    pub async fn calendars(&self) -> CalDavResult<Vec<DiscoveredCalendar>> {
+        let home_set = self.calendar_home_set().await?;
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:"
+                        xmlns:C="urn:ietf:params:xml:ns:caldav"
+                        xmlns:A="http://apple.com/ns/ical/"
+                        xmlns:CS="http://calendarserver.org/ns/">
+                <D:prop>
+                    <D:resourcetype/>
+                    <D:displayname/>
+                    <A:calendar-color/>
+                    <C:supported-calendar-component-set/>
+                    <CS:getctag/>
+                </D:prop>
+            </D:propfind>
+        }
+        .to_string();
+        let document = self.propfind(&home_set, "1", body).await?;
+        let client = Arc::new(self.clone());
+        parse_collections(&home_set, &document)?
+            .into_iter()
+            .filter(|collection| collection.is_calendar)
+            .map(|collection| {
+                let url = Self::resolve_href(&home_set, &collection.href)?;
+                Ok(DiscoveredCalendar {
+                    calendar: Calendar {
+                        client: client.clone(),
+                        url: Arc::new(url.trim_end_matches('/').to_string()),
+                    },
+                    display_name: collection.display_name,
+                    colour: collection.colour,
+                    components: collection.components,
+                    ctag: collection.ctag,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_discovery {
+    use super::{parse_collections, parse_href_property, CALDAV, DAV};
+    use crate::error::CalDavError;
+
+    #[test]
+    fn test_parse_current_user_principal() {
+        let document = r#"<?xml version="1.0" encoding="utf-8"?>
+            <multistatus xmlns="DAV:">
+                <response>
+                    <href>/</href>
+                    <propstat>
+                        <prop>
+                            <current-user-principal><href>/user/</href></current-user-principal>
+                        </prop>
+                        <status>HTTP/1.1 200 OK</status>
+                    </propstat>
+                </response>
+            </multistatus>"#;
+        assert_eq!(
+            parse_href_property("http://localhost/", document, DAV, "current-user-principal")
+                .unwrap(),
+            Some("/user/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_missing_properties_are_ignored() {
+        let document = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:response>
+                    <d:href>/user/</d:href>
+                    <d:propstat>
+                        <d:prop><c:calendar-home-set/></d:prop>
+                        <d:status>HTTP/1.1 404 Not Found</d:status>
+                    </d:propstat>
+                </d:response>
+            </d:multistatus>"#;
+        assert_eq!(
+            parse_href_property(
+                "http://localhost/user/",
+                document,
+                CALDAV,
+                "calendar-home-set"
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_calendar_listing() {
+        let document = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:multistatus xmlns:d="DAV:"
+                           xmlns:c="urn:ietf:params:xml:ns:caldav"
+                           xmlns:a="http://apple.com/ns/ical/"
+                           xmlns:cs="http://calendarserver.org/ns/">
+                <d:response>
+                    <d:href>/user/calendars/</d:href>
+                    <d:propstat>
+                        <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+                <d:response>
+                    <d:href>/user/calendars/work/</d:href>
+                    <d:propstat>
+                        <d:prop>
+                            <d:resourcetype><d:collection/><c:calendar/></d:resourcetype>
+                            <d:displayname>Work</d:displayname>
+                            <a:calendar-color>#FF0000FF</a:calendar-color>
+                            <c:supported-calendar-component-set>
+                                <c:comp name="VEVENT"/>
+                                <c:comp name="VTODO"/>
+                            </c:supported-calendar-component-set>
+                            <cs:getctag>"abc123"</cs:getctag>
+                        </d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+            </d:multistatus>"#;
+        let collections = parse_collections("http://localhost/user/calendars/", document).unwrap();
+        assert_eq!(collections.len(), 2);
+        assert!(!collections[0].is_calendar);
+        let work = &collections[1];
+        assert!(work.is_calendar);
+        assert_eq!(work.href, "/user/calendars/work/");
+        assert_eq!(work.display_name.as_deref(), Some("Work"));
+        assert_eq!(work.colour.as_deref(), Some("#FF0000FF"));
+        assert_eq!(work.components, vec!["VEVENT", "VTODO"]);
+        assert_eq!(work.ctag.as_deref(), Some("\"abc123\""));
+    }
+
+    #[test]
+    fn test_invalid_documents_are_an_error() {
+        assert!(matches!(
+            parse_collections("http://localhost/", "<not-xml"),
+            Err(CalDavError::XmlError { .. })
+        ));
+    }
+}
@@ -1,29 +1,86 @@
-use http::uri::InvalidUri;
-use reqwest::{header::ToStrError, Error};
+use http::{method::InvalidMethod, uri::InvalidUri};
+use reqwest::{header::ToStrError, Error, Response, StatusCode};
+
+/// The longest part of a response body which is included in an error.
+const BODY_SNIPPET_LENGTH: usize = 200;
 
 #[derive(Error, Debug)]
 pub enum CalDavError {
-    #[error("request error")]
+    #[error("request error: {0}")]
     RequestError(Error),
-    #[error("other error")]
-    OtherError,
+    /// The server did not accept our credentials (if you are using OAuth this usually means that
+    /// the access token has expired).
+    #[error("unauthorized")]
+    Unauthorized,
+    /// The server responded with a status code which we weren't expecting.
+    #[error("{url} responded with status {status}: {body}")]
+    UnexpectedStatus {
+        url: String,
+        status: u16,
+        /// The start of the response body.
+        body: String,
+    },
+    /// The server sent back XML which could not be parsed (the error includes the position at
+    /// which parsing failed).
+    #[error("could not parse the XML returned by {url}: {source}")]
+    XmlError {
+        url: String,
+        source: roxmltree::Error,
+    },
+    /// The server sent back calendar data which could not be parsed.
+    #[error("could not parse the calendar data at {location}: {message}")]
+    ICalError { location: String, message: String },
+    /// A response from the server was missing something which we need.
+    #[error("the response from {url} did not contain a {property}")]
+    MissingResponseProperty { url: String, property: &'static str },
+    /// An event does not have a property which we need.
+    #[error("the event does not have a {0} property")]
+    MissingProperty(String),
+    /// An event has a property with a value which could not be understood.
+    #[error("could not parse the {property} property of the event ({value:?})")]
+    InvalidProperty { property: String, value: String },
+    /// The event has been changed (by somebody else) since we last fetched it, so we didn't
+    /// overwrite it. Fetch the event again to see the changes.
+    #[error("the event at {url} has been changed since it was last fetched")]
+    Conflict { url: String },
+    /// The server no longer recognises the sync token which was supplied, so the calendar needs
+    /// to be synced from scratch.
+    #[error("the sync token is no longer valid")]
+    InvalidSyncToken,
+    /// The server does not have an event with the provided UID.
+    #[error("could not find an event with the UID {0:?}")]
+    EventNotFound(String),
+    #[error("could not authenticate with the server: {0}")]
+    AuthenticationError(String),
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("invalid header: {0}")]
+    InvalidHeader(ToStrError),
+    #[error("invalid HTTP method")]
+    InvalidMethod(InvalidMethod),
 }
 
 impl From<digest_auth::Error> for CalDavError {
-    fn from(_: digest_auth::Error) -> Self {
-        Self::OtherError
+    fn from(e: digest_auth::Error) -> Self {
+        Self::AuthenticationError(format!("{:?}", e))
     }
 }
 
 impl From<InvalidUri> for CalDavError {
-    fn from(_: InvalidUri) -> Self {
-        CalDavError::OtherError
+    fn from(e: InvalidUri) -> Self {
+        CalDavError::InvalidUrl(e.to_string())
     }
 }
 
 impl From<ToStrError> for CalDavError {
-    fn from(_: ToStrError) -> Self {
-        CalDavError::OtherError
+    fn from(e: ToStrError) -> Self {
+        CalDavError::InvalidHeader(e)
+    }
+}
+
+impl From<InvalidMethod> for CalDavError {
+    fn from(e: InvalidMethod) -> Self {
+        CalDavError::InvalidMethod(e)
     }
 }
 
@@ -34,3 +91,42 @@ impl From<Error> for CalDavError {
 }
 
 pub type CalDavResult<T> = Result<T, CalDavError>;
+
+/// Returns `CalDavError::Unauthorized` if the server rejected our credentials.
+pub(crate) fn check_authorized(response: &Response) -> CalDavResult<()> {
+    if response.status() == StatusCode::UNAUTHORIZED {
+        Err(CalDavError::Unauthorized)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that the request succeeded (returning the response if it did). If it didn't, then the
+/// error contains the start of the response body (which usually explains what went wrong).
+pub(crate) async fn check_status(response: Response) -> CalDavResult<Response> {
+    check_authorized(&response)?;
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let url = response.url().to_string();
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    Err(CalDavError::UnexpectedStatus {
+        url,
+        status,
+        body: snippet(&body),
+    })
+}
+
+/// Returns the start of a response body (so that it can be included in an error).
+pub(crate) fn snippet(body: &str) -> String {
+    body.chars().take(BODY_SNIPPET_LENGTH).collect()
+}
+
+/// Parses an XML document which was returned by the server at the provided URL.
+pub(crate) fn parse_xml<'a>(url: &str, text: &'a str) -> CalDavResult<roxmltree::Document<'a>> {
+    roxmltree::Document::parse(text).map_err(|source| CalDavError::XmlError {
+        url: url.to_string(),
+        source,
+    })
+}
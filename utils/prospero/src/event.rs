@@ -1,43 +1,113 @@
 use atomic_refcell::AtomicRefCell;
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use format_xml::xml;
-use http::Method;
-use ical::parser::ical::component::IcalEvent;
-use roxmltree::Document;
+use http::{Method, StatusCode};
+use ical::{
+    parser::ical::component::{IcalCalendar, IcalEvent, IcalTimeZone},
+    property::Property,
+};
+use icalendar::Component;
 use std::sync::Arc;
 
 pub(crate) const DATETIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const FLOATING_DATETIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+const DATE_FORMAT: &str = "%Y%m%d";
 pub(crate) const DELETE: &[u8] = b"DELETE";
 
 use crate::{
-    calendar::get_calendar_data,
+    builder::NewEvent,
+    calendar::{get_calendar_data, Etag},
     client::{DavClient, REPORT},
-    error::{CalDavError, CalDavResult},
+    error::{check_status, parse_xml, CalDavError, CalDavResult},
+    properties::{split_list, unescape, Alarm, EventStatus, Person, Transparency},
+    recurrence::{self, Occurrence},
+    timezone::LocalTime,
+    xml::{child, is, successful_props, text, DAV},
 };
 
 const DTSTART: &str = "DTSTART";
 const DTEND: &str = "DTEND";
 const SUMMARY: &str = "SUMMARY";
+const DESCRIPTION: &str = "DESCRIPTION";
+const LOCATION: &str = "LOCATION";
+const URL: &str = "URL";
+const CATEGORIES: &str = "CATEGORIES";
+const STATUS: &str = "STATUS";
+const TRANSP: &str = "TRANSP";
+const ORGANIZER: &str = "ORGANIZER";
+const ATTENDEE: &str = "ATTENDEE";
+const UID: &str = "UID";
+const RECURRENCE_ID: &str = "RECURRENCE-ID";
+const EXDATE: &str = "EXDATE";
+
+/// The time at which an event starts (or ends).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventTime {
+    /// A specific point in time (e.g. `DTSTART:20210301T090000Z`).
+    Utc(DateTime<Utc>),
+    /// A "floating" time, which is the same local time in every timezone (e.g.
+    /// `DTSTART:20210301T090000`).
+    ///
+    /// Times which come with a `TZID` parameter are converted into UTC, unless we don't know
+    /// about the timezone (in which case they are also treated as floating).
+    Floating(NaiveDateTime),
+    /// A whole day (e.g. `DTSTART;VALUE=DATE:20210301`) – these are used for all-day events.
+    Date(NaiveDate),
+}
+
+impl EventTime {
+    /// Parses the value of a `DTSTART` or `DTEND` property.
+    pub fn parse<T>(value: T) -> Result<Self, ParseCalendarEventError>
+    where
+        T: AsRef<str>,
+    {
+        let value = value.as_ref().trim();
+        if value.ends_with('Z') {
+            parse_date(value).map(Self::Utc)
+        } else if value.contains('T') {
+            NaiveDateTime::parse_from_str(value, FLOATING_DATETIME_FORMAT)
+                .map(Self::Floating)
+                .map_err(|_| ParseCalendarEventError::InvalidTime(value.to_string()))
+        } else {
+            NaiveDate::parse_from_str(value, DATE_FORMAT)
+                .map(Self::Date)
+                .map_err(|_| ParseCalendarEventError::InvalidTime(value.to_string()))
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum EventPointerData {
-    FetchedEvent(IcalEvent),
-    CreatedEventResponse { uid: String },
+    FetchedEvent {
+        event: IcalEvent,
+        /// The timezones which are defined in the calendar that the event came from.
+        timezones: Arc<Vec<IcalTimeZone>>,
+    },
+    CreatedEventResponse {
+        uid: String,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct EventPointer {
+    /// The `href` of the resource which contains the event (this is usually, but not always, the
+    /// URL of the calendar followed by the UID of the event).
+    pub(crate) href: Option<String>,
     pub(crate) data: AtomicRefCell<EventPointerData>,
+    /// The ETag of the event (if we know it).
+    pub(crate) etag: AtomicRefCell<Option<Etag>>,
     pub(crate) url: Arc<String>,
     pub(crate) client: Arc<DavClient>,
 }
 
 impl EventPointer {
     /// Resolves the request and retreives the event from the server.s
-    async fn resolve(&self) -> CalDavResult<IcalEvent> {
+    async fn resolve(&self) -> CalDavResult<(IcalEvent, Arc<Vec<IcalTimeZone>>)> {
         let borrow = self.data.borrow();
         match &*borrow {
-            EventPointerData::FetchedEvent(event) => Ok(event.clone()),
+            EventPointerData::FetchedEvent { event, timezones } => {
+                Ok((event.clone(), timezones.clone()))
+            }
             EventPointerData::CreatedEventResponse { uid } => {
                 let body_string = xml! {
                     <?xml version="1.0" encoding="utf-8" ?>
@@ -59,47 +129,75 @@ impl EventPointer {
                     </C:calendar-query>
                 }
                 .to_string();
-                let res = self
+                let req = self
                     .client
-                    .request(Method::from_bytes(REPORT).unwrap(), self.url.as_str())
-                    .await?
+                    .request(Method::from_bytes(REPORT)?, self.url.as_str())?
                     .header("Content-Type", "application/xml; charset=\"utf-8\"")
-                    .body(body_string)
-                    .send()
-                    .await?;
-                let document = res.text().await.unwrap();
-                let document = Document::parse(&document).unwrap();
-                let event = get_calendar_data(document.descendants())
-                    .map(|e| e.events)
-                    .flatten()
-                    .next()
-                    .map(Ok)
-                    .unwrap_or(Err(CalDavError::OtherError))?;
+                    .body(body_string);
+                let res = self.client.send(req).await?;
+                let document = check_status(res).await?.text().await?;
+                let document = parse_xml(&self.url, &document)?;
+                let ((event, timezones), etag) = get_calendar_data(&self.url, &document)?
+                    .into_iter()
+                    .find_map(|data| {
+                        let etag = data.etag;
+                        // occurrences which have been changed are returned alongside the event
+                        // which they belong to
+                        fetched_events(data.calendar)
+                            .into_iter()
+                            .min_by_key(|(event, _)| property(event, RECURRENCE_ID).is_some())
+                            .map(|fetched| (fetched, etag))
+                    })
+                    .ok_or_else(|| CalDavError::EventNotFound(uid.clone()))?;
                 std::mem::drop(borrow);
+                *self.etag.borrow_mut() = etag;
                 let mut d = self.data.borrow_mut();
-                *d = EventPointerData::FetchedEvent(event.clone());
+                *d = EventPointerData::FetchedEvent {
+                    event: event.clone(),
+                    timezones: timezones.clone(),
+                };
                 std::mem::drop(d);
-                Ok(event)
+                Ok((event, timezones))
             }
         }
     }
 
+    /// The URL of the resource which contains this event. If the server didn't tell us where the
+    /// event is stored, we assume that it is stored under its UID.
+    pub async fn resource_url(&self) -> CalDavResult<String> {
+        match &self.href {
+            Some(href) => DavClient::resolve_href(&self.url, href),
+            None => Ok(format!("{}/{}.ics", self.url, self.uid().await?)),
+        }
+    }
+
+    /// Returns the UID of this event.
+    pub async fn uid(&self) -> CalDavResult<String> {
+        let borrow = self.data.borrow();
+        if let EventPointerData::CreatedEventResponse { uid } = &*borrow {
+            return Ok(uid.clone());
+        }
+        std::mem::drop(borrow);
+        self.resolve()
+            .await?
+            .0
+            .properties
+            .iter()
+            .find(|prop| prop.name == UID)
+            .map(|prop| prop.value.clone())
+            .flatten()
+            .ok_or_else(|| CalDavError::MissingProperty(UID.to_string()))
+    }
+
     /// Refreshes the event (by sending a request to the server.)
     pub async fn refresh(&self) -> CalDavResult<()> {
         let borrow = self.data.borrow();
         let data = match &*borrow {
-            EventPointerData::FetchedEvent(event) => EventPointerData::CreatedEventResponse {
-                uid: event
-                    .properties
-                    .iter()
-                    .find(|p| p.name == "UID")
-                    .map(Ok)
-                    .unwrap_or(Err(CalDavError::OtherError))?
-                    .value
-                    .clone()
-                    .map(Ok)
-                    .unwrap_or(Err(CalDavError::OtherError))?,
-            },
+            EventPointerData::FetchedEvent { event, .. } => {
+                EventPointerData::CreatedEventResponse {
+                    uid: fetched_uid(event)?,
+                }
+            }
             EventPointerData::CreatedEventResponse { uid } => {
                 EventPointerData::CreatedEventResponse { uid: uid.clone() }
             }
@@ -110,74 +208,281 @@ impl EventPointer {
         Ok(())
     }
 
-    /// Returns the start time of the event.
+    /// Returns the start time of the event. This fails for all-day events and events which
+    /// happen at a "floating" time (use `start` for those).
     pub async fn start_time(&self) -> CalDavResult<DateTime<Utc>> {
-        self.resolve()
+        self.utc_property(DTSTART).await
+    }
+    /// Returns the finish time of the event (see `start_time`).
+    pub async fn end_time(&self) -> CalDavResult<DateTime<Utc>> {
+        self.utc_property(DTEND).await
+    }
+    async fn utc_property(&self, name: &str) -> CalDavResult<DateTime<Utc>> {
+        let (event, timezones) = self.resolve().await?;
+        let prop =
+            property(&event, name).ok_or_else(|| CalDavError::MissingProperty(name.to_string()))?;
+        match LocalTime::from_property(prop, &timezones)?.event_time() {
+            EventTime::Utc(time) => Ok(time),
+            _ => Err(CalDavError::InvalidProperty {
+                property: name.to_string(),
+                value: prop.value.clone().unwrap_or_default(),
+            }),
+        }
+    }
+    /// Returns the time at which the event starts. Unlike `start_time` this also works for all-day
+    /// events and events which happen at a "floating" time.
+    ///
+    /// Returns `None` if the event does not have a start time.
+    pub async fn start(&self) -> CalDavResult<Option<EventTime>> {
+        self.time_property(DTSTART).await
+    }
+    /// Returns the time at which the event finishes (see `start`).
+    ///
+    /// Returns `None` if the event does not have an end time.
+    pub async fn end(&self) -> CalDavResult<Option<EventTime>> {
+        self.time_property(DTEND).await
+    }
+    async fn time_property(&self, name: &str) -> CalDavResult<Option<EventTime>> {
+        let (event, timezones) = self.resolve().await?;
+        property(&event, name)
+            .map(|prop| LocalTime::from_property(prop, &timezones).map(LocalTime::event_time))
+            .transpose()
+    }
+    /// Returns the occurrences of the event which overlap the period between `from` and `until`.
+    /// Recurring events are expanded (so a weekly event has one occurrence each week), and
+    /// events which don't recur have (at most) one occurrence.
+    ///
+    /// Floating times (and dates) can't be compared with `from` and `until` exactly, so
+    /// occurrences which are up to a day either side of the period are also returned.
+    pub async fn occurrences(
+        &self,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> CalDavResult<Vec<Occurrence>> {
+        let (event, timezones) = self.resolve().await?;
+        recurrence::occurrences(&event, &timezones, from, until)
+    }
+    /// Returns the summary of this event.
+    pub async fn summary(&self) -> CalDavResult<String> {
+        self.text_property(SUMMARY)
             .await?
+            .ok_or_else(|| CalDavError::MissingProperty(SUMMARY.to_string()))
+    }
+    /// Returns the (unescaped) value of a property which contains text.
+    async fn text_property(&self, name: &str) -> CalDavResult<Option<String>> {
+        Ok(property(&self.resolve().await?.0, name)
+            .and_then(|prop| prop.value.as_deref())
+            .map(unescape))
+    }
+    pub async fn description(&self) -> CalDavResult<Option<String>> {
+        self.text_property(DESCRIPTION).await
+    }
+    pub async fn location(&self) -> CalDavResult<Option<String>> {
+        self.text_property(LOCATION).await
+    }
+    /// Returns the web page which the event links to (the `URL` property).
+    pub async fn link(&self) -> CalDavResult<Option<String>> {
+        Ok(property(&self.resolve().await?.0, URL)
+            .and_then(|prop| prop.value.as_deref())
+            .map(|url| url.trim().to_string()))
+    }
+    pub async fn categories(&self) -> CalDavResult<Vec<String>> {
+        Ok(self
+            .resolve()
+            .await?
+            .0
             .properties
             .iter()
-            .find(|prop| prop.name == DTSTART)
-            .map(|prop| prop.value.as_ref())
-            .flatten()
-            .map(|t| parse_date(t).map_err(|_| CalDavError::OtherError))
-            .unwrap_or(Err(CalDavError::OtherError))
+            .filter(|prop| prop.name == CATEGORIES)
+            .filter_map(|prop| prop.value.as_deref())
+            .flat_map(split_list)
+            .collect())
     }
-    /// Returns the finish time of the event.
-    pub async fn end_time(&self) -> CalDavResult<DateTime<Utc>> {
-        self.resolve()
+    /// Returns `None` if the event doesn't have a (valid) status.
+    pub async fn status(&self) -> CalDavResult<Option<EventStatus>> {
+        Ok(property(&self.resolve().await?.0, STATUS)
+            .and_then(|prop| prop.value.as_deref())
+            .and_then(EventStatus::parse))
+    }
+    /// Returns whether the event takes up time (events are opaque unless they say otherwise).
+    pub async fn transparency(&self) -> CalDavResult<Transparency> {
+        Ok(property(&self.resolve().await?.0, TRANSP)
+            .and_then(|prop| prop.value.as_deref())
+            .map(Transparency::parse)
+            .unwrap_or_default())
+    }
+    pub async fn organizer(&self) -> CalDavResult<Option<Person>> {
+        Ok(property(&self.resolve().await?.0, ORGANIZER).and_then(Person::from_property))
+    }
+    pub async fn attendees(&self) -> CalDavResult<Vec<Person>> {
+        Ok(self
+            .resolve()
             .await?
+            .0
             .properties
             .iter()
-            .find(|prop| prop.name == DTEND)
-            .map(|prop| prop.value.as_ref())
-            .flatten()
-            .map(|t| parse_date(t).map_err(|_| CalDavError::OtherError))
-            .unwrap_or(Err(CalDavError::OtherError))
+            .filter(|prop| prop.name == ATTENDEE)
+            .filter_map(Person::from_property)
+            .collect())
     }
-    /// Returns the summary of this event.
-    pub async fn summary(&self) -> CalDavResult<String> {
-        self.resolve()
+    /// Returns the alarms which are attached to the event (alarms which we can't understand are
+    /// skipped).
+    pub async fn alarms(&self) -> CalDavResult<Vec<Alarm>> {
+        Ok(self
+            .resolve()
             .await?
-            .properties
+            .0
+            .alarms
             .iter()
-            .find(|prop| prop.name == SUMMARY)
-            .map(|prop| prop.value.clone())
-            .flatten()
-            .map(Ok)
-            .unwrap_or(Err(CalDavError::OtherError))
+            .filter_map(Alarm::from_component)
+            .collect())
     }
-    pub async fn delete(self) -> CalDavResult<()> {
-        let borrow = self.data.borrow();
-        let uid = match &*borrow {
-            EventPointerData::FetchedEvent(event) => event
-                .properties
-                .iter()
-                .find(|p| p.name == "UID")
-                .map(Ok)
-                .unwrap_or(Err(CalDavError::OtherError))?
-                .value
-                .clone()
-                .map(Ok)
-                .unwrap_or(Err(CalDavError::OtherError))?,
-            EventPointerData::CreatedEventResponse { uid } => uid.clone(),
+    /// The ETag of the version of the event which we last saw (if the server told us what it
+    /// was).
+    pub fn etag(&self) -> Option<Etag> {
+        self.etag.borrow().clone()
+    }
+    /// Replaces this event with the provided one. The UID of the provided event is set to the UID
+    /// of this event.
+    ///
+    /// If we know the ETag of the event, then the event is only replaced if nobody else has
+    /// changed it since we fetched it – if they have, `CalDavError::Conflict` is returned (and the
+    /// event is left alone).
+    pub async fn update<E>(&self, event: E) -> CalDavResult<()>
+    where
+        E: Into<NewEvent>,
+    {
+        let mut event = event.into();
+        let uid = self.uid().await?;
+        event.set_uid(&uid);
+        let url = self.resource_url().await?;
+        let mut req = self
+            .client
+            .request(Method::PUT, &url)?
+            .header("Content-Type", "text/calendar");
+        if let Some(etag) = self.etag() {
+            req = req.header("If-Match", etag.as_str());
+        }
+        let res = self.client.send(req.body(event.to_ical())).await?;
+        if res.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(CalDavError::Conflict { url });
+        }
+        let res = check_status(res).await?;
+        // servers which change the event before storing it don't tell us the new ETag, so we have
+        // to ask for it (otherwise the next update would overwrite the event regardless of whether
+        // anybody else has changed it)
+        let etag = match Etag::from_response(&res) {
+            Some(etag) => Some(etag),
+            // the event has been updated, so this is best-effort
+            None => self.fetch_etag(&url).await.unwrap_or(None),
         };
-        std::mem::drop(borrow);
-        self.client
-            .request(
-                Method::from_bytes(DELETE).unwrap(),
-                format!("{}/{}.ics", self.url, uid),
-            )
-            .await?
-            .send()
-            .await?;
+        *self.etag.borrow_mut() = etag;
+        let mut d = self.data.borrow_mut();
+        *d = EventPointerData::CreatedEventResponse { uid };
+        Ok(())
+    }
+
+    /// Asks the server for the current ETag of the resource at `url`.
+    async fn fetch_etag(&self, url: &str) -> CalDavResult<Option<Etag>> {
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:">
+                <D:prop>
+                    <D:getetag/>
+                </D:prop>
+            </D:propfind>
+        }
+        .to_string();
+        let text_response = self.client.propfind(url, "0", body).await?;
+        let document = parse_xml(url, &text_response)?;
+        Ok(document
+            .descendants()
+            .filter(|node| is(node, DAV, "response"))
+            .flat_map(successful_props)
+            .filter_map(|prop| child(prop, DAV, "getetag"))
+            .find_map(text)
+            .map(Etag))
+    }
+
+    pub async fn delete(self) -> CalDavResult<()> {
+        let req = self
+            .client
+            .request(Method::from_bytes(DELETE)?, self.resource_url().await?)?;
+        let res = self.client.send(req).await?;
+        check_status(res).await?;
         Ok(())
     }
 }
 
+/// Returns the first property of the event with the provided name.
+fn property<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a Property> {
+    event.properties.iter().find(|prop| prop.name == name)
+}
+
+/// Splits a calendar which was fetched from the server into its events (along with the timezones
+/// which they use).
+///
+/// Occurrences of a recurring event which have been changed are stored as separate events (with a
+/// `RECURRENCE-ID`), so they are excluded from the recurring event – otherwise they would appear
+/// twice.
+pub(crate) fn fetched_events(calendar: IcalCalendar) -> Vec<(IcalEvent, Arc<Vec<IcalTimeZone>>)> {
+    let timezones = Arc::new(calendar.timezones);
+    let changed = calendar
+        .events
+        .iter()
+        .filter_map(|event| Some((fetched_uid(event).ok()?, property(event, RECURRENCE_ID)?)))
+        .map(|(uid, id)| (uid, id.clone()))
+        .collect::<Vec<_>>();
+    calendar
+        .events
+        .into_iter()
+        .map(|mut event| {
+            if property(&event, RECURRENCE_ID).is_none() {
+                let uid = fetched_uid(&event).ok();
+                for (_, id) in changed
+                    .iter()
+                    .filter(|(changed, _)| Some(changed) == uid.as_ref())
+                {
+                    event.properties.push(Property {
+                        name: EXDATE.to_string(),
+                        params: id.params.clone(),
+                        value: id.value.clone(),
+                    });
+                }
+            }
+            (event, timezones.clone())
+        })
+        .collect()
+}
+
+/// Returns the UID of an event which was fetched from the server.
+fn fetched_uid(event: &IcalEvent) -> CalDavResult<String> {
+    event
+        .properties
+        .iter()
+        .find(|p| p.name == UID)
+        .and_then(|p| p.value.clone())
+        .ok_or_else(|| CalDavError::MissingProperty(UID.to_string()))
+}
+
+/// Returns the UID of the provided event (if it has one).
+pub(crate) fn uid_of(event: &icalendar::Event) -> Option<String> {
+    if !event.properties().contains_key(UID) {
+        return None;
+    }
+    event
+        .to_string()
+        .lines()
+        .find_map(|line| line.strip_prefix("UID:"))
+        .map(|uid| uid.trim().to_string())
+}
+
 #[derive(Error, Debug)]
 pub enum ParseCalendarEventError {
-    #[error("couldn't parse")]
-    CouldntParse,
+    #[error("could not parse {0:?} as a date or time")]
+    InvalidTime(String),
+    #[error("could not understand the recurrence rule {0:?}")]
+    InvalidRule(String),
 }
 
 fn parse_date<T>(date: T) -> Result<DateTime<Utc>, ParseCalendarEventError>
@@ -185,6 +490,6 @@ where
     T: AsRef<str>,
 {
     let naive_date = NaiveDateTime::parse_from_str(date.as_ref(), DATETIME_FORMAT)
-        .map_err(|_| ParseCalendarEventError::CouldntParse)?;
+        .map_err(|_| ParseCalendarEventError::InvalidTime(date.as_ref().to_string()))?;
     Ok(Utc.from_utc_datetime(&naive_date))
 }
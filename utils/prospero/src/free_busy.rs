@@ -0,0 +1,260 @@
+//! Works out when the owner of a calendar is busy, without downloading their events.
+//!
+//! The `free-busy-query` REPORT (RFC 4791, section 7.10) returns a `VFREEBUSY` component which
+//! lists the periods during which the calendar is busy (and nothing else about the events). Not
+//! every server supports it, so `Calendar::free_busy` falls back to searching for the events in
+//! the period and working out the busy time from them.
+
+use chrono::{DateTime, Utc};
+use reqwest::Method;
+
+use crate::{
+    calendar::Calendar,
+    client::REPORT,
+    error::{check_status, CalDavError, CalDavResult},
+    event::{EventTime, DATETIME_FORMAT},
+    properties::{parse_duration, EventStatus, Transparency},
+    timezone::param,
+};
+
+/// A period of time during which the owner of a calendar is busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusyPeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Sorts the periods, merges the ones which overlap (or touch) and removes the parts which are
+/// outside `start` to `end`.
+fn merge(
+    mut periods: Vec<BusyPeriod>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<BusyPeriod> {
+    periods.sort_by_key(|period| period.start);
+    let mut merged: Vec<BusyPeriod> = vec![];
+    for period in periods {
+        let period = BusyPeriod {
+            start: period.start.max(start),
+            end: period.end.min(end),
+        };
+        if period.start >= period.end {
+            continue;
+        }
+        match merged.last_mut() {
+            Some(last) if period.start <= last.end => last.end = last.end.max(period.end),
+            _ => merged.push(period),
+        }
+    }
+    merged
+}
+
+/// Parses a period in a `FREEBUSY` property (e.g. `20210301T090000Z/20210301T100000Z` or
+/// `20210301T090000Z/PT1H`).
+fn parse_period(period: &str) -> Option<BusyPeriod> {
+    let (start, end) = period.trim().split_at(period.trim().find('/')?);
+    let start = match EventTime::parse(start).ok()? {
+        EventTime::Utc(start) => start,
+        _ => return None,
+    };
+    let end = match EventTime::parse(&end[1..]) {
+        Ok(EventTime::Utc(end)) => end,
+        Ok(_) => return None,
+        Err(_) => start.checked_add_signed(parse_duration(&end[1..])?)?,
+    };
+    Some(BusyPeriod { start, end })
+}
+
+/// Parses the response to a `free-busy-query` REPORT.
+pub(crate) fn parse_free_busy(
+    url: &str,
+    text: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> CalDavResult<Vec<BusyPeriod>> {
+    let calendar = match ical::IcalParser::new(text.as_bytes()).next() {
+        Some(Ok(calendar)) => calendar,
+        Some(Err(e)) => {
+            return Err(CalDavError::ICalError {
+                location: url.to_string(),
+                message: format!("{:?}", e),
+            })
+        }
+        None => {
+            return Err(CalDavError::ICalError {
+                location: url.to_string(),
+                message: "no calendar was found".to_string(),
+            })
+        }
+    };
+    let periods = calendar
+        .free_busys
+        .iter()
+        .flat_map(|free_busy| free_busy.properties.iter())
+        .filter(|prop| prop.name == "FREEBUSY")
+        // periods without a type are busy (RFC 5545, section 3.2.9)
+        .filter(|prop| {
+            !matches!(param(prop, "FBTYPE"), Some(kind) if kind.eq_ignore_ascii_case("FREE"))
+        })
+        .filter_map(|prop| prop.value.as_deref())
+        .flat_map(|value| value.split(','))
+        .map(|period| {
+            parse_period(period).ok_or_else(|| CalDavError::InvalidProperty {
+                property: "FREEBUSY".to_string(),
+                value: period.to_string(),
+            })
+        })
+        .collect::<CalDavResult<Vec<_>>>()?;
+    Ok(merge(periods, start, end))
+}
+
+/// Converts a time into UTC (floating times and dates are taken to be in UTC).
+fn approximate_utc(time: EventTime) -> DateTime<Utc> {
+    match time {
+        EventTime::Utc(time) => time,
+        EventTime::Floating(time) => DateTime::from_utc(time, Utc),
+        EventTime::Date(date) => DateTime::from_utc(date.and_hms(0, 0, 0), Utc),
+    }
+}
+
+impl Calendar {
+    /// Returns the periods between `start` and `end` during which the owner of the calendar is
+    /// busy (in order, and with overlapping periods merged) using a `free-busy-query` REPORT.
+    ///
+    /// If the server doesn't support the report then `CalDavError::UnexpectedStatus` is returned
+    /// (use `free_busy` to fall back to working out the busy time from the events instead).
+    pub async fn free_busy_query(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> CalDavResult<Vec<BusyPeriod>> {
+        let start_string = start.format(DATETIME_FORMAT).to_string();
+        let end_string = end.format(DATETIME_FORMAT).to_string();
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <C:free-busy-query xmlns:C="urn:ietf:params:xml:ns:caldav">
+                <C:time-range start={start_string} end={end_string}/>
+            </C:free-busy-query>
+        }
+        .to_string();
+        let req = self
+            .client
+            .request(Method::from_bytes(REPORT)?, self.url.as_str())?
+            .header("Content-Type", "application/xml; charset=\"utf-8\"")
+            .header("Depth", "1")
+            .body(body);
+        let res = self.client.send(req).await?;
+        let text = check_status(res).await?.text().await?;
+        parse_free_busy(&self.url, &text, start, end)
+    }
+
+    /// Like `free_busy_query`, but if the server doesn't support free/busy queries then the busy
+    /// time is worked out from the events in the calendar. Transparent and cancelled events don't
+    /// count as busy time.
+    ///
+    /// When falling back to the events, floating times (and all-day events) are taken to be in
+    /// UTC.
+    pub async fn free_busy(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> CalDavResult<Vec<BusyPeriod>> {
+        match self.free_busy_query(start, end).await {
+            Err(CalDavError::UnexpectedStatus { .. }) => {}
+            result => return result,
+        }
+        let mut periods = vec![];
+        for event in self.date_search(start, end).await? {
+            if event.transparency().await? == Transparency::Transparent
+                || event.status().await? == Some(EventStatus::Cancelled)
+            {
+                continue;
+            }
+            for occurrence in event.occurrences(start, end).await? {
+                let period_end = match (occurrence.start, occurrence.end) {
+                    (_, Some(end)) => approximate_utc(end),
+                    (EventTime::Date(date), None) => approximate_utc(EventTime::Date(date.succ())),
+                    (start, None) => approximate_utc(start),
+                };
+                periods.push(BusyPeriod {
+                    start: approximate_utc(occurrence.start),
+                    end: period_end,
+                });
+            }
+        }
+        Ok(merge(periods, start, end))
+    }
+}
+
+#[cfg(test)]
+mod test_free_busy {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{merge, parse_free_busy, parse_period, BusyPeriod};
+
+    fn period(from_hours: i64, until_hours: i64) -> BusyPeriod {
+        let start = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        BusyPeriod {
+            start: start + Duration::hours(from_hours),
+            end: start + Duration::hours(until_hours),
+        }
+    }
+
+    #[test]
+    fn test_periods_are_merged_and_clipped() {
+        assert_eq!(
+            merge(
+                vec![
+                    period(5, 7),
+                    period(1, 3),
+                    period(2, 4),
+                    period(7, 8),
+                    period(9, 12)
+                ],
+                period(0, 0).start,
+                period(0, 10).end
+            ),
+            vec![period(1, 4), period(5, 8), period(9, 10)]
+        );
+    }
+
+    #[test]
+    fn test_parse_free_busy_response() {
+        let text = "BEGIN:VCALENDAR
+VERSION:2.0
+BEGIN:VFREEBUSY
+DTSTART:20210301T000000Z
+DTEND:20210302T000000Z
+FREEBUSY;FBTYPE=BUSY:20210301T090000Z/20210301T100000Z,20210301T093000Z/PT1H
+FREEBUSY;FBTYPE=FREE:20210301T120000Z/20210301T130000Z
+FREEBUSY;FBTYPE=BUSY-TENTATIVE:20210301T140000Z/PT30M
+END:VFREEBUSY
+END:VCALENDAR
+";
+        let busy = parse_free_busy(
+            "http://localhost/",
+            text,
+            period(0, 0).start,
+            period(0, 24).end,
+        )
+        .unwrap();
+        assert_eq!(
+            busy,
+            vec![
+                BusyPeriod {
+                    start: period(9, 9).start,
+                    end: period(9, 9).start + Duration::minutes(90),
+                },
+                BusyPeriod {
+                    start: period(14, 14).start,
+                    end: period(14, 14).start + Duration::minutes(30),
+                },
+            ]
+        );
+    }
+    #[test]
+    fn test_periods_which_end_too_late_are_rejected() {
+        assert_eq!(parse_period("20210301T090000Z/PT1H"), Some(period(9, 10)));
+        assert_eq!(parse_period("20210301T090000Z/P10000000000D"), None);
+    }
+}
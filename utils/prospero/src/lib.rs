@@ -14,9 +14,17 @@ extern crate derivative;
 #[macro_use]
 extern crate format_xml;
 
+pub mod builder;
 pub mod calendar;
 pub mod client;
+pub mod discovery;
 pub mod error;
 pub mod event;
+pub mod free_busy;
+pub mod properties;
+pub mod recurrence;
+pub mod sync;
+mod timezone;
+mod xml;
 
 pub use icalendar;
@@ -0,0 +1,351 @@
+//! Typed versions of the properties of an event (and the components, such as alarms, which it
+//! contains).
+//!
+//! Text in iCalendar data is escaped (commas, semicolons, backslashes and newlines are preceded
+//! by a backslash) – the values here have already been unescaped.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ical::{parser::ical::component::IcalAlarm, property::Property};
+
+use crate::{event::DATETIME_FORMAT, timezone::param};
+
+/// Whether an event is going ahead (the `STATUS` property).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventStatus {
+    Tentative,
+    Confirmed,
+    Cancelled,
+}
+
+impl EventStatus {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "TENTATIVE" => Some(Self::Tentative),
+            "CONFIRMED" => Some(Self::Confirmed),
+            "CANCELLED" => Some(Self::Cancelled),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tentative => "TENTATIVE",
+            Self::Confirmed => "CONFIRMED",
+            Self::Cancelled => "CANCELLED",
+        }
+    }
+}
+
+/// Whether an event takes up time (the `TRANSP` property). Transparent events (e.g. reminders)
+/// don't stop somebody from doing something else at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transparency {
+    Opaque,
+    Transparent,
+}
+
+impl Default for Transparency {
+    fn default() -> Self {
+        Self::Opaque
+    }
+}
+
+impl Transparency {
+    pub(crate) fn parse(value: &str) -> Self {
+        if value.trim().eq_ignore_ascii_case("TRANSPARENT") {
+            Self::Transparent
+        } else {
+            Self::Opaque
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Opaque => "OPAQUE",
+            Self::Transparent => "TRANSPARENT",
+        }
+    }
+}
+
+/// The organizer of (or an attendee at) an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Person {
+    /// Usually a `mailto:` URI.
+    pub address: String,
+    /// The person's name (the `CN` parameter).
+    pub name: Option<String>,
+    /// Whether the person has accepted the invitation (the `PARTSTAT` parameter, e.g.
+    /// `ACCEPTED`).
+    pub participation: Option<String>,
+}
+
+impl Person {
+    pub(crate) fn from_property(property: &Property) -> Option<Self> {
+        Some(Self {
+            address: property.value.as_deref()?.trim().to_string(),
+            name: param(property, "CN").map(|name| name.trim_matches('"').to_string()),
+            participation: param(property, "PARTSTAT").map(str::to_string),
+        })
+    }
+
+    /// The person's email address (if their address is a `mailto:` URI).
+    pub fn email(&self) -> Option<&str> {
+        let (scheme, email) = self.address.split_at(self.address.find(':')? + 1);
+        if scheme.eq_ignore_ascii_case("mailto:") {
+            Some(email)
+        } else {
+            None
+        }
+    }
+}
+
+/// When an alarm goes off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmTrigger {
+    /// Relative to the start of the event (negative durations are before the event starts).
+    Start(Duration),
+    /// Relative to the end of the event.
+    End(Duration),
+    /// At a specific time.
+    At(DateTime<Utc>),
+}
+
+/// A reminder which is attached to an event (a `VALARM` component).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alarm {
+    /// What happens when the alarm goes off (e.g. `DISPLAY` or `EMAIL`).
+    pub action: String,
+    pub trigger: AlarmTrigger,
+    pub description: Option<String>,
+}
+
+impl Alarm {
+    /// An alarm which displays `description` the provided amount of time before the event
+    /// starts.
+    pub fn reminder(before: Duration, description: &str) -> Self {
+        Self {
+            action: "DISPLAY".to_string(),
+            trigger: AlarmTrigger::Start(-before),
+            description: Some(description.to_string()),
+        }
+    }
+
+    /// Reads an alarm from an event. Alarms without a (valid) trigger are skipped.
+    pub(crate) fn from_component(alarm: &IcalAlarm) -> Option<Self> {
+        let property = |name: &'static str| alarm.properties.iter().find(|prop| prop.name == name);
+        let trigger = property("TRIGGER")?;
+        let value = trigger.value.as_deref()?.trim();
+        let trigger = match param(trigger, "VALUE") {
+            Some(kind) if kind.eq_ignore_ascii_case("DATE-TIME") => {
+                AlarmTrigger::At(Utc.datetime_from_str(value, DATETIME_FORMAT).ok()?)
+            }
+            _ => match param(trigger, "RELATED") {
+                Some(related) if related.eq_ignore_ascii_case("END") => {
+                    AlarmTrigger::End(parse_duration(value)?)
+                }
+                _ => AlarmTrigger::Start(parse_duration(value)?),
+            },
+        };
+        Some(Self {
+            action: property("ACTION")
+                .and_then(|prop| prop.value.as_deref())
+                .unwrap_or("DISPLAY")
+                .trim()
+                .to_string(),
+            trigger,
+            description: property("DESCRIPTION")
+                .and_then(|prop| prop.value.as_deref())
+                .map(unescape),
+        })
+    }
+
+    /// Writes out the alarm as a `VALARM` component.
+    pub(crate) fn to_ical(&self) -> String {
+        let trigger = match self.trigger {
+            AlarmTrigger::Start(offset) => format!("TRIGGER:{}", format_duration(offset)),
+            AlarmTrigger::End(offset) => {
+                format!("TRIGGER;RELATED=END:{}", format_duration(offset))
+            }
+            AlarmTrigger::At(time) => {
+                format!("TRIGGER;VALUE=DATE-TIME:{}", time.format(DATETIME_FORMAT))
+            }
+        };
+        let mut lines = vec![
+            "BEGIN:VALARM".to_string(),
+            format!("ACTION:{}", self.action),
+            trigger,
+        ];
+        if let Some(description) = &self.description {
+            lines.push(format!("DESCRIPTION:{}", escape(description)));
+        }
+        lines.push("END:VALARM".to_string());
+        lines.iter().map(|line| format!("{}\r\n", line)).collect()
+    }
+}
+
+/// Escapes text so that it can be used as the value of a property.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(char);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape`.
+pub(crate) fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            unescaped.push(char);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(next) => unescaped.push(next),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Splits a list of values (e.g. in `CATEGORIES`), which are separated by unescaped commas.
+pub(crate) fn split_list(text: &str) -> Vec<String> {
+    let mut values = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, char) in text.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => {
+                values.push(unescape(&text[start..index]));
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    values.push(unescape(&text[start..]));
+    values
+        .into_iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Parses a duration (e.g. `-PT15M` or `P1DT12H`).
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let (sign, value) = match value.as_bytes().first()? {
+        b'-' => (-1, &value[1..]),
+        b'+' => (1, &value[1..]),
+        _ => (1, value),
+    };
+    let value = value.strip_prefix('P')?;
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for char in value.chars() {
+        match char {
+            '0'..='9' => number.push(char),
+            'T' if number.is_empty() && !in_time => in_time = true,
+            unit => {
+                let amount = number.parse::<i64>().ok()?;
+                number.clear();
+                let seconds_per_unit = match (unit, in_time) {
+                    ('W', false) => 7 * 24 * 60 * 60,
+                    ('D', false) => 24 * 60 * 60,
+                    ('H', true) => 60 * 60,
+                    ('M', true) => 60,
+                    ('S', true) => 1,
+                    _ => return None,
+                };
+                // durations come from the server, so they might be too long to represent (the
+                // `Duration` constructors would panic)
+                let milliseconds = amount.checked_mul(seconds_per_unit)?.checked_mul(1000)?;
+                total = total.checked_add(&Duration::milliseconds(milliseconds))?;
+            }
+        }
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(total * sign)
+}
+
+/// Writes out a duration in the form which `parse_duration` accepts.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let sign = if duration < Duration::zero() { "-" } else { "" };
+    let mut seconds = duration.num_seconds().abs();
+    let days = seconds / 86400;
+    seconds %= 86400;
+    let (hours, minutes) = (seconds / 3600, seconds % 3600 / 60);
+    seconds %= 60;
+    let mut formatted = format!("{}P", sign);
+    if days > 0 {
+        formatted.push_str(&format!("{}D", days));
+    }
+    if hours > 0 || minutes > 0 || seconds > 0 || days == 0 {
+        formatted.push('T');
+        if hours > 0 {
+            formatted.push_str(&format!("{}H", hours));
+        }
+        if minutes > 0 {
+            formatted.push_str(&format!("{}M", minutes));
+        }
+        if seconds > 0 || (hours == 0 && minutes == 0) {
+            formatted.push_str(&format!("{}S", seconds));
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod test_properties {
+    use chrono::Duration;
+
+    use super::{escape, format_duration, parse_duration, split_list, unescape};
+
+    #[test]
+    fn test_escaping_text() {
+        let text = "Revise; then practise\\check, again\nTomorrow";
+        assert_eq!(
+            escape(text),
+            "Revise\\; then practise\\\\check\\, again\\nTomorrow"
+        );
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(
+            split_list("Lovelace,Work\\, revision, "),
+            vec!["Lovelace", "Work, revision"]
+        );
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("-PT15M"), Some(Duration::minutes(-15)));
+        assert_eq!(
+            parse_duration("P1DT12H"),
+            Some(Duration::days(1) + Duration::hours(12))
+        );
+        assert_eq!(parse_duration("P2W"), Some(Duration::weeks(2)));
+        assert_eq!(parse_duration("PT5"), None);
+        assert_eq!(parse_duration("15M"), None);
+        assert_eq!(parse_duration("P9999999999999W"), None);
+        assert_eq!(parse_duration("PT9223372036854775807S"), None);
+        assert_eq!(parse_duration("P9223372036854775807D"), None);
+        assert_eq!(format_duration(Duration::minutes(-15)), "-PT15M");
+        assert_eq!(
+            format_duration(Duration::days(1) + Duration::seconds(30)),
+            "P1DT30S"
+        );
+        assert_eq!(format_duration(Duration::zero()), "PT0S");
+    }
+}
@@ -0,0 +1,675 @@
+//! Expands recurring events into their individual occurrences.
+//!
+//! An event recurs if it has an `RRULE` (e.g. `FREQ=WEEKLY;BYDAY=MO,WE`) or an `RDATE` (a list of
+//! extra start times). Occurrences listed in an `EXDATE` are left out, as are occurrences which
+//! have been changed – these are stored as separate events (with a `RECURRENCE-ID`) and are
+//! returned separately.
+//!
+//! Rules are expanded in the local time of the event, so that an event which happens at 9am every
+//! week still happens at 9am after the clocks change. The `BYWEEKNO`, `BYYEARDAY`, `BYHOUR`,
+//! `BYMINUTE` and `BYSECOND` parts of rules are ignored, and frequencies of less than a day are not
+//! supported. Events with rules which we can't expand still happen at their start time (leaving
+//! them out altogether would mean that we think the user is free when they aren't).
+
+use std::{convert::TryFrom, str::FromStr};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Utc, Weekday};
+use ical::parser::ical::component::{IcalEvent, IcalTimeZone};
+
+use crate::{
+    error::{CalDavError, CalDavResult},
+    event::{EventTime, ParseCalendarEventError},
+    timezone::{LocalTime, Zone},
+};
+
+const DTSTART: &str = "DTSTART";
+const DTEND: &str = "DTEND";
+const RRULE: &str = "RRULE";
+const RDATE: &str = "RDATE";
+const EXDATE: &str = "EXDATE";
+
+/// One occurrence of an event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Occurrence {
+    pub start: EventTime,
+    /// `None` if the event does not have an end time.
+    pub end: Option<EventTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// A recurrence rule (RFC 5545, section 3.3.10).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<usize>,
+    until: Option<EventTime>,
+    /// The days of the week on which the event happens. If a number is provided, then only that
+    /// occurrence of the day in the month (or year) is included (e.g. `-1SU` is the last Sunday).
+    by_day: Vec<(Option<i32>, Weekday)>,
+    /// The days of the month on which the event happens (negative days count from the end of the
+    /// month).
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+    /// Which of the days picked out by the rest of the rule in each period (day, week, month or
+    /// year) the event happens on (e.g. `-1` is the last one).
+    by_set_pos: Vec<i32>,
+    week_start: Weekday,
+}
+
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    Some(match day {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// Parses a day in a `BYDAY` list (e.g. `MO`, `2TU` or `-1SU`).
+fn parse_by_day(day: &str) -> Option<(Option<i32>, Weekday)> {
+    let split = day.len().checked_sub(2)?;
+    let weekday = parse_weekday(day.get(split..)?)?;
+    let number = match day.get(..split)? {
+        "" => None,
+        // there are at most 53 of each weekday in a year
+        number => Some(
+            number
+                .parse()
+                .ok()
+                .filter(|n: &i32| *n != 0 && n.abs() <= 53)?,
+        ),
+    };
+    Some((number, weekday))
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    value.split(',').map(parse).collect()
+}
+
+impl FromStr for Rule {
+    type Err = ParseCalendarEventError;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseCalendarEventError::InvalidRule(rule.to_string());
+        let mut frequency = None;
+        let mut result = Rule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+            by_set_pos: vec![],
+            week_start: Weekday::Mon,
+        };
+        for part in rule.trim().split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_at(part.find('=').ok_or_else(invalid)?);
+            let value = &value[1..];
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "INTERVAL" => {
+                    result.interval = value.parse().ok().filter(|i| *i > 0).ok_or_else(invalid)?
+                }
+                "COUNT" => result.count = Some(value.parse().map_err(|_| invalid())?),
+                "UNTIL" => result.until = Some(EventTime::parse(value)?),
+                "BYDAY" => result.by_day = parse_list(value, parse_by_day).ok_or_else(invalid)?,
+                "BYMONTHDAY" => {
+                    result.by_month_day = parse_list(value, |day| {
+                        day.parse()
+                            .ok()
+                            .filter(|day: &i32| *day != 0 && day.abs() <= 31)
+                    })
+                    .ok_or_else(invalid)?
+                }
+                "BYMONTH" => {
+                    result.by_month = parse_list(value, |month| {
+                        month.parse().ok().filter(|month| (1..=12).contains(month))
+                    })
+                    .ok_or_else(invalid)?
+                }
+                "BYSETPOS" => {
+                    result.by_set_pos = parse_list(value, |position| {
+                        position
+                            .parse()
+                            .ok()
+                            .filter(|position: &i32| *position != 0 && position.abs() <= 366)
+                    })
+                    .ok_or_else(invalid)?
+                }
+                "WKST" => result.week_start = parse_weekday(value).ok_or_else(invalid)?,
+                // the parts which we don't support only ever make events happen at different times
+                // of the day or on different days, so ignoring them is better than leaving the
+                // event out
+                _ => {}
+            }
+        }
+        result.frequency = frequency.ok_or_else(invalid)?;
+        Ok(result)
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    // December always has 31 days (and this means that we never have to look at the next year,
+    // which might not be representable)
+    if month == 12 {
+        31
+    } else {
+        NaiveDate::from_ymd(year, month + 1, 1).pred().day()
+    }
+}
+
+/// Picks out the days in `days` (which must be in order) which match a `BYDAY` entry. Numbered
+/// entries count from the start (or end) of `days`.
+fn matching_weekdays(days: &[NaiveDate], by_day: &[(Option<i32>, Weekday)]) -> Vec<NaiveDate> {
+    let mut matching = vec![];
+    for (number, weekday) in by_day {
+        let candidates = days
+            .iter()
+            .filter(|day| day.weekday() == *weekday)
+            .copied()
+            .collect::<Vec<_>>();
+        match number {
+            None => matching.extend(candidates),
+            Some(n) => {
+                let index = if *n > 0 {
+                    Some(*n as usize - 1)
+                } else {
+                    candidates.len().checked_sub((-*n) as usize)
+                };
+                matching.extend(index.and_then(|index| candidates.get(index)));
+            }
+        }
+    }
+    matching
+}
+
+impl Rule {
+    fn matches_month_day(&self, date: NaiveDate) -> bool {
+        let last = days_in_month(date.year(), date.month()) as i32;
+        self.by_month_day.is_empty()
+            || self.by_month_day.iter().any(|day| {
+                let day = if *day > 0 { *day } else { last + 1 + day };
+                day == date.day() as i32
+            })
+    }
+
+    fn matches_month(&self, date: NaiveDate) -> bool {
+        self.by_month.is_empty() || self.by_month.contains(&date.month())
+    }
+
+    /// Picks out the days at the `BYSETPOS` positions in `days` (which must be in order).
+    fn set_positions(&self, days: Vec<NaiveDate>) -> Vec<NaiveDate> {
+        if self.by_set_pos.is_empty() {
+            return days;
+        }
+        let mut picked = self
+            .by_set_pos
+            .iter()
+            .filter_map(|position| {
+                let index = if *position > 0 {
+                    Some(*position as usize - 1)
+                } else {
+                    days.len().checked_sub((-*position) as usize)
+                };
+                index.and_then(|index| days.get(index)).copied()
+            })
+            .collect::<Vec<_>>();
+        picked.sort();
+        picked.dedup();
+        picked
+    }
+
+    /// The days in a month on which the event happens. `day` is the day of the month on which
+    /// the event first happened (which is used if the rule doesn't say which days to use).
+    fn month_days(&self, year: i32, month: u32, day: u32) -> Vec<NaiveDate> {
+        let days = (1..=days_in_month(year, month))
+            .map(|day| NaiveDate::from_ymd(year, month, day))
+            .collect::<Vec<_>>();
+        if !self.by_day.is_empty() {
+            matching_weekdays(&days, &self.by_day)
+                .into_iter()
+                .filter(|date| self.matches_month_day(*date))
+                .collect()
+        } else if !self.by_month_day.is_empty() {
+            days.into_iter()
+                .filter(|date| self.matches_month_day(*date))
+                .collect()
+        } else {
+            // months which don't have the day are skipped (RFC 5545, section 3.3.10)
+            days.into_iter().filter(|date| date.day() == day).collect()
+        }
+    }
+
+    /// Returns the first day of the `n`th period (day, week, month or year) after the one which
+    /// contains `start`, along with the days in that period on which the event happens. Returns
+    /// `None` if the period is too far in the future for a `NaiveDate` to represent.
+    fn period(&self, start: NaiveDate, n: u32) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+        Some(match self.frequency {
+            Frequency::Daily => {
+                let day = start.checked_add_signed(Duration::days(n as i64))?;
+                let matches = self.matches_month(day)
+                    && self.matches_month_day(day)
+                    && (self.by_day.is_empty()
+                        || self
+                            .by_day
+                            .iter()
+                            .any(|(_, weekday)| day.weekday() == *weekday));
+                (day, if matches { vec![day] } else { vec![] })
+            }
+            Frequency::Weekly => {
+                let offset = |weekday: Weekday| {
+                    (7 + weekday.num_days_from_monday() as i64
+                        - self.week_start.num_days_from_monday() as i64)
+                        % 7
+                };
+                let first = start
+                    .checked_sub_signed(Duration::days(offset(start.weekday())))?
+                    .checked_add_signed(Duration::weeks(n as i64))?;
+                let days = if self.by_day.is_empty() {
+                    vec![start.weekday()]
+                } else {
+                    self.by_day.iter().map(|(_, weekday)| *weekday).collect()
+                };
+                let days = days
+                    .into_iter()
+                    .filter_map(|weekday| first.checked_add_signed(Duration::days(offset(weekday))))
+                    .filter(|day| self.matches_month(*day))
+                    .collect();
+                (first, days)
+            }
+            Frequency::Monthly => {
+                let months = start.month0().checked_add(n)?;
+                let year = start.year().checked_add((months / 12) as i32)?;
+                let month = months % 12 + 1;
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                if !self.matches_month(first) {
+                    return Some((first, vec![]));
+                }
+                (first, self.month_days(year, month, start.day()))
+            }
+            Frequency::Yearly => {
+                let year = start.year().checked_add(i32::try_from(n).ok()?)?;
+                let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+                let days = if self.by_month.is_empty() && !self.by_day.is_empty() {
+                    // the numbers in `BYDAY` count through the whole year
+                    let days = (0..366)
+                        .filter_map(|day| first.checked_add_signed(Duration::days(day)))
+                        .take_while(|day| day.year() == year)
+                        .collect::<Vec<_>>();
+                    matching_weekdays(&days, &self.by_day)
+                        .into_iter()
+                        .filter(|date| self.matches_month_day(*date))
+                        .collect()
+                } else if self.by_month.is_empty() {
+                    self.month_days(year, start.month(), start.day())
+                } else {
+                    self.by_month
+                        .iter()
+                        .flat_map(|month| self.month_days(year, *month, start.day()))
+                        .collect()
+                };
+                (first, days)
+            }
+        })
+    }
+
+    /// Returns the start time of each occurrence of the event up to (and including) `limit`.
+    /// `start` is the (local) time of the first occurrence, and `zone` is the zone that it is
+    /// in.
+    pub(crate) fn expand(
+        &self,
+        start: NaiveDateTime,
+        zone: Zone,
+        limit: NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        let until = self.until.map(|until| match until {
+            EventTime::Utc(until) => zone.local(until),
+            EventTime::Floating(until) => until,
+            EventTime::Date(until) => until.and_hms(23, 59, 59),
+        });
+        let end = until.map_or(limit, |until| until.min(limit));
+        let count = self.count.unwrap_or(usize::MAX);
+        let mut occurrences = vec![];
+        if start > end || count == 0 {
+            return occurrences;
+        }
+        // the first occurrence always counts, even if it doesn't match the rule
+        occurrences.push(start);
+        let mut n: u32 = 0;
+        loop {
+            let (_, mut days) = match self.period(start.date(), n) {
+                Some(period) if period.0 <= end.date() => period,
+                // either we've gone past the end, or the rest of the periods are too far in the
+                // future to represent (either way there aren't any more occurrences)
+                _ => return occurrences,
+            };
+            days.sort();
+            days.dedup();
+            for day in self.set_positions(days) {
+                let occurrence = day.and_time(start.time());
+                if occurrence <= start {
+                    continue;
+                }
+                if occurrence > end || occurrences.len() >= count {
+                    return occurrences;
+                }
+                occurrences.push(occurrence);
+            }
+            n = match n.checked_add(self.interval) {
+                Some(n) => n,
+                None => return occurrences,
+            };
+        }
+    }
+}
+
+/// Returns the occurrences of an event which overlap the period between `from` and `until`.
+///
+/// Floating times (and dates) could be in any timezone, so occurrences which are up to a day
+/// either side of the period are also included.
+pub(crate) fn occurrences(
+    event: &IcalEvent,
+    timezones: &[IcalTimeZone],
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> CalDavResult<Vec<Occurrence>> {
+    let property = |name: &'static str| event.properties.iter().find(|prop| prop.name == name);
+    let all = |name: &'static str| {
+        event
+            .properties
+            .iter()
+            .filter(move |prop| prop.name == name)
+            .flat_map(|prop| LocalTime::list(prop, timezones))
+    };
+    let start = property(DTSTART)
+        .ok_or_else(|| CalDavError::MissingProperty(DTSTART.to_string()))
+        .and_then(|prop| LocalTime::from_property(prop, timezones))?;
+    let end = property(DTEND)
+        .map(|prop| LocalTime::from_property(prop, timezones))
+        .transpose()?;
+    let length = end.map_or_else(Duration::zero, |end| end.time - start.time);
+    let from = start.zone.local(from) - Duration::days(1);
+    let until = start.zone.local(until) + Duration::days(1);
+
+    // the other dates might not be in the same zone as the event
+    let to_local = |time: LocalTime| match time.event_time() {
+        EventTime::Utc(utc) => start.zone.local(utc),
+        _ => time.time,
+    };
+    let mut starts = match property(RRULE) {
+        Some(prop) => {
+            let value = prop.value.as_deref().unwrap_or_default();
+            match value.parse::<Rule>() {
+                Ok(rule) => rule.expand(start.time, start.zone, until),
+                // we still know when the first occurrence is
+                Err(_) => vec![start.time],
+            }
+        }
+        None => vec![start.time],
+    };
+    starts.extend(all(RDATE).map(to_local));
+    for excluded in all(EXDATE) {
+        match excluded.zone {
+            Zone::Date => starts.retain(|start| start.date() != excluded.time.date()),
+            _ => {
+                let excluded = to_local(excluded);
+                starts.retain(|start| *start != excluded)
+            }
+        }
+    }
+    starts.sort();
+    starts.dedup();
+
+    Ok(starts
+        .into_iter()
+        .filter(|time| *time + length >= from && *time <= until)
+        .map(|time| Occurrence {
+            start: start.zone.event_time(time),
+            end: end.map(|end| end.zone.event_time(time + length)),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test_recurrence {
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+    use super::{occurrences, Rule};
+    use crate::{event::EventTime, timezone::Zone};
+
+    fn time(year: i32, month: u32, day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, 0, 0)
+    }
+
+    fn expand(rule: &str, start: NaiveDateTime, limit: NaiveDateTime) -> Vec<NaiveDateTime> {
+        rule.parse::<Rule>()
+            .unwrap()
+            .expand(start, Zone::Floating, limit)
+    }
+
+    #[test]
+    fn test_weekly_rule() {
+        assert_eq!(
+            expand(
+                "FREQ=WEEKLY;BYDAY=MO,WE",
+                time(2021, 3, 1, 9),
+                time(2021, 3, 10, 0)
+            ),
+            vec![
+                time(2021, 3, 1, 9),
+                time(2021, 3, 3, 9),
+                time(2021, 3, 8, 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_and_until() {
+        assert_eq!(
+            expand(
+                "FREQ=DAILY;INTERVAL=2;COUNT=3",
+                time(2021, 3, 1, 9),
+                time(2022, 1, 1, 0)
+            ),
+            vec![
+                time(2021, 3, 1, 9),
+                time(2021, 3, 3, 9),
+                time(2021, 3, 5, 9)
+            ]
+        );
+        assert_eq!(
+            expand(
+                "FREQ=DAILY;UNTIL=20210302T090000Z",
+                time(2021, 3, 1, 9),
+                time(2022, 1, 1, 0)
+            ),
+            vec![time(2021, 3, 1, 9), time(2021, 3, 2, 9)]
+        );
+    }
+
+    #[test]
+    fn test_monthly_and_yearly_rules() {
+        assert_eq!(
+            expand(
+                "FREQ=MONTHLY;BYDAY=-1FR",
+                time(2021, 1, 29, 9),
+                time(2021, 4, 1, 0)
+            ),
+            vec![
+                time(2021, 1, 29, 9),
+                time(2021, 2, 26, 9),
+                time(2021, 3, 26, 9)
+            ]
+        );
+        // months without the 31st are skipped
+        assert_eq!(
+            expand("FREQ=MONTHLY", time(2021, 1, 31, 9), time(2021, 6, 1, 0)),
+            vec![
+                time(2021, 1, 31, 9),
+                time(2021, 3, 31, 9),
+                time(2021, 5, 31, 9)
+            ]
+        );
+        assert_eq!(
+            expand(
+                "FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+                time(2020, 3, 29, 1),
+                time(2022, 1, 1, 0)
+            ),
+            vec![time(2020, 3, 29, 1), time(2021, 3, 28, 1)]
+        );
+    }
+
+    #[test]
+    fn test_set_positions() {
+        // the last weekday of each month
+        assert_eq!(
+            expand(
+                "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1",
+                time(2021, 3, 31, 9),
+                time(2021, 6, 1, 0)
+            ),
+            vec![
+                time(2021, 3, 31, 9),
+                time(2021, 4, 30, 9),
+                time(2021, 5, 31, 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_parts_are_ignored() {
+        assert_eq!(
+            expand(
+                "FREQ=WEEKLY;BYHOUR=9;BYYEARDAY=1",
+                time(2021, 3, 1, 9),
+                time(2021, 3, 10, 0)
+            ),
+            vec![time(2021, 3, 1, 9), time(2021, 3, 8, 9)]
+        );
+    }
+
+    #[test]
+    fn test_huge_interval() {
+        // the second occurrence of each of these is too far in the future to represent
+        let limit = chrono::naive::MAX_DATE.and_hms(0, 0, 0);
+        for rule in &[
+            "FREQ=DAILY;INTERVAL=100000000",
+            "FREQ=WEEKLY;INTERVAL=100000000",
+            "FREQ=MONTHLY;INTERVAL=4294967295",
+            "FREQ=YEARLY;INTERVAL=300000",
+            "FREQ=YEARLY;INTERVAL=4294967295;BYDAY=1MO",
+        ] {
+            assert_eq!(
+                expand(rule, time(2021, 3, 1, 9), limit),
+                vec![time(2021, 3, 1, 9)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!("BYDAY=MO".parse::<Rule>().is_err());
+        assert!("FREQ=HOURLY".parse::<Rule>().is_err());
+        assert!("FREQ=WEEKLY;BYDAY=XX".parse::<Rule>().is_err());
+        assert!("FREQ=WEEKLY;BYDAY=-2147483648MO".parse::<Rule>().is_err());
+        assert!("FREQ=MONTHLY;BYSETPOS=0".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_recurring_event_occurrences() {
+        let calendar = ical::IcalParser::new(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:lecture
+DTSTART;TZID=Europe/London:20210322T090000
+DTEND;TZID=Europe/London:20210322T100000
+RRULE:FREQ=WEEKLY
+EXDATE;TZID=Europe/London:20210405T090000
+END:VEVENT
+END:VCALENDAR
+"
+            .as_bytes(),
+        )
+        .next()
+        .unwrap()
+        .unwrap();
+        let occurrences = occurrences(
+            &calendar.events[0],
+            &calendar.timezones,
+            Utc.ymd(2021, 3, 22).and_hms(0, 0, 0),
+            Utc.ymd(2021, 4, 14).and_hms(0, 0, 0),
+        )
+        .unwrap();
+        let starts = occurrences
+            .iter()
+            .map(|occurrence| occurrence.start)
+            .collect::<Vec<_>>();
+        // the clocks change on the 28th of March
+        assert_eq!(
+            starts,
+            vec![
+                EventTime::Utc(Utc.ymd(2021, 3, 22).and_hms(9, 0, 0)),
+                EventTime::Utc(Utc.ymd(2021, 3, 29).and_hms(8, 0, 0)),
+                EventTime::Utc(Utc.ymd(2021, 4, 12).and_hms(8, 0, 0)),
+            ]
+        );
+        assert_eq!(
+            occurrences[1].end,
+            Some(EventTime::Utc(Utc.ymd(2021, 3, 29).and_hms(9, 0, 0)))
+        );
+    }
+    #[test]
+    fn test_event_with_invalid_rule_still_happens_once() {
+        let calendar = ical::IcalParser::new(
+            "BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:meeting
+DTSTART:20210322T090000Z
+DTEND:20210322T100000Z
+RRULE:FREQ=SECONDLY
+END:VEVENT
+END:VCALENDAR
+"
+            .as_bytes(),
+        )
+        .next()
+        .unwrap()
+        .unwrap();
+        let occurrences = occurrences(
+            &calendar.events[0],
+            &calendar.timezones,
+            Utc.ymd(2021, 3, 22).and_hms(0, 0, 0),
+            Utc.ymd(2021, 3, 23).and_hms(0, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences
+                .iter()
+                .map(|occurrence| occurrence.start)
+                .collect::<Vec<_>>(),
+            vec![EventTime::Utc(Utc.ymd(2021, 3, 22).and_hms(9, 0, 0))]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Fetches only the events which have changed since the last time a calendar was synced.
+//!
+//! There are two ways of telling whether a calendar has changed:
+//! * the calendar's `ctag` (a non-standard but widely supported property) changes whenever
+//!   anything in the calendar changes, so if it is the same as last time then there is nothing to
+//!   do
+//! * a `sync-collection` REPORT (RFC 6578) returns the resources which have changed (or been
+//!   removed) since the state identified by a sync token, along with a new sync token
+//!
+//! Callers should store the sync token (and the ctag) so that they can be used next time.
+
+use atomic_refcell::AtomicRefCell;
+use reqwest::{Method, StatusCode};
+
+use crate::{
+    calendar::{Calendar, Etag},
+    client::{PROPFIND, REPORT},
+    error::{check_status, parse_xml, snippet, CalDavError, CalDavResult},
+    event::{fetched_events, EventPointer, EventPointerData},
+    xml::{child, is, successful_props, text, CALDAV, DAV},
+};
+
+const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// A resource (which contains one or more events) which has changed since the last sync.
+#[derive(Debug, Clone)]
+pub struct SyncedResource {
+    /// Identifies the resource – this is the same every time it is changed.
+    pub href: String,
+    pub etag: Option<Etag>,
+    /// The iCalendar data of the resource. Use `Calendar::events_from_data` to read the events
+    /// which it contains.
+    pub data: String,
+}
+
+/// The changes to a calendar since the last sync.
+#[derive(Debug, Clone)]
+pub struct SyncResult {
+    /// The token which should be supplied the next time the calendar is synced.
+    pub token: String,
+    /// The resources which have been created or changed.
+    pub changed: Vec<SyncedResource>,
+    /// The `href`s of the resources which have been removed.
+    pub removed: Vec<String>,
+}
+
+/// Parses the response to a `sync-collection` REPORT.
+pub(crate) fn parse_sync_response(url: &str, document: &str) -> CalDavResult<SyncResult> {
+    let document = parse_xml(url, document)?;
+    let root = document.root_element();
+    let token = child(root, DAV, "sync-token")
+        .and_then(text)
+        .ok_or_else(|| CalDavError::MissingResponseProperty {
+            url: url.to_string(),
+            property: "sync-token",
+        })?;
+    let mut changed = vec![];
+    let mut removed = vec![];
+    for response in root.children().filter(|node| is(node, DAV, "response")) {
+        let href = match child(response, DAV, "href").and_then(text) {
+            Some(href) => href,
+            None => continue,
+        };
+        // removed resources have a status (and no properties)
+        let status = child(response, DAV, "status").and_then(text);
+        if matches!(status, Some(status) if status.split_whitespace().nth(1) == Some("404")) {
+            removed.push(href);
+            continue;
+        }
+        let mut etag = None;
+        let mut data = None;
+        for prop in successful_props(response) {
+            if let Some(value) = child(prop, DAV, "getetag").and_then(text) {
+                etag = Some(Etag(value));
+            }
+            if let Some(value) = child(prop, CALDAV, "calendar-data").and_then(text) {
+                data = Some(value);
+            }
+        }
+        // resources which aren't calendar objects (e.g. the collection itself) don't have any
+        // calendar data
+        if let Some(data) = data {
+            changed.push(SyncedResource { href, etag, data });
+        }
+    }
+    Ok(SyncResult {
+        token,
+        changed,
+        removed,
+    })
+}
+
+impl Calendar {
+    /// Returns the calendar's ctag (if the server supports them).
+    pub async fn ctag(&self) -> CalDavResult<Option<String>> {
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:propfind xmlns:D="DAV:" xmlns:CS="http://calendarserver.org/ns/">
+                <D:prop>
+                    <CS:getctag/>
+                </D:prop>
+            </D:propfind>
+        }
+        .to_string();
+        let req = self
+            .client
+            .request(Method::from_bytes(PROPFIND)?, self.url.as_str())?
+            .header("Content-Type", "application/xml; charset=\"utf-8\"")
+            .header("Depth", "0")
+            .body(body);
+        let res = self.client.send(req).await?;
+        let text = check_status(res).await?.text().await?;
+        let document = parse_xml(&self.url, &text)?;
+        Ok(document
+            .descendants()
+            .filter(|node| is(node, DAV, "response"))
+            .flat_map(successful_props)
+            .filter_map(|prop| child(prop, CALENDARSERVER, "getctag"))
+            .find_map(text))
+    }
+
+    /// Returns the changes to the calendar since the state identified by `token`. If `token` is
+    /// `None` then every resource in the calendar is returned.
+    ///
+    /// If the server no longer recognises the token (servers don't keep them forever) then
+    /// `CalDavError::InvalidSyncToken` is returned, in which case the calendar should be synced
+    /// again from scratch.
+    pub async fn sync(&self, token: Option<&str>) -> CalDavResult<SyncResult> {
+        let token = token.unwrap_or_default();
+        let body = xml! {
+            <?xml version="1.0" encoding="utf-8" ?>
+            <D:sync-collection xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+                <D:sync-token>{token}</D:sync-token>
+                <D:sync-level>1</D:sync-level>
+                <D:prop>
+                    <D:getetag/>
+                    <C:calendar-data/>
+                </D:prop>
+            </D:sync-collection>
+        }
+        .to_string();
+        let req = self
+            .client
+            .request(Method::from_bytes(REPORT)?, self.url.as_str())?
+            .header("Content-Type", "application/xml; charset=\"utf-8\"")
+            .body(body);
+        let res = self.client.send(req).await?;
+        // servers reject tokens which they don't recognise with the `valid-sync-token`
+        // precondition (RFC 6578 section 3.2)
+        if !token.is_empty()
+            && (res.status() == StatusCode::FORBIDDEN || res.status() == StatusCode::CONFLICT)
+        {
+            let url = res.url().to_string();
+            let status = res.status().as_u16();
+            let body = res.text().await?;
+            return Err(if body.contains("valid-sync-token") {
+                CalDavError::InvalidSyncToken
+            } else {
+                CalDavError::UnexpectedStatus {
+                    url,
+                    status,
+                    body: snippet(&body),
+                }
+            });
+        }
+        let text = check_status(res).await?.text().await?;
+        parse_sync_response(&self.url, &text)
+    }
+
+    /// Reads the events in a resource which was returned by `sync` (this means that resources can
+    /// be stored, and read again later without contacting the server).
+    pub fn events_from_data(&self, resource: &SyncedResource) -> CalDavResult<Vec<EventPointer>> {
+        let calendar = match ical::IcalParser::new(resource.data.as_bytes()).next() {
+            Some(Ok(calendar)) => calendar,
+            Some(Err(e)) => {
+                return Err(CalDavError::ICalError {
+                    location: resource.href.clone(),
+                    message: format!("{:?}", e),
+                })
+            }
+            None => {
+                return Err(CalDavError::ICalError {
+                    location: resource.href.clone(),
+                    message: "no calendar was found".to_string(),
+                })
+            }
+        };
+        Ok(fetched_events(calendar)
+            .into_iter()
+            .map(|(event, timezones)| EventPointer {
+                href: Some(resource.href.clone()),
+                data: AtomicRefCell::new(EventPointerData::FetchedEvent { event, timezones }),
+                etag: AtomicRefCell::new(resource.etag.clone()),
+                url: self.url.clone(),
+                client: self.client.clone(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test_sync {
+    use super::parse_sync_response;
+
+    #[test]
+    fn test_parse_sync_response() {
+        let document = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:response>
+                    <d:href>/user/calendars/calendar/changed.ics</d:href>
+                    <d:propstat>
+                        <d:prop>
+                            <d:getetag>"2"</d:getetag>
+                            <c:calendar-data>BEGIN:VCALENDAR
+END:VCALENDAR
+</c:calendar-data>
+                        </d:prop>
+                        <d:status>HTTP/1.1 200 OK</d:status>
+                    </d:propstat>
+                </d:response>
+                <d:response>
+                    <d:href>/user/calendars/calendar/removed.ics</d:href>
+                    <d:status>HTTP/1.1 404 Not Found</d:status>
+                </d:response>
+                <d:sync-token>http://example.com/sync/2</d:sync-token>
+            </d:multistatus>"#;
+        let result = parse_sync_response("http://localhost/", document).unwrap();
+        assert_eq!(result.token, "http://example.com/sync/2");
+        assert_eq!(result.changed.len(), 1);
+        assert_eq!(
+            result.changed[0].href,
+            "/user/calendars/calendar/changed.ics"
+        );
+        assert_eq!(result.changed[0].etag.as_ref().unwrap().as_str(), "\"2\"");
+        assert!(result.changed[0].data.starts_with("BEGIN:VCALENDAR"));
+        assert_eq!(result.removed, vec!["/user/calendars/calendar/removed.ics"]);
+    }
+
+    #[test]
+    fn test_sync_response_without_token_is_an_error() {
+        assert!(parse_sync_response(
+            "http://localhost/",
+            r#"<d:multistatus xmlns:d="DAV:"></d:multistatus>"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,390 @@
+//! Converts the times in events into UTC.
+//!
+//! A time in an event can be written in UTC (`20210301T090000Z`), as a "floating" local time
+//! (`20210301T090000`) or as a local time in the timezone named by its `TZID` parameter
+//! (`DTSTART;TZID=Europe/London:20210301T090000`). Most servers use IANA names for timezones, but
+//! some (e.g. Outlook) use their own names – in that case we use the `VTIMEZONE` in the calendar,
+//! which describes the timezone's UTC offsets and when they change.
+//!
+//! When we create a calendar we also write out a `VTIMEZONE` for the IANA timezone which it uses
+//! (see `vtimezone`).
+
+use chrono::{DateTime, Duration, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, Tz};
+use ical::{parser::ical::component::IcalTimeZone, property::Property};
+
+use crate::{
+    error::{CalDavError, CalDavResult},
+    event::EventTime,
+    recurrence::Rule,
+};
+
+const TZID: &str = "TZID";
+const DTSTART: &str = "DTSTART";
+const RRULE: &str = "RRULE";
+const TZOFFSETFROM: &str = "TZOFFSETFROM";
+const TZOFFSETTO: &str = "TZOFFSETTO";
+
+/// Returns the (first) value of a parameter of the property.
+pub(crate) fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// Returns the value of the first property with the provided name.
+fn value<'a>(properties: &'a [Property], name: &str) -> Option<&'a str> {
+    properties
+        .iter()
+        .find(|property| property.name == name)
+        .and_then(|property| property.value.as_deref())
+        .map(str::trim)
+}
+
+/// How the local time in a property should be converted into UTC.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Zone<'a> {
+    /// The property is a date (rather than a time).
+    Date,
+    Utc,
+    Floating,
+    /// A timezone from the IANA database.
+    Named(Tz),
+    /// A timezone which is described by a `VTIMEZONE` in the calendar.
+    Defined(&'a IcalTimeZone),
+}
+
+impl<'a> Zone<'a> {
+    /// Finds the timezone with the provided `TZID`. Times in timezones which we don't know about
+    /// are treated as floating.
+    fn find(tzid: &str, timezones: &'a [IcalTimeZone]) -> Self {
+        if let Ok(tz) = tzid.parse::<Tz>() {
+            return Zone::Named(tz);
+        }
+        timezones
+            .iter()
+            .find(|timezone| value(&timezone.properties, TZID) == Some(tzid))
+            .map(Zone::Defined)
+            .unwrap_or(Zone::Floating)
+    }
+
+    /// Converts a local time in this zone into an `EventTime`.
+    pub(crate) fn event_time(self, local: NaiveDateTime) -> EventTime {
+        let utc = match self {
+            Zone::Date => return EventTime::Date(local.date()),
+            Zone::Utc => Some(Utc.from_utc_datetime(&local)),
+            Zone::Floating => None,
+            // some local times happen twice (in which case we take the earlier one) and some
+            // never happen at all (in which case we move forward by an hour)
+            Zone::Named(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .or_else(|| {
+                    tz.from_local_datetime(&(local + Duration::hours(1)))
+                        .earliest()
+                })
+                .map(|time| time.with_timezone(&Utc)),
+            Zone::Defined(timezone) => defined_offset(timezone, local)
+                .map(|offset| Utc.from_utc_datetime(&(local - offset))),
+        };
+        utc.map(EventTime::Utc)
+            .unwrap_or(EventTime::Floating(local))
+    }
+
+    /// Converts a point in time into the local time in this zone (times which don't have a
+    /// timezone are taken to be in UTC).
+    pub(crate) fn local(self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Named(tz) => time.with_timezone(&tz).naive_local(),
+            Zone::Defined(timezone) => {
+                time.naive_utc()
+                    + defined_offset(timezone, time.naive_utc()).unwrap_or_else(Duration::zero)
+            }
+            Zone::Date | Zone::Utc | Zone::Floating => time.naive_utc(),
+        }
+    }
+}
+
+/// A time as it is written in an event, along with the zone which it is in.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LocalTime<'a> {
+    pub time: NaiveDateTime,
+    pub zone: Zone<'a>,
+}
+
+impl<'a> LocalTime<'a> {
+    /// Parses a date or time. `tzid` is the value of the `TZID` parameter of the property which
+    /// the value came from (if there was one).
+    pub fn parse(value: &str, tzid: Option<&str>, timezones: &'a [IcalTimeZone]) -> Option<Self> {
+        Some(match EventTime::parse(value).ok()? {
+            EventTime::Utc(time) => LocalTime {
+                time: time.naive_utc(),
+                zone: Zone::Utc,
+            },
+            EventTime::Floating(time) => LocalTime {
+                time,
+                zone: tzid
+                    .map(|tzid| Zone::find(tzid, timezones))
+                    .unwrap_or(Zone::Floating),
+            },
+            EventTime::Date(date) => LocalTime {
+                time: date.and_hms(0, 0, 0),
+                zone: Zone::Date,
+            },
+        })
+    }
+
+    /// Parses the value of a property (e.g. `DTSTART`) which contains a single date or time.
+    pub fn from_property(property: &Property, timezones: &'a [IcalTimeZone]) -> CalDavResult<Self> {
+        let value = property.value.as_deref().unwrap_or_default();
+        Self::parse(value, param(property, TZID), timezones).ok_or_else(|| {
+            CalDavError::InvalidProperty {
+                property: property.name.clone(),
+                value: value.to_string(),
+            }
+        })
+    }
+
+    /// Parses the value of a property (e.g. `EXDATE`) which contains a list of dates or times.
+    /// Values which can't be parsed are skipped.
+    pub fn list(property: &Property, timezones: &'a [IcalTimeZone]) -> Vec<Self> {
+        let tzid = param(property, TZID);
+        property
+            .value
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|value| Self::parse(value, tzid, timezones))
+            .collect()
+    }
+
+    pub fn event_time(self) -> EventTime {
+        self.zone.event_time(self.time)
+    }
+}
+
+/// Parses a UTC offset (e.g. `+0100` or `-053000`).
+fn parse_offset(offset: &str) -> Option<Duration> {
+    let (sign, digits) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[0..2].parse().ok()?;
+    let minutes: i64 = digits[2..4].parse().ok()?;
+    let seconds: i64 = digits.get(4..6).map_or(Some(0), |s| s.parse().ok())?;
+    Some(Duration::seconds(
+        sign * (hours * 3600 + minutes * 60 + seconds),
+    ))
+}
+
+/// Works out the UTC offset of a timezone described by a `VTIMEZONE` at the provided local time.
+///
+/// Each `STANDARD` or `DAYLIGHT` component says when it starts (and, if it happens every year,
+/// how it recurs) – the offset is the one which started most recently.
+fn defined_offset(timezone: &IcalTimeZone, local: NaiveDateTime) -> Option<Duration> {
+    let onsets = timezone.transitions.iter().filter_map(|transition| {
+        let properties = &transition.properties;
+        let start = match EventTime::parse(value(properties, DTSTART)?).ok()? {
+            EventTime::Floating(start) => start,
+            _ => return None,
+        };
+        let onset = match value(properties, RRULE).and_then(|rule| rule.parse::<Rule>().ok()) {
+            Some(rule) => rule.expand(start, Zone::Floating, local).pop(),
+            None => Some(start).filter(|start| *start <= local),
+        };
+        Some((onset, start, properties))
+    });
+    let (mut latest, mut earliest) = (None, None);
+    for (onset, start, properties) in onsets {
+        if let Some(onset) = onset {
+            if latest.map_or(true, |(latest, _)| onset > latest) {
+                latest = Some((onset, properties));
+            }
+        }
+        if earliest.map_or(true, |(earliest, _)| start < earliest) {
+            earliest = Some((start, properties));
+        }
+    }
+    match (latest, earliest) {
+        (Some((_, properties)), _) => value(properties, TZOFFSETTO).and_then(parse_offset),
+        // the time is before the timezone's first change, so the offset is the one it changed
+        // from
+        (None, Some((_, properties))) => value(properties, TZOFFSETFROM).and_then(parse_offset),
+        (None, None) => None,
+    }
+}
+
+/// Writes out a UTC offset in the form which `parse_offset` accepts.
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    let formatted = format!("{}{:02}{:02}", sign, seconds / 3600, seconds % 3600 / 60);
+    match seconds % 60 {
+        0 => formatted,
+        extra => format!("{}{:02}", formatted, extra),
+    }
+}
+
+/// Writes out a `VTIMEZONE` which describes an IANA timezone between `from` and `until`.
+///
+/// Rather than working out recurrence rules, each change in the UTC offset is listed as a separate
+/// `STANDARD` or `DAYLIGHT` component (the first of which starts at `from`). The changes are found
+/// by checking the offset once a day, and then searching for the second at which it changed.
+pub(crate) fn vtimezone(tz: Tz, from: DateTime<Utc>, until: DateTime<Utc>) -> String {
+    let offset_at = |time: DateTime<Utc>| {
+        let offset = tz.offset_from_utc_datetime(&time.naive_utc());
+        (
+            offset.fix().local_minus_utc(),
+            offset.dst_offset() != Duration::zero(),
+            offset.to_string(),
+        )
+    };
+    let component = |onset: DateTime<Utc>, offset_from: i32, (offset_to, dst, name)| {
+        let kind = if dst { "DAYLIGHT" } else { "STANDARD" };
+        // the start of each component is written in the local time before it starts
+        let start = onset.naive_utc() + Duration::seconds(offset_from.into());
+        vec![
+            format!("BEGIN:{}", kind),
+            format!("{}:{}", DTSTART, start.format("%Y%m%dT%H%M%S")),
+            format!("{}:{}", TZOFFSETFROM, format_offset(offset_from)),
+            format!("{}:{}", TZOFFSETTO, format_offset(offset_to)),
+            format!("TZNAME:{}", name),
+            format!("END:{}", kind),
+        ]
+    };
+    let mut lines = vec![
+        "BEGIN:VTIMEZONE".to_string(),
+        format!("{}:{}", TZID, tz.name()),
+    ];
+    let mut offset = offset_at(from);
+    lines.extend(component(from, offset.0, offset.clone()));
+    let mut time = from;
+    while time < until {
+        let next = time + Duration::days(1);
+        if offset_at(next) != offset {
+            // the offset changed at some point after `time` and at or before `next`
+            let (mut before, mut after) = (time, next);
+            while after - before > Duration::seconds(1) {
+                let middle = before + (after - before) / 2;
+                if offset_at(middle) == offset {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            let new_offset = offset_at(after);
+            lines.extend(component(after, offset.0, new_offset.clone()));
+            offset = new_offset;
+        }
+        time = next;
+    }
+    lines.push("END:VTIMEZONE".to_string());
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+#[cfg(test)]
+mod test_timezone {
+    use chrono::{Duration, NaiveDate, TimeZone, Utc};
+
+    use super::{defined_offset, vtimezone, LocalTime};
+    use crate::event::EventTime;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR
+BEGIN:VTIMEZONE
+TZID:GMT Standard Time
+BEGIN:STANDARD
+DTSTART:16011028T020000
+RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10
+TZOFFSETFROM:+0100
+TZOFFSETTO:-0000
+END:STANDARD
+BEGIN:DAYLIGHT
+DTSTART:16010325T010000
+RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3
+TZOFFSETFROM:-0000
+TZOFFSETTO:+0100
+END:DAYLIGHT
+END:VTIMEZONE
+END:VCALENDAR
+";
+
+    #[test]
+    fn test_iana_timezones() {
+        let time = LocalTime::parse("20210601T090000", Some("Europe/London"), &[]).unwrap();
+        assert_eq!(
+            time.event_time(),
+            EventTime::Utc(Utc.ymd(2021, 6, 1).and_hms(8, 0, 0))
+        );
+        let time = LocalTime::parse("20210101T090000", Some("Europe/London"), &[]).unwrap();
+        assert_eq!(
+            time.event_time(),
+            EventTime::Utc(Utc.ymd(2021, 1, 1).and_hms(9, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_timezones_defined_in_the_calendar() {
+        let calendar = ical::IcalParser::new(CALENDAR.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let timezones = calendar.timezones;
+        let summer = LocalTime::parse("20210601T090000", Some("GMT Standard Time"), &timezones);
+        assert_eq!(
+            summer.unwrap().event_time(),
+            EventTime::Utc(Utc.ymd(2021, 6, 1).and_hms(8, 0, 0))
+        );
+        let winter = LocalTime::parse("20211101T090000", Some("GMT Standard Time"), &timezones);
+        assert_eq!(
+            winter.unwrap().event_time(),
+            EventTime::Utc(Utc.ymd(2021, 11, 1).and_hms(9, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_unknown_timezones_are_floating() {
+        let time = LocalTime::parse("20210601T090000", Some("Somewhere/Else"), &[]).unwrap();
+        assert_eq!(
+            time.event_time(),
+            EventTime::Floating(NaiveDate::from_ymd(2021, 6, 1).and_hms(9, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_written_timezones_can_be_read_back() {
+        let text = format!(
+            "BEGIN:VCALENDAR\r\n{}END:VCALENDAR\r\n",
+            vtimezone(
+                chrono_tz::Europe::London,
+                Utc.ymd(2021, 1, 1).and_hms(0, 0, 0),
+                Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)
+            )
+        );
+        let calendar = ical::IcalParser::new(text.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let timezone = &calendar.timezones[0];
+        // one component for the start of the year, and one for each change
+        assert_eq!(timezone.transitions.len(), 3);
+        let offset = |month, day, hour| {
+            defined_offset(
+                timezone,
+                NaiveDate::from_ymd(2021, month, day).and_hms(hour, 0, 0),
+            )
+        };
+        assert_eq!(offset(1, 1, 9), Some(Duration::zero()));
+        assert_eq!(offset(3, 28, 0), Some(Duration::zero()));
+        assert_eq!(offset(3, 28, 2), Some(Duration::hours(1)));
+        assert_eq!(offset(6, 1, 9), Some(Duration::hours(1)));
+        assert_eq!(offset(11, 1, 9), Some(Duration::zero()));
+    }
+}
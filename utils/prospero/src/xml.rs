@@ -0,0 +1,63 @@
+//! Helpers for reading the XML which CalDAV servers send back (and for writing the XML which we
+//! send to them).
+
+use roxmltree::Node;
+
+pub(crate) const DAV: &str = "DAV:";
+pub(crate) const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+/// Whether the node is an element with the provided name (in the provided namespace).
+pub(crate) fn is(node: &Node, namespace: &str, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().name() == name
+        && node.tag_name().namespace() == Some(namespace)
+}
+
+/// Returns the first child of the node with the provided name (in the provided namespace).
+pub(crate) fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| is(child, namespace, name))
+}
+
+/// Returns the (trimmed) text inside the node, if there is any.
+pub(crate) fn text(node: Node) -> Option<String> {
+    node.text()
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// Returns the `prop` elements in a `response` which were returned successfully (properties which
+/// the server doesn't have are returned in a separate `propstat` with a 404 status).
+pub(crate) fn successful_props<'a, 'input>(
+    response: Node<'a, 'input>,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    response
+        .children()
+        .filter(|node| is(node, DAV, "propstat"))
+        .filter(|propstat| {
+            child(*propstat, DAV, "status")
+                .and_then(text)
+                .map(|status| status.split_whitespace().nth(1) == Some("200"))
+                .unwrap_or(false)
+        })
+        .filter_map(|propstat| child(propstat, DAV, "prop"))
+}
+
+/// Escapes text so that it can be included in an XML document (`xml!` doesn't do this for us).
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
@@ -1,15 +1,12 @@
 #[tokio::test]
-#[cfg(feature = "caldav_test")]
-/// Note that this assumes that a test server is running at localhost:8080
-///
-/// This is automatically done on our continuous integration
 async fn test_caldav_calendars() {
     use chrono::{Duration, Utc};
     use icalendar::{Component, Event};
     use prospero::client::DavClient;
     use std::ops::Add;
 
-    let client = DavClient::new_unauthenticated("http://localhost:8080/user/calendars/calendar");
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
     let calendar = client.calendar();
     calendar
         .save_event(
@@ -59,3 +56,528 @@ async fn test_caldav_calendars() {
     assert!(sorted[1].start_time().await.is_ok());
     assert!(sorted[1].end_time().await.is_ok());
 }
+
+#[tokio::test]
+async fn test_caldav_event_uid_is_kept() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::client::DavClient;
+    use uuid::Uuid;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let uid = Uuid::new_v4().to_string();
+    let start = Utc::now() + Duration::days(20);
+    let event = calendar
+        .save_event(
+            Event::new()
+                .uid(&uid)
+                .summary("original-summary")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("failed to add event");
+    assert_eq!(event.uid().await.unwrap(), uid);
+    event
+        .update(
+            Event::new()
+                .summary("updated-summary")
+                .starts(start)
+                .ends(start + Duration::hours(2))
+                .done(),
+        )
+        .await
+        .expect("failed to update event");
+    let events = calendar
+        .date_search(start - Duration::minutes(1), start + Duration::hours(3))
+        .await
+        .expect("failed to search for dates");
+    let mut found = false;
+    for event in events {
+        if event.uid().await.unwrap() == uid {
+            assert!(!found, "the event should not have been duplicated");
+            found = true;
+            assert_eq!(event.summary().await.unwrap(), "updated-summary");
+            assert_eq!(
+                event.end_time().await.unwrap() - event.start_time().await.unwrap(),
+                Duration::hours(2)
+            );
+        }
+    }
+    assert!(found);
+}
+
+#[tokio::test]
+async fn test_caldav_discovery() {
+    use prospero::client::DavClient;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(format!("{}/", server.url()));
+    assert_eq!(
+        client.current_user_principal().await.unwrap(),
+        server.principal_url()
+    );
+    assert_eq!(
+        client.calendar_home_set().await.unwrap(),
+        server.home_set_url()
+    );
+    let calendars = client.calendars().await.expect("failed to list calendars");
+    let calendar = calendars
+        .iter()
+        .find(|calendar| calendar.calendar.url() == server.calendar_url())
+        .expect("the default calendar should have been found");
+    assert!(calendar.supports_events());
+    assert!(calendar.ctag.is_some());
+}
+
+#[tokio::test]
+async fn test_caldav_update_detects_conflicts() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::{client::DavClient, error::CalDavError};
+    use uuid::Uuid;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let uid = Uuid::new_v4().to_string();
+    let start = Utc::now() + Duration::days(30);
+    calendar
+        .save_event(
+            Event::new()
+                .uid(&uid)
+                .summary("original-summary")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("failed to add event");
+    let find = || async {
+        for event in calendar
+            .date_search(start - Duration::minutes(1), start + Duration::hours(2))
+            .await
+            .expect("failed to search for dates")
+        {
+            if event.uid().await.unwrap() == uid {
+                return event;
+            }
+        }
+        panic!("the event should have been found");
+    };
+    let first = find().await;
+    let second = find().await;
+    assert!(first.etag().is_some());
+    first
+        .update(
+            Event::new()
+                .summary("first-update")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("failed to update event");
+    assert!(matches!(
+        second
+            .update(
+                Event::new()
+                    .summary("second-update")
+                    .starts(start)
+                    .ends(start + Duration::hours(1))
+                    .done(),
+            )
+            .await,
+        Err(CalDavError::Conflict { .. })
+    ));
+    assert_eq!(find().await.summary().await.unwrap(), "first-update");
+}
+
+#[tokio::test]
+async fn test_caldav_update_fetches_missing_etag() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::{client::DavClient, error::CalDavError};
+    use uuid::Uuid;
+
+    let server = ariel::TestServer::start().await;
+    server.hide_put_etags();
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let uid = Uuid::new_v4().to_string();
+    let start = Utc::now() + Duration::days(30);
+    let event = |summary: &str| {
+        Event::new()
+            .uid(&uid)
+            .summary(summary)
+            .starts(start)
+            .ends(start + Duration::hours(1))
+            .done()
+    };
+    calendar
+        .save_event(event("original-summary"))
+        .await
+        .expect("failed to add event");
+    let find = || async {
+        for found in calendar
+            .date_search(start - Duration::minutes(1), start + Duration::hours(2))
+            .await
+            .expect("failed to search for dates")
+        {
+            if found.uid().await.unwrap() == uid {
+                return found;
+            }
+        }
+        panic!("the event should have been found");
+    };
+    let first = find().await;
+    let original = first.etag();
+    first
+        .update(event("first-update"))
+        .await
+        .expect("failed to update event");
+    // the server didn't send the new ETag, so it should have been fetched
+    assert!(first.etag().is_some());
+    assert_ne!(first.etag(), original);
+    first
+        .update(event("second-update"))
+        .await
+        .expect("failed to update event");
+    // changes made by other people still aren't overwritten
+    find()
+        .await
+        .update(event("someone-else's-update"))
+        .await
+        .expect("failed to update event");
+    assert!(matches!(
+        first.update(event("third-update")).await,
+        Err(CalDavError::Conflict { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_caldav_recurring_event_occurrences() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::{client::DavClient, event::EventTime};
+    use uuid::Uuid;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let uid = Uuid::new_v4().to_string();
+    let start = Utc::now() + Duration::days(60);
+    calendar
+        .save_event(
+            Event::new()
+                .uid(&uid)
+                .summary("weekly-event")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .add_property("RRULE", "FREQ=WEEKLY;COUNT=3")
+                .done(),
+        )
+        .await
+        .expect("failed to add event");
+    let until = start + Duration::days(28);
+    let mut occurrences = vec![];
+    for event in calendar
+        .date_search(start - Duration::minutes(1), until)
+        .await
+        .expect("failed to search for dates")
+    {
+        if event.uid().await.unwrap() == uid {
+            occurrences = event.occurrences(start, until).await.unwrap();
+        }
+    }
+    assert_eq!(occurrences.len(), 3);
+    let mut previous = None;
+    for occurrence in occurrences {
+        match (occurrence.start, occurrence.end) {
+            (EventTime::Utc(start_time), Some(EventTime::Utc(end_time))) => {
+                if let Some(previous) = previous {
+                    assert_eq!(start_time - previous, Duration::weeks(1));
+                }
+                assert_eq!(end_time - start_time, Duration::hours(1));
+                previous = Some(start_time);
+            }
+            other => panic!("expected the occurrence to be in UTC, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_caldav_free_busy() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::client::DavClient;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let start = Utc::now() + Duration::days(90);
+    calendar
+        .save_event(
+            Event::new()
+                .summary("busy")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("failed to add event");
+    let busy = calendar
+        .free_busy(start - Duration::hours(1), start + Duration::hours(2))
+        .await
+        .expect("failed to query free/busy time");
+    assert!(busy.iter().any(|period| period.start <= start
+        && period.end >= start + Duration::hours(1) - Duration::seconds(1)));
+}
+
+#[tokio::test]
+async fn test_caldav_make_calendar() {
+    use prospero::client::{DavClient, MakeCalendar};
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.home_set_url());
+    let id = uuid::Uuid::new_v4().to_string();
+    let calendar = client
+        .make_calendar(
+            MakeCalendar::new()
+                .name("Study plan <escaped> & named".to_string())
+                .id(id.clone())
+                .description("A calendar for the study plan".to_string())
+                .colour("#1b998bff".to_string())
+                .timezone(chrono_tz::Europe::London),
+        )
+        .await
+        .expect("failed to create the calendar");
+    assert_eq!(calendar.url(), server.calendar_url_of(&id));
+    let calendars = client.calendars().await.expect("failed to list calendars");
+    let created = calendars
+        .iter()
+        .find(|found| found.calendar.url() == calendar.url())
+        .expect("the new calendar should have been found");
+    assert_eq!(
+        created.display_name.as_deref(),
+        Some("Study plan <escaped> & named")
+    );
+    assert_eq!(created.colour.as_deref(), Some("#1b998bff"));
+    assert!(created.supports_events());
+    // creating the same calendar again should fail
+    assert!(client
+        .make_calendar(MakeCalendar::new().id(id))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_caldav_bearer_auth() {
+    use chrono::{Duration, Utc};
+    use prospero::{client::DavClient, error::CalDavError};
+
+    let server =
+        ariel::TestServer::start_with_auth(ariel::Auth::Bearer("some-token".to_string())).await;
+    let start = Utc::now();
+    let client = DavClient::new_oauth(server.calendar_url(), "some-token".to_string());
+    assert!(client
+        .calendar()
+        .date_search(start, start + Duration::days(1))
+        .await
+        .expect("the token should have been accepted")
+        .is_empty());
+    let client = DavClient::new_oauth(server.calendar_url(), "another-token".to_string());
+    assert!(matches!(
+        client
+            .calendar()
+            .date_search(start, start + Duration::days(1))
+            .await,
+        Err(CalDavError::Unauthorized)
+    ));
+}
+
+#[tokio::test]
+async fn test_caldav_digest_auth() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::{client::DavClient, error::CalDavError};
+
+    let server = ariel::TestServer::start_with_auth(ariel::Auth::Digest {
+        username: "someone".to_string(),
+        password: "secret".to_string(),
+    })
+    .await;
+    let start = Utc::now() + Duration::days(1);
+    let client = DavClient::new_username_password("someone", "secret", server.calendar_url());
+    let calendar = client.calendar();
+    calendar
+        .save_event(
+            Event::new()
+                .summary("Digest")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("the credentials should have been accepted");
+    let events = calendar
+        .date_search(start, start + Duration::days(1))
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    // the challenge is remembered, so only the first request is challenged
+    assert_eq!(server.challenges(), 1);
+
+    server.expire_nonce();
+    let events = calendar
+        .date_search(start, start + Duration::days(1))
+        .await
+        .expect("the request should have been retried with the new nonce");
+    assert_eq!(events.len(), 1);
+    assert_eq!(server.challenges(), 2);
+
+    let client = DavClient::new_username_password("someone", "wrong", server.calendar_url());
+    assert!(matches!(
+        client
+            .calendar()
+            .date_search(start, start + Duration::days(1))
+            .await,
+        Err(CalDavError::Unauthorized)
+    ));
+}
+
+#[tokio::test]
+async fn test_caldav_basic_auth() {
+    use chrono::{Duration, Utc};
+    use prospero::{client::DavClient, error::CalDavError};
+
+    let server = ariel::TestServer::start_with_auth(ariel::Auth::Basic {
+        username: "someone".to_string(),
+        password: "secret".to_string(),
+    })
+    .await;
+    let start = Utc::now();
+    let client = DavClient::new_username_password("someone", "secret", server.calendar_url());
+    let calendar = client.calendar();
+    for _ in 0..2 {
+        assert!(calendar
+            .date_search(start, start + Duration::days(1))
+            .await
+            .expect("the credentials should have been accepted")
+            .is_empty());
+    }
+    assert_eq!(server.challenges(), 1);
+
+    let client = DavClient::new_username_password("someone", "wrong", server.calendar_url());
+    assert!(matches!(
+        client
+            .calendar()
+            .date_search(start, start + Duration::days(1))
+            .await,
+        Err(CalDavError::Unauthorized)
+    ));
+}
+
+#[tokio::test]
+async fn test_caldav_sync() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::client::DavClient;
+
+    let server = ariel::TestServer::start().await;
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let start = Utc::now() + Duration::days(1);
+    let event = |summary: &'static str| {
+        Event::new()
+            .summary(summary)
+            .starts(start)
+            .ends(start + Duration::hours(1))
+            .done()
+    };
+    let first = calendar
+        .save_event(event("first"))
+        .await
+        .expect("failed to add event");
+    let ctag = calendar.ctag().await.unwrap();
+    assert!(ctag.is_some());
+    let synced = calendar.sync(None).await.expect("failed to sync");
+    assert_eq!(synced.changed.len(), 1);
+
+    calendar
+        .save_event(event("second"))
+        .await
+        .expect("failed to add event");
+    first.delete().await.expect("failed to delete event");
+    assert_ne!(calendar.ctag().await.unwrap(), ctag);
+    let changes = calendar
+        .sync(Some(&synced.token))
+        .await
+        .expect("failed to sync");
+    assert_eq!(changes.changed.len(), 1);
+    assert!(changes.changed[0].data.contains("second"));
+    assert_eq!(changes.removed, vec![synced.changed[0].href.clone()]);
+}
+
+#[tokio::test]
+async fn test_caldav_events_not_stored_under_their_uid() {
+    use chrono::{Duration, Utc};
+    use icalendar::{Component, Event};
+    use prospero::client::DavClient;
+
+    let server = ariel::TestServer::start().await;
+    let start = Utc::now() + Duration::days(2);
+    // other clients choose their own names for the resources which they create
+    let event = Event::new()
+        .uid("the-uid")
+        .summary("created elsewhere")
+        .starts(start)
+        .ends(start + Duration::hours(1))
+        .done();
+    let mut calendar_data = icalendar::Calendar::new();
+    calendar_data.push(event);
+    let res = reqwest::Client::new()
+        .put(format!("{}/some-other-name.ics", server.calendar_url()))
+        .header("Content-Type", "text/calendar")
+        .body(calendar_data.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+
+    let client = DavClient::new_unauthenticated(server.calendar_url());
+    let calendar = client.calendar();
+    let events = calendar
+        .date_search(start - Duration::hours(1), start + Duration::hours(2))
+        .await
+        .expect("failed to search for dates");
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].resource_url().await.unwrap(),
+        format!("{}/some-other-name.ics", server.calendar_url())
+    );
+    events[0]
+        .update(
+            Event::new()
+                .summary("changed")
+                .starts(start)
+                .ends(start + Duration::hours(1))
+                .done(),
+        )
+        .await
+        .expect("failed to update event");
+    let objects = server.objects("calendar");
+    assert_eq!(objects.len(), 1);
+    assert!(objects[0].contains("changed"));
+    assert!(objects[0].contains("the-uid"));
+
+    events[0]
+        .clone()
+        .delete()
+        .await
+        .expect("failed to delete event");
+    assert!(server.objects("calendar").is_empty());
+}